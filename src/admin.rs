@@ -0,0 +1,563 @@
+use fibers::net::futures::{Connected, TcpListenerBind};
+use fibers::net::streams::Incoming;
+use fibers::net::{TcpListener, TcpStream};
+use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout};
+use futures::{Async, Future, Poll, Stream};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use trackable::error::ErrorKindExt;
+use url::form_urlencoded;
+
+use audit::{self, Caller};
+use control::Command;
+use event::{ConnectionEvent, ConnectionEvents, EventHub};
+use proxy_server::ACCEPT_RETRY_DELAY;
+use secret::Secret;
+#[cfg(unix)]
+use unix::{self, SocketPermissions, UnixListener, UnixStream};
+use {Error, ErrorKind, Result};
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// A server of the admin HTTP API.
+///
+/// The API has the following endpoints:
+/// - `GET /events?client=<ip>&backend=<addr>`: streams connection events as newline-delimited JSON.
+///   The optional `client` and `backend` parameters filter the events.
+/// - `POST /commands`: applies the `Command` in the request body (e.g., `drain`) to the proxy server.
+///
+/// If `token` is `Some(_)`, every request must have the `Authorization: Bearer <token>` header.
+///
+/// Every request is recorded in the audit log with the address (or the peer credentials) of the client.
+#[derive(Debug)]
+pub(crate) struct AdminServer {
+    listener: AdminListener,
+    accept_retry: Option<Timeout>,
+    token: Option<Secret>,
+    commands: mpsc::Sender<Command>,
+    events: EventHub,
+}
+impl AdminServer {
+    pub fn new(
+        listener: AdminListener,
+        token: Option<Secret>,
+        commands: mpsc::Sender<Command>,
+        events: EventHub,
+    ) -> Self {
+        AdminServer {
+            listener,
+            accept_retry: None,
+            token,
+            commands,
+            events,
+        }
+    }
+
+    /// Polls the listener until it has been bound.
+    pub fn poll_bind(&mut self) -> Poll<(), Error> {
+        track!(self.listener.poll_bind())
+    }
+}
+impl Stream for AdminServer {
+    type Item = AdminSession;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(ref mut retry) = self.accept_retry {
+                if let Async::NotReady = retry.poll().unwrap_or(Async::Ready(())) {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.accept_retry = None;
+            let client = match self.listener.poll() {
+                Err(e) => {
+                    // Only the failures of setting up the listener (e.g., binding) are fatal.
+                    if !self.listener.is_listening() {
+                        return Err(track!(e));
+                    }
+                    log::warn!(
+                        "Cannot accept an admin client (retries after {:?}): {}",
+                        ACCEPT_RETRY_DELAY,
+                        e
+                    );
+                    self.accept_retry = Some(timer::timeout(ACCEPT_RETRY_DELAY));
+                    continue;
+                }
+                Ok(Async::Ready(Some(client))) => client,
+                Ok(_) => return Ok(Async::NotReady),
+            };
+            let session = AdminSession {
+                connected: Some(client),
+                stream: None,
+                caller: None,
+                state: SessionState::ReadRequest,
+                buf: Vec::new(),
+                offset: 0,
+                token: self.token.clone(),
+                commands: self.commands.clone(),
+                events: self.events.clone(),
+            };
+            return Ok(Async::Ready(Some(session)));
+        }
+    }
+}
+
+/// A listener of the admin API, bound to either a TCP address or a Unix socket file.
+#[derive(Debug)]
+pub(crate) enum AdminListener {
+    Tcp {
+        bind: Option<TcpListenerBind>,
+        incoming: Option<Incoming>,
+    },
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+impl AdminListener {
+    pub fn tcp(addr: SocketAddr) -> Self {
+        AdminListener::Tcp {
+            bind: Some(TcpListener::bind(addr)),
+            incoming: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn unix(path: &Path, permissions: &SocketPermissions) -> Self {
+        AdminListener::Unix(UnixListener::bind(path, permissions))
+    }
+
+    fn poll_bind(&mut self) -> Poll<(), Error> {
+        match *self {
+            AdminListener::Tcp {
+                ref mut bind,
+                ref mut incoming,
+            } => {
+                if let Async::Ready(Some(listener)) = track!(bind.poll().map_err(Error::from))? {
+                    log::info!("Admin server started");
+                    *incoming = Some(listener.incoming());
+                    *bind = None;
+                }
+                if incoming.is_some() {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }
+            // The socket file is bound when the listener is made.
+            #[cfg(unix)]
+            AdminListener::Unix(_) => Ok(Async::Ready(())),
+        }
+    }
+
+    fn is_listening(&self) -> bool {
+        match *self {
+            AdminListener::Tcp { ref incoming, .. } => incoming.is_some(),
+            #[cfg(unix)]
+            AdminListener::Unix(ref listener) => listener.is_listening(),
+        }
+    }
+}
+impl Stream for AdminListener {
+    type Item = AdminConnected;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::NotReady = track!(self.poll_bind())? {
+            return Ok(Async::NotReady);
+        }
+        match *self {
+            AdminListener::Tcp {
+                ref mut incoming, ..
+            } => {
+                if let Some(ref mut incoming) = *incoming {
+                    if let Async::Ready(Some((client, addr))) =
+                        track!(incoming.poll().map_err(Error::from))?
+                    {
+                        log::debug!("New admin client: {}", addr);
+                        return Ok(Async::Ready(Some(AdminConnected::Tcp(client))));
+                    }
+                }
+                Ok(Async::NotReady)
+            }
+            #[cfg(unix)]
+            AdminListener::Unix(ref mut listener) => {
+                if let Async::Ready(Some(client)) =
+                    track!(listener.poll(), "{:?}", listener.path())?
+                {
+                    log::debug!("New admin client on {:?}", listener.path());
+                    return Ok(Async::Ready(Some(AdminConnected::Unix(client))));
+                }
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AdminConnected {
+    Tcp(Connected),
+    #[cfg(unix)]
+    Unix(unix::Connected),
+}
+impl Future for AdminConnected {
+    type Item = (AdminStream, Caller);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            AdminConnected::Tcp(ref mut f) => {
+                if let Async::Ready(stream) = track!(f.poll().map_err(Error::from))? {
+                    let addr = track!(stream.peer_addr().map_err(Error::from))?;
+                    Ok(Async::Ready((AdminStream::Tcp(stream), Caller::Tcp(addr))))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }
+            #[cfg(unix)]
+            AdminConnected::Unix(ref mut f) => {
+                if let Async::Ready(stream) = track!(f.poll())? {
+                    let credentials = stream
+                        .peer_credentials()
+                        .map_err(|e| log::debug!("Cannot get the peer credentials: {}", e))
+                        .ok();
+                    Ok(Async::Ready((
+                        AdminStream::Unix(stream),
+                        Caller::Unix(credentials),
+                    )))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AdminStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+impl Read for AdminStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            AdminStream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            AdminStream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+impl Write for AdminStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            AdminStream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            AdminStream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            AdminStream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            AdminStream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// A future which handles an admin client.
+#[derive(Debug)]
+pub(crate) struct AdminSession {
+    connected: Option<AdminConnected>,
+    stream: Option<AdminStream>,
+    caller: Option<Caller>,
+    state: SessionState,
+    buf: Vec<u8>,
+    offset: usize,
+    token: Option<Secret>,
+    commands: mpsc::Sender<Command>,
+    events: EventHub,
+}
+impl AdminSession {
+    fn is_authorized(&self, req: &Request) -> bool {
+        let token = if let Some(ref token) = self.token {
+            token
+        } else {
+            return true;
+        };
+        req.authorization
+            .as_ref()
+            .and_then(|v| v.expose().strip_prefix("Bearer "))
+            .is_some_and(|t| constant_time_eq(t.trim().as_bytes(), token.expose().as_bytes()))
+    }
+
+    fn audit(&self, action: &str, outcome: &str) {
+        let caller = self.caller.as_ref().expect("Never fails");
+        audit::record(caller, action, outcome);
+    }
+
+    fn handle_request(&mut self, req: Request) {
+        self.buf.clear();
+        self.offset = 0;
+        let action = if req.query.is_empty() {
+            format!("{} {}", req.method, req.path)
+        } else {
+            format!("{} {}?{}", req.method, req.path, req.query)
+        };
+        if !self.is_authorized(&req) {
+            self.audit(&action, "unauthorized");
+            log::warn!(
+                "Rejected an unauthorized admin request: {} {}",
+                req.method,
+                req.path
+            );
+            self.buf = b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n\
+                         Content-Type: text/plain\r\nContent-Length: 13\r\n\
+                         Connection: close\r\n\r\nUnauthorized\n"
+                .to_vec();
+            self.state = SessionState::Respond;
+            return;
+        }
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/events") => {
+                let mut filter = EventFilter::default();
+                for (k, v) in form_urlencoded::parse(req.query.as_bytes()) {
+                    match k.as_ref() {
+                        "client" => filter.client = Some(v.into_owned()),
+                        "backend" => filter.backend = Some(v.into_owned()),
+                        _ => {}
+                    }
+                }
+                self.buf.extend_from_slice(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+                      Connection: close\r\n\r\n",
+                );
+                self.state = SessionState::StreamEvents {
+                    events: self.events.subscribe(),
+                    filter,
+                };
+                self.audit(&action, "accepted");
+            }
+            ("POST", "/commands") => {
+                let body = String::from_utf8_lossy(&req.body);
+                let action = format!("{} {}", action, body.trim());
+                let result = body
+                    .trim()
+                    .parse::<Command>()
+                    .map_err(|e| e.to_string())
+                    .and_then(|c| {
+                        log::info!("Received the command {:?} via the admin API", c);
+                        self.commands
+                            .send(c)
+                            .map_err(|_| "Server stopped".to_owned())
+                    });
+                self.buf = match result {
+                    Ok(()) => {
+                        self.audit(&action, "accepted");
+                        response("200 OK", "OK\n")
+                    }
+                    Err(e) => {
+                        self.audit(&action, "rejected");
+                        response("400 Bad Request", &format!("{}\n", e))
+                    }
+                };
+                self.state = SessionState::Respond;
+            }
+            _ => {
+                self.audit(&action, "not_found");
+                self.buf = response("404 Not Found", "Not Found\n");
+                self.state = SessionState::Respond;
+            }
+        }
+    }
+}
+impl Future for AdminSession {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(mut f) = self.connected.take() {
+            if let Async::Ready((stream, caller)) = track!(f.poll())? {
+                self.stream = Some(stream);
+                self.caller = Some(caller);
+            } else {
+                self.connected = Some(f);
+                return Ok(Async::NotReady);
+            }
+        }
+        loop {
+            let stream = self.stream.as_mut().expect("Never fails");
+            match self.state {
+                SessionState::ReadRequest => {
+                    if let Some(req) = track!(Request::parse(&self.buf))? {
+                        self.handle_request(req);
+                        continue;
+                    }
+                    track_assert!(
+                        self.buf.len() < MAX_REQUEST_SIZE,
+                        ErrorKind::InvalidInput,
+                        "Too large request"
+                    );
+                    let mut chunk = [0; 4096];
+                    match stream.read(&mut chunk) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(track!(Error::from(e))),
+                        Ok(0) => track_panic!(ErrorKind::Io, "Unexpected EOS"),
+                        Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+                    }
+                }
+                SessionState::Respond => {
+                    if track!(write_buf(stream, &self.buf, &mut self.offset))? {
+                        return Ok(Async::Ready(()));
+                    }
+                    return Ok(Async::NotReady);
+                }
+                SessionState::StreamEvents {
+                    ref mut events,
+                    ref filter,
+                } => {
+                    if !track!(write_buf(stream, &self.buf, &mut self.offset))? {
+                        return Ok(Async::NotReady);
+                    }
+                    self.buf.clear();
+                    self.offset = 0;
+                    match events.poll().expect("Never fails") {
+                        Async::NotReady => return Ok(Async::NotReady),
+                        Async::Ready(None) => return Ok(Async::Ready(())),
+                        Async::Ready(Some(event)) => {
+                            if filter.is_match(&event) {
+                                let json = track!(serdeconv::to_json_string(&event)
+                                    .map_err(|e| Error::from(ErrorKind::Other.takes_over(e))))?;
+                                self.buf.extend_from_slice(json.as_bytes());
+                                self.buf.push(b'\n');
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SessionState {
+    ReadRequest,
+    Respond,
+    StreamEvents {
+        events: ConnectionEvents,
+        filter: EventFilter,
+    },
+}
+
+#[derive(Debug, Default)]
+struct EventFilter {
+    client: Option<String>,
+    backend: Option<String>,
+}
+impl EventFilter {
+    fn is_match(&self, event: &ConnectionEvent) -> bool {
+        if let Some(ref client) = self.client {
+            if !is_addr_match(client, event.client) {
+                return false;
+            }
+        }
+        if let Some(ref backend) = self.backend {
+            if !event.backend().is_some_and(|b| is_addr_match(backend, b)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn is_addr_match(pattern: &str, addr: SocketAddr) -> bool {
+    if let Ok(a) = pattern.parse::<SocketAddr>() {
+        a == addr
+    } else {
+        pattern.parse() == Ok(addr.ip())
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<Secret>,
+    body: Vec<u8>,
+}
+impl Request {
+    fn parse(buf: &[u8]) -> Result<Option<Self>> {
+        let head_end = if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            i
+        } else {
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&buf[..head_end]);
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split_whitespace();
+        let method = request_line.next().unwrap_or("").to_owned();
+        let target = track_assert_some!(
+            request_line.next(),
+            ErrorKind::InvalidInput,
+            "Malformed request line"
+        );
+        let (path, query) = match target.find('?') {
+            Some(i) => (target[..i].to_owned(), target[i + 1..].to_owned()),
+            None => (target.to_owned(), String::new()),
+        };
+
+        let mut content_length = 0;
+        let mut authorization = None;
+        for line in lines {
+            let mut tokens = line.splitn(2, ':');
+            let name = tokens.next().unwrap_or("").trim();
+            let value = tokens.next().unwrap_or("").trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = track!(value.parse::<usize>().map_err(Error::from))?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(Secret::new(value));
+            }
+        }
+
+        let body_start = head_end + 4;
+        if buf.len() < body_start + content_length {
+            return Ok(None);
+        }
+        let body = buf[body_start..body_start + content_length].to_vec();
+        Ok(Some(Request {
+            method,
+            path,
+            query,
+            authorization,
+            body,
+        }))
+    }
+}
+
+fn response(status: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Compares `a` and `b` in time independent of the position of the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn write_buf(stream: &mut AdminStream, buf: &[u8], offset: &mut usize) -> Result<bool> {
+    while *offset < buf.len() {
+        match stream.write(&buf[*offset..]) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(track!(Error::from(e))),
+            Ok(0) => track_panic!(ErrorKind::Io, "Cannot write to the admin client"),
+            Ok(size) => *offset += size,
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,28 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A hook which decides whether each accepted client is admitted.
+///
+/// A filter is added to a proxy server by `ProxyServerBuilder::accept_filter`, and is consulted
+/// immediately after a client is accepted (i.e., before any other checks, the Consul query and
+/// the connect to a server). Since it is invoked on the accepting fiber, it should return quickly.
+pub trait AcceptFilter: fmt::Debug + Send + Sync {
+    /// Returns whether the client connected from `peer` is admitted.
+    fn filter(&self, peer: SocketAddr) -> Admission;
+}
+
+/// A decision made by `AcceptFilter::filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Admits the client.
+    Allow,
+
+    /// Admits the client, but delays the setup of the connection for the given duration.
+    ///
+    /// If the connection is also delayed by rate limits, the longest delay is applied.
+    Delay(Duration),
+
+    /// Refuses (i.e., closes) the connection.
+    Deny,
+}
@@ -0,0 +1,74 @@
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+
+use event::unix_time_ms;
+#[cfg(unix)]
+use unix::PeerCredentials;
+
+/// The `log` target of audit records (e.g., `RUST_LOG=cotoxy::audit=info`).
+const TARGET: &str = "cotoxy::audit";
+
+/// The originator of an administrative operation.
+#[derive(Debug, Clone)]
+pub(crate) enum Caller {
+    /// A client of the admin API on TCP.
+    Tcp(SocketAddr),
+
+    /// A client of the admin API on a Unix socket.
+    #[cfg(unix)]
+    Unix(Option<PeerCredentials>),
+
+    /// A Consul user event which carries a command.
+    ConsulEvent(String),
+
+    /// A scheduled maintenance window.
+    MaintenanceWindow,
+
+    /// Settings stored under the given prefix of the Consul KV store.
+    ConsulKv(String),
+
+    /// A `CommandSender` handle in the process.
+    Handle,
+}
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Caller::Tcp(addr) => write!(f, "tcp:{}", addr),
+            #[cfg(unix)]
+            Caller::Unix(Some(ref c)) => {
+                write!(f, "unix:pid={},uid={},gid={}", c.pid, c.uid, c.gid)
+            }
+            #[cfg(unix)]
+            Caller::Unix(None) => write!(f, "unix"),
+            Caller::ConsulEvent(ref id) => write!(f, "consul-event:{}", id),
+            Caller::MaintenanceWindow => write!(f, "maintenance-window"),
+            Caller::ConsulKv(ref prefix) => write!(f, "consul-kv:{}", prefix),
+            Caller::Handle => write!(f, "handle"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    unix_time_ms: u64,
+    caller: String,
+    action: &'a str,
+    outcome: &'a str,
+}
+
+/// Logs an audit record saying that `caller` requested `action`, which resulted in `outcome`.
+///
+/// Records are logged as JSON at the `info` level to the `cotoxy::audit` target.
+pub(crate) fn record(caller: &Caller, action: &str, outcome: &str) {
+    let record = AuditRecord {
+        unix_time_ms: unix_time_ms(),
+        caller: caller.to_string(),
+        action,
+        outcome,
+    };
+    match serde_json::to_string(&record) {
+        Ok(json) => log::info!(target: TARGET, "{}", json),
+        Err(e) => log::error!("Cannot serialize an audit record {:?}: {}", record, e),
+    }
+}
@@ -0,0 +1,148 @@
+use fibers::sync::oneshot;
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use futures::{future, Future as Future01};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use event::EventHub;
+use {CommandSender, ConnectionEvents, Error, ProxyServerBuilder, Result, Stats};
+
+/// A proxy server running on a dedicated thread, which implements `std::future::Future`.
+///
+/// The server is driven by an executor of `fibers` on the thread,
+/// so applications using `async`/`await` based executors can embed it without any compatibility layer.
+///
+/// The future resolves when the server stops (i.e., on an error).
+/// Dropping the future stops the server.
+#[derive(Debug)]
+pub struct BackgroundServer {
+    local_addr: SocketAddr,
+    stats: Arc<Stats>,
+    event_hub: EventHub,
+    commands: CommandSender,
+    state: Arc<Mutex<State>>,
+    _stop: oneshot::Sender<()>,
+}
+impl BackgroundServer {
+    pub(crate) fn spawn(builder: &ProxyServerBuilder) -> Result<Self> {
+        let builder = builder.clone();
+        let state = Arc::new(Mutex::new(State::default()));
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let thread_state = Arc::clone(&state);
+        let thread = thread::Builder::new()
+            .name("cotoxy".to_owned())
+            .spawn(move || {
+                let result = run(&builder, stop_rx, started_tx);
+                let mut state = thread_state.lock().expect("Never fails");
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+        track!(thread.map_err(Error::from))?;
+
+        let (local_addr, stats, event_hub, commands) = match started_rx.recv() {
+            Ok(started) => started,
+            Err(_) => {
+                let mut state = state.lock().expect("Never fails");
+                return Err(track!(state
+                    .result
+                    .take()
+                    .expect("Never fails")
+                    .unwrap_err()));
+            }
+        };
+        Ok(BackgroundServer {
+            local_addr,
+            stats,
+            event_hub,
+            commands,
+            state,
+            _stop: stop_tx,
+        })
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Returns a stream of the events which occur on the connections of the server from now on.
+    ///
+    /// See `ProxyServer::events`.
+    pub fn events(&self) -> ConnectionEvents {
+        self.event_hub.subscribe()
+    }
+
+    /// Returns a handle to apply `Command`s to the server.
+    ///
+    /// See `ProxyServer::commands`.
+    pub fn commands(&self) -> CommandSender {
+        self.commands.clone()
+    }
+}
+impl Future for BackgroundServer {
+    type Output = Result<()>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().expect("Never fails");
+        if let Some(result) = state.result.take() {
+            Poll::Ready(result)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+type Started = (SocketAddr, Arc<Stats>, EventHub, CommandSender);
+
+#[derive(Debug, Default)]
+struct State {
+    result: Option<Result<()>>,
+    waker: Option<Waker>,
+}
+
+/// Runs the server built by `builder` until `stop` completes.
+///
+/// The address, the statistics, the event hub and the command handle of the server
+/// are sent to `started` when it is bound.
+fn run(
+    builder: &ProxyServerBuilder,
+    stop: oneshot::Receiver<()>,
+    started: mpsc::Sender<Started>,
+) -> Result<()> {
+    let mut executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+    let mut server = builder.finish(executor.handle());
+    let mut started = Some(started);
+    let server = future::poll_fn(move || {
+        let result = server.poll();
+        if let Some(addr) = server.local_addr() {
+            if let Some(started) = started.take() {
+                let _ = started.send((
+                    addr,
+                    server.stats(),
+                    server.event_hub().clone(),
+                    server.commands(),
+                ));
+            }
+        }
+        result
+    });
+
+    // The sender is never used for sending, so the receiver completes (with an error) when it is dropped.
+    let stop = stop.then(|_| Ok(()));
+    let fiber = executor.spawn_monitor(server.select(stop).map(|_| ()).map_err(|(e, _)| e));
+    let result = track!(executor.run_fiber(fiber).map_err(Error::from))?;
+    track!(result.map_err(Error::from))
+}
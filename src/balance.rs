@@ -0,0 +1,364 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use consul::{HealthStatus, ServiceNode};
+use random;
+use stats::Stats;
+use {Error, ErrorKind, Result};
+
+/// A strategy of balancing connections across the candidate servers.
+///
+/// The strategy decides the order in which the candidates are tried for each connection
+/// (if a candidate cannot be connected, the next one is tried).
+///
+/// This is (de)serialized as `"ordered"`, `"round_robin"`, `"random"`, `"weighted"`, `"least_loaded"`
+/// or `"lowest_latency"`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// The candidates are tried in the order returned by Consul
+    /// (which is sorted by round trip time if `ConsulSettings::near` is set).
+    #[default]
+    Ordered,
+
+    /// The first candidate rotates through the candidates across connections,
+    /// so that connections are spread evenly even if Consul returns the nodes in a stable order.
+    RoundRobin,
+
+    /// The candidates are shuffled for each connection,
+    /// so that the proxies in a fleet do not all connect to the same node first.
+    Random,
+
+    /// The candidates are ordered randomly in proportion to their weights (see `ServiceNode::weights`),
+    /// so that traffic can be shifted gradually by changing the weights in Consul.
+    ///
+    /// The `warning` weight is used for the nodes whose health checks are warning, and the `passing` one otherwise.
+    /// The nodes whose weight is zero are tried only after the others.
+    Weighted,
+
+    /// The candidates are ordered by the "power of two choices": two random candidates are sampled,
+    /// and the one which has fewer active connections from the proxy is tried first, and so on.
+    ///
+    /// This spreads connections evenly across backends which serve them at different speeds,
+    /// without the herd behavior of always picking the least loaded one.
+    LeastLoaded,
+
+    /// The candidates are ordered by the "power of two choices" like `LeastLoaded`,
+    /// but by the exponentially weighted moving average of their recent TCP connect latencies.
+    ///
+    /// A failed connect counts as a latency of the connect timeout, and the averages decay toward zero
+    /// while not updated, so that slow (or far-away) backends are routed around but retried eventually.
+    LowestLatency,
+}
+impl FromStr for LoadBalancing {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ordered" => Ok(LoadBalancing::Ordered),
+            "round_robin" => Ok(LoadBalancing::RoundRobin),
+            "random" => Ok(LoadBalancing::Random),
+            "weighted" => Ok(LoadBalancing::Weighted),
+            "least_loaded" => Ok(LoadBalancing::LeastLoaded),
+            "lowest_latency" => Ok(LoadBalancing::LowestLatency),
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "Unknown load balancing strategy: {:?}",
+                s
+            ),
+        }
+    }
+}
+impl fmt::Display for LoadBalancing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadBalancing::Ordered => write!(f, "ordered"),
+            LoadBalancing::RoundRobin => write!(f, "round_robin"),
+            LoadBalancing::Random => write!(f, "random"),
+            LoadBalancing::Weighted => write!(f, "weighted"),
+            LoadBalancing::LeastLoaded => write!(f, "least_loaded"),
+            LoadBalancing::LowestLatency => write!(f, "lowest_latency"),
+        }
+    }
+}
+
+/// Orders the candidates of each connection by a `LoadBalancing` strategy.
+///
+/// If a preferred node metadata entry is given (see `ProxyServerBuilder::prefer_node_meta`),
+/// the candidates which have it are ordered before the others, each group by the strategy.
+/// If a slow-start window is given (see `ProxyServerBuilder::slow_start`), newly discovered candidates
+/// are then moved behind the others at random, with a probability decreasing over the window.
+///
+/// This is shared by the connections of a proxy server.
+#[derive(Debug)]
+pub(crate) struct Balancer {
+    strategy: LoadBalancing,
+    preferred_meta: Option<(String, String)>,
+    stats: Arc<Stats>,
+    next: AtomicUsize,
+    latencies: Mutex<HashMap<SocketAddr, Latency>>,
+    slow_start: Option<Duration>,
+    seen: Mutex<Option<HashMap<SocketAddr, Seen>>>,
+}
+impl Balancer {
+    /// The weight of a new sample in the moving averages of connect latencies.
+    const LATENCY_SAMPLE_WEIGHT: f64 = 0.3;
+
+    /// The time constant with which the average connect latency of a backend decays toward zero while not updated.
+    const LATENCY_DECAY: Duration = Duration::from_secs(30);
+
+    /// The time after which the average connect latency of a backend which is not updated is forgotten.
+    const LATENCY_RETENTION: Duration = Duration::from_secs(600);
+
+    /// The fraction of connections which a candidate receives at the beginning of its slow-start window.
+    const SLOW_START_MIN_FRACTION: f64 = 0.1;
+
+    /// The minimum time after which a backend which is no longer seen is forgotten
+    /// (and starts slowly again if it appears later).
+    const MIN_SEEN_RETENTION: Duration = Duration::from_secs(60);
+
+    pub fn new(
+        strategy: LoadBalancing,
+        preferred_meta: Option<(String, String)>,
+        slow_start: Option<Duration>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Balancer {
+            strategy,
+            preferred_meta,
+            stats,
+            next: AtomicUsize::new(0),
+            latencies: Mutex::new(HashMap::new()),
+            slow_start,
+            seen: Mutex::new(None),
+        }
+    }
+
+    /// Records the time taken by a TCP connect to `addr` (or the connect timeout, if it failed).
+    pub fn record_connect(&self, addr: SocketAddr, latency: Duration) {
+        if self.strategy != LoadBalancing::LowestLatency {
+            return;
+        }
+        let now = Instant::now();
+        let mut latencies = self.latencies.lock().expect("Never fails");
+        latencies.retain(|_, l| now.duration_since(l.updated_at) < Self::LATENCY_RETENTION);
+        let latency = latency.as_secs_f64();
+        let average = latencies.get(&addr).map_or(latency, |l| {
+            let current = l.current(now);
+            current + Self::LATENCY_SAMPLE_WEIGHT * (latency - current)
+        });
+        latencies.insert(
+            addr,
+            Latency {
+                average,
+                updated_at: now,
+            },
+        );
+    }
+
+    /// Returns the current average connect latency of `addr` in seconds (zero if unknown).
+    fn latency(&self, addr: SocketAddr) -> f64 {
+        let latencies = self.latencies.lock().expect("Never fails");
+        latencies
+            .get(&addr)
+            .map_or(0.0, |l| l.current(Instant::now()))
+    }
+
+    /// Reorders `candidates` so that they are tried from the first one.
+    ///
+    /// `addr` returns the address to connect to for a candidate (i.e., the one by which its connections are counted).
+    pub fn order<F>(&self, candidates: &mut Vec<ServiceNode>, addr: F)
+    where
+        F: Fn(&ServiceNode) -> SocketAddr,
+    {
+        let fractions = self.slow_start_fractions(candidates, &addr);
+        if let Some((ref key, ref value)) = self.preferred_meta {
+            let (mut local, mut remote): (Vec<_>, Vec<_>) = candidates
+                .drain(..)
+                .partition(|node| node.node_meta.get(key) == Some(value));
+            self.order_by_strategy(&mut local, &addr);
+            self.order_by_strategy(&mut remote, &addr);
+            ramp_up(&mut local, &fractions, &addr);
+            ramp_up(&mut remote, &fractions, &addr);
+            candidates.extend(local);
+            candidates.extend(remote);
+        } else {
+            self.order_by_strategy(candidates, &addr);
+            ramp_up(candidates, &fractions, &addr);
+        }
+    }
+
+    /// Records when `candidates` are seen, and returns the fractions of connections
+    /// which the ones in their slow-start windows receive.
+    ///
+    /// The candidates seen first are not regarded as new, since the proxy has just started.
+    fn slow_start_fractions<F>(
+        &self,
+        candidates: &[ServiceNode],
+        addr: &F,
+    ) -> HashMap<SocketAddr, f64>
+    where
+        F: Fn(&ServiceNode) -> SocketAddr,
+    {
+        let window = if let Some(window) = self.slow_start {
+            window
+        } else {
+            return HashMap::new();
+        };
+        let now = Instant::now();
+        let mut seen = self.seen.lock().expect("Never fails");
+        let initial = seen.is_none();
+        let seen = seen.get_or_insert_with(HashMap::new);
+        let retention = (window * 2).max(Self::MIN_SEEN_RETENTION);
+        seen.retain(|_, s| now.duration_since(s.last) < retention);
+
+        let mut fractions = HashMap::new();
+        for node in candidates {
+            let addr = addr(node);
+            let entry = seen.entry(addr).or_insert(Seen {
+                first: if initial { None } else { Some(now) },
+                last: now,
+            });
+            entry.last = now;
+            if let Some(first) = entry.first {
+                let elapsed = now.duration_since(first);
+                if elapsed < window {
+                    let progress = elapsed.as_secs_f64() / window.as_secs_f64();
+                    let fraction = Self::SLOW_START_MIN_FRACTION
+                        + (1.0 - Self::SLOW_START_MIN_FRACTION) * progress;
+                    fractions.insert(addr, fraction);
+                }
+            }
+        }
+        fractions
+    }
+
+    fn order_by_strategy<F>(&self, candidates: &mut Vec<ServiceNode>, addr: &F)
+    where
+        F: Fn(&ServiceNode) -> SocketAddr,
+    {
+        if candidates.len() < 2 {
+            return;
+        }
+        match self.strategy {
+            LoadBalancing::Ordered => {}
+            LoadBalancing::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = candidates.len();
+                candidates.rotate_left(next % len);
+            }
+            LoadBalancing::Random => {
+                // Fisher-Yates shuffle
+                for i in (1..candidates.len()).rev() {
+                    let j = (random::next_u64() % (i as u64 + 1)) as usize;
+                    candidates.swap(i, j);
+                }
+            }
+            LoadBalancing::Weighted => {
+                // Weighted random sampling without replacement (Efraimidis and Spirakis):
+                // sorting by `-ln(u) / weight` is equivalent to drawing the candidates one by one
+                // with probabilities proportional to their weights.
+                let mut keyed = candidates
+                    .drain(..)
+                    .map(|node| {
+                        let weight = weight(&node);
+                        let key = if weight == 0 {
+                            f64::INFINITY
+                        } else {
+                            let u = 1.0 - random::next_f64(); // (0.0, 1.0]
+                            -u.ln() / f64::from(weight)
+                        };
+                        (key, node)
+                    })
+                    .collect::<Vec<_>>();
+                keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+                candidates.extend(keyed.into_iter().map(|(_, node)| node));
+            }
+            LoadBalancing::LeastLoaded => {
+                two_choices(candidates, |node| {
+                    self.stats.active_connections_of(addr(node)) as f64
+                });
+            }
+            LoadBalancing::LowestLatency => {
+                two_choices(candidates, |node| self.latency(addr(node)));
+            }
+        }
+    }
+}
+
+/// Moves the candidates in their slow-start windows behind the others, unless they win a draw
+/// with the probabilities of `fractions`.
+fn ramp_up<F>(candidates: &mut Vec<ServiceNode>, fractions: &HashMap<SocketAddr, f64>, addr: &F)
+where
+    F: Fn(&ServiceNode) -> SocketAddr,
+{
+    if fractions.is_empty() {
+        return;
+    }
+    let (admitted, deferred): (Vec<_>, Vec<_>) = candidates.drain(..).partition(|node| {
+        fractions
+            .get(&addr(node))
+            .is_none_or(|&fraction| random::next_f64() < fraction)
+    });
+    candidates.extend(admitted);
+    candidates.extend(deferred);
+}
+
+/// Orders `candidates` by repeatedly sampling two of the remaining ones and taking the one whose `cost` is lower.
+fn two_choices<F>(candidates: &mut Vec<ServiceNode>, cost: F)
+where
+    F: Fn(&ServiceNode) -> f64,
+{
+    let mut rest = mem::take(candidates);
+    while rest.len() > 1 {
+        let i = (random::next_u64() % rest.len() as u64) as usize;
+        let mut j = (random::next_u64() % (rest.len() as u64 - 1)) as usize;
+        if j >= i {
+            j += 1;
+        }
+        let chosen = if cost(&rest[j]) < cost(&rest[i]) {
+            j
+        } else {
+            i
+        };
+        candidates.push(rest.swap_remove(chosen));
+    }
+    candidates.extend(rest);
+}
+
+/// When a backend was seen as a candidate first (`None` if it was among the initial candidates) and last.
+#[derive(Debug)]
+struct Seen {
+    first: Option<Instant>,
+    last: Instant,
+}
+
+/// A moving average of the connect latencies of a backend.
+#[derive(Debug)]
+struct Latency {
+    average: f64,
+    updated_at: Instant,
+}
+impl Latency {
+    /// Returns the average decayed by the time since it was updated.
+    fn current(&self, now: Instant) -> f64 {
+        let age = now.duration_since(self.updated_at).as_secs_f64();
+        self.average * (-age / Balancer::LATENCY_DECAY.as_secs_f64()).exp()
+    }
+}
+
+/// Returns the weight of `node` in its current health status.
+fn weight(node: &ServiceNode) -> u32 {
+    if node.status == Some(HealthStatus::Warning) {
+        node.weights.warning
+    } else {
+        node.weights.passing
+    }
+}
@@ -0,0 +1,158 @@
+use fibers::time::timer::{self, Timeout};
+use futures::{Async, Future};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rate_limit::{RateLimit, TokenBucket};
+use {Error, ErrorKind, Result};
+
+/// A cap on the total throughput of proxy channels.
+///
+/// Bytes relayed in both directions are counted against the same limit.
+/// Each channel reserves a small quantum of bytes right before reading and waits for its turn
+/// when the limit is exceeded, so the bandwidth is fairly shared among busy channels.
+/// Only the bytes actually read are charged: the rest of a quantum is returned when the read would block.
+/// A limit can be shared by multiple proxy servers by cloning it.
+///
+/// This is (de)serialized as the number of bytes per second.
+/// Note that each deserialized instance is a distinct limit, which is not shared with the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct BandwidthLimit {
+    inner: Arc<Inner>,
+}
+impl BandwidthLimit {
+    /// Maximum number of bytes reserved by a channel at a time.
+    const MAX_QUANTUM: u64 = 64 * 1024;
+
+    /// Makes a new `BandwidthLimit` which allows `bytes_per_sec` bytes to be relayed per second.
+    pub fn new(bytes_per_sec: u64) -> Result<Self> {
+        track_assert!(
+            bytes_per_sec > 0,
+            ErrorKind::Config,
+            "Bandwidth must be positive"
+        );
+
+        // The bucket holds 100ms worth of bytes, which absorbs the coarse granularity of timers.
+        let quantum = (bytes_per_sec / 100).clamp(1, Self::MAX_QUANTUM);
+        let burst = (bytes_per_sec / 10).max(quantum).min(u64::from(u32::MAX));
+        let limit = track!(RateLimit::new(
+            bytes_per_sec as f64,
+            burst as u32,
+            Duration::from_secs(u64::MAX)
+        ))?;
+        let bucket = TokenBucket::new(&limit, Instant::now());
+        Ok(BandwidthLimit {
+            inner: Arc::new(Inner {
+                bytes_per_sec,
+                quantum: quantum as usize,
+                limit,
+                bucket: Mutex::new(bucket),
+            }),
+        })
+    }
+
+    /// Returns the maximum number of bytes relayed per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.inner.bytes_per_sec
+    }
+
+    /// Reserves a quantum of bytes, and returns its size and how long to wait before using it.
+    fn reserve(&self) -> (usize, Duration) {
+        let mut bucket = self.inner.bucket.lock().expect("Never fails");
+        let size = self.inner.quantum;
+        let wait = bucket
+            .take(&self.inner.limit, size as f64, Instant::now())
+            .expect("Never fails");
+        (size, wait)
+    }
+
+    /// Returns `size` bytes reserved by `reserve` but not used.
+    fn release(&self, size: usize) {
+        if size != 0 {
+            let mut bucket = self.inner.bucket.lock().expect("Never fails");
+            bucket.give_back(size as f64);
+        }
+    }
+}
+impl TryFrom<u64> for BandwidthLimit {
+    type Error = Error;
+    fn try_from(f: u64) -> Result<Self> {
+        track!(BandwidthLimit::new(f))
+    }
+}
+impl From<BandwidthLimit> for u64 {
+    fn from(f: BandwidthLimit) -> Self {
+        f.bytes_per_sec()
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    bytes_per_sec: u64,
+    quantum: usize,
+    limit: RateLimit,
+    bucket: Mutex<TokenBucket>,
+}
+
+/// A handle through which a channel relays bytes in one direction under a `BandwidthLimit`.
+#[derive(Debug)]
+pub struct Throttle {
+    limit: BandwidthLimit,
+    granted: usize,
+    timeout: Option<Timeout>,
+}
+impl Throttle {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Throttle {
+            limit,
+            granted: 0,
+            timeout: None,
+        }
+    }
+
+    /// Returns the number of bytes which can be read now, reserving them if none are left.
+    ///
+    /// This must be called right before a read.
+    /// If it is zero, the current fiber will be woken up when the reserved bytes become available.
+    pub fn allowance(&mut self) -> usize {
+        if self.granted == 0 {
+            let (size, wait) = self.limit.reserve();
+            self.granted = size;
+            if wait > Duration::from_secs(0) {
+                self.timeout = Some(timer::timeout(wait));
+            }
+        }
+        let expired = if let Some(ref mut timeout) = self.timeout {
+            timeout.poll().unwrap_or(Async::Ready(())).is_ready()
+        } else {
+            true
+        };
+        if !expired {
+            return 0;
+        }
+        self.timeout = None;
+        self.granted
+    }
+
+    /// Consumes `size` bytes of the allowance, which have just been read.
+    pub fn consume(&mut self, size: usize) {
+        self.granted -= size;
+    }
+
+    /// Returns the rest of the allowance to the limit, because a read would block.
+    ///
+    /// Otherwise idle channels would hold the bytes (and put the shared limit into debt)
+    /// until they receive something.
+    pub fn release(&mut self) {
+        self.limit.release(self.granted);
+        self.granted = 0;
+    }
+}
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.limit.release(self.granted);
+    }
+}
@@ -0,0 +1,101 @@
+use fibers::fiber::{self, Unpark};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A cap on the total number of bytes buffered by proxy channels.
+///
+/// When the budget is exhausted, channels stop reading from their sockets
+/// until other channels write out their buffered bytes.
+/// A budget can be shared by multiple proxy servers by cloning it.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+impl MemoryBudget {
+    /// Makes a new `MemoryBudget` which allows at most `limit` bytes to be buffered.
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Inner {
+                limit,
+                used: AtomicUsize::new(0),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Makes a new `MemoryBudget` which has no limit.
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    /// Returns the maximum number of bytes allowed to be buffered.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Returns the number of bytes currently buffered.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    /// Reserves up to `max` bytes, and returns the number of the reserved bytes.
+    ///
+    /// If no bytes are available, the current fiber will be unparked when some bytes are released.
+    pub(crate) fn acquire(&self, max: usize) -> usize {
+        if self.inner.limit == usize::MAX {
+            return max;
+        }
+        let size = self.try_acquire(max);
+        if size != 0 || max == 0 {
+            return size;
+        }
+
+        // Registers the fiber before retrying so that a concurrent `release` never be missed.
+        if let Some(unpark) = fiber::with_current_context(|mut c| c.park()) {
+            self.inner.waiters.lock().expect("Never fails").push(unpark);
+        }
+        self.try_acquire(max)
+    }
+
+    /// Releases `size` bytes reserved by `acquire`.
+    pub(crate) fn release(&self, size: usize) {
+        if self.inner.limit == usize::MAX || size == 0 {
+            return;
+        }
+        self.inner.used.fetch_sub(size, Ordering::SeqCst);
+        // Dropping `Unpark`s wakes the waiting fibers up.
+        let waiters = std::mem::take(&mut *self.inner.waiters.lock().expect("Never fails"));
+        drop(waiters);
+    }
+
+    fn try_acquire(&self, max: usize) -> usize {
+        let mut used = self.inner.used.load(Ordering::SeqCst);
+        loop {
+            let size = max.min(self.inner.limit.saturating_sub(used));
+            if size == 0 {
+                return 0;
+            }
+            match self.inner.used.compare_exchange(
+                used,
+                used + size,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return size,
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+    waiters: Mutex<Vec<Unpark>>,
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// The slots of simultaneous connections to each backend server, shared by the connection fibers of a proxy server
+/// (see `ProxyServerBuilder::max_server_connections`).
+///
+/// Unlike `Stats::active_connections_of`, a slot is taken before connecting,
+/// so that concurrent connects to a server never exceed the limit.
+#[derive(Debug)]
+pub(crate) struct ServerSlots {
+    limit: u64,
+    servers: Mutex<HashMap<SocketAddr, u64>>,
+}
+impl ServerSlots {
+    pub fn new(limit: u64) -> Self {
+        ServerSlots {
+            limit,
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a slot of `server`, or returns `None` if the server is at capacity.
+    ///
+    /// The slot is released when the returned `ServerSlot` is dropped.
+    pub fn acquire(self: &Arc<Self>, server: SocketAddr) -> Option<ServerSlot> {
+        let mut servers = self.servers.lock().expect("Never fails");
+        let used = servers.entry(server).or_insert(0);
+        if *used >= self.limit {
+            return None;
+        }
+        *used += 1;
+        Some(ServerSlot {
+            slots: self.clone(),
+            server,
+        })
+    }
+
+    fn release(&self, server: SocketAddr) {
+        let mut servers = self.servers.lock().expect("Never fails");
+        if let Some(used) = servers.get_mut(&server) {
+            *used -= 1;
+            if *used == 0 {
+                servers.remove(&server);
+            }
+        }
+    }
+}
+
+/// A connection slot of a backend server.
+#[derive(Debug)]
+pub(crate) struct ServerSlot {
+    slots: Arc<ServerSlots>,
+    server: SocketAddr,
+}
+impl Drop for ServerSlot {
+    fn drop(&mut self) {
+        self.slots.release(self.server);
+    }
+}
@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, Result};
+
+/// Settings of the detection of clients which rapidly open and close connections.
+///
+/// A client which closes `threshold` connections, each shorter than `short_lifetime`,
+/// within `window` is banned (i.e., its new connections are refused) for `ban_duration`.
+///
+/// This is (de)serialized as a table which has the `threshold`, `short_lifetime_ms`,
+/// `window_secs` and `ban_duration_secs` fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawChurnLimit", into = "RawChurnLimit")]
+pub struct ChurnLimit {
+    threshold: u32,
+    short_lifetime: Duration,
+    window: Duration,
+    ban_duration: Duration,
+}
+impl ChurnLimit {
+    /// Makes a new `ChurnLimit` instance.
+    pub fn new(
+        threshold: u32,
+        short_lifetime: Duration,
+        window: Duration,
+        ban_duration: Duration,
+    ) -> Result<Self> {
+        track_assert!(
+            threshold > 0,
+            ErrorKind::Config,
+            "Threshold must be positive"
+        );
+        Ok(ChurnLimit {
+            threshold,
+            short_lifetime,
+            window,
+            ban_duration,
+        })
+    }
+
+    /// Returns the number of short-lived connections within a window which makes a client banned.
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    /// Returns the lifetime below which a connection is regarded as short-lived.
+    pub fn short_lifetime(&self) -> Duration {
+        self.short_lifetime
+    }
+
+    /// Returns the length of the window in which short-lived connections are counted.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Returns how long a client is banned.
+    pub fn ban_duration(&self) -> Duration {
+        self.ban_duration
+    }
+}
+impl TryFrom<RawChurnLimit> for ChurnLimit {
+    type Error = Error;
+    fn try_from(f: RawChurnLimit) -> Result<Self> {
+        track!(ChurnLimit::new(
+            f.threshold,
+            Duration::from_millis(f.short_lifetime_ms),
+            Duration::from_secs(f.window_secs),
+            Duration::from_secs(f.ban_duration_secs)
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawChurnLimit {
+    threshold: u32,
+    short_lifetime_ms: u64,
+    window_secs: u64,
+    ban_duration_secs: u64,
+}
+impl From<ChurnLimit> for RawChurnLimit {
+    fn from(f: ChurnLimit) -> Self {
+        RawChurnLimit {
+            threshold: f.threshold,
+            short_lifetime_ms: f.short_lifetime.as_millis() as u64,
+            window_secs: f.window.as_secs(),
+            ban_duration_secs: f.ban_duration.as_secs(),
+        }
+    }
+}
+
+/// A detector of connection churn, shared by the accepting fiber and the connection fibers.
+#[derive(Debug)]
+pub(crate) struct ChurnDetector {
+    limit: ChurnLimit,
+    clients: Mutex<Clients>,
+}
+impl ChurnDetector {
+    /// Minimum number of tracked clients which triggers pruning.
+    const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+    pub fn new(limit: ChurnLimit) -> Self {
+        ChurnDetector {
+            limit,
+            clients: Mutex::new(Clients {
+                entries: HashMap::new(),
+                prune_threshold: Self::MIN_PRUNE_THRESHOLD,
+            }),
+        }
+    }
+
+    /// Returns `true` if `client` is currently banned.
+    pub fn is_banned(&self, client: IpAddr) -> bool {
+        let clients = self.clients.lock().expect("Never fails");
+        clients
+            .entries
+            .get(&client)
+            .and_then(|c| c.banned_until)
+            .is_some_and(|t| Instant::now() < t)
+    }
+
+    /// Records a closed connection of `client`, and returns `true` if the client has been newly banned.
+    pub fn record_close(&self, client: IpAddr, lifetime: Duration) -> bool {
+        if lifetime >= self.limit.short_lifetime {
+            return false;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().expect("Never fails");
+        if clients.entries.len() >= clients.prune_threshold {
+            clients.prune(&self.limit, now);
+        }
+        let entry = clients.entries.entry(client).or_insert(ClientChurn {
+            window_start: now,
+            short_connections: 0,
+            banned_until: None,
+        });
+        if entry.banned_until.is_some_and(|t| now < t) {
+            return false;
+        }
+        if now.saturating_duration_since(entry.window_start) > self.limit.window {
+            entry.window_start = now;
+            entry.short_connections = 0;
+        }
+        entry.short_connections += 1;
+        if entry.short_connections < self.limit.threshold {
+            return false;
+        }
+        entry.short_connections = 0;
+        entry.window_start = now;
+        entry.banned_until = Some(now + self.limit.ban_duration);
+        true
+    }
+
+    pub fn limit(&self) -> &ChurnLimit {
+        &self.limit
+    }
+}
+
+#[derive(Debug)]
+struct Clients {
+    entries: HashMap<IpAddr, ClientChurn>,
+    prune_threshold: usize,
+}
+impl Clients {
+    /// Removes the entries of the clients which are neither banned nor in a window.
+    fn prune(&mut self, limit: &ChurnLimit, now: Instant) {
+        self.entries.retain(|_, c| {
+            c.banned_until.is_some_and(|t| now < t)
+                || now.saturating_duration_since(c.window_start) <= limit.window
+        });
+        self.prune_threshold = (self.entries.len() * 2).max(ChurnDetector::MIN_PRUNE_THRESHOLD);
+    }
+}
+
+#[derive(Debug)]
+struct ClientChurn {
+    window_start: Instant,
+    short_connections: u32,
+    banned_until: Option<Instant>,
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use {Error, ErrorKind, Result};
+
+/// An IP network in the CIDR notation (e.g., `192.168.0.0/16` or `fd00::/8`).
+///
+/// An address without the prefix length (e.g., `10.0.0.1`) denotes the single address.
+///
+/// This is (de)serialized as a string in the notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+impl Cidr {
+    /// Makes a new `Cidr` instance.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        track_assert!(
+            prefix_len <= max,
+            ErrorKind::Config,
+            "Too long prefix: {}/{}",
+            addr,
+            prefix_len
+        );
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Returns `true` if `addr` belongs to this network.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated as IPv4 ones.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                prefix_eq(&net.octets(), &a.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                prefix_eq(&net.octets(), &a.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = if let Some(i) = s.find('/') {
+            let addr = track!(s[..i].parse::<IpAddr>().map_err(Error::from), "{:?}", s)?;
+            let prefix_len = track!(s[i + 1..].parse::<u8>().map_err(Error::from), "{:?}", s)?;
+            (addr, prefix_len)
+        } else {
+            let addr = track!(s.parse::<IpAddr>().map_err(Error::from), "{:?}", s)?;
+            (addr, if addr.is_ipv4() { 32 } else { 128 })
+        };
+        track!(Cidr::new(addr, prefix_len))
+    }
+}
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+    fn try_from(f: String) -> Result<Self> {
+        track!(f.parse())
+    }
+}
+impl From<Cidr> for String {
+    fn from(f: Cidr) -> Self {
+        f.to_string()
+    }
+}
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let bytes = usize::from(prefix_len / 8);
+    let bits = prefix_len % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_works() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("fd00::1").to_string(), "fd00::1/128");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_works() {
+        let net = cidr("192.168.0.0/16");
+        assert!(net.contains(ip("192.168.0.1")));
+        assert!(net.contains(ip("192.168.255.255")));
+        assert!(!net.contains(ip("192.169.0.1")));
+
+        let net = cidr("10.0.0.0/13");
+        assert!(net.contains(ip("10.7.255.255")));
+        assert!(!net.contains(ip("10.8.0.0")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("fd00::1")));
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+
+        let net = cidr("fd00::/8");
+        assert!(net.contains(ip("fd12:3456::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped_ipv6_addresses() {
+        let net = cidr("10.0.0.0/8");
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("::ffff:11.1.2.3")));
+
+        // IPv4-compatible addresses (deprecated) are not IPv4 ones.
+        assert!(!net.contains(ip("::10.1.2.3")));
+    }
+
+    #[test]
+    fn serde_works() {
+        let net: Cidr = serde_json::from_str("\"10.0.0.0/8\"").unwrap();
+        assert_eq!(net, cidr("10.0.0.0/8"));
+        assert_eq!(serde_json::to_string(&net).unwrap(), "\"10.0.0.0/8\"");
+        assert!(serde_json::from_str::<Cidr>("\"10.0.0.0/40\"").is_err());
+    }
+}
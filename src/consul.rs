@@ -1,47 +1,356 @@
-use futures::Future;
-use serde::de;
-use serde::{Deserialize, Deserializer};
+use fibers::sync::oneshot;
+use fibers::time::timer::{self, Timeout, TimeoutAfter, TimerExt};
+use futures::future::Join;
+use futures::{Async, Future, Poll, Stream};
+use serde::de::{self, DeserializeOwned, DeserializeSeed};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json;
 use serdeconv;
 use std;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future as StdFuture;
+use std::marker::PhantomData;
 use std::net::{IpAddr, SocketAddr};
-use trackable::error::{ErrorKindExt, Failed};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tokio")]
+use std::task::{self, Context};
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
 use url::Url;
 
-use http;
-use {AsyncResult, Error};
+use audit::{self, Caller};
+use base64;
+use control::{self, Command, DynamicConfig, Exclusions, ServiceTarget};
+use dns::SrvQuery;
+use http::{
+    self, DefaultHttpTransport, HttpFuture, HttpResponse, HttpTransport, ResponseBody,
+    SuccessfulResponse,
+};
+use random;
+use registration::{RegistrationCheck, ServiceRegistration};
+use resolver::{AgentAddr, ConsulAddr};
+use retry::RetryPolicy;
+use secret::{Secret, SecretSource};
+use snapshot::SnapshotFile;
+use stats::{Stats, StatsSnapshot};
+use tls::{self, ConnectCerts, ConnectService, TlsPolicy, TlsSettings, TlsVersion};
+use {Error, ErrorKind, Result};
 
 /// Settings for Consul.
-#[derive(Debug, Clone)]
+///
+/// This is (de)serialized as a table which has the `service`, `consul_addr`, `consul_addr_failover`, `dc`, `dc_failover`, `namespace`, `peer`, `tag`, `tags`, `near`,
+/// `resolve_interval_secs`, `node_meta` (an array of `<key>:<value>` strings), `only_passing` (default: `true`), `tagged_address`, `connect`, `spiffe_id_template`, `consistency`,
+/// `max_stale_ms`, `cached`, `max_cache_age_ms`, `request_timeout_ms`, `retry` (see `RetryPolicy`), `dns_fallback`, `dns_domain`,
+/// `snapshot_file`, `token` and `token_file` fields,
+/// and the TLS related `https`, `ca_file`, `client_cert`, `client_key`, `tls_skip_verify`, `tls_server_name`, `tls_min_version`,
+/// `tls_cipher_suites` and `tls_curves` fields.
+/// The token is redacted when serialized (see `Secret`), and the transport is not (de)serialized
+/// (i.e., deserialized settings use `DefaultHttpTransport`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawConsulSettings", into = "RawConsulSettings")]
 pub struct ConsulSettings {
-    consul_addr: SocketAddr,
+    consul_addr: AgentAddr,
+    resolve_interval: Duration,
     service: String,
     dc: Option<String>,
-    tag: Option<String>,
+    dc_failover: Vec<String>,
+    namespace: Option<String>,
+    peer: Option<String>,
+    tags: Vec<String>,
     near: Option<String>,
     node_meta: Vec<(String, String)>,
+    only_passing: bool,
+    tagged_address: Option<String>,
+    connect: bool,
+    spiffe_id_template: Option<String>,
+    consistency: Consistency,
+    max_stale: Option<Duration>,
+    cached: bool,
+    max_cache_age: Option<Duration>,
+    request_timeout: Duration,
+    retry: Option<RetryPolicy>,
+    dns_fallback: Option<SocketAddr>,
+    dns_domain: String,
+    snapshot: Option<Arc<SnapshotFile>>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    tls_policy: TlsPolicy,
+    transport: Arc<dyn HttpTransport>,
+    custom_transport: bool,
 }
 impl ConsulSettings {
     /// The default consul agent address.
     pub const DEFAULT_CONSUL_ADDR: &'static str = "127.0.0.1:8500";
 
+    /// The default interval of re-resolving the hostname of the consul agent.
+    pub const DEFAULT_RESOLVE_INTERVAL_SECS: u64 = 30;
+
+    /// The default timeout in milliseconds of the queries of the candidate nodes.
+    pub const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 5000;
+
+    /// The default domain of the DNS interface of the consul agent.
+    pub const DEFAULT_DNS_DOMAIN: &'static str = "consul";
+
     /// Makes a new `ConsulSettings` instance.
     pub fn new(service: &str) -> Self {
         ConsulSettings {
-            consul_addr: Self::DEFAULT_CONSUL_ADDR.parse().expect("Never fails"),
+            consul_addr: AgentAddr::new(
+                vec![Self::DEFAULT_CONSUL_ADDR.parse().expect("Never fails")],
+                Duration::from_secs(Self::DEFAULT_RESOLVE_INTERVAL_SECS),
+            ),
+            resolve_interval: Duration::from_secs(Self::DEFAULT_RESOLVE_INTERVAL_SECS),
             service: service.to_owned(),
             dc: None,
-            tag: None,
+            dc_failover: Vec::new(),
+            namespace: None,
+            peer: None,
+            tags: Vec::new(),
             near: None,
             node_meta: Vec::new(),
+            only_passing: true,
+            tagged_address: None,
+            connect: false,
+            spiffe_id_template: None,
+            consistency: Consistency::Default,
+            max_stale: None,
+            cached: false,
+            max_cache_age: None,
+            request_timeout: Duration::from_millis(Self::DEFAULT_REQUEST_TIMEOUT_MS),
+            retry: None,
+            dns_fallback: None,
+            dns_domain: Self::DEFAULT_DNS_DOMAIN.to_owned(),
+            snapshot: None,
+            token: None,
+            tls: None,
+            tls_policy: TlsPolicy::default(),
+            transport: Arc::new(DefaultHttpTransport),
+            custom_transport: false,
+        }
+    }
+
+    /// Makes a new `ConsulSettings` instance from a URL such as
+    /// `https://consul.internal:8501/dc1/my-service?tag=primary&token=...`.
+    ///
+    /// The URL is `<scheme>://<host>[:<port>]/[<dc>/]<service>[?<parameters>]`:
+    /// - `scheme` is `http` or `https` (which implies `https(true)`).
+    /// - `host` and `port` are the address of the consul agent (see `consul_addr`).
+    ///   If the port is omitted, it is `8500` for `http` and `8501` for `https`
+    ///   (an explicit `:80` or `:443` is kept as it is).
+    /// - `dc` is the optional datacenter (see `dc`), and `service` is the name of the service.
+    /// - `parameters` are named after the serialized fields, and set the corresponding settings:
+    ///   `tag` (repeatable), `token`, `token_file`, `namespace`, `peer`, `near`, `node_meta` (`<key>:<value>`, repeatable),
+    ///   `only_passing`, `tagged_address`, `connect`, `spiffe_id_template`, `consistency`, `max_stale_ms`, `cached`, `max_cache_age_ms`,
+    ///   `request_timeout_ms`, `dc_failover` (comma-separated), `dns_fallback`, `dns_domain`, `snapshot_file`,
+    ///   `ca_file`, `client_cert`, `client_key`, `tls_skip_verify`, `tls_server_name`, `tls_min_version` (e.g., `1.2`),
+    ///   `tls_cipher_suites` and `tls_curves` (comma-separated).
+    ///
+    /// Unknown parameters are errors, so that typos are not silently ignored.
+    /// The URL (which may contain the token) never appears in the errors.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let explicit_port = has_explicit_port(url);
+        let url =
+            track!(Url::parse(url).map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+        let default_port = match url.scheme() {
+            "http" => 8500,
+            "https" => 8501,
+            scheme => track_panic!(ErrorKind::InvalidInput, "Unsupported scheme: {:?}", scheme),
+        };
+        let host = track_assert_some!(url.host_str(), ErrorKind::InvalidInput, "No host");
+        // `Url::port` omits the default port of the scheme even if it is written explicitly.
+        let port = if explicit_port {
+            url.port_or_known_default()
+        } else {
+            None
+        };
+        let port = port.unwrap_or(default_port);
+        let consul_addr: ConsulAddr = track!(format!("{}:{}", host, port).parse())?;
+
+        let segments = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let (dc, service) = match segments[..] {
+            [service] => (None, service),
+            [dc, service] => (Some(dc), service),
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "The path is not `/[<dc>/]<service>`: {:?}",
+                url.path()
+            ),
+        };
+        let mut settings = ConsulSettings::new(service);
+        settings.consul_addr(consul_addr);
+        if let Some(dc) = dc {
+            settings.dc(dc);
+        }
+        if url.scheme() == "https" {
+            settings.https(true);
+        }
+
+        let mut client_cert = None;
+        let mut client_key = None;
+        for (key, value) in url.query_pairs() {
+            let value = &*value;
+            match &*key {
+                "tag" => {
+                    settings.add_tag(value);
+                }
+                "token" => {
+                    settings.token(value);
+                }
+                "token_file" => {
+                    settings.token_file(value);
+                }
+                "namespace" => {
+                    settings.namespace(value);
+                }
+                "peer" => {
+                    settings.peer(value);
+                }
+                "near" => {
+                    settings.near(value);
+                }
+                "node_meta" => {
+                    let (key, value) = track!(control::parse_node_meta(value))?;
+                    settings.add_node_meta(&key, &value);
+                }
+                "only_passing" => {
+                    settings.only_passing(track!(parse_bool_param(&key, value))?);
+                }
+                "tagged_address" => {
+                    settings.tagged_address(value);
+                }
+                "connect" => {
+                    settings.connect(track!(parse_bool_param(&key, value))?);
+                }
+                "spiffe_id_template" => {
+                    track!(tls::validate_spiffe_id_template(value))?;
+                    settings.spiffe_id_template(value);
+                }
+                "consistency" => {
+                    settings.consistency(track!(value.parse())?);
+                }
+                "max_stale_ms" => {
+                    settings.max_stale(Duration::from_millis(track!(value
+                        .parse()
+                        .map_err(Error::from))?));
+                }
+                "cached" => {
+                    settings.cached(track!(parse_bool_param(&key, value))?);
+                }
+                "max_cache_age_ms" => {
+                    settings.max_cache_age(Duration::from_millis(track!(value
+                        .parse()
+                        .map_err(Error::from))?));
+                }
+                "request_timeout_ms" => {
+                    settings.request_timeout(Duration::from_millis(track!(value
+                        .parse()
+                        .map_err(Error::from))?));
+                }
+                "dc_failover" => {
+                    settings.dc_failover(value.split(',').map(|dc| dc.to_owned()).collect());
+                }
+                "dns_fallback" => {
+                    settings.dns_fallback(track!(value.parse().map_err(Error::from))?);
+                }
+                "dns_domain" => {
+                    settings.dns_domain(value);
+                }
+                "snapshot_file" => {
+                    settings.snapshot_file(value);
+                }
+                "ca_file" => {
+                    settings.tls_ca_file(value);
+                }
+                "client_cert" => client_cert = Some(value.to_owned()),
+                "client_key" => client_key = Some(value.to_owned()),
+                "tls_skip_verify" => {
+                    settings.tls_skip_verify(track!(parse_bool_param(&key, value))?);
+                }
+                "tls_server_name" => {
+                    settings.tls_server_name(value);
+                }
+                "tls_min_version" => {
+                    settings.tls_min_version(track!(value.parse())?);
+                }
+                "tls_cipher_suites" => {
+                    settings.tls_cipher_suites(value.split(',').map(|s| s.to_owned()).collect());
+                }
+                "tls_curves" => {
+                    settings.tls_curves(value.split(',').map(|s| s.to_owned()).collect());
+                }
+                _ => track_panic!(ErrorKind::InvalidInput, "Unknown parameter: {:?}", key),
+            }
+        }
+        match (client_cert, client_key) {
+            (None, None) => {}
+            (Some(cert), Some(key)) => {
+                settings.tls_client_cert(cert, key);
+            }
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "`client_cert` and `client_key` need to be specified together"
+            ),
         }
+        Ok(settings)
     }
 
     /// Sets the address of the consul agent used by `ProxyServer`.
     ///
+    /// This accepts a `SocketAddr` or a `ConsulAddr`, which may have a hostname instead of an IP address.
+    /// The hostname is resolved in the background, and re-resolved every `resolve_interval`
+    /// so that the agent can fail over by DNS. Requests made before the first resolution fail.
+    ///
+    /// A `ConsulAddr::Unix` address makes requests go over the Unix domain socket of the agent,
+    /// which cannot be combined with `https`.
+    ///
     /// The default value is `ConsulSettings::DEFAULT_CONSUL_ADDR`.
-    pub fn consul_addr(&mut self, addr: SocketAddr) -> &mut Self {
-        self.consul_addr = addr;
+    ///
+    /// This clears `consul_addr_failover`.
+    pub fn consul_addr<A: Into<ConsulAddr>>(&mut self, addr: A) -> &mut Self {
+        self.consul_addr = AgentAddr::new(vec![addr.into()], self.resolve_interval);
+        self
+    }
+
+    /// Sets the addresses of the consul agents to which requests fail over, in order of preference.
+    ///
+    /// When a request to an agent fails (i.e., the agent cannot be connected or returns a 5xx response),
+    /// the following requests are sent to the next available agent, and the failed agent is not used
+    /// for 10 seconds. After that, requests return to the more preferred agents.
+    ///
+    /// The URLs of the HTTP API are made from `consul_addr`, so set `tls_server_name`
+    /// if the agents are queried over HTTPS and have certificates for different names.
+    ///
+    /// The default value is empty.
+    pub fn consul_addr_failover(&mut self, addrs: Vec<ConsulAddr>) -> &mut Self {
+        let mut all = vec![self
+            .consul_addr
+            .addrs()
+            .next()
+            .expect("Never fails")
+            .clone()];
+        all.extend(addrs);
+        self.consul_addr = AgentAddr::new(all, self.resolve_interval);
+        self
+    }
+
+    /// Sets the interval of re-resolving the hostname of `consul_addr`.
+    ///
+    /// The default value is `ConsulSettings::DEFAULT_RESOLVE_INTERVAL_SECS`.
+    pub fn resolve_interval(&mut self, interval: Duration) -> &mut Self {
+        self.resolve_interval = interval;
+        self.consul_addr = AgentAddr::new(self.consul_addr.addrs().cloned().collect(), interval);
         self
     }
 
@@ -53,11 +362,59 @@ impl ConsulSettings {
         self
     }
 
+    /// Sets the datacenters to which the queries fail over, in order of preference.
+    ///
+    /// If the datacenter of `dc` (or of the consul agent, if `dc` is not set) has no available candidates
+    /// (i.e., no nodes are found, or all of them have failed to be connected), the next datacenter in `dcs`
+    /// is queried with the same parameters, and so on.
+    /// If a query fails and a datacenter remains, it is also failed over.
+    ///
+    /// The default value is empty.
+    pub fn dc_failover(&mut self, dcs: Vec<String>) -> &mut Self {
+        self.dc_failover = dcs;
+        self
+    }
+
+    /// Sets the value of the `ns` query parameter of [List Nodes for Service] API.
+    ///
+    /// This selects the [namespace] of the service (Consul Enterprise only).
+    ///
+    /// [List Nodes for Service]: https://www.consul.io/api/catalog.html#list-nodes-for-service
+    /// [namespace]: https://www.consul.io/docs/enterprise/namespaces
+    pub fn namespace(&mut self, namespace: &str) -> &mut Self {
+        self.namespace = Some(namespace.to_owned());
+        self
+    }
+
+    /// Sets the value of the `peer` query parameter of [List Nodes for Service] API.
+    ///
+    /// This selects the service imported from the [peered cluster] of the given name.
+    ///
+    /// [List Nodes for Service]: https://www.consul.io/api/health.html#list-nodes-for-service
+    /// [peered cluster]: https://www.consul.io/docs/connect/cluster-peering
+    pub fn peer(&mut self, peer: &str) -> &mut Self {
+        self.peer = Some(peer.to_owned());
+        self
+    }
+
     /// Sets the value of the `tag` query parameter of [List Nodes for Service] API.
     ///
+    /// This replaces the tags added by `add_tag`.
+    ///
     /// [List Nodes for Service]: https://www.consul.io/api/catalog.html#list-nodes-for-service.
     pub fn tag(&mut self, tag: &str) -> &mut Self {
-        self.tag = Some(tag.to_owned());
+        self.tags = vec![tag.to_owned()];
+        self
+    }
+
+    /// Adds a value of the `tag` query parameter of [List Nodes for Service] API.
+    ///
+    /// The parameter is repeated for each tag, so that only the nodes which have all the tags are candidates
+    /// (this needs Consul 1.9 or later).
+    ///
+    /// [List Nodes for Service]: https://www.consul.io/api/catalog.html#list-nodes-for-service.
+    pub fn add_tag(&mut self, tag: &str) -> &mut Self {
+        self.tags.push(tag.to_owned());
         self
     }
 
@@ -77,129 +434,2899 @@ impl ConsulSettings {
         self
     }
 
-    pub(crate) fn client(&self) -> ConsulClient {
-        ConsulClient {
-            consul_addr: self.consul_addr,
-            query_url: self.build_query_url(),
+    /// Sets whether only the nodes whose health checks are passing are candidates.
+    ///
+    /// If `true`, the [List Nodes for Service (health)] API is queried with the `passing` parameter.
+    /// Otherwise, the [List Nodes for Service] API of the catalog, which also returns failing nodes, is queried.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [List Nodes for Service (health)]: https://www.consul.io/api/health.html#list-nodes-for-service
+    /// [List Nodes for Service]: https://www.consul.io/api/catalog.html#list-nodes-for-service
+    pub fn only_passing(&mut self, only_passing: bool) -> &mut Self {
+        self.only_passing = only_passing;
+        self
+    }
+
+    /// Sets the name of the tagged address of the service (e.g., `lan_ipv6` or `wan_ipv4`) to connect to.
+    ///
+    /// The address and port in the `ServiceTaggedAddresses` of each node are used instead of the default ones
+    /// (see `ServiceNode::tagged_addresses`). Nodes which do not have the tagged address use the default ones.
+    ///
+    /// If omitted, the default address and port are used.
+    pub fn tagged_address(&mut self, name: &str) -> &mut Self {
+        self.tagged_address = Some(name.to_owned());
+        self
+    }
+
+    /// Sets whether the [Connect] sidecar proxies of the service are candidates instead of the service itself.
+    ///
+    /// If `true`, the `connect` variants of the APIs (e.g., `/v1/health/connect/<service>`) are queried.
+    /// Connections to the sidecar proxies need mTLS (see `ProxyServerBuilder::connect`).
+    ///
+    /// The default value is `false`.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub fn connect(&mut self, connect: bool) -> &mut Self {
+        self.connect = connect;
+        self
+    }
+
+    /// Sets the template of the SPIFFE IDs by which the certificates of the [Connect] sidecar proxies
+    /// have to identify the service (see `ProxyServerBuilder::connect`).
+    ///
+    /// The placeholders `{service}`, `{namespace}`, `{dc}` and `{trust_domain}` are replaced with
+    /// the service, the namespace (`default` if omitted), each of the queried datacenters and the trust domain
+    /// of the Connect CA, respectively. The template has to contain `{service}`.
+    ///
+    /// The default value is `spiffe://{trust_domain}/ns/{namespace}/dc/{dc}/svc/{service}`.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub fn spiffe_id_template(&mut self, template: &str) -> &mut Self {
+        self.spiffe_id_template = Some(template.to_owned());
+        self
+    }
+
+    /// Sets the [consistency mode] of the queries of the candidate nodes.
+    ///
+    /// `Consistency::Stale` lets any server of the cluster answer the queries,
+    /// so that the candidates can be found even while the cluster has no leader.
+    ///
+    /// The default value is `Consistency::Default`.
+    ///
+    /// [consistency mode]: https://www.consul.io/api/features/consistency.html
+    pub fn consistency(&mut self, consistency: Consistency) -> &mut Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Sets the maximum staleness of the responses to the queries of the candidate nodes.
+    ///
+    /// Responses whose `X-Consul-LastContact` header (i.e., the time since the answering server
+    /// last contacted the leader) exceeds this are treated as failures.
+    /// This is meaningful only with `Consistency::Stale`, since the other modes are answered by the leader.
+    ///
+    /// If omitted, responses are accepted regardless of their staleness.
+    pub fn max_stale(&mut self, max_stale: Duration) -> &mut Self {
+        self.max_stale = Some(max_stale);
+        self
+    }
+
+    /// Sets whether the queries of the candidate nodes are answered from the [agent cache].
+    ///
+    /// Cached responses are served by the consul agent without querying the servers,
+    /// which greatly reduces the load on them in large fleets. This cannot be combined with `Consistency::Consistent`.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [agent cache]: https://www.consul.io/api/features/caching.html
+    pub fn cached(&mut self, cached: bool) -> &mut Self {
+        self.cached = cached;
+        self
+    }
+
+    /// Sets the maximum age of the cached responses to the queries of the candidate nodes.
+    ///
+    /// Responses whose `Age` header (i.e., the time since the agent cache was updated) exceeds this
+    /// are treated as failures. This is meaningful only with `cached(true)`.
+    ///
+    /// If omitted, cached responses are accepted regardless of their age.
+    pub fn max_cache_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_cache_age = Some(max_age);
+        self
+    }
+
+    /// Sets the timeout of the queries of the candidate nodes.
+    ///
+    /// A query which does not complete within this fails with `ErrorKind::ConsulUnavailable`
+    /// (and is retried, if `retry` is set), so that a hung agent does not stall connections forever.
+    ///
+    /// The default value is `ConsulSettings::DEFAULT_REQUEST_TIMEOUT_MS` milliseconds.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Sets the policy of retrying the queries of the candidate nodes which failed transiently
+    /// (i.e., with `ErrorKind::ConsulUnavailable`, such as a 5xx response or a connection failure to the agent).
+    ///
+    /// The queries are retried before falling back to DNS (see `dns_fallback`) or to the next datacenter.
+    ///
+    /// If omitted, failed queries are not retried.
+    pub fn retry(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Sets the address of the [DNS interface] of the consul agent (e.g., `127.0.0.1:8600`),
+    /// which is queried for the SRV records of the service when a query to the HTTP API fails.
+    ///
+    /// The SRV records of `[<first tag>.]<service>.service[.<dc>|.<peer>.peer].<dns_domain>` are queried
+    /// (`connect` instead of `service` in the Connect mode). The other query parameters
+    /// (e.g., `near` and `node_meta`) are not applied, and the nodes have no metadata.
+    ///
+    /// If omitted, the queries do not fall back to DNS.
+    ///
+    /// [DNS interface]: https://www.consul.io/docs/discovery/dns
+    pub fn dns_fallback(&mut self, addr: SocketAddr) -> &mut Self {
+        self.dns_fallback = Some(addr);
+        self
+    }
+
+    /// Sets the domain of the DNS interface of the consul agent.
+    ///
+    /// The default value is `ConsulSettings::DEFAULT_DNS_DOMAIN`.
+    pub fn dns_domain(&mut self, domain: &str) -> &mut Self {
+        self.dns_domain = domain.trim_matches('.').to_owned();
+        self
+    }
+
+    /// Sets the file to which the last successful responses to the queries of the candidate nodes are written.
+    ///
+    /// The file is loaded when the nodes are queried for the first time, and when a query fails
+    /// (after the retries and the DNS fallback, if any), the nodes in the last response are used instead.
+    /// So a proxy restarted during an outage of the agent can serve with the last known nodes.
+    ///
+    /// If omitted, the responses are not persisted.
+    pub fn snapshot_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.snapshot = Some(Arc::new(SnapshotFile::new(path.as_ref())));
+        self
+    }
+
+    /// Sets the [ACL token] sent to the consul agent in the `X-Consul-Token` header.
+    ///
+    /// The token never appears in query URLs or debug output (see `Secret`).
+    ///
+    /// [ACL token]: https://www.consul.io/api/index.html#authentication
+    pub fn token(&mut self, token: &str) -> &mut Self {
+        self.token = Some(SecretSource::Value(Secret::new(token)));
+        self
+    }
+
+    /// Sets the file which contains the [ACL token] sent to the consul agent.
+    ///
+    /// The file is re-read when its modification time changes, so rotated tokens take effect
+    /// on the next request without restarting the proxy.
+    /// If the file cannot be read, the last token read from it keeps being used.
+    ///
+    /// This overrides `token`.
+    ///
+    /// [ACL token]: https://www.consul.io/api/index.html#authentication
+    pub fn token_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.token = Some(SecretSource::file(path.as_ref()));
+        self
+    }
+
+    /// Sets whether the consul agent is queried over HTTPS.
+    ///
+    /// HTTPS is supported by `DefaultHttpTransport` only if the `tls` feature of this crate is enabled.
+    /// Disabling it also clears the other TLS settings.
+    ///
+    /// The default value is `false`.
+    pub fn https(&mut self, https: bool) -> &mut Self {
+        if !https {
+            self.tls = None;
+        } else {
+            self.tls_mut();
         }
+        self
     }
 
-    fn build_query_url(&self) -> Url {
-        let mut url = Url::parse(&format!("http://{}/v1/catalog/service", self.consul_addr))
-            .expect("Never fails");
-        url.path_segments_mut()
-            .expect("Never fails")
-            .push(&self.service);
-        if let Some(ref dc) = self.dc {
-            url.query_pairs_mut().append_pair("dc", dc);
+    /// Sets the PEM file of the CA certificates used to verify the consul agent.
+    ///
+    /// If omitted, the CA certificates of the system are used.
+    /// This implies `https(true)`.
+    pub fn tls_ca_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.tls_mut().set_ca_file(path.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the PEM files of the client certificate and its private key presented to the consul agent.
+    ///
+    /// The private key needs to be in the PKCS #8, SEC1 (EC) or PKCS #1 (RSA) format, and unencrypted.
+    /// This implies `https(true)`.
+    pub fn tls_client_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cert: P,
+        key: Q,
+    ) -> &mut Self {
+        self.tls_mut()
+            .set_client_cert(cert.as_ref().to_owned(), key.as_ref().to_owned());
+        self
+    }
+
+    /// Sets whether the certificate of the consul agent is left unverified.
+    ///
+    /// This is insecure, and should only be used for testing.
+    /// This implies `https(true)`.
+    ///
+    /// The default value is `false`.
+    pub fn tls_skip_verify(&mut self, skip: bool) -> &mut Self {
+        self.tls_mut().set_skip_verify(skip);
+        self
+    }
+
+    /// Sets the name used to verify the certificate of the consul agent.
+    ///
+    /// If omitted, the hostname (or the IP address) of `consul_addr` is used.
+    /// This implies `https(true)`.
+    pub fn tls_server_name(&mut self, name: &str) -> &mut Self {
+        self.tls_mut().set_server_name(name.to_owned());
+        self
+    }
+
+    /// Sets the minimum TLS version negotiated with the consul agent (if `https` is enabled)
+    /// and with [Connect] sidecar proxies (see `ProxyServerBuilder::connect`).
+    ///
+    /// Unlike the other TLS settings, this does not imply `https(true)`.
+    /// If omitted, the default of OpenSSL is used.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub fn tls_min_version(&mut self, version: TlsVersion) -> &mut Self {
+        self.tls_policy.min_version = Some(version);
+        self.update_tls_policy()
+    }
+
+    /// Sets the cipher suites which may be negotiated with the consul agent (if `https` is enabled)
+    /// and with [Connect] sidecar proxies.
+    ///
+    /// The suites are specified by their OpenSSL names, such as `ECDHE-ECDSA-AES128-GCM-SHA256` (TLS 1.2 or earlier)
+    /// and `TLS_AES_128_GCM_SHA256` (TLS 1.3). The suites of TLS 1.3 are configured separately from the earlier ones,
+    /// thus specifying only either kind leaves the other to the defaults of OpenSSL.
+    /// Unknown names are reported by validation.
+    ///
+    /// Unlike the other TLS settings, this does not imply `https(true)`.
+    /// If empty (the default), the defaults of OpenSSL are used.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub fn tls_cipher_suites(&mut self, suites: Vec<String>) -> &mut Self {
+        self.tls_policy.cipher_suites = suites;
+        self.update_tls_policy()
+    }
+
+    /// Sets the curves (groups), such as `X25519` and `P-256`, which may be used for key exchanges
+    /// with the consul agent (if `https` is enabled) and with [Connect] sidecar proxies, in order of preference.
+    ///
+    /// Unlike the other TLS settings, this does not imply `https(true)`.
+    /// If empty (the default), the defaults of OpenSSL are used.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub fn tls_curves(&mut self, curves: Vec<String>) -> &mut Self {
+        self.tls_policy.curves = curves;
+        self.update_tls_policy()
+    }
+
+    fn update_tls_policy(&mut self) -> &mut Self {
+        if self.tls.is_some() {
+            let policy = self.tls_policy.clone();
+            self.tls_mut().set_policy(policy);
         }
-        if let Some(ref tag) = self.tag {
-            url.query_pairs_mut().append_pair("tag", tag);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        track_assert_ne!(
+            self.resolve_interval,
+            Duration::from_secs(0),
+            ErrorKind::Config,
+            "Zero resolve interval"
+        );
+        track_assert!(
+            !(self.cached && self.consistency == Consistency::Consistent),
+            ErrorKind::Config,
+            "The agent cache cannot be used with the consistent mode"
+        );
+        track_assert_ne!(
+            self.request_timeout,
+            Duration::from_secs(0),
+            ErrorKind::Config,
+            "Zero request timeout"
+        );
+        if let Some(ref template) = self.spiffe_id_template {
+            track!(tls::validate_spiffe_id_template(template))?;
         }
-        if let Some(ref near) = self.near {
-            url.query_pairs_mut().append_pair("near", near);
+        track!(self.tls_policy.validate())?;
+        if let Some(ref tls) = self.tls {
+            track_assert!(
+                !self
+                    .consul_addr
+                    .addrs()
+                    .any(|addr| matches!(addr, ConsulAddr::Unix(_))),
+                ErrorKind::Config,
+                "HTTPS over a Unix domain socket is not supported: consul_addr={}",
+                self.consul_addr
+            );
+            track!(tls.load())?;
         }
-        for (k, v) in &self.node_meta {
-            url.query_pairs_mut()
-                .append_pair("node_meta", &format!("{}:{}", k, v));
+        Ok(())
+    }
+
+    /// Validates that the files and sockets opened after the proxy changes its root directory to `dir`
+    /// (see `ProxyServerBuilder::chroot`) exist inside of it.
+    pub(crate) fn validate_chroot(&self, dir: &Path) -> Result<()> {
+        // The working directory is also changed to the new root.
+        let jailed = |path: &Path| dir.join(path.strip_prefix("/").unwrap_or(path));
+        for addr in self.consul_addr.addrs() {
+            match *addr {
+                ConsulAddr::Socket(_) => {}
+                ConsulAddr::Host(ref host, _) => track_assert!(
+                    jailed(Path::new("/etc/resolv.conf")).is_file(),
+                    ErrorKind::Config,
+                    "The agent host {:?} cannot be resolved without etc/resolv.conf in the chroot directory {:?}",
+                    host,
+                    dir
+                ),
+                ConsulAddr::Unix(ref path) => track_assert!(
+                    jailed(path).exists(),
+                    ErrorKind::Config,
+                    "The agent socket {:?} does not exist in the chroot directory {:?}",
+                    path,
+                    dir
+                ),
+            }
         }
-        url
+        if let Some(SecretSource::File(ref file)) = self.token {
+            track_assert!(
+                jailed(file.path()).is_file(),
+                ErrorKind::Config,
+                "The token file {:?} does not exist in the chroot directory {:?}",
+                file.path(),
+                dir
+            );
+        }
+        if let Some(ref snapshot) = self.snapshot {
+            let path = jailed(snapshot.path());
+            track_assert!(
+                path.parent().is_some_and(|p| p.is_dir()),
+                ErrorKind::Config,
+                "The directory of the snapshot file {:?} does not exist in the chroot directory {:?}",
+                snapshot.path(),
+                dir
+            );
+        }
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct ConsulClient {
-    consul_addr: SocketAddr,
-    query_url: Url,
-}
-impl ConsulClient {
-    pub fn find_candidates(&self) -> AsyncResult<Vec<ServiceNode>> {
-        let future = http::get(self.consul_addr, self.query_url.clone()).and_then(|body| {
-            track!(serdeconv::from_json_slice(&body).map_err(|e| Error::from(Failed.takes_over(e))))
-        });
-        Box::new(future)
+    fn tls_mut(&mut self) -> &mut TlsSettings {
+        let policy = &self.tls_policy;
+        Arc::make_mut(self.tls.get_or_insert_with(|| {
+            let mut tls = TlsSettings::default();
+            tls.set_policy(policy.clone());
+            Arc::new(tls)
+        }))
     }
 
-    pub fn query_url(&self) -> &Url {
-        &self.query_url
+    /// Sets the transport used to send HTTP requests to the consul agent.
+    ///
+    /// The default value is `DefaultHttpTransport`.
+    pub fn transport<T: HttpTransport + 'static>(&mut self, transport: T) -> &mut Self {
+        self.transport = Arc::new(transport);
+        self.custom_transport = true;
+        self
     }
-}
 
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-pub struct ServiceNode {
-    #[serde(rename = "ID")]
-    pub id: String,
+    /// Queries the candidate nodes of the service.
+    ///
+    /// This issues the same query as `ProxyServer` does to select a server for a client.
+    /// Like the other futures of this crate, the returned future needs to be run on a fiber of `fibers`
+    /// (unless the configured `HttpTransport` does not depend on it).
+    pub fn find_candidates(&self) -> FindCandidates {
+        self.client()
+            .find_candidates(Arc::new(Exclusions::default()))
+    }
 
-    #[serde(rename = "Node")]
-    pub node: String,
+    /// Queries the candidate nodes of the service on the runtime of `tokio`.
+    ///
+    /// This is the counterpart of `find_candidates` for the `tokio` feature,
+    /// and fails if the settings are invalid or not supported on `tokio` (see `ProxyServerBuilder::finish_tokio`).
+    /// The returned future needs to be polled within the runtime.
+    #[cfg(feature = "tokio")]
+    pub fn find_candidates_tokio(&self) -> Result<TokioFindCandidates> {
+        track!(self.validate())?;
+        track!(self.validate_tokio())?;
+        Ok(self
+            .client()
+            .find_candidates_tokio(Arc::new(Exclusions::default())))
+    }
 
-    #[serde(rename = "Address")]
-    pub address: IpAddr,
+    /// Reports an `ErrorKind::Config` error if these settings need a feature which is not implemented on `tokio`.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn validate_tokio(&self) -> Result<()> {
+        let unsupported = [
+            ("https", self.tls.is_some()),
+            ("connect", self.connect),
+            ("dc_failover", !self.dc_failover.is_empty()),
+            ("retry", self.retry.is_some()),
+            ("dns_fallback", self.dns_fallback.is_some()),
+            ("snapshot_file", self.snapshot.is_some()),
+            ("transport", self.custom_transport),
+        ];
+        if let Some(&(name, _)) = unsupported.iter().find(|&&(_, used)| used) {
+            track_panic!(
+                ErrorKind::Config,
+                "The Consul setting `{}` is not supported on tokio",
+                name
+            );
+        }
+        Ok(())
+    }
 
-    #[serde(rename = "Datacenter")]
-    pub datacenter: String,
+    pub(crate) fn service_name(&self) -> &str {
+        &self.service
+    }
 
-    #[serde(rename = "TaggedAddresses")]
-    pub tagged_addresses: TaggedAddresses,
+    /// Returns a copy of the settings which queries `target` instead.
+    pub(crate) fn retarget(&self, target: &ServiceTarget) -> Self {
+        let mut settings = self.clone();
+        settings.service = target.service.clone();
+        settings.tags = target.tag.iter().cloned().collect();
+        settings.dc = target.dc.clone();
+        settings.near = target.near.clone();
+        settings.node_meta = target.node_meta.clone();
+        settings
+    }
 
-    #[serde(rename = "NodeMeta")]
-    pub node_meta: HashMap<String, String>,
+    /// Returns a copy of the settings which queries `service` (having `tag`, if any) instead.
+    pub(crate) fn with_service(&self, service: &str, tag: Option<&str>) -> Self {
+        let mut settings = self.clone();
+        settings.service = service.to_owned();
+        settings.tags = tag.into_iter().map(ToOwned::to_owned).collect();
+        settings
+    }
+
+    pub(crate) fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns a copy of the settings which filters service nodes on `tags` instead.
+    pub(crate) fn with_tags(&self, tags: &[String]) -> Self {
+        let mut settings = self.clone();
+        settings.tags = tags.to_vec();
+        settings
+    }
+
+    pub(crate) fn client(&self) -> ConsulClient {
+        // Resolves the hostname of the agent (if any) up front, so that the first requests are less likely to fail.
+        self.consul_addr.start();
+        let query_url = Arc::new(self.build_query_url());
+        let failover = self.dc_failover.split_first().map(|(dc, rest)| {
+            let mut settings = self.clone();
+            settings.dc = Some(dc.clone());
+            settings.dc_failover = rest.to_vec();
+            Arc::new(settings.client())
+        });
+        ConsulClient {
+            consul_addr: self.consul_addr.clone(),
+            query_url: query_url.clone(),
+            parse: ParseOptions {
+                health: self.only_passing,
+                tagged_address: self.tagged_address.clone(),
+            },
+            max_stale: self.max_stale,
+            max_cache_age: self.max_cache_age,
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            cache: None,
+            failover,
+            retry: self.retry.clone(),
+            dns: self.srv_query(),
+            snapshot: self.snapshot.clone(),
+            query: Arc::new(CandidatesQuery {
+                consul_addr: self.consul_addr.clone(),
+                url: query_url,
+                max_stale: self.max_stale,
+                max_cache_age: self.max_cache_age,
+                timeout: self.request_timeout,
+                token: self.token.clone(),
+                tls: self.tls.clone(),
+                transport: self.transport.clone(),
+                waiters: Mutex::new(None),
+            }),
+            connect: if self.connect {
+                Some(self.connect_service())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the service whose sidecar proxies are queried in the Connect mode.
+    pub(crate) fn connect_service(&self) -> ConnectService {
+        let service = ConnectService::new(
+            &self.service,
+            self.namespace.as_deref(),
+            self.dc.as_deref(),
+            &self.dc_failover,
+        );
+        if let Some(ref template) = self.spiffe_id_template {
+            service.spiffe_id_template(template)
+        } else {
+            service
+        }
+    }
 
-    #[serde(rename = "CreateIndex")]
-    pub create_index: u64,
+    pub(crate) fn event_watcher(
+        &self,
+        event_name: &str,
+        interval: Duration,
+        jitter: f64,
+    ) -> EventWatcher {
+        let mut url = self.api_url("event/list");
+        url.query_pairs_mut().append_pair("name", event_name);
+        let mut watcher = EventWatcher {
+            consul_addr: self.consul_addr.clone(),
+            url: Arc::new(url),
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            last_ltime: None,
+            interval,
+            jitter,
+            state: EventWatcherState::Wait(timer::timeout(Duration::from_secs(0))),
+            commands: VecDeque::new(),
+        };
+        watcher.state = EventWatcherState::Fetch(Box::new(watcher.fetch()));
+        watcher
+    }
 
-    #[serde(rename = "ModifyIndex")]
-    pub modify_index: u64,
+    /// Makes a watcher of the settings stored under `kv_prefix` of the Consul KV store.
+    ///
+    /// The keys are watched by blocking queries which wait up to `wait`,
+    /// and failed queries are retried every `interval`.
+    pub(crate) fn config_watcher(
+        &self,
+        kv_prefix: &str,
+        wait: Duration,
+        interval: Duration,
+        jitter: f64,
+    ) -> ConfigWatcher {
+        let prefix = kv_prefix.trim_matches('/').to_owned();
+        let mut url = self.api_url("kv");
+        url.path_segments_mut()
+            .expect("Never fails")
+            .extend(prefix.split('/').filter(|s| !s.is_empty()))
+            .push("");
+        url.query_pairs_mut().append_key_only("recurse");
+        let mut watcher = ConfigWatcher {
+            consul_addr: self.consul_addr.clone(),
+            url: Arc::new(url),
+            prefix,
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            index: 0,
+            wait,
+            interval,
+            jitter,
+            last: None,
+            state: ConfigWatcherState::Wait(timer::timeout(Duration::from_secs(0))),
+        };
+        watcher.state = ConfigWatcherState::Fetch(watcher.fetch());
+        watcher
+    }
 
-    #[serde(
-        rename = "ServiceAddress",
-        deserialize_with = "deserialize_maybe_ipaddr"
-    )]
-    pub service_address: Option<IpAddr>,
+    pub(crate) fn stats_publisher(
+        &self,
+        key: &str,
+        interval: Duration,
+        jitter: f64,
+        stats: Arc<Stats>,
+    ) -> StatsPublisher {
+        let mut url = self.api_url("kv");
+        url.path_segments_mut()
+            .expect("Never fails")
+            .extend(key.split('/').filter(|s| !s.is_empty()));
+        StatsPublisher {
+            consul_addr: self.consul_addr.clone(),
+            url: Arc::new(url),
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            service: self.service.clone(),
+            stats,
+            interval,
+            jitter,
+            state: StatsPublisherState::Wait(timer::timeout(random::jitter(interval, jitter))),
+        }
+    }
 
-    #[serde(rename = "ServiceEnableTagOverride")]
-    pub service_enable_tag_override: bool,
+    /// Returns the URL of the HTTP API `/v1/{path}` of the consul agent.
+    fn api_url(&self, path: &str) -> Url {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let host = self.consul_addr.url_host();
+        Url::parse(&format!("{}://{}/v1/{}", scheme, host, path)).expect("Never fails")
+    }
 
-    #[serde(rename = "ServiceID")]
-    pub service_id: String,
+    /// Makes a watcher which keeps the [Connect] certificates of `identity` in `certs` up to date.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    pub(crate) fn connect_watcher(
+        &self,
+        identity: &str,
+        interval: Duration,
+        jitter: f64,
+        certs: Arc<ConnectCerts>,
+    ) -> ConnectWatcher {
+        let roots_url = self.api_url("agent/connect/ca/roots");
+        let mut leaf_url = self.api_url("agent/connect/ca/leaf");
+        leaf_url
+            .path_segments_mut()
+            .expect("Never fails")
+            .push(identity);
+        let mut watcher = ConnectWatcher {
+            consul_addr: self.consul_addr.clone(),
+            roots_url: Arc::new(roots_url),
+            leaf_url: Arc::new(leaf_url),
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            tls_policy: self.tls_policy.clone(),
+            transport: self.transport.clone(),
+            interval,
+            jitter,
+            certs,
+            last: None,
+            state: ConnectWatcherState::Wait(timer::timeout(Duration::from_secs(0))),
+        };
+        watcher.state = ConnectWatcherState::Fetch(Box::new(watcher.fetch()));
+        watcher
+    }
 
-    #[serde(rename = "ServiceName")]
-    pub service_name: String,
+    /// Makes a registrar which registers the proxy server listening on `local_addr` as a Consul service.
+    ///
+    /// Failed registrations are retried every `interval`.
+    pub(crate) fn registrar(
+        &self,
+        registration: &ServiceRegistration,
+        local_addr: SocketAddr,
+        interval: Duration,
+        jitter: f64,
+    ) -> Registrar {
+        let id = registration.service_id(local_addr);
+        let check_id = format!("service:{}", id);
+        let address = registration.service_address(local_addr);
+        let check = match registration.health_check() {
+            RegistrationCheck::Ttl(ttl) => RegisterCheck {
+                check_id: check_id.clone(),
+                ttl: Some(format!("{}ms", ttl.as_millis())),
+                tcp: None,
+                interval: None,
+            },
+            RegistrationCheck::Tcp(interval) => {
+                let ip = address.unwrap_or(match local_addr {
+                    SocketAddr::V4(_) => IpAddr::from([127, 0, 0, 1]),
+                    SocketAddr::V6(_) => IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+                });
+                RegisterCheck {
+                    check_id: check_id.clone(),
+                    ttl: None,
+                    tcp: Some(SocketAddr::new(ip, local_addr.port()).to_string()),
+                    interval: Some(format!("{}ms", interval.as_millis())),
+                }
+            }
+        };
+        let document = RegisterDocument {
+            id: &id,
+            name: registration.name(),
+            tags: registration.tags(),
+            address: address.map(|a| a.to_string()),
+            port: local_addr.port(),
+            check,
+        };
+        let body = serde_json::to_vec(&document).expect("Never fails");
 
-    #[serde(rename = "ServicePort")]
-    pub service_port: u16, // TODO: option
+        let register_url = self.agent_url(&["service", "register"]);
+        let deregister_url = self.agent_url(&["service", "deregister", &id]);
+        let pass_url = if let RegistrationCheck::Ttl(_) = registration.health_check() {
+            Some(Arc::new(self.agent_url(&["check", "pass", &check_id])))
+        } else {
+            None
+        };
+        let mut registrar = Registrar {
+            consul_addr: self.consul_addr.clone(),
+            register_url: Arc::new(register_url),
+            pass_url,
+            deregister_url: Arc::new(deregister_url),
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            service_id: id,
+            body,
+            ttl: match registration.health_check() {
+                RegistrationCheck::Ttl(ttl) => Some(ttl),
+                RegistrationCheck::Tcp(_) => None,
+            },
+            interval,
+            jitter,
+            registered: false,
+            state: RegistrarState::Idle,
+        };
+        registrar.state = registrar.pass_or_register();
+        registrar
+    }
 
-    #[serde(rename = "ServiceTags")]
-    pub service_tags: Vec<String>,
-}
-impl ServiceNode {
-    pub fn socket_addr(&self, port: Option<u16>) -> SocketAddr {
-        SocketAddr::new(
-            self.service_address.unwrap_or(self.address),
-            port.unwrap_or(self.service_port),
-        )
+    /// Returns the URL of the HTTP API `/v1/agent/{segments}` of the consul agent.
+    ///
+    /// The `ns` query parameter is added if the namespace is set.
+    fn agent_url(&self, segments: &[&str]) -> Url {
+        let mut url = self.api_url("agent");
+        url.path_segments_mut()
+            .expect("Never fails")
+            .extend(segments);
+        if let Some(ref ns) = self.namespace {
+            url.query_pairs_mut().append_pair("ns", ns);
+        }
+        url
     }
-}
 
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-pub struct TaggedAddresses {
-    pub lan: IpAddr,
-    pub wan: IpAddr,
-}
-
-fn deserialize_maybe_ipaddr<'de, D>(
-    deserializer: D,
-) -> std::result::Result<Option<IpAddr>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let addr = String::deserialize(deserializer)?;
-    if addr.is_empty() {
-        Ok(None)
-    } else {
-        let addr = addr.parse().map_err(de::Error::custom)?;
-        Ok(Some(addr))
+    /// Returns the DNS query of the service, if `dns_fallback` is set.
+    fn srv_query(&self) -> Option<SrvQuery> {
+        let server = self.dns_fallback?;
+        let mut name = String::new();
+        // DNS queries can filter on a single tag.
+        if let Some(tag) = self.tags.first() {
+            name.push_str(tag);
+            name.push('.');
+        }
+        name.push_str(&self.service);
+        name.push_str(if self.connect { ".connect" } else { ".service" });
+        if let Some(ref peer) = self.peer {
+            name.push('.');
+            name.push_str(peer);
+            name.push_str(".peer");
+        } else if let Some(ref dc) = self.dc {
+            name.push('.');
+            name.push_str(dc);
+        }
+        name.push('.');
+        name.push_str(&self.dns_domain);
+        Some(SrvQuery::new(server, name))
+    }
+
+    fn build_query_url(&self) -> Url {
+        let api = if self.only_passing {
+            "health"
+        } else {
+            "catalog"
+        };
+        let mut url = self.api_url(api);
+        url.path_segments_mut()
+            .expect("Never fails")
+            .push(if self.connect { "connect" } else { "service" })
+            .push(&self.service);
+        if self.only_passing {
+            url.query_pairs_mut().append_pair("passing", "true");
+        }
+        if self.cached {
+            url.query_pairs_mut().append_key_only("cached");
+        }
+        match self.consistency {
+            Consistency::Default => {}
+            Consistency::Stale => {
+                url.query_pairs_mut().append_key_only("stale");
+            }
+            Consistency::Consistent => {
+                url.query_pairs_mut().append_key_only("consistent");
+            }
+        }
+        if let Some(ref dc) = self.dc {
+            url.query_pairs_mut().append_pair("dc", dc);
+        }
+        if let Some(ref ns) = self.namespace {
+            url.query_pairs_mut().append_pair("ns", ns);
+        }
+        if let Some(ref peer) = self.peer {
+            url.query_pairs_mut().append_pair("peer", peer);
+        }
+        for tag in &self.tags {
+            url.query_pairs_mut().append_pair("tag", tag);
+        }
+        if let Some(ref near) = self.near {
+            url.query_pairs_mut().append_pair("near", near);
+        }
+        for (k, v) in &self.node_meta {
+            url.query_pairs_mut()
+                .append_pair("node_meta", &format!("{}:{}", k, v));
+        }
+        url
+    }
+}
+
+impl TryFrom<RawConsulSettings> for ConsulSettings {
+    type Error = Error;
+    fn try_from(f: RawConsulSettings) -> Result<Self> {
+        let mut settings = ConsulSettings::new(&f.service);
+        settings.resolve_interval(Duration::from_secs(f.resolve_interval_secs));
+        settings.consul_addr(f.consul_addr);
+        settings.consul_addr_failover(f.consul_addr_failover);
+        settings.dc = f.dc;
+        settings.dc_failover = f.dc_failover;
+        settings.namespace = f.namespace;
+        settings.peer = f.peer;
+        settings.tags = f.tag.into_iter().chain(f.tags).collect();
+        settings.near = f.near;
+        settings.only_passing = f.only_passing;
+        settings.tagged_address = f.tagged_address;
+        settings.connect = f.connect;
+        settings.spiffe_id_template = f.spiffe_id_template;
+        settings.consistency = f.consistency;
+        settings.max_stale = f.max_stale_ms.map(Duration::from_millis);
+        settings.cached = f.cached;
+        settings.max_cache_age = f.max_cache_age_ms.map(Duration::from_millis);
+        settings.request_timeout = Duration::from_millis(f.request_timeout_ms);
+        settings.retry = f.retry;
+        settings.dns_fallback = f.dns_fallback;
+        settings.dns_domain(&f.dns_domain);
+        if let Some(ref path) = f.snapshot_file {
+            settings.snapshot_file(path);
+        }
+        match (f.token, f.token_file) {
+            (Some(token), None) => settings.token = Some(SecretSource::Value(token)),
+            (None, Some(path)) => {
+                settings.token_file(path);
+            }
+            (None, None) => {}
+            _ => track_panic!(
+                ErrorKind::Config,
+                "`token` and `token_file` must not be specified together"
+            ),
+        }
+        if f.https {
+            settings.https(true);
+        }
+        if let Some(ref path) = f.ca_file {
+            settings.tls_ca_file(path);
+        }
+        match (f.client_cert, f.client_key) {
+            (Some(cert), Some(key)) => {
+                settings.tls_client_cert(cert, key);
+            }
+            (None, None) => {}
+            _ => track_panic!(
+                ErrorKind::Config,
+                "`client_cert` and `client_key` must be specified together"
+            ),
+        }
+        if f.tls_skip_verify {
+            settings.tls_skip_verify(true);
+        }
+        if let Some(ref name) = f.tls_server_name {
+            settings.tls_server_name(name);
+        }
+        if let Some(version) = f.tls_min_version {
+            settings.tls_min_version(version);
+        }
+        settings.tls_cipher_suites(f.tls_cipher_suites);
+        settings.tls_curves(f.tls_curves);
+        for meta in &f.node_meta {
+            settings
+                .node_meta
+                .push(track!(control::parse_node_meta(meta))?);
+        }
+        Ok(settings)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConsulSettings {
+    service: String,
+
+    #[serde(default = "default_consul_addr")]
+    consul_addr: ConsulAddr,
+
+    #[serde(default)]
+    consul_addr_failover: Vec<ConsulAddr>,
+
+    #[serde(default = "default_resolve_interval_secs")]
+    resolve_interval_secs: u64,
+
+    dc: Option<String>,
+
+    #[serde(default)]
+    dc_failover: Vec<String>,
+
+    namespace: Option<String>,
+    peer: Option<String>,
+    tag: Option<String>,
+
+    #[serde(default)]
+    tags: Vec<String>,
+
+    near: Option<String>,
+
+    #[serde(default)]
+    node_meta: Vec<String>,
+
+    #[serde(default = "default_only_passing")]
+    only_passing: bool,
+
+    tagged_address: Option<String>,
+
+    #[serde(default)]
+    connect: bool,
+
+    spiffe_id_template: Option<String>,
+
+    #[serde(default)]
+    consistency: Consistency,
+
+    max_stale_ms: Option<u64>,
+
+    #[serde(default)]
+    cached: bool,
+
+    max_cache_age_ms: Option<u64>,
+
+    #[serde(default = "default_request_timeout_ms")]
+    request_timeout_ms: u64,
+
+    retry: Option<RetryPolicy>,
+
+    dns_fallback: Option<SocketAddr>,
+
+    #[serde(default = "default_dns_domain")]
+    dns_domain: String,
+
+    snapshot_file: Option<PathBuf>,
+
+    token: Option<Secret>,
+    token_file: Option<PathBuf>,
+
+    #[serde(default)]
+    https: bool,
+
+    ca_file: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+
+    #[serde(default)]
+    tls_skip_verify: bool,
+
+    tls_server_name: Option<String>,
+    tls_min_version: Option<TlsVersion>,
+
+    #[serde(default)]
+    tls_cipher_suites: Vec<String>,
+
+    #[serde(default)]
+    tls_curves: Vec<String>,
+}
+impl From<ConsulSettings> for RawConsulSettings {
+    fn from(f: ConsulSettings) -> Self {
+        let tls = f.tls.as_deref();
+        RawConsulSettings {
+            service: f.service,
+            consul_addr: f.consul_addr.addrs().next().expect("Never fails").clone(),
+            consul_addr_failover: f.consul_addr.addrs().skip(1).cloned().collect(),
+            resolve_interval_secs: f.resolve_interval.as_secs(),
+            dc: f.dc,
+            dc_failover: f.dc_failover,
+            namespace: f.namespace,
+            peer: f.peer,
+            tag: None,
+            tags: f.tags,
+            near: f.near,
+            node_meta: f
+                .node_meta
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, v))
+                .collect(),
+            only_passing: f.only_passing,
+            tagged_address: f.tagged_address,
+            connect: f.connect,
+            spiffe_id_template: f.spiffe_id_template,
+            consistency: f.consistency,
+            max_stale_ms: f.max_stale.map(|d| d.as_millis() as u64),
+            cached: f.cached,
+            max_cache_age_ms: f.max_cache_age.map(|d| d.as_millis() as u64),
+            request_timeout_ms: f.request_timeout.as_millis() as u64,
+            retry: f.retry,
+            dns_fallback: f.dns_fallback,
+            dns_domain: f.dns_domain,
+            snapshot_file: f.snapshot.as_ref().map(|s| s.path().to_owned()),
+            token: match f.token {
+                Some(SecretSource::Value(ref token)) => Some(token.clone()),
+                _ => None,
+            },
+            token_file: match f.token {
+                Some(SecretSource::File(ref file)) => Some(file.path().to_owned()),
+                _ => None,
+            },
+            https: f.tls.is_some(),
+            ca_file: tls.and_then(|t| t.ca_file()).map(ToOwned::to_owned),
+            client_cert: tls
+                .and_then(|t| t.client_cert())
+                .map(|(cert, _)| cert.to_owned()),
+            client_key: tls
+                .and_then(|t| t.client_cert())
+                .map(|(_, key)| key.to_owned()),
+            tls_skip_verify: tls.is_some_and(|t| t.skip_verify()),
+            tls_server_name: tls.and_then(|t| t.server_name()).map(ToOwned::to_owned),
+            tls_min_version: f.tls_policy.min_version,
+            tls_cipher_suites: f.tls_policy.cipher_suites,
+            tls_curves: f.tls_policy.curves,
+        }
+    }
+}
+
+fn default_only_passing() -> bool {
+    true
+}
+
+fn default_consul_addr() -> ConsulAddr {
+    ConsulSettings::DEFAULT_CONSUL_ADDR
+        .parse()
+        .expect("Never fails")
+}
+
+fn default_resolve_interval_secs() -> u64 {
+    ConsulSettings::DEFAULT_RESOLVE_INTERVAL_SECS
+}
+
+fn default_request_timeout_ms() -> u64 {
+    ConsulSettings::DEFAULT_REQUEST_TIMEOUT_MS
+}
+
+fn default_dns_domain() -> String {
+    ConsulSettings::DEFAULT_DNS_DOMAIN.to_owned()
+}
+
+/// Returns `true` if the authority of the URL `url` has a port.
+fn has_explicit_port(url: &str) -> bool {
+    let rest = url.split_once("://").map_or("", |x| x.1);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host_port = authority.rsplit('@').next().unwrap_or("");
+
+    // The colons of an IPv6 address are in the brackets.
+    let port = host_port.rsplit(']').next().unwrap_or("");
+    port.rfind(':').is_some_and(|i| i + 1 < port.len())
+}
+
+/// Parses a boolean parameter of a URL given to `ConsulSettings::from_url`.
+fn parse_bool_param(key: &str, value: &str) -> Result<bool> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => track_panic!(
+            ErrorKind::InvalidInput,
+            "Not a boolean: {}={:?}",
+            key,
+            value
+        ),
+    }
+}
+
+/// The [consistency mode] of queries to the Consul agent.
+///
+/// This is (de)serialized as `"default"`, `"stale"` or `"consistent"`.
+///
+/// [consistency mode]: https://www.consul.io/api/features/consistency.html
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    /// Queries are answered by the leader, which may return stale values in rare cases.
+    #[default]
+    Default,
+
+    /// Queries are answered by any server, so they work even while the cluster has no leader.
+    Stale,
+
+    /// Queries are answered by the leader after confirming its leadership.
+    Consistent,
+}
+impl FromStr for Consistency {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Consistency::Default),
+            "stale" => Ok(Consistency::Stale),
+            "consistent" => Ok(Consistency::Consistent),
+            _ => track_panic!(ErrorKind::InvalidInput, "Unknown consistency mode: {:?}", s),
+        }
+    }
+}
+impl fmt::Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Consistency::Default => write!(f, "default"),
+            Consistency::Stale => write!(f, "stale"),
+            Consistency::Consistent => write!(f, "consistent"),
+        }
+    }
+}
+
+/// The latest candidate nodes of a service (before exclusions), which are updated by `CandidatesWatcher`.
+type CandidatesCache = Arc<Mutex<Option<Arc<Vec<ServiceNode>>>>>;
+
+/// The time after which a query made by `CandidatesWatcher` is regarded as timed out
+/// (in addition to the wait time, if it is a blocking query).
+const WATCH_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+
+/// The time after which the deregistration made by `Registrar` is given up (so as not to block shutdown).
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct ConsulClient {
+    consul_addr: AgentAddr,
+    query_url: Arc<Url>,
+    parse: ParseOptions,
+    max_stale: Option<Duration>,
+    max_cache_age: Option<Duration>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    cache: Option<CandidatesCache>,
+    failover: Option<Arc<ConsulClient>>,
+    retry: Option<RetryPolicy>,
+    dns: Option<SrvQuery>,
+    snapshot: Option<Arc<SnapshotFile>>,
+    query: Arc<CandidatesQuery>,
+    connect: Option<ConnectService>,
+}
+impl ConsulClient {
+    /// Queries the candidate nodes of the service except `excluded` ones.
+    ///
+    /// Concurrent queries are coalesced: while a query is in flight,
+    /// the later ones wait for its response instead of issuing new requests.
+    /// If the query fails, it is retried (see `ConsulSettings::retry`),
+    /// and then falls back to DNS (see `ConsulSettings::dns_fallback`)
+    /// and to the last known nodes (see `ConsulSettings::snapshot_file`).
+    pub fn find_candidates(&self, excluded: Arc<Exclusions>) -> FindCandidates {
+        FindCandidates {
+            state: self.query.join(),
+            query: self.query.clone(),
+            retry: self.retry.clone(),
+            retries: 0,
+            dns: self.dns.clone(),
+            snapshot: self.snapshot.clone(),
+            parse: self.parse.clone(),
+            excluded,
+        }
+    }
+
+    /// Queries the candidate nodes of the service except `excluded` ones on the runtime of `tokio`.
+    ///
+    /// Unlike `find_candidates`, queries are neither coalesced nor retried (see `ConsulSettings::validate_tokio`).
+    #[cfg(feature = "tokio")]
+    pub fn find_candidates_tokio(&self, excluded: Arc<Exclusions>) -> TokioFindCandidates {
+        TokioFindCandidates {
+            request: http::get_tokio(
+                &self.consul_addr,
+                self.query_url.clone(),
+                self.token.as_ref().and_then(SecretSource::current),
+                self.query.timeout,
+            ),
+            max_stale: self.max_stale,
+            max_cache_age: self.max_cache_age,
+            parse: self.parse.clone(),
+            excluded,
+        }
+    }
+
+    /// Returns the cached candidate nodes of the service except `excluded` ones.
+    ///
+    /// Returns `None` if the client is not watched, or the watcher has not received the nodes yet.
+    pub fn cached_candidates(&self, excluded: &Exclusions) -> Option<Vec<ServiceNode>> {
+        let nodes = self.cache.as_ref()?.lock().expect("Never fails").clone()?;
+        let candidates = nodes
+            .iter()
+            .filter(|n| {
+                let meta = n.node_meta.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                !excluded.is_excluded(&n.node, meta)
+            })
+            .cloned()
+            .collect();
+        Some(candidates)
+    }
+
+    /// Makes a watcher which keeps the cached candidate nodes of this client up to date.
+    ///
+    /// If `wait` is `Some(_)`, the nodes are watched by [blocking queries], each of which waits up to it for changes.
+    /// Otherwise (or if the agent does not support blocking queries, i.e., responses have no `X-Consul-Index`
+    /// header), the nodes are queried every `interval` (with `jitter`).
+    /// Failed queries are retried after `interval`, and in the meantime the last received nodes keep being used.
+    ///
+    /// [blocking queries]: https://www.consul.io/api/features/blocking.html
+    pub fn watch(
+        &mut self,
+        wait: Option<Duration>,
+        interval: Duration,
+        jitter: f64,
+    ) -> CandidatesWatcher {
+        let cache = CandidatesCache::default();
+        self.cache = Some(cache.clone());
+        let mut watcher = CandidatesWatcher {
+            consul_addr: self.consul_addr.clone(),
+            query_url: self.query_url.clone(),
+            parse: self.parse.clone(),
+            max_stale: self.max_stale,
+            max_cache_age: self.max_cache_age,
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+            transport: self.transport.clone(),
+            index: 0,
+            wait,
+            interval,
+            jitter,
+            cache,
+            snapshot: self.snapshot.clone(),
+            state: CandidatesWatcherState::Wait(timer::timeout(Duration::from_secs(0))),
+        };
+        watcher.state = CandidatesWatcherState::Fetch(watcher.fetch());
+        watcher
+    }
+
+    pub fn query_url(&self) -> &Url {
+        &self.query_url
+    }
+
+    /// Returns the client of the next datacenter to which the queries fail over (see `ConsulSettings::dc_failover`).
+    pub fn failover(&self) -> Option<Arc<ConsulClient>> {
+        self.failover.clone()
+    }
+
+    /// Returns the service whose sidecar proxies are queried, if this is a client of the Connect mode.
+    pub(crate) fn connect_service(&self) -> Option<&ConnectService> {
+        self.connect.as_ref()
+    }
+}
+
+/// A future which watches the candidate nodes of a service by [blocking queries] or periodic queries.
+///
+/// This never terminates. Failures of queries are only logged.
+///
+/// [blocking queries]: https://www.consul.io/api/features/blocking.html
+pub struct CandidatesWatcher {
+    consul_addr: AgentAddr,
+    query_url: Arc<Url>,
+    parse: ParseOptions,
+    max_stale: Option<Duration>,
+    max_cache_age: Option<Duration>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    index: u64,
+    wait: Option<Duration>,
+    interval: Duration,
+    jitter: f64,
+    cache: CandidatesCache,
+    snapshot: Option<Arc<SnapshotFile>>,
+    state: CandidatesWatcherState,
+}
+impl CandidatesWatcher {
+    fn fetch(&self) -> TimeoutAfter<SuccessfulResponse> {
+        let mut url = (*self.query_url).clone();
+        let mut timeout = WATCH_TIMEOUT_MARGIN;
+        if let Some(wait) = self.wait {
+            url.query_pairs_mut()
+                .append_pair("index", &self.index.to_string())
+                .append_pair("wait", &format!("{}ms", wait.as_millis()));
+            timeout += wait + wait / 16;
+        }
+        http::get_response(
+            &*self.transport,
+            &self.consul_addr,
+            Arc::new(url),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+        )
+        .timeout_after(timeout)
+    }
+
+    /// Stores the nodes in `response`, and returns `true` if the next query can be issued immediately.
+    fn handle_response(&mut self, response: HttpResponse) -> Result<bool> {
+        track!(check_staleness(
+            &response,
+            self.max_stale,
+            self.max_cache_age
+        ))?;
+        let nodes = track!(parse_candidates(
+            &response.body,
+            &self.parse,
+            &Exclusions::default()
+        ))?;
+        log::debug!(
+            "Watched candidates of {}: {:?}",
+            self.query_url.as_str(),
+            nodes
+        );
+        *self.cache.lock().expect("Never fails") = Some(Arc::new(nodes));
+        if let Some(ref snapshot) = self.snapshot {
+            snapshot.save(&self.query_url, &Arc::new(response.body.clone()));
+        }
+        if self.wait.is_none() {
+            return Ok(false);
+        }
+
+        // See "Implementation Details" of https://www.consul.io/api/features/blocking.html
+        let index = response
+            .header("X-Consul-Index")
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        match index {
+            Some(index) if index > self.index => {
+                self.index = index;
+                Ok(true)
+            }
+            Some(index) if index < self.index => {
+                self.index = 0;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+impl Future for CandidatesWatcher {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let immediate = match self.state {
+                CandidatesWatcherState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    true
+                }
+                CandidatesWatcherState::Fetch(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        let e = e.unwrap_or_else(|| {
+                            ErrorKind::ConsulUnavailable
+                                .cause("Blocking query timeout")
+                                .into()
+                        });
+                        log::warn!("Cannot watch {}: {}", self.query_url, e);
+                        false
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(response)) => match self.handle_response(response) {
+                        Err(e) => {
+                            log::warn!("Cannot watch {}: {}", self.query_url, e);
+                            false
+                        }
+                        Ok(immediate) => immediate,
+                    },
+                },
+            };
+            self.state = if immediate {
+                CandidatesWatcherState::Fetch(self.fetch())
+            } else {
+                let interval = random::jitter(self.interval, self.jitter);
+                CandidatesWatcherState::Wait(timer::timeout(interval))
+            };
+        }
+    }
+}
+impl fmt::Debug for CandidatesWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CandidatesWatcher {{ url: {:?}, index: {}, .. }}",
+            self.query_url.as_str(),
+            self.index
+        )
+    }
+}
+
+enum CandidatesWatcherState {
+    Fetch(TimeoutAfter<SuccessfulResponse>),
+    Wait(Timeout),
+}
+
+/// A stream which watches [user events] named for the proxy and yields the commands they carry.
+///
+/// Events which had been fired before the watcher started are ignored.
+///
+/// [user events]: https://www.consul.io/api/event.html
+pub struct EventWatcher {
+    consul_addr: AgentAddr,
+    url: Arc<Url>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    last_ltime: Option<u64>,
+    interval: Duration,
+    jitter: f64,
+    state: EventWatcherState,
+    commands: VecDeque<Command>,
+}
+impl EventWatcher {
+    /// Returns `true` if this watcher watches the same events as `other`.
+    pub fn is_same_source(&self, other: &EventWatcher) -> bool {
+        self.consul_addr == other.consul_addr
+            && self.url == other.url
+            && self.token == other.token
+            && self.tls == other.tls
+            && Arc::ptr_eq(&self.transport, &other.transport)
+    }
+
+    fn fetch(&self) -> GetJson<Vec<UserEvent>> {
+        GetJson::new(http::get(
+            &*self.transport,
+            &self.consul_addr,
+            self.url.clone(),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+        ))
+    }
+
+    fn handle_events(&mut self, events: Vec<UserEvent>) {
+        if let Some(last_ltime) = self.last_ltime {
+            for event in events.iter().filter(|e| e.ltime > last_ltime) {
+                let payload = event
+                    .payload
+                    .as_ref()
+                    .and_then(|p| base64::decode(p))
+                    .and_then(|p| String::from_utf8(p).ok())
+                    .unwrap_or_default();
+                let caller = Caller::ConsulEvent(event.id.clone());
+                let action = format!("command {}", payload.trim());
+                match payload.parse() {
+                    Err(e) => {
+                        log::warn!("Ignored the event {}: {}", event.id, e);
+                        audit::record(&caller, &action, "rejected");
+                    }
+                    Ok(command) => {
+                        audit::record(&caller, &action, "accepted");
+                        log::info!(
+                            "Received the command {:?} by the event {}",
+                            command,
+                            event.id
+                        );
+                        self.commands.push_back(command);
+                    }
+                }
+            }
+        }
+        let max_ltime = events.iter().map(|e| e.ltime).max().unwrap_or(0);
+        self.last_ltime = Some(max_ltime.max(self.last_ltime.unwrap_or(0)));
+    }
+}
+impl Stream for EventWatcher {
+    type Item = Command;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(command) = self.commands.pop_front() {
+                return Ok(Async::Ready(Some(command)));
+            }
+            let next = match self.state {
+                EventWatcherState::Fetch(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        log::warn!("Cannot fetch events: {}", e);
+                        None
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(events)) => Some(events),
+                },
+                EventWatcherState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = EventWatcherState::Fetch(Box::new(self.fetch()));
+                    continue;
+                }
+            };
+            if let Some(events) = next {
+                self.handle_events(events);
+            }
+            let interval = random::jitter(self.interval, self.jitter);
+            self.state = EventWatcherState::Wait(timer::timeout(interval));
+        }
+    }
+}
+impl fmt::Debug for EventWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "EventWatcher {{ url: {:?}, last_ltime: {:?}, .. }}",
+            self.url.as_str(),
+            self.last_ltime
+        )
+    }
+}
+
+enum EventWatcherState {
+    Fetch(Box<GetJson<Vec<UserEvent>>>),
+    Wait(Timeout),
+}
+
+#[derive(Debug, Deserialize)]
+struct UserEvent {
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Payload")]
+    payload: Option<String>,
+
+    #[serde(rename = "LTime")]
+    ltime: u64,
+}
+
+/// A stream which watches the keys under a prefix of the Consul KV store by [blocking queries],
+/// and yields the settings they carry whenever the settings change.
+///
+/// The first successful query always yields the settings (even if there are no keys).
+/// This never terminates. Failures of queries are only logged.
+///
+/// [blocking queries]: https://www.consul.io/api/features/blocking.html
+pub(crate) struct ConfigWatcher {
+    consul_addr: AgentAddr,
+    url: Arc<Url>,
+    prefix: String,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    index: u64,
+    wait: Duration,
+    interval: Duration,
+    jitter: f64,
+    last: Option<DynamicConfig>,
+    state: ConfigWatcherState,
+}
+impl ConfigWatcher {
+    /// Returns the watched prefix (without leading and trailing slashes).
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn fetch(&self) -> TimeoutAfter<HttpFuture> {
+        let mut url = (*self.url).clone();
+        url.query_pairs_mut()
+            .append_pair("index", &self.index.to_string())
+            .append_pair("wait", &format!("{}ms", self.wait.as_millis()));
+        let timeout = WATCH_TIMEOUT_MARGIN + self.wait + self.wait / 16;
+        http::get_any_response(
+            &*self.transport,
+            &self.consul_addr,
+            Arc::new(url),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+        )
+        .timeout_after(timeout)
+    }
+
+    /// Returns the settings in `response` if they have changed,
+    /// and whether the next query can be issued immediately.
+    fn handle_response(&mut self, response: HttpResponse) -> Result<(Option<DynamicConfig>, bool)> {
+        // The agent returns 404 if there are no keys under the prefix.
+        let entries: Vec<KvEntry> = if response.status == 404 {
+            Vec::new()
+        } else {
+            track_assert_eq!(
+                response.status / 100,
+                2,
+                ErrorKind::ConsulUnavailable,
+                "http_status:{}",
+                response.status
+            );
+            track!(serdeconv::from_json_slice(&response.body)
+                .map_err(|e| Error::from(ErrorKind::DeserializeFailed.takes_over(e))))?
+        };
+        let values = entries
+            .iter()
+            .filter_map(|entry| {
+                let key = entry.key.strip_prefix(self.prefix.as_str())?;
+                let key = key.strip_prefix('/').unwrap_or(key);
+                let value = entry
+                    .value
+                    .as_ref()
+                    .and_then(|v| base64::decode(v))
+                    .and_then(|v| String::from_utf8(v).ok())
+                    .unwrap_or_default();
+                Some((key, value))
+            })
+            .collect::<Vec<_>>();
+        let config = DynamicConfig::from_entries(values.iter().map(|(k, v)| (*k, v.as_str())));
+        let changed = if self.last.as_ref() == Some(&config) {
+            None
+        } else {
+            log::debug!("Watched settings under {:?}: {:?}", self.prefix, config);
+            self.last = Some(config.clone());
+            Some(config)
+        };
+
+        // See "Implementation Details" of https://www.consul.io/api/features/blocking.html
+        let index = response
+            .header("X-Consul-Index")
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        let immediate = match index {
+            Some(index) if index > self.index => {
+                self.index = index;
+                true
+            }
+            Some(index) if index < self.index => {
+                self.index = 0;
+                true
+            }
+            _ => false,
+        };
+        Ok((changed, immediate))
+    }
+}
+impl Stream for ConfigWatcher {
+    type Item = DynamicConfig;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let (changed, immediate) = match self.state {
+                ConfigWatcherState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    (None, true)
+                }
+                ConfigWatcherState::Fetch(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        let e = e.unwrap_or_else(|| {
+                            ErrorKind::ConsulUnavailable
+                                .cause("Blocking query timeout")
+                                .into()
+                        });
+                        log::warn!("Cannot watch {}: {}", self.url, e);
+                        (None, false)
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(response)) => match self.handle_response(response) {
+                        Err(e) => {
+                            log::warn!("Cannot watch {}: {}", self.url, e);
+                            (None, false)
+                        }
+                        Ok(result) => result,
+                    },
+                },
+            };
+            self.state = if immediate {
+                ConfigWatcherState::Fetch(self.fetch())
+            } else {
+                let interval = random::jitter(self.interval, self.jitter);
+                ConfigWatcherState::Wait(timer::timeout(interval))
+            };
+            if let Some(config) = changed {
+                return Ok(Async::Ready(Some(config)));
+            }
+        }
+    }
+}
+impl fmt::Debug for ConfigWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ConfigWatcher {{ url: {:?}, index: {}, .. }}",
+            self.url.as_str(),
+            self.index
+        )
+    }
+}
+
+enum ConfigWatcherState {
+    Fetch(TimeoutAfter<HttpFuture>),
+    Wait(Timeout),
+}
+
+#[derive(Debug, Deserialize)]
+struct KvEntry {
+    #[serde(rename = "Key")]
+    key: String,
+
+    #[serde(rename = "Value")]
+    value: Option<String>,
+}
+
+/// A future which periodically fetches the [Connect] CA roots and the leaf certificate of an identity,
+/// and updates `ConnectCerts` with them.
+///
+/// This never terminates. Failures of fetches are only logged, and in the meantime the last certificates keep being used.
+///
+/// [Connect]: https://www.consul.io/docs/connect
+pub struct ConnectWatcher {
+    consul_addr: AgentAddr,
+    roots_url: Arc<Url>,
+    leaf_url: Arc<Url>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    tls_policy: TlsPolicy,
+    transport: Arc<dyn HttpTransport>,
+    interval: Duration,
+    jitter: f64,
+    certs: Arc<ConnectCerts>,
+    last: Option<(CaRoots, LeafCert)>,
+    state: ConnectWatcherState,
+}
+impl ConnectWatcher {
+    fn fetch(&self) -> Join<GetJson<CaRoots>, GetJson<LeafCert>> {
+        self.get(&self.roots_url).join(self.get(&self.leaf_url))
+    }
+
+    fn get<T: DeserializeOwned>(&self, url: &Arc<Url>) -> GetJson<T> {
+        GetJson::new(http::get(
+            &*self.transport,
+            &self.consul_addr,
+            url.clone(),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+        ))
+    }
+
+    fn handle_certs(&mut self, roots: CaRoots, leaf: LeafCert) -> Result<()> {
+        if self.last.as_ref() == Some(&(roots.clone(), leaf.clone())) {
+            return Ok(());
+        }
+        let root_certs = roots
+            .roots
+            .iter()
+            .map(|r| r.root_cert.as_str())
+            .collect::<Vec<_>>();
+        track!(self.certs.update(
+            &root_certs,
+            &leaf.cert_pem,
+            &leaf.private_key_pem,
+            &self.tls_policy
+        ))?;
+        log::info!(
+            "Updated the Connect certificates: leaf_serial={}, roots={}",
+            leaf.serial_number,
+            root_certs.len()
+        );
+        self.last = Some((roots, leaf));
+        Ok(())
+    }
+}
+impl Future for ConnectWatcher {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.state {
+                ConnectWatcherState::Fetch(ref mut f) => match f.poll() {
+                    Err(e) => log::warn!("Cannot fetch the Connect certificates: {}", e),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready((roots, leaf))) => {
+                        if let Err(e) = track!(self.handle_certs(roots, leaf)) {
+                            log::warn!("Cannot update the Connect certificates: {}", e);
+                        }
+                    }
+                },
+                ConnectWatcherState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = ConnectWatcherState::Fetch(Box::new(self.fetch()));
+                    continue;
+                }
+            }
+            let interval = random::jitter(self.interval, self.jitter);
+            self.state = ConnectWatcherState::Wait(timer::timeout(interval));
+        }
+    }
+}
+impl fmt::Debug for ConnectWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ConnectWatcher {{ leaf_url: {:?}, .. }}",
+            self.leaf_url.as_str()
+        )
+    }
+}
+
+enum ConnectWatcherState {
+    Fetch(Box<Join<GetJson<CaRoots>, GetJson<LeafCert>>>),
+    Wait(Timeout),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct CaRoots {
+    #[serde(rename = "Roots")]
+    roots: Vec<CaRoot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct CaRoot {
+    #[serde(rename = "RootCert")]
+    root_cert: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+struct LeafCert {
+    #[serde(rename = "SerialNumber")]
+    serial_number: String,
+
+    #[serde(rename = "CertPEM")]
+    cert_pem: String,
+
+    #[serde(rename = "PrivateKeyPEM")]
+    private_key_pem: String,
+}
+
+/// A future which periodically writes the statistics of the proxy server into the Consul KV store.
+///
+/// This never terminates. Failures of writes are only logged.
+pub struct StatsPublisher {
+    consul_addr: AgentAddr,
+    url: Arc<Url>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    service: String,
+    stats: Arc<Stats>,
+    interval: Duration,
+    jitter: f64,
+    state: StatsPublisherState,
+}
+impl StatsPublisher {
+    fn put(&self) -> Result<ResponseBody> {
+        let document = StatsDocument {
+            service: &self.service,
+            stats: self.stats.snapshot(),
+        };
+        let body = track!(serdeconv::to_json_string(&document)
+            .map_err(|e| Error::from(ErrorKind::Other.takes_over(e))))?;
+        Ok(http::put(
+            &*self.transport,
+            &self.consul_addr,
+            self.url.clone(),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+            body.into_bytes(),
+        ))
+    }
+}
+impl Future for StatsPublisher {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.state {
+                StatsPublisherState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                StatsPublisherState::Put(ref mut f) => match f.poll() {
+                    Err(e) => log::warn!("Cannot publish stats to {}: {}", self.url, e),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(_)) => log::debug!("Published stats to {}", self.url),
+                },
+            }
+            let put = match self.state {
+                StatsPublisherState::Wait(_) => match self.put() {
+                    Err(e) => {
+                        log::warn!("Cannot publish stats to {}: {}", self.url, e);
+                        None
+                    }
+                    Ok(f) => Some(f),
+                },
+                StatsPublisherState::Put(_) => None,
+            };
+            self.state = if let Some(f) = put {
+                StatsPublisherState::Put(Box::new(f))
+            } else {
+                let interval = random::jitter(self.interval, self.jitter);
+                StatsPublisherState::Wait(timer::timeout(interval))
+            };
+        }
+    }
+}
+impl fmt::Debug for StatsPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StatsPublisher {{ url: {:?}, .. }}", self.url.as_str())
+    }
+}
+
+enum StatsPublisherState {
+    Wait(Timeout),
+    Put(Box<ResponseBody>),
+}
+
+/// A future which registers the proxy server itself as a Consul service, and keeps its TTL check passing.
+///
+/// This terminates only after `deregister` is called and the deregistration finishes.
+/// Failures of requests are only logged. If the registration or the TTL check update fails
+/// (e.g., the consul agent has been restarted), the service is registered again after `interval`.
+pub struct Registrar {
+    consul_addr: AgentAddr,
+    register_url: Arc<Url>,
+    pass_url: Option<Arc<Url>>,
+    deregister_url: Arc<Url>,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+    service_id: String,
+    body: Vec<u8>,
+    ttl: Option<Duration>,
+    interval: Duration,
+    jitter: f64,
+    registered: bool,
+    state: RegistrarState,
+}
+impl Registrar {
+    /// Starts the deregistration of the service, after which this future terminates.
+    pub fn deregister(&mut self) {
+        if let RegistrarState::Deregister(_) = self.state {
+            return;
+        }
+        let deregister = self.put(&self.deregister_url, Vec::new());
+        self.state = RegistrarState::Deregister(deregister.timeout_after(DEREGISTER_TIMEOUT));
+    }
+
+    fn put(&self, url: &Arc<Url>, body: Vec<u8>) -> ResponseBody {
+        http::put(
+            &*self.transport,
+            &self.consul_addr,
+            url.clone(),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+            body,
+        )
+    }
+
+    fn pass_or_register(&self) -> RegistrarState {
+        match self.pass_url {
+            Some(ref url) if self.registered => {
+                RegistrarState::Pass(Box::new(self.put(url, Vec::new())))
+            }
+            _ => {
+                RegistrarState::Register(Box::new(self.put(&self.register_url, self.body.clone())))
+            }
+        }
+    }
+
+    fn retry(&self) -> RegistrarState {
+        RegistrarState::Wait(timer::timeout(random::jitter(self.interval, self.jitter)))
+    }
+}
+impl Future for Registrar {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                RegistrarState::Idle => return Ok(Async::NotReady),
+                RegistrarState::Wait(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.pass_or_register()
+                }
+                RegistrarState::Register(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        log::warn!("Cannot register the service {:?}: {}", self.service_id, e);
+                        self.retry()
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(_)) => {
+                        log::info!("Registered the service {:?}", self.service_id);
+                        self.registered = true;
+                        if self.pass_url.is_some() {
+                            self.pass_or_register()
+                        } else {
+                            // The consul agent runs the TCP check by itself.
+                            RegistrarState::Idle
+                        }
+                    }
+                },
+                RegistrarState::Pass(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        log::warn!(
+                            "Cannot update the TTL check of the service {:?}: {}",
+                            self.service_id,
+                            e
+                        );
+                        self.registered = false;
+                        self.retry()
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(_)) => {
+                        let ttl = self.ttl.expect("Never fails");
+                        RegistrarState::Wait(timer::timeout(ttl / 2))
+                    }
+                },
+                RegistrarState::Deregister(ref mut f) => {
+                    match f.poll() {
+                        Err(e) => {
+                            let e = e.unwrap_or_else(|| {
+                                ErrorKind::ConsulUnavailable
+                                    .cause("Deregistration timeout")
+                                    .into()
+                            });
+                            log::warn!(
+                                "Cannot deregister the service {:?}: {}",
+                                self.service_id,
+                                e
+                            );
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(_)) => {
+                            log::info!("Deregistered the service {:?}", self.service_id);
+                        }
+                    }
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+impl fmt::Debug for Registrar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registrar {{ service_id: {:?}, .. }}", self.service_id)
+    }
+}
+
+enum RegistrarState {
+    Idle,
+    Wait(Timeout),
+    Register(Box<ResponseBody>),
+    Pass(Box<ResponseBody>),
+    Deregister(TimeoutAfter<ResponseBody>),
+}
+
+#[derive(Serialize)]
+struct RegisterDocument<'a> {
+    #[serde(rename = "ID")]
+    id: &'a str,
+
+    #[serde(rename = "Name")]
+    name: &'a str,
+
+    #[serde(rename = "Tags")]
+    tags: &'a [String],
+
+    #[serde(rename = "Address", skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+
+    #[serde(rename = "Port")]
+    port: u16,
+
+    #[serde(rename = "Check")]
+    check: RegisterCheck,
+}
+
+#[derive(Serialize)]
+struct RegisterCheck {
+    #[serde(rename = "CheckID")]
+    check_id: String,
+
+    #[serde(rename = "TTL", skip_serializing_if = "Option::is_none")]
+    ttl: Option<String>,
+
+    #[serde(rename = "TCP", skip_serializing_if = "Option::is_none")]
+    tcp: Option<String>,
+
+    #[serde(rename = "Interval", skip_serializing_if = "Option::is_none")]
+    interval: Option<String>,
+}
+
+/// A future which issues a GET request and decodes the JSON response body.
+#[derive(Debug)]
+pub struct GetJson<T> {
+    request: ResponseBody,
+    _item: PhantomData<fn() -> T>,
+}
+impl<T: DeserializeOwned> GetJson<T> {
+    fn new(request: ResponseBody) -> Self {
+        GetJson {
+            request,
+            _item: PhantomData,
+        }
+    }
+}
+impl<T: DeserializeOwned> Future for GetJson<T> {
+    type Item = T;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(body) = track!(self.request.poll())? {
+            let item = track!(serdeconv::from_json_slice(&body)
+                .map_err(|e| Error::from(ErrorKind::DeserializeFailed.takes_over(e))))?;
+            Ok(Async::Ready(item))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StatsDocument<'a> {
+    service: &'a str,
+
+    #[serde(flatten)]
+    stats: StatsSnapshot,
+}
+
+/// A service node which is a candidate of the destination of a connection.
+///
+/// Fields which are not given by the source of the node (e.g., the health status in the responses of
+/// the catalog API, or everything but the address of a static fallback server) have the default values.
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct ServiceNode {
+    /// Name of the node.
+    pub node: String,
+
+    /// Address of the service (or of the node, if the service has no address).
+    pub address: IpAddr,
+
+    /// Port of the service.
+    pub service_port: u16,
+
+    /// Tagged addresses of the service (e.g., `lan_ipv4` and `wan_ipv6`), which are the `ServiceTaggedAddresses`
+    /// registered in Consul.
+    ///
+    /// Addresses which are not IP addresses are omitted.
+    pub tagged_addresses: HashMap<String, SocketAddr>,
+
+    /// Tagged addresses of the node (e.g., `lan` and `wan`), which are the `TaggedAddresses` registered in Consul.
+    ///
+    /// This is empty if the node has none, as is the case for external nodes registered directly
+    /// into the catalog (e.g., by consul-esm).
+    pub node_tagged_addresses: HashMap<String, IpAddr>,
+
+    /// Metadata of the node.
+    ///
+    /// This is empty if the node has no `NodeMeta`.
+    pub node_meta: HashMap<String, String>,
+
+    /// Metadata of the service (i.e., `ServiceMeta`).
+    pub service_meta: HashMap<String, String>,
+
+    /// Weights of the service in DNS responses and load balancing.
+    pub weights: Weights,
+
+    /// Aggregated status of the health checks of the node and the service.
+    ///
+    /// This is `None` unless the node was queried by the health API (see `ConsulSettings::only_passing`).
+    pub status: Option<HealthStatus>,
+}
+impl ServiceNode {
+    pub(crate) fn new(node: String, addr: SocketAddr) -> Self {
+        ServiceNode {
+            node,
+            address: addr.ip(),
+            service_port: addr.port(),
+            tagged_addresses: HashMap::new(),
+            node_tagged_addresses: HashMap::new(),
+            node_meta: HashMap::new(),
+            service_meta: HashMap::new(),
+            weights: Weights::default(),
+            status: None,
+        }
+    }
+
+    /// Returns the address to connect to, using `port` instead of `service_port` if it is `Some(_)`.
+    pub fn socket_addr(&self, port: Option<u16>) -> SocketAddr {
+        SocketAddr::new(self.address, port.unwrap_or(self.service_port))
+    }
+}
+
+/// Weights of a service (i.e., `Weights` in the service definition).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Weights {
+    /// Weight used while the health checks are passing.
+    #[serde(rename = "Passing")]
+    pub passing: u32,
+
+    /// Weight used while some health checks are warning.
+    #[serde(rename = "Warning")]
+    pub warning: u32,
+}
+impl Default for Weights {
+    /// Returns the default weights of Consul, which are both `1`.
+    fn default() -> Self {
+        Weights {
+            passing: 1,
+            warning: 1,
+        }
+    }
+}
+
+/// Status of health checks.
+///
+/// The statuses are ordered by severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// All checks are passing.
+    Passing,
+
+    /// Some checks are warning.
+    Warning,
+
+    /// Some checks are critical (or have an unknown status, e.g., `maintenance`).
+    Critical,
+}
+impl HealthStatus {
+    fn parse(s: &str) -> Self {
+        match s {
+            "passing" => HealthStatus::Passing,
+            "warning" => HealthStatus::Warning,
+            _ => HealthStatus::Critical,
+        }
+    }
+}
+
+/// A future which queries the candidate nodes of a service.
+///
+/// The response is parsed in a streaming fashion: only the needed fields are decoded,
+/// borrowing from the response body, and excluded nodes are dropped without being allocated.
+#[derive(Debug)]
+pub struct FindCandidates {
+    query: Arc<CandidatesQuery>,
+    retry: Option<RetryPolicy>,
+    retries: u32,
+    dns: Option<SrvQuery>,
+    snapshot: Option<Arc<SnapshotFile>>,
+    state: QueryState,
+    parse: ParseOptions,
+    excluded: Arc<Exclusions>,
+}
+impl Future for FindCandidates {
+    type Item = Vec<ServiceNode>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let result = match self.state {
+                QueryState::Lead(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(response)) => track!(check_staleness(
+                        &response,
+                        self.query.max_stale,
+                        self.query.max_cache_age
+                    ))
+                    .map(|()| Arc::new(response.body)),
+                    Err(e) => Err(e.unwrap_or_else(|| {
+                        ErrorKind::ConsulUnavailable
+                            .cause(format!("Request timeout: {:?}", self.query.timeout))
+                            .into()
+                    })),
+                },
+                QueryState::Follow(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(result)) => result,
+                    Err(_) => {
+                        // The leading query was dropped before completing, so takes it over.
+                        self.state = self.query.join();
+                        continue;
+                    }
+                },
+                QueryState::Backoff(ref mut f) => {
+                    if let Async::NotReady = f.poll().unwrap_or(Async::Ready(())) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = self.query.join();
+                    continue;
+                }
+                QueryState::Dns(ref mut f) => {
+                    let result = match f.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(result)) => result,
+                        Err(_) => Err(ErrorKind::Other.cause("DNS query aborted").into()),
+                    };
+                    self.state = QueryState::Done;
+                    let result = result.or_else(|e| self.load_snapshot(e));
+                    return track!(result).map(Async::Ready);
+                }
+                QueryState::Done => panic!("Cannot poll FindCandidates twice"),
+            };
+            if let QueryState::Lead(_) = self.state {
+                self.query.finish(Some(&result));
+            }
+            self.state = QueryState::Done;
+            let candidates = track!(result).and_then(|body| {
+                let candidates = track!(parse_candidates(&body, &self.parse, &self.excluded))?;
+                if let Some(ref snapshot) = self.snapshot {
+                    snapshot.save(&self.query.url, &body);
+                }
+                Ok(candidates)
+            });
+            if let Err(ref e) = candidates {
+                if let Some(backoff) = self.backoff(e) {
+                    log::warn!(
+                        "Cannot query {}; retries after {:?}: {}",
+                        self.query.url,
+                        backoff,
+                        e
+                    );
+                    self.state = QueryState::Backoff(timer::timeout(backoff));
+                    continue;
+                }
+            }
+            match (candidates, self.dns.take()) {
+                (Err(e), Some(dns)) => {
+                    log::warn!(
+                        "Cannot query {}; falls back to the DNS query of {:?}: {}",
+                        self.query.url,
+                        dns.name(),
+                        e
+                    );
+                    self.state = QueryState::Dns(dns.lookup(&self.excluded));
+                }
+                (Err(e), None) => return track!(self.load_snapshot(e)).map(Async::Ready),
+                (candidates, _) => return candidates.map(Async::Ready),
+            }
+        }
+    }
+}
+impl FindCandidates {
+    /// Returns the backoff before retrying the query failed with `e`, if it can be retried.
+    fn backoff(&mut self, e: &Error) -> Option<Duration> {
+        let retry = self.retry.as_ref()?;
+        if *e.kind() != ErrorKind::ConsulUnavailable || self.retries >= retry.max_retries() {
+            return None;
+        }
+        let backoff = retry.backoff(self.retries);
+        self.retries += 1;
+        Some(backoff)
+    }
+
+    /// Returns the nodes in the last successful response saved in the snapshot file, if any,
+    /// instead of failing with `e`.
+    fn load_snapshot(&self, e: Error) -> Result<Vec<ServiceNode>> {
+        let body = match self.snapshot.as_ref().and_then(|s| s.load(&self.query.url)) {
+            None => return Err(e),
+            Some(body) => body,
+        };
+        log::warn!(
+            "Cannot query {}; uses the last known nodes in the snapshot file: {}",
+            self.query.url,
+            e
+        );
+        track!(parse_candidates(&body, &self.parse, &self.excluded))
+    }
+}
+impl Drop for FindCandidates {
+    fn drop(&mut self) {
+        if let QueryState::Lead(_) = self.state {
+            self.query.finish(None);
+        }
+    }
+}
+
+/// A future which queries the candidate nodes of a service on the runtime of `tokio`.
+///
+/// This is the counterpart of `FindCandidates` for the `tokio` feature (see `ConsulSettings::find_candidates_tokio`).
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct TokioFindCandidates {
+    request: http::TokioExchange,
+    max_stale: Option<Duration>,
+    max_cache_age: Option<Duration>,
+    parse: ParseOptions,
+    excluded: Arc<Exclusions>,
+}
+#[cfg(feature = "tokio")]
+impl StdFuture for TokioFindCandidates {
+    type Output = Result<Vec<ServiceNode>>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Self::Output> {
+        let this = &mut *self;
+        let response = match Pin::new(&mut this.request).poll(cx) {
+            task::Poll::Pending => return task::Poll::Pending,
+            task::Poll::Ready(response) => response,
+        };
+        task::Poll::Ready(track!(response).and_then(|response| {
+            track!(check_staleness(
+                &response,
+                this.max_stale,
+                this.max_cache_age
+            ))?;
+            track!(parse_candidates(
+                &response.body,
+                &this.parse,
+                &this.excluded
+            ))
+        }))
+    }
+}
+
+/// The result of a query of candidates, which is shared by the coalesced queries.
+type QueryResult = Result<Arc<Vec<u8>>>;
+
+/// The query of the candidate nodes made by a `ConsulClient`.
+#[derive(Debug)]
+struct CandidatesQuery {
+    consul_addr: AgentAddr,
+    url: Arc<Url>,
+    max_stale: Option<Duration>,
+    max_cache_age: Option<Duration>,
+    timeout: Duration,
+    token: Option<SecretSource>,
+    tls: Option<Arc<TlsSettings>>,
+    transport: Arc<dyn HttpTransport>,
+
+    /// The senders to the queries waiting for the in-flight one, if any.
+    waiters: Mutex<Option<Vec<oneshot::Sender<QueryResult>>>>,
+}
+impl CandidatesQuery {
+    /// Issues a new request, or waits for the in-flight one.
+    fn join(&self) -> QueryState {
+        let mut waiters = self.waiters.lock().expect("Never fails");
+        if let Some(ref mut waiters) = *waiters {
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            return QueryState::Follow(rx);
+        }
+        *waiters = Some(Vec::new());
+        let request = http::get_response(
+            &*self.transport,
+            &self.consul_addr,
+            self.url.clone(),
+            self.token.as_ref().and_then(SecretSource::current),
+            self.tls.clone(),
+        );
+        QueryState::Lead(request.timeout_after(self.timeout))
+    }
+
+    /// Completes the in-flight request, delivering `result` to the waiting queries.
+    ///
+    /// If `result` is `None` (i.e., the request was cancelled), one of them issues a new request.
+    fn finish(&self, result: Option<&QueryResult>) {
+        let waiters = self.waiters.lock().expect("Never fails").take();
+        if let Some(result) = result {
+            for waiter in waiters.into_iter().flatten() {
+                let _ = waiter.send(result.clone());
+            }
+        }
+    }
+}
+
+enum QueryState {
+    Lead(TimeoutAfter<SuccessfulResponse>),
+    Follow(oneshot::Receiver<QueryResult>),
+    Backoff(Timeout),
+    Dns(oneshot::Receiver<Result<Vec<ServiceNode>>>),
+    Done,
+}
+impl fmt::Debug for QueryState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryState::Lead(_) => write!(f, "Lead(_)"),
+            QueryState::Follow(_) => write!(f, "Follow(_)"),
+            QueryState::Backoff(_) => write!(f, "Backoff(_)"),
+            QueryState::Dns(_) => write!(f, "Dns(_)"),
+            QueryState::Done => write!(f, "Done"),
+        }
+    }
+}
+
+/// Fails if `response` is staler than `max_stale` (if any) according to its `X-Consul-LastContact` header,
+/// or older than `max_cache_age` (if any) according to its `Age` header (i.e., it is served by the agent cache).
+fn check_staleness(
+    response: &HttpResponse,
+    max_stale: Option<Duration>,
+    max_cache_age: Option<Duration>,
+) -> Result<()> {
+    let last_contact = response
+        .header("X-Consul-LastContact")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_millis);
+    if let (Some(last_contact), Some(max_stale)) = (last_contact, max_stale) {
+        track_assert!(
+            last_contact <= max_stale,
+            ErrorKind::ConsulUnavailable,
+            "Too stale response: last_contact={:?}, max_stale={:?}",
+            last_contact,
+            max_stale
+        );
+    }
+
+    let age = response
+        .header("Age")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    if let Some(age) = age {
+        log::debug!(
+            "Cached response: x_cache={:?}, age={:?}",
+            response.header("X-Cache"),
+            age
+        );
+        if let Some(max_cache_age) = max_cache_age {
+            track_assert!(
+                age <= max_cache_age,
+                ErrorKind::ConsulUnavailable,
+                "Too old cached response: age={:?}, max_cache_age={:?}",
+                age,
+                max_cache_age
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Options of parsing the responses of the queries of candidate nodes.
+#[derive(Debug, Clone)]
+struct ParseOptions {
+    /// Whether the responses are of the health API (rather than the catalog API).
+    health: bool,
+
+    /// Name of the tagged address used instead of the default address (see `ConsulSettings::tagged_address`).
+    tagged_address: Option<String>,
+}
+
+fn parse_candidates(
+    body: &[u8],
+    options: &ParseOptions,
+    excluded: &Exclusions,
+) -> Result<Vec<ServiceNode>> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let seed = CandidatesSeed { options, excluded };
+    track!(seed
+        .deserialize(&mut deserializer)
+        .and_then(|candidates| deserializer.end().map(|()| candidates))
+        .map_err(|e| Error::from(ErrorKind::DeserializeFailed.cause(e))))
+}
+
+struct CandidatesSeed<'a> {
+    options: &'a ParseOptions,
+    excluded: &'a Exclusions,
+}
+impl<'a, 'de> de::DeserializeSeed<'de> for CandidatesSeed<'a> {
+    type Value = Vec<ServiceNode>;
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+impl<'a, 'de> de::Visitor<'de> for CandidatesSeed<'a> {
+    type Value = Vec<ServiceNode>;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of service nodes")
+    }
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut candidates = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        loop {
+            let raw = if self.options.health {
+                seq.next_element::<RawHealthEntry>()?
+                    .map(RawServiceNode::from)
+            } else {
+                seq.next_element::<RawServiceNode>()?
+            };
+            let raw = if let Some(raw) = raw {
+                raw
+            } else {
+                break;
+            };
+            let meta = raw.node_meta.iter().flatten();
+            if self
+                .excluded
+                .is_excluded(&raw.node, meta.map(|(k, v)| (&*k.0, &*v.0)))
+            {
+                continue;
+            }
+            let tagged_addresses = raw
+                .service_tagged_addresses
+                .iter()
+                .flatten()
+                .filter_map(|(name, tagged)| {
+                    let ip = tagged.address.parse().ok()?;
+                    let port = if tagged.port == 0 {
+                        raw.service_port
+                    } else {
+                        tagged.port
+                    };
+                    Some((name.0.clone().into_owned(), SocketAddr::new(ip, port)))
+                })
+                .collect::<HashMap<_, _>>();
+            let tagged = self
+                .options
+                .tagged_address
+                .as_ref()
+                .and_then(|name| tagged_addresses.get(name));
+            let (address, service_port) = if let Some(tagged) = tagged {
+                (tagged.ip(), tagged.port())
+            } else {
+                let address = if raw.service_address.is_empty() {
+                    &raw.address
+                } else {
+                    &raw.service_address
+                };
+                match address.parse() {
+                    Ok(address) => (address, raw.service_port),
+                    Err(_) => {
+                        // e.g., an external service registered with a hostname
+                        log::debug!(
+                            "Skipped the node {:?} which has a non-IP address {:?}",
+                            raw.node,
+                            address
+                        );
+                        continue;
+                    }
+                }
+            };
+            let node_tagged_addresses = raw
+                .tagged_addresses
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, address)| Some((name.0.into_owned(), address.0.parse().ok()?)))
+                .collect();
+            candidates.push(ServiceNode {
+                address,
+                node: raw.node.into_owned(),
+                service_port,
+                tagged_addresses,
+                node_tagged_addresses,
+                node_meta: raw
+                    .node_meta
+                    .into_iter()
+                    .flatten()
+                    .map(|(k, v)| (k.0.into_owned(), v.0.into_owned()))
+                    .collect(),
+                service_meta: raw
+                    .service_meta
+                    .into_iter()
+                    .flatten()
+                    .map(|(k, v)| (k.0.into_owned(), v.0.into_owned()))
+                    .collect(),
+                weights: raw.service_weights.unwrap_or_default(),
+                status: raw.status,
+            });
+        }
+        Ok(candidates)
+    }
+}
+
+/// An entry of the response of the `/v1/catalog/service/:service` API.
+///
+/// Fields which are not needed to select a server are skipped.
+/// Fields which may be missing or `null` (e.g., for external services registered directly into the catalog)
+/// have defaults.
+#[derive(Deserialize)]
+struct RawServiceNode<'a> {
+    #[serde(rename = "Node", borrow)]
+    node: Cow<'a, str>,
+
+    #[serde(rename = "Address", default, borrow)]
+    address: Cow<'a, str>,
+
+    #[serde(rename = "TaggedAddresses", default, borrow)]
+    tagged_addresses: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+
+    #[serde(rename = "ServiceAddress", default, borrow)]
+    service_address: Cow<'a, str>,
+
+    #[serde(rename = "ServicePort", default)]
+    service_port: u16,
+
+    #[serde(rename = "ServiceTaggedAddresses", default, borrow)]
+    service_tagged_addresses: Option<HashMap<JsonStr<'a>, RawTaggedAddress<'a>>>,
+
+    #[serde(rename = "NodeMeta", default, borrow)]
+    node_meta: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+
+    #[serde(rename = "ServiceMeta", default, borrow)]
+    service_meta: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+
+    #[serde(rename = "ServiceWeights", default)]
+    service_weights: Option<Weights>,
+
+    /// The catalog API does not return health checks, so this is set only when converted from `RawHealthEntry`.
+    #[serde(skip)]
+    status: Option<HealthStatus>,
+}
+
+/// An entry of the response of the `/v1/health/service/:service` API.
+///
+/// Fields which are not needed to select a server are skipped.
+#[derive(Deserialize)]
+struct RawHealthEntry<'a> {
+    #[serde(rename = "Node", borrow)]
+    node: RawHealthNode<'a>,
+
+    #[serde(rename = "Service", borrow)]
+    service: RawHealthService<'a>,
+
+    #[serde(rename = "Checks", default, borrow)]
+    checks: Option<Vec<RawHealthCheck<'a>>>,
+}
+
+#[derive(Deserialize)]
+struct RawHealthNode<'a> {
+    #[serde(rename = "Node", borrow)]
+    node: Cow<'a, str>,
+
+    #[serde(rename = "Address", default, borrow)]
+    address: Cow<'a, str>,
+
+    #[serde(rename = "TaggedAddresses", default, borrow)]
+    tagged_addresses: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+
+    #[serde(rename = "Meta", default, borrow)]
+    meta: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+}
+
+#[derive(Deserialize)]
+struct RawHealthService<'a> {
+    #[serde(rename = "Address", default, borrow)]
+    address: Cow<'a, str>,
+
+    #[serde(rename = "Port", default)]
+    port: u16,
+
+    #[serde(rename = "TaggedAddresses", default, borrow)]
+    tagged_addresses: Option<HashMap<JsonStr<'a>, RawTaggedAddress<'a>>>,
+
+    #[serde(rename = "Meta", default, borrow)]
+    meta: Option<HashMap<JsonStr<'a>, JsonStr<'a>>>,
+
+    #[serde(rename = "Weights", default)]
+    weights: Option<Weights>,
+}
+
+#[derive(Deserialize)]
+struct RawHealthCheck<'a> {
+    #[serde(rename = "Status", borrow)]
+    status: JsonStr<'a>,
+}
+
+/// A value of `ServiceTaggedAddresses` (or of `TaggedAddresses` of a service in the health API).
+#[derive(Deserialize)]
+struct RawTaggedAddress<'a> {
+    #[serde(rename = "Address", borrow)]
+    address: Cow<'a, str>,
+
+    #[serde(rename = "Port", default)]
+    port: u16,
+}
+
+impl<'a> From<RawHealthEntry<'a>> for RawServiceNode<'a> {
+    fn from(f: RawHealthEntry<'a>) -> Self {
+        RawServiceNode {
+            node: f.node.node,
+            address: f.node.address,
+            tagged_addresses: f.node.tagged_addresses,
+            service_address: f.service.address,
+            service_port: f.service.port,
+            service_tagged_addresses: f.service.tagged_addresses,
+            node_meta: f.node.meta,
+            service_meta: f.service.meta,
+            service_weights: f.service.weights,
+            status: Some(
+                f.checks
+                    .iter()
+                    .flatten()
+                    .map(|c| HealthStatus::parse(&c.status.0))
+                    .max()
+                    .unwrap_or(HealthStatus::Passing),
+            ),
+        }
+    }
+}
+
+/// A string in a response body, which is borrowed unless it contains escaped characters.
+#[derive(PartialEq, Eq, Hash, Deserialize)]
+struct JsonStr<'a>(#[serde(borrow)] Cow<'a, str>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_url_works() {
+        let settings = ConsulSettings::from_url(
+            "http://consul.internal/dc1/web?tag=primary&tag=v2&namespace=team&node_meta=rack:a1\
+             &only_passing=false&connect=true&consistency=stale&max_stale_ms=1500&dc_failover=dc2,dc3",
+        )
+        .unwrap();
+        assert_eq!(settings.service, "web");
+        assert_eq!(settings.dc.as_deref(), Some("dc1"));
+        assert_eq!(settings.dc_failover, ["dc2", "dc3"]);
+        assert_eq!(settings.max_stale, Some(Duration::from_millis(1500)));
+        assert!(settings.tls.is_none());
+        assert_eq!(
+            settings.build_query_url().as_str(),
+            "http://consul.internal:8500/v1/catalog/connect/web\
+             ?stale&dc=dc1&ns=team&tag=primary&tag=v2&node_meta=rack%3Aa1"
+        );
+
+        // The default ports of the schemes are kept if they are explicit.
+        let settings = ConsulSettings::from_url("http://consul:80/web").unwrap();
+        assert_eq!(settings.consul_addr.to_string(), "consul:80");
+        let settings = ConsulSettings::from_url("https://consul:443/web").unwrap();
+        assert_eq!(settings.consul_addr.to_string(), "consul:443");
+        let settings = ConsulSettings::from_url("http://[::1]/web").unwrap();
+        assert_eq!(settings.consul_addr.to_string(), "[::1]:8500");
+
+        let settings = ConsulSettings::from_url(
+            "http://consul/web?connect=true&spiffe_id_template=spiffe://dc1/ns/default/svc/%7Bservice%7D",
+        )
+        .unwrap();
+        assert!(settings.validate().is_ok());
+        assert_eq!(
+            settings.connect_service(),
+            ConnectService::new("web", None, None, &[])
+                .spiffe_id_template("spiffe://dc1/ns/default/svc/{service}")
+        );
+
+        let settings = ConsulSettings::from_url("http://127.0.0.1:18500/web?token=foo").unwrap();
+        assert!(settings.token.is_some());
+        assert_eq!(
+            settings.build_query_url().as_str(),
+            "http://127.0.0.1:18500/v1/health/service/web?passing=true"
+        );
+    }
+
+    #[test]
+    fn from_url_with_https_works() {
+        let settings = ConsulSettings::from_url(
+            "https://10.0.0.1/web?ca_file=/etc/ca.pem&client_cert=/etc/cert.pem&client_key=/etc/key.pem\
+             &tls_skip_verify=true&tls_server_name=consul.internal&tls_min_version=1.2",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.build_query_url().as_str(),
+            "https://10.0.0.1:8501/v1/health/service/web?passing=true"
+        );
+
+        let tls = settings.tls.as_ref().unwrap();
+        assert_eq!(tls.ca_file(), Some(Path::new("/etc/ca.pem")));
+        assert_eq!(
+            tls.client_cert(),
+            Some((Path::new("/etc/cert.pem"), Path::new("/etc/key.pem")))
+        );
+        assert!(tls.skip_verify());
+        assert_eq!(tls.server_name(), Some("consul.internal"));
+        assert_eq!(tls.min_version(), Some(TlsVersion::Tls12));
+        assert!(tls.cipher_suites().is_empty());
+        assert!(tls.curves().is_empty());
+
+        let settings = ConsulSettings::from_url(
+            "https://10.0.0.1/web?tls_min_version=1.3&tls_curves=X25519,P-256\
+             &tls_cipher_suites=TLS_AES_128_GCM_SHA256,ECDHE-ECDSA-AES128-GCM-SHA256",
+        )
+        .unwrap();
+        let tls = settings.tls.as_ref().unwrap();
+        assert_eq!(tls.min_version(), Some(TlsVersion::Tls13));
+        assert_eq!(
+            tls.cipher_suites(),
+            ["TLS_AES_128_GCM_SHA256", "ECDHE-ECDSA-AES128-GCM-SHA256"]
+        );
+        assert_eq!(tls.curves(), ["X25519", "P-256"]);
+    }
+
+    #[test]
+    fn from_url_rejects_invalid_urls() {
+        for url in &[
+            "consul/web",
+            "ftp://127.0.0.1/web",
+            "http://127.0.0.1/",
+            "http://127.0.0.1/dc1/web/extra",
+            "http://127.0.0.1/web?unknown=1",
+            "http://127.0.0.1/web?connect=yes",
+            "http://127.0.0.1/web?max_stale_ms=-1",
+            "http://127.0.0.1/web?consistency=strong",
+            "http://127.0.0.1/web?tls_min_version=1.4",
+            "http://127.0.0.1/web?client_cert=/etc/cert.pem",
+            "http://127.0.0.1/web?spiffe_id_template=spiffe://dc1/svc/%7Bnode%7D",
+        ] {
+            assert!(ConsulSettings::from_url(url).is_err(), "url={:?}", url);
+        }
+
+        let e =
+            ConsulSettings::from_url("http://127.0.0.1/web?token=secret&unknown=1").unwrap_err();
+        assert!(!e.to_string().contains("secret"));
     }
 }
@@ -0,0 +1,309 @@
+use fibers::sync::mpsc;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use audit::{self, Caller};
+use {Error, ErrorKind};
+
+/// An operational command applied to a running proxy server.
+///
+/// The textual representations are `drain`, `resume`, `reload`, `shutdown`, `eject <node>`, `readmit <node>`,
+/// `quarantine <key>:<value>`, `release <key>:<value>` and
+/// `retarget <service> [tag=<tag>] [dc=<dc>] [near=<near>] [node-meta=<key>:<value>]...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Refuses new connections until `Resume` or `Reload` is received.
+    Drain,
+
+    /// Stops draining.
+    Resume,
+
+    /// Resets the runtime state changed by commands
+    /// (i.e., stops draining, readmits all ejected and quarantined nodes and restores the configured target).
+    Reload,
+
+    /// Stops accepting connections, deregisters the proxy server if it has been registered as a Consul service
+    /// (see `ProxyServerBuilder::register`), and then terminates the server future.
+    ///
+    /// Connections which have already been relayed are not affected.
+    Shutdown,
+
+    /// Removes the given node from the candidate servers.
+    Eject(String),
+
+    /// Returns the given node, previously ejected, to the candidate servers.
+    Readmit(String),
+
+    /// Removes the nodes which have the given node metadata (key and value) from the candidate servers.
+    Quarantine(String, String),
+
+    /// Returns the nodes, previously quarantined by the given node metadata, to the candidate servers.
+    Release(String, String),
+
+    /// Switches the service (and the query parameters) of the Consul queries for new connections.
+    ///
+    /// Connections which have already been relayed are not affected.
+    Retarget(ServiceTarget),
+}
+impl FromStr for Command {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let name = tokens.next().unwrap_or("");
+        if name == "retarget" {
+            let target = track!(ServiceTarget::parse(tokens), "command={:?}", s)?;
+            return Ok(Command::Retarget(target));
+        }
+        let arg = tokens.next();
+        track_assert_eq!(
+            tokens.next(),
+            None,
+            ErrorKind::InvalidInput,
+            "Too many arguments: {:?}",
+            s
+        );
+        match (name, arg) {
+            ("drain", None) => Ok(Command::Drain),
+            ("resume", None) => Ok(Command::Resume),
+            ("reload", None) => Ok(Command::Reload),
+            ("shutdown", None) => Ok(Command::Shutdown),
+            ("eject", Some(node)) => Ok(Command::Eject(node.to_owned())),
+            ("readmit", Some(node)) => Ok(Command::Readmit(node.to_owned())),
+            ("quarantine", Some(meta)) => {
+                let (key, value) = track!(parse_node_meta(meta))?;
+                Ok(Command::Quarantine(key, value))
+            }
+            ("release", Some(meta)) => {
+                let (key, value) = track!(parse_node_meta(meta))?;
+                Ok(Command::Release(key, value))
+            }
+            _ => track_panic!(ErrorKind::InvalidInput, "Unknown command: {:?}", s),
+        }
+    }
+}
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Drain => write!(f, "drain"),
+            Command::Resume => write!(f, "resume"),
+            Command::Reload => write!(f, "reload"),
+            Command::Shutdown => write!(f, "shutdown"),
+            Command::Eject(ref node) => write!(f, "eject {}", node),
+            Command::Readmit(ref node) => write!(f, "readmit {}", node),
+            Command::Quarantine(ref key, ref value) => write!(f, "quarantine {}:{}", key, value),
+            Command::Release(ref key, ref value) => write!(f, "release {}:{}", key, value),
+            Command::Retarget(ref target) => {
+                write!(f, "retarget {}", target.service)?;
+                if let Some(ref tag) = target.tag {
+                    write!(f, " tag={}", tag)?;
+                }
+                if let Some(ref dc) = target.dc {
+                    write!(f, " dc={}", dc)?;
+                }
+                if let Some(ref near) = target.near {
+                    write!(f, " near={}", near)?;
+                }
+                for (key, value) in &target.node_meta {
+                    write!(f, " node-meta={}:{}", key, value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The service (and the query parameters) which the Consul queries of a proxy server target.
+///
+/// The query parameters which are not set are not sent.
+/// The other settings of `ConsulSettings` (e.g., the agent address) are retained.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceTarget {
+    /// Name of the service.
+    pub service: String,
+
+    /// Value of the `tag` query parameter.
+    pub tag: Option<String>,
+
+    /// Value of the `dc` query parameter.
+    pub dc: Option<String>,
+
+    /// Value of the `near` query parameter.
+    pub near: Option<String>,
+
+    /// Entries of the `node-meta` query parameter.
+    pub node_meta: Vec<(String, String)>,
+}
+impl ServiceTarget {
+    /// Makes a new `ServiceTarget` instance which has no query parameters.
+    pub fn new(service: &str) -> Self {
+        ServiceTarget {
+            service: service.to_owned(),
+            tag: None,
+            dc: None,
+            near: None,
+            node_meta: Vec::new(),
+        }
+    }
+
+    fn parse<'a, I>(mut tokens: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let service = track_assert_some!(tokens.next(), ErrorKind::InvalidInput, "No service");
+        let mut target = ServiceTarget::new(service);
+        for token in tokens {
+            let mut kv = token.splitn(2, '=');
+            let key = kv.next().expect("Never fails");
+            let value = track_assert_some!(
+                kv.next(),
+                ErrorKind::InvalidInput,
+                "Not a `<name>=<value>` parameter: {:?}",
+                token
+            );
+            match key {
+                "tag" => target.tag = Some(value.to_owned()),
+                "dc" => target.dc = Some(value.to_owned()),
+                "near" => target.near = Some(value.to_owned()),
+                "node-meta" => target.node_meta.push(track!(parse_node_meta(value))?),
+                _ => track_panic!(ErrorKind::InvalidInput, "Unknown parameter: {:?}", token),
+            }
+        }
+        Ok(target)
+    }
+}
+
+/// A handle to apply `Command`s to a running proxy server.
+#[derive(Debug, Clone)]
+pub struct CommandSender(mpsc::Sender<Command>);
+impl CommandSender {
+    pub(crate) fn new(tx: mpsc::Sender<Command>) -> Self {
+        CommandSender(tx)
+    }
+
+    /// Sends `command` to the server.
+    ///
+    /// The command is applied asynchronously, when the server is polled next time.
+    pub fn send(&self, command: Command) -> Result<(), Error> {
+        let action = format!("command {}", command);
+        log::info!("Received the command {:?} via the handle", command);
+        if self.0.send(command).is_err() {
+            audit::record(&Caller::Handle, &action, "rejected");
+            track_panic!(ErrorKind::Other, "Server stopped");
+        }
+        audit::record(&Caller::Handle, &action, "accepted");
+        Ok(())
+    }
+}
+
+/// The command channels of the replicas of a proxy server (see `ProxyServerBuilder::replica`).
+///
+/// Clones share the same channels.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Replicas(Arc<Mutex<Vec<mpsc::Sender<Command>>>>);
+impl Replicas {
+    pub fn add(&self, tx: mpsc::Sender<Command>) {
+        self.0.lock().expect("Never fails").push(tx);
+    }
+
+    /// Forwards `command` to the replicas, and forgets the ones which have stopped.
+    pub fn forward(&self, command: &Command) {
+        self.0
+            .lock()
+            .expect("Never fails")
+            .retain(|tx| tx.send(command.clone()).is_ok());
+    }
+}
+
+/// Parses a `<key>:<value>` pair of node metadata.
+pub(crate) fn parse_node_meta(s: &str) -> Result<(String, String), Error> {
+    let mut tokens = s.splitn(2, ':');
+    let key = tokens.next().expect("Never fails");
+    let value = track_assert_some!(
+        tokens.next(),
+        ErrorKind::InvalidInput,
+        "Not a `<key>:<value>` pair: {:?}",
+        s
+    );
+    track_assert!(
+        !key.is_empty(),
+        ErrorKind::InvalidInput,
+        "Empty node metadata key: {:?}",
+        s
+    );
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// The nodes excluded from the candidate servers by commands.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Exclusions {
+    /// Names of the ejected nodes.
+    pub nodes: HashSet<String>,
+
+    /// Node metadata (key and value pairs) of the quarantined nodes.
+    pub node_meta: HashSet<(String, String)>,
+}
+impl Exclusions {
+    /// Returns `true` if the node which has the given name and metadata is excluded.
+    pub fn is_excluded<'a, I>(&self, node: &str, meta: I) -> bool
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        if self.nodes.contains(node) {
+            return true;
+        }
+        if self.node_meta.is_empty() {
+            return false;
+        }
+        meta.into_iter().any(|(k, v)| {
+            self.node_meta
+                .iter()
+                .any(|(key, value)| key == k && value == v)
+        })
+    }
+}
+
+/// The settings read from the Consul KV store at runtime (see `ProxyServerBuilder::config_kv_prefix`).
+///
+/// The keys under the prefix are `tag`, `connect_timeout_ms` and `maintenance` (`true` or `false`).
+/// The settings of absent keys are left as configured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DynamicConfig {
+    /// Tag which replaces the configured ones.
+    pub tag: Option<String>,
+
+    /// Timeout which replaces the configured `ProxyServerBuilder::connect_timeout`.
+    pub connect_timeout: Option<Duration>,
+
+    /// Whether new connections are refused.
+    pub maintenance: bool,
+}
+impl DynamicConfig {
+    /// Makes the settings from the given key and value pairs, where the keys are relative to the prefix.
+    ///
+    /// Unknown keys and invalid values are ignored.
+    pub fn from_entries<'a, I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut config = DynamicConfig::default();
+        for (key, value) in entries {
+            let value = value.trim();
+            match key {
+                "tag" => config.tag = Some(value.to_owned()).filter(|t| !t.is_empty()),
+                "connect_timeout_ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.connect_timeout = Some(Duration::from_millis(ms)),
+                    _ => log::warn!("Ignored the invalid value of {:?}: {:?}", key, value),
+                },
+                "maintenance" => match value.parse() {
+                    Ok(maintenance) => config.maintenance = maintenance,
+                    Err(_) => log::warn!("Ignored the invalid value of {:?}: {:?}", key, value),
+                },
+                _ => log::debug!("Ignored the unknown key {:?}", key),
+            }
+        }
+        config
+    }
+}
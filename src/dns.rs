@@ -0,0 +1,354 @@
+use fibers::sync::oneshot;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use consul::ServiceNode;
+use control::Exclusions;
+use random;
+use {Error, ErrorKind, Result};
+
+/// The time after which a query is regarded as failed.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum size of a UDP response (Consul truncates larger ones).
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_SRV: u16 = 33;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// A query of the SRV records of a service (e.g., `<tag>.<service>.service.<dc>.consul`)
+/// to the [DNS interface] of the consul agent.
+///
+/// [DNS interface]: https://www.consul.io/docs/discovery/dns
+#[derive(Debug, Clone)]
+pub(crate) struct SrvQuery {
+    server: SocketAddr,
+    name: String,
+}
+impl SrvQuery {
+    pub fn new(server: SocketAddr, name: String) -> Self {
+        SrvQuery { server, name }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Issues the query on a background thread, so that fibers are never blocked by it.
+    ///
+    /// The nodes in `excluded` are dropped from the result. Since DNS responses have no node metadata,
+    /// only the exclusions by node names take effect.
+    pub fn lookup(&self, excluded: &Exclusions) -> oneshot::Receiver<Result<Vec<ServiceNode>>> {
+        let (tx, rx) = oneshot::channel();
+        let query = self.clone();
+        let nodes = excluded.nodes.clone();
+        let thread = thread::Builder::new()
+            .name("cotoxy-dns".to_owned())
+            .spawn(move || {
+                let result = track!(query.run()).map(|candidates| {
+                    candidates
+                        .into_iter()
+                        .filter(|n| !nodes.contains(&n.node))
+                        .collect()
+                });
+                let _ = tx.send(result);
+            });
+        if let Err(e) = thread {
+            log::warn!("Cannot start querying {:?}: {}", self.name, e);
+        }
+        rx
+    }
+
+    fn run(&self) -> Result<Vec<ServiceNode>> {
+        let bind_addr: SocketAddr = if self.server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = track!(UdpSocket::bind(bind_addr).map_err(Error::from))?;
+        track!(socket
+            .set_read_timeout(Some(QUERY_TIMEOUT))
+            .map_err(Error::from))?;
+        track!(socket.connect(self.server).map_err(Error::from))?;
+
+        let id = random::next_u64() as u16;
+        let request = track!(encode_query(id, &self.name))?;
+        track!(socket.send(&request).map_err(Error::from))?;
+        let mut buf = vec![0; MAX_RESPONSE_SIZE];
+        loop {
+            let size = track!(
+                socket.recv(&mut buf).map_err(Error::from),
+                "server={}, name={:?}",
+                self.server,
+                self.name
+            )?;
+            let response = &buf[..size];
+            if response.len() >= 2 && u16::from_be_bytes([response[0], response[1]]) != id {
+                // A late response to another query.
+                continue;
+            }
+            return track!(decode_response(response), "name={:?}", self.name);
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(18 + name.len());
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00]); // Recursion desired
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // QDCOUNT=1
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        track_assert!(
+            label.len() < 64,
+            ErrorKind::InvalidInput,
+            "Too long label: {:?}",
+            label
+        );
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Decodes the SRV records in the answer section and the addresses of their targets in the additional section.
+///
+/// The nodes are ordered by the priorities of the records.
+fn decode_response(buf: &[u8]) -> Result<Vec<ServiceNode>> {
+    let mut reader = Reader { buf, pos: 0 };
+    let header = track!(reader.bytes(12))?;
+    let rcode = header[3] & 0x0F;
+    track_assert_eq!(
+        rcode,
+        0,
+        ErrorKind::ConsulUnavailable,
+        "DNS error response: rcode={}",
+        rcode
+    );
+    let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    let (qdcount, ancount, nscount, arcount) = (count(4), count(6), count(8), count(10));
+
+    for _ in 0..qdcount {
+        track!(reader.name())?;
+        track!(reader.bytes(4))?;
+    }
+
+    let mut records = Vec::new();
+    let mut addrs = HashMap::new();
+    for i in 0..(ancount as usize + nscount as usize + arcount as usize) {
+        let name = track!(reader.name())?;
+        let rtype = track!(reader.u16())?;
+        track!(reader.bytes(6))?; // Class and TTL
+        let rdlen = track!(reader.u16())? as usize;
+        let rdata_pos = reader.pos;
+        match rtype {
+            TYPE_SRV if i < ancount as usize => {
+                let priority = track!(reader.u16())?;
+                track!(reader.u16())?; // Weight
+                let port = track!(reader.u16())?;
+                let target = track!(reader.name())?;
+                records.push((priority, port, target));
+            }
+            TYPE_A if rdlen == 4 => {
+                let b = track!(reader.bytes(4))?;
+                addrs
+                    .entry(name)
+                    .or_insert_with(|| IpAddr::from([b[0], b[1], b[2], b[3]]));
+            }
+            TYPE_AAAA if rdlen == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(track!(reader.bytes(16))?);
+                addrs.entry(name).or_insert_with(|| IpAddr::from(octets));
+            }
+            _ => {}
+        }
+        reader.pos = rdata_pos;
+        track!(reader.bytes(rdlen))?;
+    }
+
+    records.sort_by_key(|r| r.0);
+    let mut candidates = Vec::with_capacity(records.len());
+    for (_, port, target) in records {
+        let address = if let Some(address) = addrs.get(&target) {
+            *address
+        } else {
+            log::debug!("No address of the SRV target {:?}", target);
+            continue;
+        };
+        // The targets are `<node>.node.<dc>.<domain>` or `<hex address>.addr.<dc>.<domain>`.
+        let node = target.split('.').next().unwrap_or("").to_owned();
+        candidates.push(ServiceNode::new(node, SocketAddr::new(address, port)));
+    }
+    Ok(candidates)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        track_assert!(
+            self.pos + n <= self.buf.len(),
+            ErrorKind::DeserializeFailed,
+            "Unexpected end of DNS message"
+        );
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = track!(self.bytes(2))?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Reads a (possibly compressed) domain name, which is returned without the trailing dot.
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        for _ in 0..self.buf.len() {
+            track_assert!(
+                pos < self.buf.len(),
+                ErrorKind::DeserializeFailed,
+                "Unexpected end of DNS message"
+            );
+            let len = self.buf[pos] as usize;
+            if len == 0 {
+                self.pos = end.unwrap_or(pos + 1);
+                return Ok(labels.join("."));
+            }
+            if len & 0xC0 == 0xC0 {
+                track_assert!(
+                    pos + 1 < self.buf.len(),
+                    ErrorKind::DeserializeFailed,
+                    "Unexpected end of DNS message"
+                );
+                end.get_or_insert(pos + 2);
+                pos = ((len & 0x3F) << 8) | self.buf[pos + 1] as usize;
+                continue;
+            }
+            track_assert!(
+                pos + 1 + len <= self.buf.len(),
+                ErrorKind::DeserializeFailed,
+                "Unexpected end of DNS message"
+            );
+            labels.push(String::from_utf8_lossy(&self.buf[pos + 1..pos + 1 + len]).into_owned());
+            pos += 1 + len;
+        }
+        track_panic!(
+            ErrorKind::DeserializeFailed,
+            "Too many DNS compression pointers"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 12] = [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 2];
+    const QUESTION: &[u8] = b"\x03web\x07service\x06consul\x00\x00\x21\x00\x01";
+
+    fn push_record(buf: &mut Vec<u8>, name: &[u8], rtype: u16, rdata: &[u8]) {
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, 0, 0, 0]); // Class and TTL
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rdata);
+    }
+
+    fn pointer(pos: usize) -> [u8; 2] {
+        [0xC0 | (pos >> 8) as u8, pos as u8]
+    }
+
+    fn srv(priority: u16, port: u16, target: &[u8]) -> Vec<u8> {
+        let mut rdata = priority.to_be_bytes().to_vec();
+        rdata.extend_from_slice(&[0, 1]); // Weight
+        rdata.extend_from_slice(&port.to_be_bytes());
+        rdata.extend_from_slice(target);
+        rdata
+    }
+
+    /// A response which has three SRV records (one of which has no address),
+    /// whose names and targets are compressed.
+    fn response() -> Vec<u8> {
+        let mut buf = HEADER.to_vec();
+        buf.extend_from_slice(QUESTION);
+
+        // The offset of the target in the first SRV record (after the name, the fixed fields and the SRV fields).
+        let node1 = buf.len() + 2 + 10 + 6;
+        push_record(
+            &mut buf,
+            &pointer(12),
+            TYPE_SRV,
+            &srv(2, 8080, b"\x05node1\x04node\x03dc1\x06consul\x00"),
+        );
+        let node2 = buf.len() + 2 + 10 + 6;
+        let target = [&b"\x05node2"[..], &pointer(node1 + 6)].concat();
+        push_record(&mut buf, &pointer(12), TYPE_SRV, &srv(1, 8081, &target));
+        let target = [&b"\x05node3"[..], &pointer(node1 + 6)].concat();
+        push_record(&mut buf, &pointer(12), TYPE_SRV, &srv(0, 8082, &target));
+
+        push_record(&mut buf, &pointer(node1), TYPE_A, &[10, 0, 0, 1]);
+        let v6: Ipv6Addr = "fd00::2".parse().unwrap();
+        push_record(&mut buf, &pointer(node2), TYPE_AAAA, &v6.octets());
+        buf
+    }
+
+    #[test]
+    fn encode_query_works() {
+        let query = encode_query(0x1234, "web.service.consul.").unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(&query[12..], QUESTION);
+
+        let label = "a".repeat(64);
+        assert!(encode_query(0, &format!("{}.consul", label)).is_err());
+    }
+
+    #[test]
+    fn decode_response_works() {
+        let nodes = decode_response(&response())
+            .unwrap()
+            .into_iter()
+            .map(|n| (n.node.clone(), n.socket_addr(None)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            [
+                ("node2".to_owned(), "[fd00::2]:8081".parse().unwrap()),
+                ("node1".to_owned(), "10.0.0.1:8080".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_response_rejects_truncated_messages() {
+        let buf = response();
+        for size in 0..buf.len() {
+            assert!(decode_response(&buf[..size]).is_err(), "size={}", size);
+        }
+    }
+
+    #[test]
+    fn decode_response_rejects_pointer_loops() {
+        let mut buf = HEADER.to_vec();
+        buf.extend_from_slice(&pointer(12));
+        buf.extend_from_slice(&[0x00, 0x21, 0x00, 0x01]);
+        assert!(decode_response(&buf).is_err());
+    }
+
+    #[test]
+    fn decode_response_rejects_error_responses() {
+        let mut buf = response();
+        buf[3] |= 3; // NXDOMAIN
+        assert!(decode_response(&buf).is_err());
+    }
+}
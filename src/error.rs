@@ -1,28 +1,145 @@
 use fibers::sync::oneshot::MonitorError;
 use std;
-use trackable::error::{ErrorKindExt, Failed, TrackableError};
+use std::fmt;
+use std::net::SocketAddr;
+use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, TrackableError};
 
 /// This crate specific `Error` type.
 #[derive(Debug, Clone)]
-pub struct Error(TrackableError<Failed>);
-derive_traits_for_trackable_error_newtype!(Error, Failed);
+pub struct Error(TrackableError<ErrorKind>);
+derive_traits_for_trackable_error_newtype!(Error, ErrorKind);
 impl From<std::io::Error> for Error {
     fn from(f: std::io::Error) -> Self {
-        Failed.cause(f).into()
+        ErrorKind::Io.cause(f).into()
     }
 }
 impl From<std::net::AddrParseError> for Error {
     fn from(f: std::net::AddrParseError) -> Self {
-        Failed.cause(f).into()
+        ErrorKind::InvalidInput.cause(f).into()
     }
 }
 impl From<std::num::ParseIntError> for Error {
     fn from(f: std::num::ParseIntError) -> Self {
-        Failed.cause(f).into()
+        ErrorKind::InvalidInput.cause(f).into()
     }
 }
 impl From<MonitorError<Error>> for Error {
     fn from(f: MonitorError<Error>) -> Self {
-        f.unwrap_or_else(|| Failed.cause("monitoring channel disconnected").into())
+        f.unwrap_or_else(|| {
+            ErrorKind::Other
+                .cause("monitoring channel disconnected")
+                .into()
+        })
+    }
+}
+
+/// Possible error kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The Consul agent could not be reached, or returned a non-2xx response.
+    ConsulUnavailable,
+
+    /// A response (e.g., of the Consul agent) could not be deserialized.
+    DeserializeFailed,
+
+    /// There are no service servers to which connections can be relayed.
+    NoCandidates,
+
+    /// Connecting to a service server timed out.
+    ConnectTimeout,
+
+    /// Connecting to a service server was refused.
+    ConnectRefused,
+
+    /// An I/O error.
+    Io,
+
+    /// Invalid settings (e.g., a non-positive rate or a malformed schedule).
+    Config,
+
+    /// Invalid input (e.g., a malformed admin request or an unknown command).
+    InvalidInput,
+
+    /// Other errors.
+    Other,
+}
+impl ErrorKind {
+    /// Returns `true` if the operation which failed with this kind of error may succeed when retried
+    /// (possibly with another service server).
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ErrorKind::ConsulUnavailable
+            | ErrorKind::NoCandidates
+            | ErrorKind::ConnectTimeout
+            | ErrorKind::ConnectRefused
+            | ErrorKind::Io => true,
+            ErrorKind::DeserializeFailed
+            | ErrorKind::Config
+            | ErrorKind::InvalidInput
+            | ErrorKind::Other => false,
+        }
+    }
+}
+impl TrackableErrorKind for ErrorKind {}
+
+/// The connection attempts made before giving up on finding an available service server.
+///
+/// This is the cause of `ErrorKind::NoCandidates` errors which are returned
+/// when every candidate has failed, and can be retrieved by `Error::concrete_cause`.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectAttempts(Vec<ConnectAttempt>);
+impl ConnectAttempts {
+    pub(crate) fn push(&mut self, attempt: ConnectAttempt) {
+        self.0.push(attempt);
+    }
+
+    /// Returns the attempts in the order they were made.
+    ///
+    /// This is empty if there were no candidates at all.
+    pub fn attempts(&self) -> &[ConnectAttempt] {
+        &self.0
+    }
+}
+impl fmt::Display for ConnectAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No available service servers (no candidates)");
+        }
+        write!(f, "No available service servers (tried: ")?;
+        for (i, attempt) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", attempt)?;
+        }
+        write!(f, ")")
+    }
+}
+impl std::error::Error for ConnectAttempts {}
+
+/// A failed attempt to connect to a candidate server.
+#[derive(Debug, Clone)]
+pub struct ConnectAttempt {
+    /// Name of the node.
+    ///
+    /// This is empty if the server was specified by `Route::Backend`.
+    pub node: String,
+
+    /// Address which was tried.
+    pub addr: SocketAddr,
+
+    /// Reason of the failure.
+    pub error: Error,
+}
+impl fmt::Display for ConnectAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The tracking history, which follows the first line, is omitted.
+        let error = self.error.to_string();
+        let reason = error.lines().next().unwrap_or("");
+        if self.node.is_empty() {
+            write!(f, "{}: {}", self.addr, reason)
+        } else {
+            write!(f, "{} (node: {}): {}", self.addr, self.node, reason)
+        }
     }
 }
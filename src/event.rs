@@ -0,0 +1,159 @@
+use fibers::sync::mpsc;
+use futures::{Poll, Stream};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use {Error, ServiceNode};
+
+/// An event which occurred on a client connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEvent {
+    /// Milliseconds since the UNIX epoch at which the event occurred.
+    pub unix_time_ms: u64,
+
+    /// Address of the client.
+    pub client: SocketAddr,
+
+    /// The kind of the event.
+    #[serde(flatten)]
+    pub kind: ConnectionEventKind,
+}
+impl ConnectionEvent {
+    fn new(client: SocketAddr, kind: ConnectionEventKind) -> Self {
+        ConnectionEvent {
+            unix_time_ms: unix_time_ms(),
+            client,
+            kind,
+        }
+    }
+
+    /// Returns the address of the backend server related to the event.
+    pub fn backend(&self) -> Option<SocketAddr> {
+        match self.kind {
+            ConnectionEventKind::CandidateSelected { backend, .. }
+            | ConnectionEventKind::Connected { backend, .. }
+            | ConnectionEventKind::Closed { backend, .. } => Some(backend),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of a `ConnectionEvent`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnectionEventKind {
+    /// The connection was accepted.
+    Accepted,
+
+    /// The connection was refused by the proxy.
+    Refused {
+        /// Why the connection was refused.
+        reason: String,
+    },
+
+    /// The proxy selected a backend server to connect to on behalf of the client.
+    ///
+    /// If the connect fails, the next candidate is selected.
+    CandidateSelected {
+        /// Address of the backend server.
+        backend: SocketAddr,
+
+        /// Name of the node of the backend server.
+        node: String,
+    },
+
+    /// The proxy connected to a backend server on behalf of the client.
+    Connected {
+        /// Address of the backend server.
+        backend: SocketAddr,
+
+        /// The node of the backend server.
+        node: ServiceNode,
+    },
+
+    /// The proxied connection was closed.
+    Closed {
+        /// Address of the backend server.
+        backend: SocketAddr,
+
+        /// Lifetime of the proxied connection in milliseconds.
+        duration_ms: u64,
+
+        /// Number of bytes relayed from the client to the backend server.
+        upstream_bytes: u64,
+
+        /// Number of bytes relayed from the backend server to the client.
+        downstream_bytes: u64,
+
+        /// Which side closed the connection (`client_closed` or `server_closed`).
+        reason: String,
+    },
+
+    /// The connection was terminated due to an error.
+    Failed {
+        /// Description of the error.
+        reason: String,
+    },
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+pub(crate) fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+/// A stream of the events which occur on the connections of a proxy server.
+///
+/// Events are buffered until they are polled, so the stream should be polled continuously
+/// (or dropped if no longer needed).
+#[derive(Debug)]
+pub struct ConnectionEvents(mpsc::Receiver<ConnectionEvent>);
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(self.0.poll().expect("Never fails"))
+    }
+}
+
+/// A hub which delivers connection events to the subscribers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventHub {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<ConnectionEvent>>>>,
+    has_subscribers: Arc<AtomicBool>,
+}
+impl EventHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> ConnectionEvents {
+        let (tx, rx) = mpsc::channel();
+        let mut subscribers = self.subscribers.lock().expect("Never fails");
+        subscribers.push(tx);
+        self.has_subscribers.store(true, Ordering::SeqCst);
+        ConnectionEvents(rx)
+    }
+
+    /// Emits the event made by `kind` to the subscribers.
+    ///
+    /// If there are no subscribers, `kind` is never called (and no lock is taken).
+    pub fn emit<F>(&self, client: SocketAddr, kind: F)
+    where
+        F: FnOnce() -> ConnectionEventKind,
+    {
+        if !self.has_subscribers.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().expect("Never fails");
+        let event = ConnectionEvent::new(client, kind());
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+        self.has_subscribers
+            .store(!subscribers.is_empty(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,347 @@
+use fibers::time::timer::{self, Timeout};
+use futures::{Async, Future};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use bandwidth::Throttle;
+use middleware::BoxEndpoint;
+use random;
+use {BandwidthLimit, Endpoint, Error, ErrorKind, Result};
+
+/// Faults injected into the connections of a proxy server, which is useful to chaos-test its clients.
+///
+/// By default, no faults are injected.
+///
+/// Note that the connections into which byte-level faults (i.e., latency, bandwidth clamps or aborts)
+/// are injected relay bytes through a userspace buffer.
+///
+/// This is (de)serialized as a table which has the `connect_delay_ms`, `connect_delay_jitter`,
+/// `latency_ms`, `latency_jitter`, `bandwidth_limit`, `abort_probability` and `abort_within_ms` fields
+/// (all of them are optional).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "RawFaultInjection", into = "RawFaultInjection")]
+pub struct FaultInjection {
+    connect_delay: Duration,
+    connect_delay_jitter: f64,
+    latency: Duration,
+    latency_jitter: f64,
+    bandwidth: Option<u64>,
+    abort_probability: f64,
+    abort_within: Duration,
+}
+impl FaultInjection {
+    /// Makes a new `FaultInjection` instance which injects no faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays the setup (i.e., the Consul query and the connect to a server) of each connection.
+    ///
+    /// The delay is randomly scaled within `±jitter` of it (e.g., `0.1` means ±10%).
+    pub fn connect_delay(&mut self, delay: Duration, jitter: f64) -> &mut Self {
+        self.connect_delay = delay;
+        self.connect_delay_jitter = jitter;
+        self
+    }
+
+    /// Delays the bytes relayed in both directions.
+    ///
+    /// The latency of each chunk of bytes is randomly scaled within `±jitter` of it,
+    /// but the order of the bytes is preserved.
+    pub fn latency(&mut self, latency: Duration, jitter: f64) -> &mut Self {
+        self.latency = latency;
+        self.latency_jitter = jitter;
+        self
+    }
+
+    /// Clamps the throughput of each connection to `bytes_per_sec` bytes per second.
+    ///
+    /// Bytes relayed in both directions are counted against the same limit.
+    pub fn bandwidth(&mut self, bytes_per_sec: u64) -> &mut Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    /// Aborts each connection with the given probability, at a random time within `within` after it is set up.
+    pub fn abort(&mut self, probability: f64, within: Duration) -> &mut Self {
+        self.abort_probability = probability;
+        self.abort_within = within;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        for &(name, jitter) in &[
+            ("Connect delay", self.connect_delay_jitter),
+            ("Latency", self.latency_jitter),
+        ] {
+            track_assert!(
+                (0.0..=1.0).contains(&jitter),
+                ErrorKind::Config,
+                "{} jitter must be in the range [0.0, 1.0]: {}",
+                name,
+                jitter
+            );
+        }
+        track_assert_ne!(
+            self.bandwidth,
+            Some(0),
+            ErrorKind::Config,
+            "Bandwidth must be positive"
+        );
+        track_assert!(
+            (0.0..=1.0).contains(&self.abort_probability),
+            ErrorKind::Config,
+            "Abort probability must be in the range [0.0, 1.0]: {}",
+            self.abort_probability
+        );
+        Ok(())
+    }
+
+    /// Returns the delay of the setup of a new connection.
+    pub(crate) fn next_connect_delay(&self) -> Duration {
+        random::jitter(self.connect_delay, self.connect_delay_jitter)
+    }
+
+    /// Returns `true` if faults are injected into the relayed bytes.
+    pub(crate) fn is_byte_level(&self) -> bool {
+        self.latency > Duration::from_secs(0)
+            || self.bandwidth.is_some()
+            || self.abort_probability > 0.0
+    }
+
+    /// Wraps the endpoints of a new connection to inject the faults.
+    pub(crate) fn inject(
+        &self,
+        client: BoxEndpoint,
+        server: BoxEndpoint,
+    ) -> Result<(BoxEndpoint, BoxEndpoint)> {
+        let bandwidth = if let Some(bytes_per_sec) = self.bandwidth {
+            Some(track!(BandwidthLimit::new(bytes_per_sec))?)
+        } else {
+            None
+        };
+        let abort = if random::next_f64() < self.abort_probability {
+            let after = self.abort_within.mul_f64(random::next_f64());
+            log::debug!("The connection will be aborted after {:?}", after);
+            Some(timer::timeout(after))
+        } else {
+            None
+        };
+        let client = FaultyEndpoint::new(client, self, bandwidth.clone(), None);
+        let server = FaultyEndpoint::new(server, self, bandwidth, abort);
+        Ok((Box::new(client), Box::new(server)))
+    }
+}
+
+impl TryFrom<RawFaultInjection> for FaultInjection {
+    type Error = Error;
+    fn try_from(f: RawFaultInjection) -> Result<Self> {
+        let fault = FaultInjection {
+            connect_delay: Duration::from_millis(f.connect_delay_ms),
+            connect_delay_jitter: f.connect_delay_jitter,
+            latency: Duration::from_millis(f.latency_ms),
+            latency_jitter: f.latency_jitter,
+            bandwidth: f.bandwidth_limit,
+            abort_probability: f.abort_probability,
+            abort_within: Duration::from_millis(f.abort_within_ms),
+        };
+        track!(fault.validate())?;
+        Ok(fault)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawFaultInjection {
+    connect_delay_ms: u64,
+    connect_delay_jitter: f64,
+    latency_ms: u64,
+    latency_jitter: f64,
+    bandwidth_limit: Option<u64>,
+    abort_probability: f64,
+    abort_within_ms: u64,
+}
+impl From<FaultInjection> for RawFaultInjection {
+    fn from(f: FaultInjection) -> Self {
+        RawFaultInjection {
+            connect_delay_ms: f.connect_delay.as_millis() as u64,
+            connect_delay_jitter: f.connect_delay_jitter,
+            latency_ms: f.latency.as_millis() as u64,
+            latency_jitter: f.latency_jitter,
+            bandwidth_limit: f.bandwidth,
+            abort_probability: f.abort_probability,
+            abort_within_ms: f.abort_within.as_millis() as u64,
+        }
+    }
+}
+
+/// An endpoint whose reads are delayed, throttled or aborted.
+///
+/// Bytes read from the inner endpoint are queued until their latency elapses.
+/// Like `Throttle`, the current fiber is woken up by a timer when the next bytes become due.
+struct FaultyEndpoint {
+    inner: BoxEndpoint,
+    latency: Duration,
+    latency_jitter: f64,
+    queue: VecDeque<Chunk>,
+    queued_bytes: usize,
+    last_due: Option<Instant>,
+    eof_due: Option<Instant>,
+    // Kept until the next read, so that the timer is not cancelled.
+    wakeup: Option<Timeout>,
+    throttle: Option<Throttle>,
+    abort: Option<Timeout>,
+}
+impl FaultyEndpoint {
+    /// Maximum number of bytes read from the inner endpoint at a time.
+    const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+    /// Maximum number of bytes queued while their latency elapses.
+    const MAX_QUEUED_BYTES: usize = 256 * 1024;
+
+    fn new(
+        inner: BoxEndpoint,
+        fault: &FaultInjection,
+        bandwidth: Option<BandwidthLimit>,
+        abort: Option<Timeout>,
+    ) -> Self {
+        FaultyEndpoint {
+            inner,
+            latency: fault.latency,
+            latency_jitter: fault.latency_jitter,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            last_due: None,
+            eof_due: None,
+            wakeup: None,
+            throttle: bandwidth.map(Throttle::new),
+            abort,
+        }
+    }
+
+    /// Returns the number of bytes which can be read from the inner endpoint now.
+    fn allowance(&mut self) -> usize {
+        self.throttle.as_mut().map_or(usize::MAX, |t| t.allowance())
+    }
+
+    fn consume(&mut self, size: usize) {
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.consume(size);
+        }
+    }
+
+    /// Returns the time when bytes read now become due, which is never before the previous one.
+    fn next_due(&mut self) -> Instant {
+        let due = Instant::now() + random::jitter(self.latency, self.latency_jitter);
+        let due = self.last_due.map_or(due, |last| due.max(last));
+        self.last_due = Some(due);
+        due
+    }
+
+    /// Reads from the inner endpoint into the queue until it would block.
+    fn fill(&mut self) -> io::Result<()> {
+        while self.eof_due.is_none() && self.queued_bytes < Self::MAX_QUEUED_BYTES {
+            let max = self.allowance().min(Self::MAX_CHUNK_SIZE);
+            if max == 0 {
+                break;
+            }
+            let mut bytes = vec![0; max];
+            match self.inner.read(&mut bytes) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+                Ok(0) => self.eof_due = Some(self.next_due()),
+                Ok(size) => {
+                    self.consume(size);
+                    bytes.truncate(size);
+                    let due = self.next_due();
+                    self.queue.push_back(Chunk {
+                        bytes,
+                        offset: 0,
+                        due,
+                    });
+                    self.queued_bytes += size;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the current fiber be woken up at `due`, and returns `WouldBlock`.
+    fn wait_until(&mut self, due: Instant) -> io::Result<usize> {
+        let mut wakeup = timer::timeout(due.saturating_duration_since(Instant::now()));
+        if wakeup.poll().unwrap_or(Async::Ready(())).is_ready() {
+            futures::task::current().notify();
+        }
+        self.wakeup = Some(wakeup);
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+impl Read for FaultyEndpoint {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut abort) = self.abort {
+            if abort.poll().unwrap_or(Async::Ready(())).is_ready() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Aborted by fault injection",
+                ));
+            }
+        }
+        if self.latency == Duration::from_secs(0) {
+            let max = self.allowance().min(buf.len());
+            if max == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let size = self.inner.read(&mut buf[..max])?;
+            self.consume(size);
+            return Ok(size);
+        }
+
+        self.fill()?;
+        let now = Instant::now();
+        if let Some(due) = self.queue.front().map(|c| c.due) {
+            if due > now {
+                return self.wait_until(due);
+            }
+            let chunk = self.queue.front_mut().expect("Never fails");
+            let size = (chunk.bytes.len() - chunk.offset).min(buf.len());
+            buf[..size].copy_from_slice(&chunk.bytes[chunk.offset..][..size]);
+            chunk.offset += size;
+            if chunk.offset == chunk.bytes.len() {
+                self.queue.pop_front();
+            }
+            self.queued_bytes -= size;
+            return Ok(size);
+        }
+        match self.eof_due {
+            Some(due) if due > now => self.wait_until(due),
+            Some(_) => Ok(0),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+impl Write for FaultyEndpoint {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Endpoint for FaultyEndpoint {
+    // `raw_fd` is not passed through, since reads must go through the queue.
+
+    fn set_cork(&self, cork: bool) -> io::Result<()> {
+        self.inner.set_cork(cork)
+    }
+}
+
+#[derive(Debug)]
+struct Chunk {
+    bytes: Vec<u8>,
+    offset: usize,
+    due: Instant,
+}
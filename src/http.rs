@@ -1,53 +1,745 @@
-use futures::Future;
+use fibers::net::TcpStream;
+use futures::{future, Async, Future, Poll};
+use miasht::builtin::futures::{ReadAllBytes, WriteAllBytes};
 use miasht::builtin::headers::{Connection, ContentLength};
+use miasht::builtin::io::BodyReader;
 use miasht::builtin::{FutureExt, IoExt};
+use miasht::client::{self, Connect, ReadResponse, Response};
 use miasht::Client as HttpClient;
 use miasht::Method;
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future as StdFuture;
+#[cfg(feature = "tokio")]
+use std::mem;
 use std::net::SocketAddr;
-use trackable::error::{ErrorKindExt, Failed};
+use std::path::PathBuf;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use std::task::{self, Context};
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use trackable::error::ErrorKindExt;
 use url::Url;
 
-use {AsyncResult, Error};
+use resolver::AgentAddr;
+use secret::Secret;
+use tls::{self, TlsSettings};
+#[cfg(unix)]
+use unix;
+use {Error, ErrorKind, Result};
 
-pub fn get(addr: SocketAddr, url: Url) -> AsyncResult<Vec<u8>> {
-    let mut path = url.path().to_owned();
-    if let Some(query) = url.query() {
+/// Maximum number of response headers parsed by `parse_response`.
+const MAX_HEADERS: usize = 64;
+
+/// The method of an `HttpRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    /// `GET`
+    Get,
+
+    /// `PUT`
+    Put,
+}
+
+/// The endpoint of the Consul agent to which an `HttpRequest` is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AgentEndpoint {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+impl fmt::Display for AgentEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AgentEndpoint::Tcp(ref addr) => write!(f, "{}", addr),
+            AgentEndpoint::Unix(ref path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// An HTTP request to the Consul agent.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    /// Request method.
+    pub method: HttpMethod,
+
+    /// Endpoint of the Consul agent.
+    pub addr: AgentEndpoint,
+
+    /// Request URL (its host part is the same as `addr`, the hostname which has been resolved to `addr`,
+    /// or `localhost` if `addr` is a Unix domain socket).
+    pub url: Arc<Url>,
+
+    /// ACL token, to be sent in the `X-Consul-Token` header.
+    pub token: Option<Secret>,
+
+    /// TLS settings, if the request is sent over HTTPS (i.e., the scheme of `url` is `https`).
+    pub tls: Option<Arc<TlsSettings>>,
+
+    /// Request body.
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response from the Consul agent.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    /// Status code.
+    pub status: u16,
+
+    /// Response headers (e.g., `X-Consul-Index`).
+    pub headers: Vec<(String, String)>,
+
+    /// Response body.
+    pub body: Vec<u8>,
+}
+impl HttpResponse {
+    /// Makes a new `HttpResponse` instance which has no headers.
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// Returns the value of the header `name` (case-insensitive), if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.as_str())
+    }
+}
+
+/// A future which represents an HTTP exchange issued by `HttpTransport`.
+pub type HttpFuture = Box<dyn Future<Item = HttpResponse, Error = Error> + Send>;
+
+/// A transport which sends HTTP requests to the Consul agent.
+///
+/// Implementing this trait, users can replace the built-in client with one which supports
+/// connection pooling, TLS or proxies. The returned futures are polled on fibers.
+pub trait HttpTransport: fmt::Debug + Send + Sync {
+    /// Sends `request`, and returns a future which resolves to the response.
+    ///
+    /// Responses with non-2xx status codes should be returned as they are.
+    /// Response headers should be kept, since `X-Consul-Index` is used by blocking queries.
+    fn send(&self, request: HttpRequest) -> HttpFuture;
+}
+
+/// The default `HttpTransport`, which opens a new connection for each request.
+///
+/// HTTPS requests are supported only if the `tls` feature is enabled,
+/// and Unix domain sockets are supported only on Unix (and not over HTTPS).
+#[derive(Debug, Default, Clone)]
+pub struct DefaultHttpTransport;
+impl HttpTransport for DefaultHttpTransport {
+    fn send(&self, request: HttpRequest) -> HttpFuture {
+        match request.addr {
+            AgentEndpoint::Tcp(addr) if request.tls.is_some() => tls::exchange(addr, request),
+            AgentEndpoint::Tcp(addr) => Box::new(Exchange::new(addr, request)),
+            AgentEndpoint::Unix(_) if request.tls.is_some() => {
+                let e = ErrorKind::Config.cause(format!(
+                    "HTTPS over a Unix domain socket is not supported: addr={}",
+                    request.addr
+                ));
+                Box::new(future::failed(track!(Error::from(e))))
+            }
+            AgentEndpoint::Unix(ref path) => unix_exchange(path.clone(), request),
+        }
+    }
+}
+
+/// Sends `request` over the Unix domain socket at `path`.
+#[cfg(unix)]
+fn unix_exchange(path: PathBuf, request: HttpRequest) -> HttpFuture {
+    unix::exchange(path, request)
+}
+
+/// Fails, since Unix domain sockets are not supported on this platform.
+#[cfg(not(unix))]
+fn unix_exchange(path: PathBuf, _request: HttpRequest) -> HttpFuture {
+    let e = ErrorKind::Config.cause(format!(
+        "Unix domain sockets are not supported on this platform: path={:?}",
+        path
+    ));
+    Box::new(future::failed(track!(Error::from(e))))
+}
+
+pub(crate) fn get(
+    transport: &dyn HttpTransport,
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    tls: Option<Arc<TlsSettings>>,
+) -> ResponseBody {
+    ResponseBody(get_response(transport, addr, url, token, tls))
+}
+
+pub(crate) fn get_response(
+    transport: &dyn HttpTransport,
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    tls: Option<Arc<TlsSettings>>,
+) -> SuccessfulResponse {
+    SuccessfulResponse(send(
+        transport,
+        HttpMethod::Get,
+        addr,
+        url,
+        token,
+        tls,
+        Vec::new(),
+    ))
+}
+
+/// Like `get_response`, but non-2xx responses are returned as they are.
+pub(crate) fn get_any_response(
+    transport: &dyn HttpTransport,
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    tls: Option<Arc<TlsSettings>>,
+) -> HttpFuture {
+    send(
+        transport,
+        HttpMethod::Get,
+        addr,
+        url,
+        token,
+        tls,
+        Vec::new(),
+    )
+}
+
+pub(crate) fn put(
+    transport: &dyn HttpTransport,
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    tls: Option<Arc<TlsSettings>>,
+    body: Vec<u8>,
+) -> ResponseBody {
+    ResponseBody(SuccessfulResponse(send(
+        transport,
+        HttpMethod::Put,
+        addr,
+        url,
+        token,
+        tls,
+        body,
+    )))
+}
+
+/// Sends a request to the current agent (see `AgentAddr`).
+///
+/// The result is reported to `addr`, so that the following requests fail over to another agent
+/// if the request fails with an error or a 5xx response.
+/// The returned future fails immediately if no addresses have been resolved yet.
+fn send(
+    transport: &dyn HttpTransport,
+    method: HttpMethod,
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    tls: Option<Arc<TlsSettings>>,
+    body: Vec<u8>,
+) -> HttpFuture {
+    let (index, endpoint) = match addr.endpoint() {
+        Err(e) => return Box::new(future::failed(track!(e))),
+        Ok(endpoint) => endpoint,
+    };
+    let agents = addr.clone();
+    let request = transport.send(HttpRequest {
+        method,
+        addr: endpoint,
+        url,
+        token,
+        tls,
+        body,
+    });
+    Box::new(request.then(move |result| {
+        let ok = result.as_ref().is_ok_and(|res| res.status < 500);
+        agents.report(index, ok);
+        result
+    }))
+}
+
+/// A future which returns the body of a successful response.
+pub(crate) struct ResponseBody(SuccessfulResponse);
+impl Future for ResponseBody {
+    type Item = Vec<u8>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track!(self.0.poll())?.map(|res| res.body))
+    }
+}
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseBody(_)")
+    }
+}
+
+/// A future which returns a successful (i.e., 2xx) response.
+pub(crate) struct SuccessfulResponse(HttpFuture);
+impl Future for SuccessfulResponse {
+    type Item = HttpResponse;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(res) = track!(self.0.poll())? {
+            track_assert_eq!(
+                res.status / 100,
+                2,
+                ErrorKind::ConsulUnavailable,
+                "http_status:{}",
+                res.status
+            );
+            Ok(Async::Ready(res))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl fmt::Debug for SuccessfulResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SuccessfulResponse(_)")
+    }
+}
+
+/// A future which issues an HTTP request by using `miasht`.
+struct Exchange {
+    request: HttpRequest,
+    state: ExchangeState,
+}
+impl Exchange {
+    fn new(addr: SocketAddr, request: HttpRequest) -> Self {
+        let connect = HttpClient::new().connect(addr);
+        Exchange {
+            request,
+            state: ExchangeState::Connect(connect),
+        }
+    }
+
+    fn build_request(
+        &mut self,
+        connection: client::Connection<TcpStream>,
+    ) -> WriteAllBytes<client::Request<TcpStream>, Vec<u8>> {
+        let request = &mut self.request;
+        let mut path = request.url.path().to_owned();
+        if let Some(query) = request.url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+
+        let method = match request.method {
+            HttpMethod::Get => Method::Get,
+            HttpMethod::Put => Method::Put,
+        };
+        let mut req = connection.build_request(method, &path);
+        if let Some(host) = request.url.host_str() {
+            req.add_raw_header("Host", host.as_bytes());
+        }
+        if let Some(ref token) = request.token {
+            req.add_raw_header("X-Consul-Token", token.expose().as_bytes());
+        }
+        req.add_header(&ContentLength(request.body.len() as u64));
+        req.add_header(&Connection::Close);
+        req.finish()
+            .write_all_bytes(std::mem::take(&mut request.body))
+    }
+}
+impl Future for Exchange {
+    type Item = HttpResponse;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                ExchangeState::Connect(ref mut f) => {
+                    if let Async::Ready(connection) = track!(f.poll().map_err(into_error))? {
+                        ExchangeState::Write(self.build_request(connection))
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                ExchangeState::Write(ref mut f) => {
+                    if let Async::Ready(req) = track!(f.poll().map_err(into_error))? {
+                        ExchangeState::Flush(req)
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                ExchangeState::Flush(ref mut f) => {
+                    if let Async::Ready(connection) = track!(f.poll().map_err(into_error))? {
+                        ExchangeState::ReadResponse(connection.read_response())
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                ExchangeState::ReadResponse(ref mut f) => {
+                    if let Async::Ready(res) = track!(f.poll().map_err(into_error))? {
+                        let status = res.status().code();
+                        let headers = res
+                            .headers()
+                            .iter()
+                            .map(|(k, v)| (k.to_owned(), String::from_utf8_lossy(v).into_owned()))
+                            .collect();
+                        let reader = track!(res.into_body_reader().map_err(into_error))?;
+                        ExchangeState::ReadBody(status, headers, reader.read_all_bytes())
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                ExchangeState::ReadBody(status, ref mut headers, ref mut f) => {
+                    let body = track!(f.poll().map_err(into_error))?;
+                    return Ok(body.map(|(_, body)| HttpResponse {
+                        status,
+                        headers: std::mem::take(headers),
+                        body,
+                    }));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+enum ExchangeState {
+    Connect(Connect),
+    Write(WriteAllBytes<client::Request<TcpStream>, Vec<u8>>),
+    Flush(client::Request<TcpStream>),
+    ReadResponse(ReadResponse<TcpStream>),
+    ReadBody(
+        u16,
+        Vec<(String, String)>,
+        ReadAllBytes<BodyReader<Response<TcpStream>>>,
+    ),
+}
+
+fn into_error(e: ::miasht::Error) -> Error {
+    Error::from(ErrorKind::ConsulUnavailable.takes_over(e))
+}
+
+/// Encodes `request` as an HTTP/1.0 request, so that the response is not chunked
+/// and ends when the connection is closed.
+///
+/// This is used by the exchanges which cannot run `miasht` on their streams.
+pub(crate) fn request_bytes(request: &HttpRequest) -> Vec<u8> {
+    let method = match request.method {
+        HttpMethod::Get => "GET",
+        HttpMethod::Put => "PUT",
+    };
+    let mut path = request.url.path().to_owned();
+    if let Some(query) = request.url.query() {
         path.push('?');
         path.push_str(query);
     }
+    let mut bytes = format!("{} {} HTTP/1.0\r\n", method, path);
+    if let Some(host) = request.url.host_str() {
+        bytes.push_str(&format!("Host: {}\r\n", host));
+    }
+    if let Some(ref token) = request.token {
+        bytes.push_str(&format!("X-Consul-Token: {}\r\n", token.expose()));
+    }
+    bytes.push_str(&format!("Content-Length: {}\r\n\r\n", request.body.len()));
+    let mut bytes = bytes.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
 
-    let future = HttpClient::new()
-        .connect(addr)
-        .map_err(|e| track!(Error::from(Failed.takes_over(e))))
-        .and_then(move |connection| {
-            let mut req = connection.build_request(Method::Get, &path);
-            if let Some(host) = url.host_str() {
-                req.add_raw_header("Host", host.as_bytes());
-            }
-            req.add_header(&ContentLength(0));
-            req.add_header(&Connection::Close);
-            req.finish()
-                .map_err(|e| track!(Error::from(Failed.takes_over(e))))
-        })
-        .and_then(|connection| {
-            connection
-                .read_response()
-                .map_err(|e| track!(Error::from(Failed.takes_over(e))))
+/// Parses `buf` as a response, returning `None` if more bytes are needed.
+///
+/// The body ends at the `Content-Length` if specified, or at the end of the stream (`eof`).
+pub(crate) fn parse_response(buf: &[u8], eof: bool) -> Result<Option<HttpResponse>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    let status = track!(res
+        .parse(buf)
+        .map_err(|e| Error::from(ErrorKind::ConsulUnavailable.cause(e))))?;
+    let header_len = match status {
+        httparse::Status::Complete(n) => n,
+        httparse::Status::Partial => {
+            track_assert!(
+                !eof,
+                ErrorKind::ConsulUnavailable,
+                "Unexpected end of response"
+            );
+            return Ok(None);
+        }
+    };
+    let headers = res
+        .headers
+        .iter()
+        .map(|h| {
+            let value = String::from_utf8_lossy(h.value).into_owned();
+            (h.name.to_owned(), value)
         })
-        .and_then(|res| {
-            let status = res.status().code();
-            track_assert_eq!(status / 100, 2, Failed, "http_status:{}", status);
+        .collect::<Vec<_>>();
+    let mut response = HttpResponse::new(res.code.unwrap_or(0), Vec::new());
+    response.headers = headers;
+    let body = &buf[header_len..];
+    let content_length = response
+        .header("Content-Length")
+        .and_then(|v| v.trim().parse::<usize>().ok());
+    match content_length {
+        Some(n) if body.len() >= n => response.body = body[..n].to_vec(),
+        Some(_) => {
+            track_assert!(
+                !eof,
+                ErrorKind::ConsulUnavailable,
+                "Unexpected end of response body"
+            );
+            return Ok(None);
+        }
+        None if eof => response.body = body.to_vec(),
+        None => return Ok(None),
+    }
+    Ok(Some(response))
+}
+
+/// Sends a GET request to the current agent (see `AgentAddr`) on the runtime of `tokio`,
+/// and returns the successful (i.e., 2xx) response.
+///
+/// This is the counterpart of `get_response` with `DefaultHttpTransport` for the `tokio` feature,
+/// and the result is reported to `addr` in the same way. HTTPS is not supported.
+#[cfg(feature = "tokio")]
+pub(crate) fn get_tokio(
+    addr: &AgentAddr,
+    url: Arc<Url>,
+    token: Option<Secret>,
+    timeout: Duration,
+) -> TokioExchange {
+    let (index, state) = match addr.endpoint() {
+        Err(e) => (None, TokioExchangeState::Failed(e)),
+        Ok((index, endpoint)) => {
+            let request = HttpRequest {
+                method: HttpMethod::Get,
+                addr: endpoint.clone(),
+                url,
+                token,
+                tls: None,
+                body: Vec::new(),
+            };
+            let bytes = request_bytes(&request);
+            (Some(index), TokioExchangeState::Connect(endpoint, bytes))
+        }
+    };
+    TokioExchange {
+        agents: addr.clone(),
+        index,
+        state,
+        timeout,
+        deadline: None,
+    }
+}
+
+/// A stream to the agent on the runtime of `tokio`.
+#[cfg(feature = "tokio")]
+trait TokioStream: AsyncRead + AsyncWrite + Unpin + Send {}
+#[cfg(feature = "tokio")]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> TokioStream for T {}
+
+#[cfg(feature = "tokio")]
+type TokioConnect<T> = Pin<Box<dyn StdFuture<Output = std::io::Result<T>> + Send>>;
+
+/// A future which sends an HTTP request to the agent on the runtime of `tokio`, and receives the response.
+///
+/// Like `UnixExchange`, this speaks a minimal subset of HTTP by itself (see `request_bytes`).
+/// The socket is connected by the first poll (and the timeout starts then), which runs on the runtime.
+#[cfg(feature = "tokio")]
+pub(crate) struct TokioExchange {
+    agents: AgentAddr,
+    index: Option<usize>,
+    state: TokioExchangeState,
+    timeout: Duration,
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+#[cfg(feature = "tokio")]
+impl TokioExchange {
+    fn poll_exchange(&mut self, cx: &mut Context) -> task::Poll<Result<HttpResponse>> {
+        loop {
+            let next = match mem::replace(&mut self.state, TokioExchangeState::Done) {
+                TokioExchangeState::Failed(e) => return task::Poll::Ready(Err(track!(e))),
+                TokioExchangeState::Connect(AgentEndpoint::Tcp(addr), buf) => {
+                    let f = tokio::net::TcpStream::connect(addr);
+                    TokioExchangeState::ConnectTcp(Box::pin(f), buf)
+                }
+                #[cfg(unix)]
+                TokioExchangeState::Connect(AgentEndpoint::Unix(path), buf) => {
+                    let f = tokio::net::UnixStream::connect(path);
+                    TokioExchangeState::ConnectUnix(Box::pin(f), buf)
+                }
+                #[cfg(not(unix))]
+                TokioExchangeState::Connect(AgentEndpoint::Unix(path), _) => {
+                    let e = ErrorKind::Config.cause(format!(
+                        "Unix domain sockets are not supported on this platform: path={:?}",
+                        path
+                    ));
+                    return task::Poll::Ready(Err(track!(Error::from(e))));
+                }
+                TokioExchangeState::ConnectTcp(mut f, buf) => match f.as_mut().poll(cx) {
+                    task::Poll::Pending => {
+                        self.state = TokioExchangeState::ConnectTcp(f, buf);
+                        return task::Poll::Pending;
+                    }
+                    task::Poll::Ready(stream) => {
+                        let stream = track!(stream.map_err(into_tokio_error))?;
+                        TokioExchangeState::Write(Box::new(stream), buf, 0)
+                    }
+                },
+                #[cfg(unix)]
+                TokioExchangeState::ConnectUnix(mut f, buf) => match f.as_mut().poll(cx) {
+                    task::Poll::Pending => {
+                        self.state = TokioExchangeState::ConnectUnix(f, buf);
+                        return task::Poll::Pending;
+                    }
+                    task::Poll::Ready(stream) => {
+                        let stream = track!(stream.map_err(into_tokio_error))?;
+                        TokioExchangeState::Write(Box::new(stream), buf, 0)
+                    }
+                },
+                TokioExchangeState::Write(mut stream, buf, mut written) => {
+                    match Pin::new(&mut stream).poll_write(cx, &buf[written..]) {
+                        task::Poll::Pending => {
+                            self.state = TokioExchangeState::Write(stream, buf, written);
+                            return task::Poll::Pending;
+                        }
+                        task::Poll::Ready(n) => written += track!(n.map_err(into_tokio_error))?,
+                    }
+                    if written < buf.len() {
+                        TokioExchangeState::Write(stream, buf, written)
+                    } else {
+                        TokioExchangeState::Read(stream, Vec::new())
+                    }
+                }
+                TokioExchangeState::Read(mut stream, mut buf) => {
+                    let mut chunk = [0; 4096];
+                    let mut chunk = ReadBuf::new(&mut chunk);
+                    match Pin::new(&mut stream).poll_read(cx, &mut chunk) {
+                        task::Poll::Pending => {
+                            self.state = TokioExchangeState::Read(stream, buf);
+                            return task::Poll::Pending;
+                        }
+                        task::Poll::Ready(result) => track!(result.map_err(into_tokio_error))?,
+                    }
+                    let eof = chunk.filled().is_empty();
+                    buf.extend_from_slice(chunk.filled());
+                    if let Some(response) = track!(parse_response(&buf, eof))? {
+                        return task::Poll::Ready(Ok(response));
+                    }
+                    TokioExchangeState::Read(stream, buf)
+                }
+                TokioExchangeState::Done => panic!("Cannot poll TokioExchange twice"),
+            };
+            self.state = next;
+        }
+    }
+}
+#[cfg(feature = "tokio")]
+impl StdFuture for TokioExchange {
+    type Output = Result<HttpResponse>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Self::Output> {
+        let this = &mut *self;
+        let timeout = this.timeout;
+        let deadline = this
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        let result = if deadline.as_mut().poll(cx).is_ready() {
+            Err(ErrorKind::ConsulUnavailable
+                .cause(format!("Request timeout: {:?}", timeout))
+                .into())
+        } else {
+            match this.poll_exchange(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            }
+        };
+        if let Some(index) = this.index.take() {
+            let ok = result.as_ref().is_ok_and(|res| res.status < 500);
+            this.agents.report(index, ok);
+        }
+        task::Poll::Ready(result.and_then(|res| {
+            track_assert_eq!(
+                res.status / 100,
+                2,
+                ErrorKind::ConsulUnavailable,
+                "http_status:{}",
+                res.status
+            );
             Ok(res)
-        })
-        .and_then(|res| {
-            res.into_body_reader()
-                .map_err(|e| track!(Error::from(Failed.takes_over(e))))
-        })
-        .and_then(|reader| {
-            reader
-                .read_all_bytes()
-                .map_err(|e| track!(Error::from(Failed.takes_over(e))))
-        })
-        .map(|(_, body)| body);
-    Box::new(future)
+        }))
+    }
+}
+#[cfg(feature = "tokio")]
+impl fmt::Debug for TokioExchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TokioExchange {{ timeout: {:?}, .. }}", self.timeout)
+    }
+}
+#[cfg(feature = "tokio")]
+enum TokioExchangeState {
+    Failed(Error),
+    Connect(AgentEndpoint, Vec<u8>),
+    ConnectTcp(TokioConnect<tokio::net::TcpStream>, Vec<u8>),
+    #[cfg(unix)]
+    ConnectUnix(TokioConnect<tokio::net::UnixStream>, Vec<u8>),
+    Write(Box<dyn TokioStream>, Vec<u8>, usize),
+    Read(Box<dyn TokioStream>, Vec<u8>),
+    Done,
+}
+
+#[cfg(feature = "tokio")]
+fn into_tokio_error(e: std::io::Error) -> Error {
+    Error::from(ErrorKind::ConsulUnavailable.cause(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &[u8] =
+        b"HTTP/1.0 200 OK\r\nX-Consul-Index: 42\r\nContent-Length: 5\r\n\r\nhello";
+
+    #[test]
+    fn parse_response_works() {
+        let response = parse_response(RESPONSE, false).unwrap().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-consul-index"), Some("42"));
+        assert_eq!(response.body, b"hello");
+
+        // Bytes after `Content-Length` are ignored.
+        let buf = [RESPONSE, b"extra"].concat();
+        assert_eq!(parse_response(&buf, true).unwrap().unwrap().body, b"hello");
+
+        // Without `Content-Length`, the body ends at the end of the stream.
+        let buf = b"HTTP/1.0 404 Not Found\r\n\r\nnot found";
+        assert!(parse_response(buf, false).unwrap().is_none());
+        let response = parse_response(buf, true).unwrap().unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"not found");
+    }
+
+    #[test]
+    fn parse_response_waits_for_more_bytes() {
+        for size in 0..RESPONSE.len() {
+            let buf = &RESPONSE[..size];
+            assert!(
+                parse_response(buf, false).unwrap().is_none(),
+                "size={}",
+                size
+            );
+            assert!(parse_response(buf, true).is_err(), "size={}", size);
+        }
+    }
+
+    #[test]
+    fn parse_response_rejects_malformed_responses() {
+        assert!(parse_response(b"HTTP/1.0 abc OK\r\n\r\n", true).is_err());
+        assert!(parse_response(b"SMTP 220\r\n\r\n", true).is_err());
+    }
 }
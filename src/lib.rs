@@ -1,12 +1,38 @@
 //! A TCP proxy using [Consul][consul] for service discovery.
 //!
+//! # Runtime
+//!
+//! The proxy servers run on the executors of [fibers] by default, and are written against `futures` 0.1.
+//!
+//! With the `tokio` feature, a server can run on the runtime of [tokio] instead:
+//! `ProxyServerBuilder::finish_tokio` builds a `TokioProxyServer`, which is a `std::future::Future`
+//! to be spawned (or awaited) on the runtime. Its channels (`TokioProxyChannel`) and Consul queries
+//! (`ConsulSettings::find_candidates_tokio`) are separate implementations on `tokio` sockets,
+//! which support a subset of the settings (the others are reported as errors by `finish_tokio`).
+//!
+//! Applications using other `async`/`await` based executors can embed a proxy server
+//! by `ProxyServerBuilder::spawn_background`,
+//! which runs the server on a dedicated thread and returns a `std::future::Future`.
+//!
 //! [consul]: https://www.consul.io/
+//! [fibers]: https://github.com/dwango/fibers-rs
+//! [tokio]: https://tokio.rs/
 #![warn(missing_docs)]
 extern crate fibers;
 extern crate futures;
+extern crate httparse;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+extern crate io_uring;
+extern crate libc;
 extern crate miasht;
+extern crate mio;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate serde;
+extern crate serde_json;
 extern crate serdeconv;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 #[macro_use]
 extern crate trackable;
 extern crate url;
@@ -17,17 +43,92 @@ macro_rules! track_err {
     };
 }
 
-pub use consul::ConsulSettings;
-pub use error::Error;
+pub use admission::{AcceptFilter, Admission};
+pub use background::BackgroundServer;
+pub use balance::LoadBalancing;
+pub use bandwidth::BandwidthLimit;
+pub use budget::MemoryBudget;
+pub use churn::ChurnLimit;
+pub use cidr::Cidr;
+#[cfg(feature = "tokio")]
+pub use consul::TokioFindCandidates;
+pub use consul::{Consistency, ConsulSettings, FindCandidates, HealthStatus, ServiceNode, Weights};
+pub use control::{Command, CommandSender, ServiceTarget};
+pub use error::{ConnectAttempt, ConnectAttempts, Error, ErrorKind};
+pub use event::{ConnectionEvent, ConnectionEventKind, ConnectionEvents};
+pub use fault::FaultInjection;
+pub use http::{
+    AgentEndpoint, DefaultHttpTransport, HttpFuture, HttpMethod, HttpRequest, HttpResponse,
+    HttpTransport,
+};
+pub use maintenance::{MaintenanceAction, MaintenanceWindow};
+pub use middleware::{BoxEndpoint, Middleware};
+pub use outlier::OutlierDetection;
+#[cfg(feature = "tokio")]
+pub use proxy_channel::TokioProxyChannel;
+pub use proxy_channel::{BufferPool, ChannelClosed, CloseReason, Endpoint, ProxyChannel};
+pub use proxy_group::ProxyGroup;
 pub use proxy_server::{ProxyServer, ProxyServerBuilder};
+pub use rate_limit::RateLimit;
+pub use registration::{RegistrationCheck, ServiceRegistration};
+pub use resolver::ConsulAddr;
+pub use retry::{RetryPolicy, UpstreamRetryPolicy};
+pub use routing::{ConnectionInfo, Route, Router};
+pub use secret::Secret;
+pub use spawner::{Spawner, Task};
+pub use stats::{BackendStats, Stats, StatsSnapshot};
+pub use tls::{TlsSettings, TlsVersion};
+#[cfg(feature = "tokio")]
+pub use tokio_server::TokioProxyServer;
+#[cfg(unix)]
+pub use unix::SocketPermissions;
 
+mod admin;
+mod admission;
+mod audit;
+mod background;
+mod balance;
+mod bandwidth;
+mod base64;
+mod budget;
+mod capacity;
+mod churn;
+mod cidr;
 mod consul;
+mod control;
+mod dns;
 mod error;
+mod event;
+mod fault;
 mod http;
+mod listener;
+mod maintenance;
+mod middleware;
+mod outlier;
 mod proxy_channel;
+mod proxy_group;
 mod proxy_server;
+mod random;
+mod rate_limit;
+mod registration;
+mod resolver;
+mod retry;
+mod routing;
+mod secret;
+mod snapshot;
+mod spawner;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+mod tls;
+#[cfg(feature = "tokio")]
+mod tokio_server;
+#[cfg(unix)]
+mod unix;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub mod testing;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
-
-type AsyncResult<T> = Box<dyn futures::Future<Item = T, Error = Error> + Send + 'static>;
@@ -0,0 +1,457 @@
+use fibers::net::futures::{Connected as TcpConnected, TcpListenerBind};
+use fibers::net::streams::Incoming as TcpIncoming;
+use fibers::net::{TcpListener, TcpStream};
+use futures::{Async, Future, Poll, Stream};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+use proxy_channel::Endpoint;
+use Error;
+
+#[cfg(unix)]
+pub(crate) use self::reuse_port::{ReusePortListener, ReusePortStream};
+
+/// A future which binds the listening socket of a proxy server.
+#[derive(Debug)]
+pub(crate) enum ListenerBind {
+    Tcp(TcpListenerBind),
+    #[cfg(unix)]
+    ReusePort(Option<SocketAddr>),
+}
+impl ListenerBind {
+    /// Binds an ordinary listener (see `fibers::net::TcpListener::bind`).
+    pub fn tcp(addr: SocketAddr) -> Self {
+        ListenerBind::Tcp(TcpListener::bind(addr))
+    }
+
+    /// Binds a listener with `SO_REUSEPORT` (see `ProxyServerBuilder::reuse_port`).
+    #[cfg(unix)]
+    pub fn reuse_port(addr: SocketAddr) -> Self {
+        ListenerBind::ReusePort(Some(addr))
+    }
+}
+impl Future for ListenerBind {
+    type Item = Listener;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            ListenerBind::Tcp(ref mut f) => {
+                Ok(track!(f.poll().map_err(Error::from))?.map(Listener::Tcp))
+            }
+            #[cfg(unix)]
+            ListenerBind::ReusePort(ref mut addr) => {
+                let addr = addr.take().expect("Cannot poll ListenerBind twice");
+                let listener = track!(ReusePortListener::bind(addr), "addr={}", addr)?;
+                Ok(Async::Ready(Listener::ReusePort(listener)))
+            }
+        }
+    }
+}
+
+/// The listening socket of a proxy server.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    ReusePort(ReusePortListener),
+}
+impl Listener {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Listener::Tcp(ref l) => l.local_addr(),
+            #[cfg(unix)]
+            Listener::ReusePort(ref l) => l.local_addr(),
+        }
+    }
+
+    pub fn incoming(self) -> Incoming {
+        match self {
+            Listener::Tcp(l) => Incoming::Tcp(l.incoming()),
+            #[cfg(unix)]
+            Listener::ReusePort(l) => Incoming::ReusePort(l),
+        }
+    }
+}
+
+/// A stream of the clients accepted by a `Listener`.
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Tcp(TcpIncoming),
+    #[cfg(unix)]
+    ReusePort(ReusePortListener),
+}
+impl Stream for Incoming {
+    type Item = (Connected, SocketAddr);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match *self {
+            Incoming::Tcp(ref mut s) => {
+                let polled = track!(s.poll().map_err(Error::from))?;
+                Ok(polled.map(|client| client.map(|(c, addr)| (Connected::Tcp(c), addr))))
+            }
+            #[cfg(unix)]
+            Incoming::ReusePort(ref mut s) => {
+                let polled = track!(s.poll())?;
+                Ok(polled.map(|client| client.map(|(c, addr)| (Connected::ReusePort(c), addr))))
+            }
+        }
+    }
+}
+
+/// A future which represents a client accepted by a `Listener`.
+#[derive(Debug)]
+pub(crate) enum Connected {
+    Tcp(TcpConnected),
+    #[cfg(unix)]
+    ReusePort(reuse_port::Connected),
+}
+impl Future for Connected {
+    type Item = ClientStream;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Connected::Tcp(ref mut f) => {
+                Ok(track!(f.poll().map_err(Error::from))?.map(ClientStream::Tcp))
+            }
+            #[cfg(unix)]
+            Connected::ReusePort(ref mut f) => Ok(track!(f.poll())?.map(ClientStream::ReusePort)),
+        }
+    }
+}
+
+/// The socket of a client accepted by a `Listener`.
+#[derive(Debug)]
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    ReusePort(ReusePortStream),
+}
+impl ClientStream {
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match *self {
+            ClientStream::Tcp(ref s) => s.set_nodelay(nodelay),
+            #[cfg(unix)]
+            ClientStream::ReusePort(ref s) => s.set_nodelay(nodelay),
+        }
+    }
+}
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            ClientStream::ReusePort(ref mut s) => s.read(buf),
+        }
+    }
+}
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            ClientStream::ReusePort(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ClientStream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            ClientStream::ReusePort(ref mut s) => s.flush(),
+        }
+    }
+}
+impl Endpoint for ClientStream {
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        match *self {
+            ClientStream::Tcp(ref s) => s.raw_fd(),
+            ClientStream::ReusePort(ref s) => s.raw_fd(),
+        }
+    }
+
+    fn set_cork(&self, cork: bool) -> io::Result<()> {
+        match *self {
+            ClientStream::Tcp(ref s) => s.set_cork(cork),
+            #[cfg(unix)]
+            ClientStream::ReusePort(ref s) => s.set_cork(cork),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod reuse_port {
+    use fibers::fiber::{self, Context};
+    use fibers::io::poll::{EventedHandle, Interest, Register};
+    use fibers::sync::oneshot::Monitor;
+    use futures::{Async, Future, Poll, Stream};
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::net::{self, SocketAddr};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::sync::Arc;
+
+    #[cfg(target_os = "linux")]
+    use proxy_channel;
+    use proxy_channel::Endpoint;
+    use unix::{into_error, into_io_error, register_error, Evented};
+    use {Error, ErrorKind, Result};
+
+    /// The backlog of the listening socket, which is the same as that of `fibers::net::TcpListener`.
+    const BACKLOG: libc::c_int = 1024;
+
+    /// A TCP listener which runs on `fibers`, and whose socket has `SO_REUSEPORT` set.
+    ///
+    /// Listeners of the same address can be bound by the executors of different threads,
+    /// and then the kernel distributes the incoming clients among them.
+    /// Unlike `fibers::net::TcpListener`, the socket is bound synchronously.
+    #[derive(Debug)]
+    pub struct ReusePortListener {
+        state: ListenerState,
+        local_addr: SocketAddr,
+        monitor: Option<Monitor<(), io::Error>>,
+    }
+    impl ReusePortListener {
+        /// Binds a new listener to `addr`.
+        pub fn bind(addr: SocketAddr) -> Result<Self> {
+            let listener = track!(bind(addr).map_err(Error::from))?;
+            let local_addr = track!(listener.local_addr().map_err(Error::from))?;
+            Ok(ReusePortListener {
+                state: ListenerState::Bound(Some(listener)),
+                local_addr,
+                monitor: None,
+            })
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.local_addr)
+        }
+    }
+    impl Stream for ReusePortListener {
+        type Item = (Connected, SocketAddr);
+        type Error = Error;
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            loop {
+                let next = match self.state {
+                    ListenerState::Bound(ref mut listener) => {
+                        let listener = listener.take().expect("Never fails");
+                        let register = |mut c: Context| c.poller().register(Evented(listener));
+                        let future = fiber::with_current_context(register);
+                        ListenerState::Registering(track_assert_some!(
+                            future,
+                            ErrorKind::Other,
+                            "Not in a fiber"
+                        ))
+                    }
+                    ListenerState::Registering(ref mut f) => {
+                        if let Async::Ready(handle) = track!(f.poll().map_err(register_error))? {
+                            ListenerState::Listening(handle)
+                        } else {
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                    ListenerState::Listening(ref handle) => {
+                        if let Some(mut monitor) = self.monitor.take() {
+                            if monitor.poll().map_err(into_error)?.is_not_ready() {
+                                self.monitor = Some(monitor);
+                                return Ok(Async::NotReady);
+                            }
+                        }
+                        match handle.inner().0.accept() {
+                            Ok((stream, addr)) => {
+                                track!(stream.set_nonblocking(true).map_err(Error::from))?;
+                                let register =
+                                    |mut c: Context| c.poller().register(Evented(stream));
+                                let future = fiber::with_current_context(register);
+                                let future =
+                                    track_assert_some!(future, ErrorKind::Other, "Not in a fiber");
+                                return Ok(Async::Ready(Some((Connected(Some(future)), addr))));
+                            }
+                            Err(e) => {
+                                if e.kind() != io::ErrorKind::WouldBlock {
+                                    return Err(track!(Error::from(e)));
+                                }
+                                self.monitor = Some(handle.monitor(Interest::Read));
+                            }
+                        }
+                        continue;
+                    }
+                };
+                self.state = next;
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    enum ListenerState {
+        Bound(Option<net::TcpListener>),
+        Registering(Register<Evented<net::TcpListener>>),
+        Listening(Arc<EventedHandle<Evented<net::TcpListener>>>),
+    }
+
+    /// Makes a listening socket with `SO_REUSEADDR` and `SO_REUSEPORT`.
+    ///
+    /// `std::net::TcpListener::bind` cannot set options before binding, thus the socket is made by hand.
+    fn bind(addr: SocketAddr) -> io::Result<net::TcpListener> {
+        let family = if addr.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The socket is closed by the drop of `listener` on errors.
+        let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_socket_option(fd, libc::SO_REUSEADDR)?;
+        set_socket_option(fd, libc::SO_REUSEPORT)?;
+
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(ref a) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(a.ip().octets()),
+                };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(ref a) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_addr = libc::in6_addr {
+                    s6_addr: a.ip().octets(),
+                };
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &storage as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::listen(fd, BACKLOG) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    fn set_socket_option(fd: RawFd, option: libc::c_int) -> io::Result<()> {
+        let value: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// A future which represents a client accepted by a `ReusePortListener`.
+    #[derive(Debug)]
+    pub struct Connected(Option<Register<Evented<net::TcpStream>>>);
+    impl Future for Connected {
+        type Item = ReusePortStream;
+        type Error = Error;
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            let mut future = self.0.take().expect("Cannot poll Connected twice");
+            if let Async::Ready(handle) = track!(future.poll().map_err(register_error))? {
+                Ok(Async::Ready(ReusePortStream {
+                    handle,
+                    read_monitor: None,
+                    write_monitor: None,
+                }))
+            } else {
+                self.0 = Some(future);
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// A TCP stream accepted by a `ReusePortListener`.
+    ///
+    /// Like `fibers::net::TcpStream`, an operation which would block returns `WouldBlock`
+    /// and the current fiber is woken up when the socket becomes available.
+    #[derive(Debug)]
+    pub struct ReusePortStream {
+        handle: Arc<EventedHandle<Evented<net::TcpStream>>>,
+        read_monitor: Option<Monitor<(), io::Error>>,
+        write_monitor: Option<Monitor<(), io::Error>>,
+    }
+    impl ReusePortStream {
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.handle.inner().0.set_nodelay(nodelay)
+        }
+
+        fn operate<F, T>(&mut self, interest: Interest, mut f: F) -> io::Result<T>
+        where
+            F: FnMut(&mut net::TcpStream) -> io::Result<T>,
+        {
+            let monitor = if interest == Interest::Read {
+                &mut self.read_monitor
+            } else {
+                &mut self.write_monitor
+            };
+            loop {
+                if let Some(mut m) = monitor.take() {
+                    if m.poll().map_err(into_io_error)?.is_not_ready() {
+                        *monitor = Some(m);
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                } else {
+                    match f(&mut self.handle.inner().0) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            *monitor = Some(self.handle.monitor(interest));
+                        }
+                        result => return result,
+                    }
+                }
+            }
+        }
+    }
+    impl Read for ReusePortStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.operate(Interest::Read, |s| s.read(buf))
+        }
+    }
+    impl Write for ReusePortStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.operate(Interest::Write, |s| s.write(buf))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.operate(Interest::Write, |s| s.flush())
+        }
+    }
+    impl Endpoint for ReusePortStream {
+        fn raw_fd(&self) -> Option<RawFd> {
+            Some(self.handle.inner().0.as_raw_fd())
+        }
+
+        #[cfg(target_os = "linux")]
+        fn set_cork(&self, cork: bool) -> io::Result<()> {
+            proxy_channel::set_tcp_cork(self.handle.inner().0.as_raw_fd(), cork)
+        }
+    }
+}
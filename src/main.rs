@@ -2,47 +2,176 @@ extern crate clap;
 extern crate cotoxy;
 extern crate fibers;
 extern crate futures;
+extern crate libc;
+extern crate log;
+extern crate serde;
+extern crate serdeconv;
+extern crate toml;
 #[macro_use]
 extern crate trackable;
+extern crate url;
 
-use clap::Parser;
-use cotoxy::Error;
-use cotoxy::ProxyServerBuilder;
+use clap::{Parser, Subcommand};
+use cotoxy::MemoryBudget;
+use cotoxy::ServiceRegistration;
+#[cfg(unix)]
+use cotoxy::SocketPermissions;
+use cotoxy::{BandwidthLimit, Consistency, ConsulSettings, Error, ErrorKind, MaintenanceWindow};
+use cotoxy::{ChurnLimit, FaultInjection, OutlierDetection, ProxyGroup, ProxyServerBuilder};
+use cotoxy::{Command, CommandSender, ConsulAddr, LoadBalancing, RegistrationCheck, RetryPolicy};
+use cotoxy::{RateLimit, Secret, Stats, TlsVersion, UpstreamRetryPolicy};
 use fibers::executor::{InPlaceExecutor, ThreadPoolExecutor};
 use fibers::{Executor, Spawn};
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use std::env;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
+use trackable::error::ErrorKindExt;
+use url::form_urlencoded;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+
     /// Name of the service to which clients connect.
-    service: String,
+    service: Option<String>,
 
-    /// TCP address to which the proxy bind.
-    #[clap(long, default_value = "0.0.0.0:17382")]
-    bind_addr: SocketAddr,
+    /// Configuration file in TOML format.
+    ///
+    /// Command line options and environment variables take precedence over the file.
+    /// `${VAR}` and `${VAR:-default}` in the string values of the file are expanded with environment variables.
+    #[clap(long, env = "COTOXY_CONFIG")]
+    config: Option<PathBuf>,
 
-    /// TCP address of the consul agent which the proxy queries.
-    #[clap(long, default_value = "127.0.0.1:8500")]
-    consul_addr: SocketAddr,
+    /// Name of the profile (i.e., `[profiles.<NAME>]` section in the configuration file) to apply.
+    #[clap(long, env = "COTOXY_PROFILE")]
+    profile: Option<String>,
 
-    /// Port number of the service.
+    /// Prints the effective configuration in TOML format and exits.
     #[clap(long)]
+    print_config: bool,
+
+    /// TCP address to which the proxy bind [default: 0.0.0.0:17382].
+    #[clap(long, env = "COTOXY_BIND_ADDR")]
+    bind_addr: Option<SocketAddr>,
+
+    /// Address (`<ip>:<port>`, `<hostname>:<port>` or `unix://<path>`) of the consul agent which the proxy queries
+    /// [default: 127.0.0.1:8500].
+    /// This can be specified multiple times, in which case requests fail over to the next agent
+    /// while the preceding ones are unavailable.
+    #[clap(long, env = "COTOXY_CONSUL_ADDR", value_delimiter = ',')]
+    consul_addr: Vec<ConsulAddr>,
+
+    /// Interval in seconds of re-resolving the hostname of `--consul-addr` [default: 30].
+    #[clap(long, env = "COTOXY_CONSUL_RESOLVE_INTERVAL")]
+    consul_resolve_interval: Option<u64>,
+
+    /// ACL token used for requests to the consul agent.
+    #[clap(long, env = "CONSUL_HTTP_TOKEN", hide_env_values = true)]
+    consul_token: Option<String>,
+
+    /// File which contains the ACL token used for requests to the consul agent.
+    /// The file is re-read when it is modified, so rotated tokens take effect without restarts.
+    /// This overrides `--consul-token`.
+    #[clap(long, env = "CONSUL_HTTP_TOKEN_FILE")]
+    consul_token_file: Option<PathBuf>,
+
+    /// Queries the consul agent over HTTPS.
+    /// This is implied by the other `--consul-*` TLS options.
+    #[clap(long, env = "COTOXY_CONSUL_HTTPS")]
+    consul_https: bool,
+
+    /// PEM file of the CA certificates used to verify the consul agent
+    /// [default: <CA certificates of the system>].
+    #[clap(long, env = "COTOXY_CONSUL_CA_FILE")]
+    consul_ca_file: Option<PathBuf>,
+
+    /// PEM file of the client certificate presented to the consul agent.
+    /// This requires `--consul-client-key`.
+    #[clap(
+        long,
+        env = "COTOXY_CONSUL_CLIENT_CERT",
+        requires = "consul_client_key"
+    )]
+    consul_client_cert: Option<PathBuf>,
+
+    /// PEM file of the (unencrypted) private key of `--consul-client-cert`.
+    #[clap(
+        long,
+        env = "COTOXY_CONSUL_CLIENT_KEY",
+        requires = "consul_client_cert"
+    )]
+    consul_client_key: Option<PathBuf>,
+
+    /// Does not verify the certificate of the consul agent (insecure).
+    #[clap(long, env = "COTOXY_CONSUL_TLS_SKIP_VERIFY")]
+    consul_tls_skip_verify: bool,
+
+    /// Name used to verify the certificate of the consul agent
+    /// [default: <hostname or IP address of `--consul-addr`>].
+    #[clap(long, env = "COTOXY_CONSUL_TLS_SERVER_NAME")]
+    consul_tls_server_name: Option<String>,
+
+    /// Minimum TLS version (`1.0`, `1.1`, `1.2` or `1.3`) negotiated with the consul agent
+    /// and with Connect sidecar proxies [default: the default of OpenSSL].
+    #[clap(long, env = "COTOXY_CONSUL_TLS_MIN_VERSION")]
+    consul_tls_min_version: Option<TlsVersion>,
+
+    /// OpenSSL names of the cipher suites negotiated with the consul agent and with Connect sidecar proxies,
+    /// such as `ECDHE-ECDSA-AES128-GCM-SHA256` (TLS 1.2) and `TLS_AES_128_GCM_SHA256` (TLS 1.3)
+    /// [default: the defaults of OpenSSL].
+    #[clap(long, env = "COTOXY_CONSUL_TLS_CIPHER_SUITES", value_delimiter = ',')]
+    consul_tls_cipher_suites: Vec<String>,
+
+    /// Curves used for key exchanges with the consul agent and with Connect sidecar proxies,
+    /// such as `X25519` and `P-256`, in order of preference [default: the defaults of OpenSSL].
+    #[clap(long, env = "COTOXY_CONSUL_TLS_CURVES", value_delimiter = ',')]
+    consul_tls_curves: Vec<String>,
+
+    /// Port number of the service.
+    #[clap(long, env = "COTOXY_SERVICE_PORT")]
     service_port: Option<u16>,
 
     /// Datacenter to query.
-    #[clap(long)]
+    #[clap(long, env = "COTOXY_DC")]
     dc: Option<String>,
 
-    /// Tag to filter service nodes on.
+    /// Datacenter to which the proxy fails over if `--dc` (or the datacenter of the consul agent)
+    /// has no available service nodes. This can be specified multiple times, in order of preference.
     #[clap(long)]
-    tag: Option<String>,
+    dc_failover: Vec<String>,
+
+    /// Namespace of the service (Consul Enterprise only).
+    #[clap(long, env = "COTOXY_NS")]
+    ns: Option<String>,
+
+    /// Name of the peered cluster from which the service is imported (cluster peering).
+    #[clap(long, env = "COTOXY_PEER")]
+    peer: Option<String>,
+
+    /// Tag to filter service nodes on.
+    /// This can be specified multiple times, in which case service nodes need to have all the tags.
+    #[clap(long, env = "COTOXY_TAG", value_delimiter = ',')]
+    tag: Vec<String>,
 
     /// Node name to sort the service node list in ascending order
     /// based on the estimated round trip time from that node.
     /// If `_agent` is specified,
     /// the node of the consul agent being queried will be used for the sort.
-    #[clap(long)]
+    #[clap(long, env = "COTOXY_NEAR")]
     near: Option<String>,
 
     /// Node metadata key/value pair of the form `key:value`.
@@ -50,61 +179,1846 @@ struct Args {
     #[clap(long)]
     node_meta: Vec<String>,
 
-    /// Number of worker threads.
-    #[clap(long, default_value_t = 1)]
-    threads: usize,
+    /// Also relays clients to the service nodes whose health checks are failing.
+    /// If specified, the catalog API is queried instead of the health API.
+    #[clap(long, env = "COTOXY_INCLUDE_FAILING")]
+    include_failing: bool,
+
+    /// Name of the tagged address of the service (e.g., `lan_ipv6` or `wan_ipv4`) to connect to.
+    /// Service nodes without the tagged address are connected to by their default addresses.
+    #[clap(long, env = "COTOXY_TAGGED_ADDRESS")]
+    tagged_address: Option<String>,
+
+    /// Relays clients to the Consul Connect sidecar proxies of the service by mTLS,
+    /// using the leaf certificate of the given service identity (requires the `tls` feature).
+    #[clap(long, env = "COTOXY_CONNECT")]
+    connect: Option<String>,
+
+    /// Template of the SPIFFE IDs by which the Connect sidecar proxies identify the service,
+    /// with the `{service}`, `{namespace}`, `{dc}` and `{trust_domain}` placeholders
+    /// [default: spiffe://{trust_domain}/ns/{namespace}/dc/{dc}/svc/{service}].
+    #[clap(long, env = "COTOXY_SPIFFE_ID_TEMPLATE")]
+    spiffe_id_template: Option<String>,
+
+    /// Consistency mode of the queries of service nodes [default: default]
+    /// [possible values: default, stale, consistent].
+    /// With `stale`, service nodes can be found even while the Consul cluster has no leader.
+    #[clap(long, env = "COTOXY_CONSISTENCY")]
+    consistency: Option<Consistency>,
+
+    /// Maximum staleness in milliseconds of the responses to the queries of service nodes.
+    /// Responses whose `X-Consul-LastContact` exceeds this are treated as failures.
+    /// If omitted, the staleness is not limited.
+    #[clap(long, env = "COTOXY_MAX_STALE")]
+    max_stale: Option<u64>,
+
+    /// Reads service nodes from the agent cache, which reduces the load on the Consul servers.
+    #[clap(long, env = "COTOXY_CACHED")]
+    cached: bool,
+
+    /// Maximum age in milliseconds of the cached responses to the queries of service nodes.
+    /// Responses whose `Age` exceeds this are treated as failures.
+    /// If omitted, the age is not limited.
+    #[clap(long, env = "COTOXY_MAX_CACHE_AGE")]
+    max_cache_age: Option<u64>,
+
+    /// Timeout in milliseconds of the queries of service nodes [default: 5000].
+    #[clap(long, env = "COTOXY_CONSUL_REQUEST_TIMEOUT")]
+    consul_request_timeout: Option<u64>,
+
+    /// Maximum number of retries of a failed query of service nodes.
+    /// If omitted, failed queries are not retried.
+    #[clap(long, env = "COTOXY_CONSUL_RETRIES")]
+    consul_retries: Option<u32>,
+
+    /// Backoff in milliseconds before the first retry of a failed query, which doubles on each retry
+    /// [default: 100].
+    #[clap(long, env = "COTOXY_CONSUL_RETRY_BACKOFF")]
+    consul_retry_backoff: Option<u64>,
+
+    /// Maximum backoff in milliseconds between retries of a failed query [default: 2000].
+    #[clap(long, env = "COTOXY_CONSUL_RETRY_MAX_BACKOFF")]
+    consul_retry_max_backoff: Option<u64>,
+
+    /// Address (e.g., `127.0.0.1:8600`) of the DNS interface of the consul agent,
+    /// whose SRV records of the service are queried when the HTTP API fails.
+    /// If omitted, the queries do not fall back to DNS.
+    #[clap(long, env = "COTOXY_CONSUL_DNS_FALLBACK")]
+    consul_dns_fallback: Option<SocketAddr>,
+
+    /// Domain of the DNS interface of the consul agent [default: consul].
+    #[clap(long, env = "COTOXY_CONSUL_DNS_DOMAIN")]
+    consul_dns_domain: Option<String>,
+
+    /// File to which the last successful responses of the Consul queries are written.
+    /// When a query fails, the nodes in the last response are used instead,
+    /// so a proxy restarted during an outage of the agent can still serve.
+    #[clap(long, env = "COTOXY_CONSUL_SNAPSHOT_FILE")]
+    consul_snapshot_file: Option<PathBuf>,
+
+    /// Network (e.g., `10.0.0.0/8`) from which clients are allowed to connect.
+    /// If omitted, clients from any network are allowed unless denied.
+    #[clap(long)]
+    allow_cidr: Vec<String>,
+
+    /// Network (e.g., `192.168.1.0/24`) from which clients are refused.
+    /// This takes precedence over `--allow-cidr`.
+    #[clap(long)]
+    deny_cidr: Vec<String>,
+
+    /// Maximum average number of new connections per second from each client IP address.
+    /// If omitted, the rate is not limited.
+    #[clap(long, env = "COTOXY_CLIENT_RATE")]
+    client_rate: Option<f64>,
+
+    /// Maximum number of new connections in a burst from each client IP address [default: 1].
+    #[clap(long, env = "COTOXY_CLIENT_BURST")]
+    client_burst: Option<u32>,
+
+    /// Maximum delay in milliseconds applied to a connection exceeding `--client-rate`
+    /// instead of refusing it [default: 0].
+    #[clap(long, env = "COTOXY_CLIENT_MAX_DELAY")]
+    client_max_delay: Option<u64>,
+
+    /// Maximum average number of new connections per second accepted by each proxy.
+    /// If omitted, the rate is not limited.
+    #[clap(long, env = "COTOXY_ACCEPT_RATE")]
+    accept_rate: Option<f64>,
+
+    /// Maximum number of new connections in a burst accepted by each proxy [default: 1].
+    #[clap(long, env = "COTOXY_ACCEPT_BURST")]
+    accept_burst: Option<u32>,
+
+    /// Maximum time in milliseconds a connection exceeding `--accept-rate` is queued
+    /// before being refused [default: 0].
+    #[clap(long, env = "COTOXY_ACCEPT_MAX_DELAY")]
+    accept_max_delay: Option<u64>,
+
+    /// Number of short-lived connections within `--churn-window` after which a client IP address
+    /// is temporarily banned. If omitted, clients are never banned.
+    #[clap(long, env = "COTOXY_CHURN_THRESHOLD")]
+    churn_threshold: Option<u32>,
+
+    /// Lifetime in milliseconds below which a connection is regarded as short-lived [default: 1000].
+    #[clap(long, env = "COTOXY_CHURN_LIFETIME")]
+    churn_lifetime: Option<u64>,
+
+    /// Length in seconds of the window in which short-lived connections are counted [default: 10].
+    #[clap(long, env = "COTOXY_CHURN_WINDOW")]
+    churn_window: Option<u64>,
+
+    /// Number of seconds a client exceeding `--churn-threshold` is banned [default: 60].
+    #[clap(long, env = "COTOXY_CHURN_BAN_DURATION")]
+    churn_ban_duration: Option<u64>,
+
+    /// Number of consecutive connect failures after which a service node is temporarily ejected
+    /// (i.e., tried only after the other nodes). If omitted, nodes are never ejected.
+    #[clap(long, env = "COTOXY_OUTLIER_FAILURES")]
+    outlier_failures: Option<u32>,
+
+    /// Number of seconds a node is ejected for the first time [default: 30].
+    /// A node which fails again right after an ejection is ejected for twice as long as the previous time.
+    #[clap(long, env = "COTOXY_OUTLIER_EJECTION")]
+    outlier_ejection: Option<u64>,
+
+    /// Maximum number of seconds a node is ejected [default: 300].
+    #[clap(long, env = "COTOXY_OUTLIER_MAX_EJECTION")]
+    outlier_max_ejection: Option<u64>,
+
+    /// Maximum number of simultaneous connections to each service node.
+    /// Nodes at capacity are skipped. If omitted, the connections are not limited.
+    #[clap(long, env = "COTOXY_MAX_SERVER_CONNECTIONS")]
+    max_server_connections: Option<u64>,
+
+    /// Number of milliseconds a client waits for a connection slot when all the nodes
+    /// are at `--max-server-connections` [default: 0].
+    #[clap(long, env = "COTOXY_SERVER_QUEUE_TIMEOUT")]
+    server_queue_timeout: Option<u64>,
+
+    /// Number of worker threads [default: 1].
+    #[clap(long, env = "COTOXY_THREADS")]
+    threads: Option<usize>,
+
+    /// Runs each worker thread on its own executor pinned to a CPU core (Linux only).
+    /// Every worker serves every proxy on its own listening socket with `SO_REUSEPORT`,
+    /// and every connection is handled entirely by the worker that accepted it.
+    /// The admin API, the registration and the stats publishing run on the first worker,
+    /// which forwards the admin commands to the other workers.
+    #[clap(long, env = "COTOXY_PIN_THREADS")]
+    pin_threads: bool,
+
+    /// TCP connect timeout in milliseconds [default: 1000].
+    #[clap(long, env = "COTOXY_CONNECT_TIMEOUT")]
+    connect_timeout: Option<u64>,
+
+    /// Maximum number of servers tried for a connection [default: all the candidates].
+    #[clap(long, env = "COTOXY_MAX_CONNECT_ATTEMPTS")]
+    max_connect_attempts: Option<u32>,
+
+    /// Backoff in milliseconds before trying the next server after a connect failure,
+    /// which doubles on each failure up to `--connect-max-backoff` [default: 0].
+    #[clap(long, env = "COTOXY_CONNECT_BACKOFF")]
+    connect_backoff: Option<u64>,
+
+    /// Maximum backoff in milliseconds between connect attempts [default: 1000].
+    #[clap(long, env = "COTOXY_CONNECT_MAX_BACKOFF")]
+    connect_max_backoff: Option<u64>,
+
+    /// Queries the candidates to Consul once more after all of them have failed to be connected.
+    #[clap(long, env = "COTOXY_REQUERY_CANDIDATES")]
+    requery_candidates: bool,
+
+    /// Static server address used when service discovery fails or returns no nodes.
+    /// This can be specified multiple times, in which case the servers are tried in the given order.
+    #[clap(long, env = "COTOXY_FALLBACK", value_delimiter = ',')]
+    fallback: Vec<SocketAddr>,
+
+    /// Strategy of balancing connections across service nodes [default: ordered]
+    /// [possible values: ordered, round_robin, random, weighted, least_loaded, lowest_latency].
+    /// With `ordered`, the nodes are tried in the order returned by Consul.
+    /// With `weighted`, they are ordered randomly in proportion to the `Weights` of the services.
+    /// With `least_loaded`, the less loaded of two random nodes is tried first,
+    /// and with `lowest_latency`, the one which has the lower recent connect latency.
+    #[clap(long, env = "COTOXY_LOAD_BALANCING")]
+    load_balancing: Option<LoadBalancing>,
 
-    /// TCP connect timeout in milliseconds.
-    #[clap(long, default_value_t = 1000)]
+    /// Node metadata (`<key>:<value>`, e.g., `zone:us-east-1a`) of the service nodes preferred over the others.
+    /// The other nodes are used only if none of the preferred ones are available.
+    #[clap(long, env = "COTOXY_PREFER_NODE_META")]
+    prefer_node_meta: Option<String>,
+
+    /// Number of seconds during which the service nodes newly appearing in Consul receive
+    /// a gradually increasing share of connections. If omitted, new nodes receive their full share immediately.
+    #[clap(long, env = "COTOXY_SLOW_START")]
+    slow_start: Option<u64>,
+
+    /// Size in bytes of the relay buffer allocated for each direction of a connection [default: 8192].
+    #[clap(long, env = "COTOXY_BUFFER_SIZE")]
+    buffer_size: Option<usize>,
+
+    /// Maximum delay in milliseconds for which relayed writes are coalesced with `TCP_CORK` (Linux only).
+    /// If omitted, writes are sent immediately.
+    #[clap(long, env = "COTOXY_CORK_DELAY")]
+    cork_delay: Option<u64>,
+
+    /// Maximum total number of bytes buffered by all connections.
+    /// When reached, the proxy stops reading from clients and servers until buffered bytes are written out.
+    #[clap(long, env = "COTOXY_MAX_BUFFERED_BYTES")]
+    max_buffered_bytes: Option<usize>,
+
+    /// Maximum total number of bytes relayed per second by all connections of each proxy.
+    /// Bytes in both directions are counted, and the bandwidth is shared fairly among connections.
+    /// If omitted, the throughput is not limited.
+    #[clap(long, env = "COTOXY_BANDWIDTH_LIMIT")]
+    bandwidth_limit: Option<u64>,
+
+    /// Name of the consul user events which carry operational commands
+    /// (`drain`, `resume`, `reload`, `shutdown`, `eject <node>`, `readmit <node>`,
+    /// `quarantine <key>:<value>` or `release <key>:<value>`) for the proxy.
+    #[clap(long, env = "COTOXY_COMMAND_EVENT")]
+    command_event: Option<String>,
+
+    /// Consul KV prefix under which the settings applied at runtime are stored
+    /// (`tag`, `connect_timeout_ms` and `maintenance`).
+    #[clap(long, env = "COTOXY_CONFIG_KV_PREFIX")]
+    config_kv_prefix: Option<String>,
+
+    /// Consul KV prefix under which the proxy periodically writes its statistics.
+    #[clap(long, env = "COTOXY_STATS_KV_PREFIX")]
+    stats_kv_prefix: Option<String>,
+
+    /// Interval in seconds of writing statistics to the Consul KV store [default: 10].
+    #[clap(long, env = "COTOXY_STATS_INTERVAL")]
+    stats_interval: Option<u64>,
+
+    /// Name of the Consul service as which the proxy registers itself (with the ID `<name>-<port>`).
+    /// The service is deregistered when the proxy is shut down by SIGTERM, SIGINT or the `shutdown` command.
+    #[clap(long, env = "COTOXY_REGISTER")]
+    register: Option<String>,
+
+    /// Tag of the service registered by `--register`.
+    #[clap(long)]
+    register_tag: Vec<String>,
+
+    /// Address of the service registered by `--register` [default: <IP address of `--bind-addr`>].
+    /// If neither is specified, the address of the consul agent node is used.
+    #[clap(long, env = "COTOXY_REGISTER_ADDRESS")]
+    register_address: Option<IpAddr>,
+
+    /// Makes the consul agent check the service registered by `--register` by TCP connections,
+    /// instead of the TTL check which the proxy keeps passing.
+    #[clap(long, env = "COTOXY_REGISTER_TCP_CHECK")]
+    register_tcp_check: bool,
+
+    /// TTL (or interval of the TCP check) in seconds of the service registered by `--register` [default: 10].
+    #[clap(long, env = "COTOXY_REGISTER_CHECK_INTERVAL")]
+    register_check_interval: Option<u64>,
+
+    /// Watches the service nodes by consul blocking queries, instead of querying them for each client.
+    #[clap(long, env = "COTOXY_WATCH_CANDIDATES")]
+    watch_candidates: bool,
+
+    /// Maximum time in seconds for which each blocking query of `--watch-candidates` waits [default: 300].
+    #[clap(long, env = "COTOXY_WATCH_WAIT")]
+    watch_wait: Option<u64>,
+
+    /// Caches the service nodes, refreshing them every the specified milliseconds in the background.
+    /// Ignored if `--watch-candidates` is specified.
+    #[clap(long, env = "COTOXY_CANDIDATES_TTL")]
+    candidates_ttl: Option<u64>,
+
+    /// Interval in milliseconds of periodic consul queries such as command event polling [default: 1000].
+    #[clap(long, env = "COTOXY_REFRESH_INTERVAL")]
+    refresh_interval: Option<u64>,
+
+    /// Fraction of random jitter applied to the intervals of periodic consul requests [default: 0.1].
+    #[clap(long, env = "COTOXY_REFRESH_JITTER")]
+    refresh_jitter: Option<f64>,
+
+    /// Identifier of this proxy instance [default: <hostname>].
+    #[clap(long, env = "COTOXY_INSTANCE_ID")]
+    instance_id: Option<String>,
+
+    /// Directory to which the proxy changes its root directory after binding.
+    /// `--consul-token-file`, `--consul-snapshot-file`, a Unix socket of the agent
+    /// and `etc/resolv.conf` (for an agent hostname) must exist relative to it.
+    #[clap(long, env = "COTOXY_CHROOT")]
+    chroot: Option<PathBuf>,
+
+    /// TCP address to which the admin HTTP API server bind.
+    /// If omitted, the admin API is disabled.
+    #[clap(long, env = "COTOXY_ADMIN_ADDR")]
+    admin_addr: Option<SocketAddr>,
+
+    /// Unix socket file to which the admin HTTP API server bind (Unix only).
+    /// This can be used together with `--admin-addr`.
+    #[clap(long, env = "COTOXY_ADMIN_SOCKET")]
+    admin_socket: Option<PathBuf>,
+
+    /// Permissions of the admin socket file in octal (e.g., `660`).
+    #[clap(long, env = "COTOXY_ADMIN_SOCKET_MODE")]
+    admin_socket_mode: Option<String>,
+
+    /// Owner of the admin socket file in the form of `<user>[:<group>]` (names or numeric IDs).
+    #[clap(long, env = "COTOXY_ADMIN_SOCKET_OWNER")]
+    admin_socket_owner: Option<String>,
+
+    /// Bearer token required to access the admin HTTP API.
+    /// If neither this nor `--admin-token-file` is specified, the API requires no authentication.
+    #[clap(long, env = "COTOXY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// File which contains the bearer token required to access the admin HTTP API.
+    #[clap(long, env = "COTOXY_ADMIN_TOKEN_FILE")]
+    admin_token_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    /// Streams connection events of a running proxy via its admin API.
+    Tail {
+        /// TCP address of the admin API server of the proxy.
+        #[clap(
+            long,
+            env = "COTOXY_ADMIN_ADDR",
+            required_unless_present = "admin_socket"
+        )]
+        admin_addr: Option<SocketAddr>,
+
+        /// Unix socket file of the admin API server of the proxy.
+        #[clap(long, env = "COTOXY_ADMIN_SOCKET", conflicts_with = "admin_addr")]
+        admin_socket: Option<PathBuf>,
+
+        /// Bearer token for the admin API.
+        #[clap(long, env = "COTOXY_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: Option<String>,
+
+        /// File which contains the bearer token for the admin API.
+        #[clap(long, env = "COTOXY_ADMIN_TOKEN_FILE")]
+        admin_token_file: Option<PathBuf>,
+
+        /// Shows only the events of the given client (an IP address or a socket address).
+        #[clap(long)]
+        client: Option<String>,
+
+        /// Shows only the events related to the given backend (an IP address or a socket address).
+        #[clap(long)]
+        backend: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    service: String,
+    bind_addr: SocketAddr,
+    consul_addr: ConsulAddr,
+    consul_addr_failover: Vec<ConsulAddr>,
+    consul_resolve_interval: u64,
+    consul_token: Option<Secret>,
+    consul_token_file: Option<PathBuf>,
+    consul_https: bool,
+    consul_ca_file: Option<PathBuf>,
+    consul_client_cert: Option<PathBuf>,
+    consul_client_key: Option<PathBuf>,
+    consul_tls_skip_verify: bool,
+    consul_tls_server_name: Option<String>,
+    consul_tls_min_version: Option<TlsVersion>,
+    consul_tls_cipher_suites: Vec<String>,
+    consul_tls_curves: Vec<String>,
+    service_port: Option<u16>,
+    dc: Option<String>,
+    dc_failover: Vec<String>,
+    ns: Option<String>,
+    peer: Option<String>,
+    tag: Option<String>,
+    tags: Vec<String>,
+    near: Option<String>,
+    node_meta: Vec<String>,
+    only_passing: bool,
+    tagged_address: Option<String>,
+    connect: Option<String>,
+    spiffe_id_template: Option<String>,
+    consistency: Consistency,
+    max_stale: Option<u64>,
+    cached: bool,
+    max_cache_age: Option<u64>,
+    consul_request_timeout: u64,
+    consul_retries: Option<u32>,
+    consul_retry_backoff: u64,
+    consul_retry_max_backoff: u64,
+    consul_dns_fallback: Option<SocketAddr>,
+    consul_dns_domain: String,
+    consul_snapshot_file: Option<PathBuf>,
+    allow_cidr: Vec<String>,
+    deny_cidr: Vec<String>,
+    client_rate: Option<f64>,
+    client_burst: u32,
+    client_max_delay: u64,
+    accept_rate: Option<f64>,
+    accept_burst: u32,
+    accept_max_delay: u64,
+    churn_threshold: Option<u32>,
+    churn_lifetime: u64,
+    churn_window: u64,
+    churn_ban_duration: u64,
+    outlier_failures: Option<u32>,
+    outlier_ejection: u64,
+    outlier_max_ejection: u64,
+    max_server_connections: Option<u64>,
+    server_queue_timeout: u64,
+    threads: usize,
+    pin_threads: bool,
     connect_timeout: u64,
+    max_connect_attempts: Option<u32>,
+    connect_backoff: u64,
+    connect_max_backoff: u64,
+    requery_candidates: bool,
+    fallback: Vec<SocketAddr>,
+    load_balancing: LoadBalancing,
+    prefer_node_meta: Option<String>,
+    slow_start: Option<u64>,
+    buffer_size: usize,
+    cork_delay: Option<u64>,
+    max_buffered_bytes: Option<usize>,
+    bandwidth_limit: Option<u64>,
+    command_event: Option<String>,
+    config_kv_prefix: Option<String>,
+    stats_kv_prefix: Option<String>,
+    stats_interval: u64,
+    register: Option<String>,
+    register_tags: Vec<String>,
+    register_address: Option<IpAddr>,
+    register_tcp_check: bool,
+    register_check_interval: u64,
+    watch_candidates: bool,
+    watch_wait: u64,
+    candidates_ttl: Option<u64>,
+    refresh_interval: u64,
+    refresh_jitter: f64,
+    instance_id: Option<String>,
+    chroot: Option<PathBuf>,
+    admin_addr: Option<SocketAddr>,
+    admin_socket: Option<PathBuf>,
+    admin_socket_mode: Option<String>,
+    admin_socket_owner: Option<String>,
+    admin_token: Option<Secret>,
+    admin_token_file: Option<PathBuf>,
+    maintenance: Vec<MaintenanceWindow>,
+    fault: Option<FaultInjection>,
+    proxies: Vec<ProxyConfig>,
+}
+impl Config {
+    fn load(args: Args) -> cotoxy::Result<Self> {
+        let mut config = if let Some(ref path) = args.config {
+            let mut table = track!(load_toml_table(path, 0))?;
+            let profiles = table.remove("profiles");
+            if let Some(ref name) = args.profile {
+                let profile = profiles
+                    .as_ref()
+                    .and_then(|p| p.get(name))
+                    .and_then(|p| p.as_table());
+                let profile =
+                    track_assert_some!(profile, ErrorKind::Config, "Unknown profile: {:?}", name);
+                for (key, value) in profile {
+                    table.insert(key.clone(), value.clone());
+                }
+            }
+            track!(toml::Value::Table(table)
+                .try_into()
+                .map_err(|e| Error::from(ErrorKind::Config.cause(e))))?
+        } else {
+            track_assert!(
+                args.profile.is_none(),
+                ErrorKind::Config,
+                "`--profile` requires a configuration file"
+            );
+            Config::default()
+        };
+        if let Some(service) = args.service {
+            config.service = service;
+        }
+        if let Some(bind_addr) = args.bind_addr {
+            config.bind_addr = bind_addr;
+        }
+        if let Some((consul_addr, failover)) = args.consul_addr.split_first() {
+            config.consul_addr = consul_addr.clone();
+            config.consul_addr_failover = failover.to_vec();
+        }
+        if let Some(interval) = args.consul_resolve_interval {
+            config.consul_resolve_interval = interval;
+        }
+        if let Some(ref token) = args.consul_token {
+            config.consul_token = Some(Secret::new(token));
+        }
+        if args.consul_token_file.is_some() {
+            config.consul_token_file = args.consul_token_file;
+        }
+        if args.consul_https {
+            config.consul_https = true;
+        }
+        if args.consul_ca_file.is_some() {
+            config.consul_ca_file = args.consul_ca_file;
+        }
+        if args.consul_client_cert.is_some() {
+            config.consul_client_cert = args.consul_client_cert;
+            config.consul_client_key = args.consul_client_key;
+        }
+        if args.consul_tls_skip_verify {
+            config.consul_tls_skip_verify = true;
+        }
+        if args.consul_tls_server_name.is_some() {
+            config.consul_tls_server_name = args.consul_tls_server_name;
+        }
+        if args.consul_tls_min_version.is_some() {
+            config.consul_tls_min_version = args.consul_tls_min_version;
+        }
+        if !args.consul_tls_cipher_suites.is_empty() {
+            config.consul_tls_cipher_suites = args.consul_tls_cipher_suites;
+        }
+        if !args.consul_tls_curves.is_empty() {
+            config.consul_tls_curves = args.consul_tls_curves;
+        }
+        if args.service_port.is_some() {
+            config.service_port = args.service_port;
+        }
+        if args.dc.is_some() {
+            config.dc = args.dc;
+        }
+        if !args.dc_failover.is_empty() {
+            config.dc_failover = args.dc_failover;
+        }
+        if args.ns.is_some() {
+            config.ns = args.ns;
+        }
+        if args.peer.is_some() {
+            config.peer = args.peer;
+        }
+        if !args.tag.is_empty() {
+            config.tag = None;
+            config.tags = args.tag;
+        }
+        if args.near.is_some() {
+            config.near = args.near;
+        }
+        if !args.node_meta.is_empty() {
+            config.node_meta = args.node_meta;
+        }
+        if args.include_failing {
+            config.only_passing = false;
+        }
+        if args.tagged_address.is_some() {
+            config.tagged_address = args.tagged_address;
+        }
+        if args.connect.is_some() {
+            config.connect = args.connect;
+        }
+        if args.spiffe_id_template.is_some() {
+            config.spiffe_id_template = args.spiffe_id_template;
+        }
+        if let Some(consistency) = args.consistency {
+            config.consistency = consistency;
+        }
+        if args.max_stale.is_some() {
+            config.max_stale = args.max_stale;
+        }
+        if args.cached {
+            config.cached = true;
+        }
+        if args.max_cache_age.is_some() {
+            config.max_cache_age = args.max_cache_age;
+        }
+        if let Some(consul_request_timeout) = args.consul_request_timeout {
+            config.consul_request_timeout = consul_request_timeout;
+        }
+        if args.consul_retries.is_some() {
+            config.consul_retries = args.consul_retries;
+        }
+        if let Some(consul_retry_backoff) = args.consul_retry_backoff {
+            config.consul_retry_backoff = consul_retry_backoff;
+        }
+        if let Some(consul_retry_max_backoff) = args.consul_retry_max_backoff {
+            config.consul_retry_max_backoff = consul_retry_max_backoff;
+        }
+        if args.consul_dns_fallback.is_some() {
+            config.consul_dns_fallback = args.consul_dns_fallback;
+        }
+        if let Some(consul_dns_domain) = args.consul_dns_domain {
+            config.consul_dns_domain = consul_dns_domain;
+        }
+        if args.consul_snapshot_file.is_some() {
+            config.consul_snapshot_file = args.consul_snapshot_file;
+        }
+        if !args.allow_cidr.is_empty() {
+            config.allow_cidr = args.allow_cidr;
+        }
+        if !args.deny_cidr.is_empty() {
+            config.deny_cidr = args.deny_cidr;
+        }
+        if args.client_rate.is_some() {
+            config.client_rate = args.client_rate;
+        }
+        if let Some(client_burst) = args.client_burst {
+            config.client_burst = client_burst;
+        }
+        if let Some(client_max_delay) = args.client_max_delay {
+            config.client_max_delay = client_max_delay;
+        }
+        if args.accept_rate.is_some() {
+            config.accept_rate = args.accept_rate;
+        }
+        if let Some(accept_burst) = args.accept_burst {
+            config.accept_burst = accept_burst;
+        }
+        if let Some(accept_max_delay) = args.accept_max_delay {
+            config.accept_max_delay = accept_max_delay;
+        }
+        if args.churn_threshold.is_some() {
+            config.churn_threshold = args.churn_threshold;
+        }
+        if let Some(churn_lifetime) = args.churn_lifetime {
+            config.churn_lifetime = churn_lifetime;
+        }
+        if let Some(churn_window) = args.churn_window {
+            config.churn_window = churn_window;
+        }
+        if let Some(churn_ban_duration) = args.churn_ban_duration {
+            config.churn_ban_duration = churn_ban_duration;
+        }
+        if args.outlier_failures.is_some() {
+            config.outlier_failures = args.outlier_failures;
+        }
+        if let Some(outlier_ejection) = args.outlier_ejection {
+            config.outlier_ejection = outlier_ejection;
+        }
+        if let Some(outlier_max_ejection) = args.outlier_max_ejection {
+            config.outlier_max_ejection = outlier_max_ejection;
+        }
+        if args.max_server_connections.is_some() {
+            config.max_server_connections = args.max_server_connections;
+        }
+        if let Some(server_queue_timeout) = args.server_queue_timeout {
+            config.server_queue_timeout = server_queue_timeout;
+        }
+        if let Some(threads) = args.threads {
+            config.threads = threads;
+        }
+        if args.pin_threads {
+            config.pin_threads = true;
+        }
+        if let Some(connect_timeout) = args.connect_timeout {
+            config.connect_timeout = connect_timeout;
+        }
+        if args.max_connect_attempts.is_some() {
+            config.max_connect_attempts = args.max_connect_attempts;
+        }
+        if let Some(connect_backoff) = args.connect_backoff {
+            config.connect_backoff = connect_backoff;
+        }
+        if let Some(connect_max_backoff) = args.connect_max_backoff {
+            config.connect_max_backoff = connect_max_backoff;
+        }
+        if args.requery_candidates {
+            config.requery_candidates = true;
+        }
+        if !args.fallback.is_empty() {
+            config.fallback = args.fallback;
+        }
+        if let Some(load_balancing) = args.load_balancing {
+            config.load_balancing = load_balancing;
+        }
+        if args.prefer_node_meta.is_some() {
+            config.prefer_node_meta = args.prefer_node_meta;
+        }
+        if args.slow_start.is_some() {
+            config.slow_start = args.slow_start;
+        }
+        if let Some(buffer_size) = args.buffer_size {
+            config.buffer_size = buffer_size;
+        }
+        if args.cork_delay.is_some() {
+            config.cork_delay = args.cork_delay;
+        }
+        if args.max_buffered_bytes.is_some() {
+            config.max_buffered_bytes = args.max_buffered_bytes;
+        }
+        if args.bandwidth_limit.is_some() {
+            config.bandwidth_limit = args.bandwidth_limit;
+        }
+        if args.command_event.is_some() {
+            config.command_event = args.command_event;
+        }
+        if args.config_kv_prefix.is_some() {
+            config.config_kv_prefix = args.config_kv_prefix;
+        }
+        if args.stats_kv_prefix.is_some() {
+            config.stats_kv_prefix = args.stats_kv_prefix;
+        }
+        if let Some(stats_interval) = args.stats_interval {
+            config.stats_interval = stats_interval;
+        }
+        if args.register.is_some() {
+            config.register = args.register;
+        }
+        if !args.register_tag.is_empty() {
+            config.register_tags = args.register_tag;
+        }
+        if args.register_address.is_some() {
+            config.register_address = args.register_address;
+        }
+        if args.register_tcp_check {
+            config.register_tcp_check = true;
+        }
+        if let Some(interval) = args.register_check_interval {
+            config.register_check_interval = interval;
+        }
+        if args.watch_candidates {
+            config.watch_candidates = true;
+        }
+        if let Some(watch_wait) = args.watch_wait {
+            config.watch_wait = watch_wait;
+        }
+        if args.candidates_ttl.is_some() {
+            config.candidates_ttl = args.candidates_ttl;
+        }
+        if let Some(refresh_interval) = args.refresh_interval {
+            config.refresh_interval = refresh_interval;
+        }
+        if let Some(refresh_jitter) = args.refresh_jitter {
+            config.refresh_jitter = refresh_jitter;
+        }
+        if args.instance_id.is_some() {
+            config.instance_id = args.instance_id;
+        }
+        if args.chroot.is_some() {
+            config.chroot = args.chroot;
+        }
+        if args.admin_addr.is_some() {
+            config.admin_addr = args.admin_addr;
+        }
+        if args.admin_socket.is_some() {
+            config.admin_socket = args.admin_socket;
+        }
+        if args.admin_socket_mode.is_some() {
+            config.admin_socket_mode = args.admin_socket_mode;
+        }
+        if args.admin_socket_owner.is_some() {
+            config.admin_socket_owner = args.admin_socket_owner;
+        }
+        if let Some(ref token) = args.admin_token {
+            config.admin_token = Some(Secret::new(token));
+        }
+        if args.admin_token_file.is_some() {
+            config.admin_token_file = args.admin_token_file;
+        }
+        track_assert!(
+            0.0 <= config.refresh_jitter && config.refresh_jitter <= 1.0,
+            ErrorKind::Config,
+            "Refresh jitter must be in the range [0.0, 1.0]: {}",
+            config.refresh_jitter
+        );
+        track_assert_ne!(
+            config.buffer_size,
+            0,
+            ErrorKind::Config,
+            "Buffer size must be positive"
+        );
+        track_assert_ne!(
+            config.threads,
+            0,
+            ErrorKind::Config,
+            "Number of threads must be positive"
+        );
+        track_assert!(
+            !config.service.is_empty() || !config.proxies.is_empty(),
+            ErrorKind::Config,
+            "No service name is specified"
+        );
+        Ok(config)
+    }
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            service: String::new(),
+            bind_addr: ProxyServerBuilder::DEFAULT_BIND_ADDR
+                .parse()
+                .expect("Never fails"),
+            consul_addr: ConsulSettings::DEFAULT_CONSUL_ADDR
+                .parse()
+                .expect("Never fails"),
+            consul_addr_failover: Vec::new(),
+            consul_resolve_interval: ConsulSettings::DEFAULT_RESOLVE_INTERVAL_SECS,
+            consul_token: None,
+            consul_token_file: None,
+            consul_https: false,
+            consul_ca_file: None,
+            consul_client_cert: None,
+            consul_client_key: None,
+            consul_tls_skip_verify: false,
+            consul_tls_server_name: None,
+            consul_tls_min_version: None,
+            consul_tls_cipher_suites: Vec::new(),
+            consul_tls_curves: Vec::new(),
+            service_port: None,
+            dc: None,
+            dc_failover: Vec::new(),
+            ns: None,
+            peer: None,
+            tag: None,
+            tags: Vec::new(),
+            near: None,
+            node_meta: Vec::new(),
+            only_passing: true,
+            tagged_address: None,
+            connect: None,
+            spiffe_id_template: None,
+            consistency: Consistency::Default,
+            max_stale: None,
+            cached: false,
+            max_cache_age: None,
+            consul_request_timeout: ConsulSettings::DEFAULT_REQUEST_TIMEOUT_MS,
+            consul_retries: None,
+            consul_retry_backoff: 100,
+            consul_retry_max_backoff: 2000,
+            consul_dns_fallback: None,
+            consul_dns_domain: ConsulSettings::DEFAULT_DNS_DOMAIN.to_owned(),
+            consul_snapshot_file: None,
+            allow_cidr: Vec::new(),
+            deny_cidr: Vec::new(),
+            client_rate: None,
+            client_burst: 1,
+            client_max_delay: 0,
+            accept_rate: None,
+            accept_burst: 1,
+            accept_max_delay: 0,
+            churn_threshold: None,
+            churn_lifetime: 1000,
+            churn_window: 10,
+            churn_ban_duration: 60,
+            outlier_failures: None,
+            outlier_ejection: 30,
+            outlier_max_ejection: 300,
+            max_server_connections: None,
+            server_queue_timeout: 0,
+            threads: 1,
+            pin_threads: false,
+            connect_timeout: ProxyServerBuilder::DEFAULT_CONNECT_TIMEOUT_MS,
+            max_connect_attempts: None,
+            connect_backoff: 0,
+            connect_max_backoff: 1000,
+            requery_candidates: false,
+            fallback: Vec::new(),
+            load_balancing: LoadBalancing::Ordered,
+            prefer_node_meta: None,
+            slow_start: None,
+            buffer_size: ProxyServerBuilder::DEFAULT_BUFFER_SIZE,
+            cork_delay: None,
+            max_buffered_bytes: None,
+            bandwidth_limit: None,
+            command_event: None,
+            config_kv_prefix: None,
+            stats_kv_prefix: None,
+            stats_interval: ProxyServerBuilder::DEFAULT_STATS_INTERVAL_SECS,
+            register: None,
+            register_tags: Vec::new(),
+            register_address: None,
+            register_tcp_check: false,
+            register_check_interval: ServiceRegistration::DEFAULT_CHECK_INTERVAL_SECS,
+            watch_candidates: false,
+            watch_wait: ProxyServerBuilder::DEFAULT_WATCH_WAIT_SECS,
+            candidates_ttl: None,
+            refresh_interval: ProxyServerBuilder::DEFAULT_REFRESH_INTERVAL_MS,
+            refresh_jitter: ProxyServerBuilder::DEFAULT_REFRESH_JITTER,
+            instance_id: None,
+            chroot: None,
+            admin_addr: None,
+            admin_socket: None,
+            admin_socket_mode: None,
+            admin_socket_owner: None,
+            admin_token: None,
+            admin_token_file: None,
+            maintenance: Vec::new(),
+            fault: None,
+            proxies: Vec::new(),
+        }
+    }
+}
+
+/// An additional proxy definition in a configuration file.
+///
+/// All proxies run in the same process and
+/// share the top-level settings except for the fields specified here.
+/// The top-level `admin_addr`, `admin_socket` and `fault` apply only to the top-level proxy,
+/// while `admin_token` (or `admin_token_file`) applies to the admin APIs of all proxies.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyConfig {
+    service: String,
+    bind_addr: SocketAddr,
+    service_port: Option<u16>,
+    tag: Option<String>,
+    admin_addr: Option<SocketAddr>,
+    admin_socket: Option<PathBuf>,
+    fault: Option<FaultInjection>,
+}
+
+/// Maximum nesting depth of `include`s in configuration files.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Loads a configuration file as a TOML table.
+///
+/// The files matching the patterns in the `include` array are merged into the table.
+/// Relative patterns are resolved from the directory of the including file, and
+/// `*` and `?` wildcards are allowed in the last path component.
+/// Values in the including file take precedence over the included ones,
+/// except that arrays (e.g., `proxies`) are concatenated.
+/// Environment variables are expanded in the string values (see `interpolate_env`), including the patterns.
+fn load_toml_table(path: &Path, depth: usize) -> cotoxy::Result<toml::Table> {
+    track_assert!(
+        depth < MAX_INCLUDE_DEPTH,
+        ErrorKind::Config,
+        "Too deep includes: path={:?}",
+        path
+    );
+    let text = track!(
+        fs::read_to_string(path).map_err(Error::from),
+        "path={:?}",
+        path
+    )?;
+    let mut table: toml::Table = track!(
+        toml::from_str(&text).map_err(|e| Error::from(ErrorKind::Config.cause(e))),
+        "path={:?}",
+        path
+    )?;
+    for (key, value) in table.iter_mut() {
+        track!(
+            interpolate_env_values(value),
+            "path={:?}, key={:?}",
+            path,
+            key
+        )?;
+    }
+
+    let mut merged = toml::Table::new();
+    if let Some(include) = table.remove("include") {
+        let patterns = track_assert_some!(
+            include.as_array(),
+            ErrorKind::Config,
+            "`include` must be an array: path={:?}",
+            path
+        );
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for pattern in patterns {
+            let pattern = track_assert_some!(
+                pattern.as_str(),
+                ErrorKind::Config,
+                "`include` must be an array of strings: path={:?}",
+                path
+            );
+            for included in track!(expand_include(base_dir, pattern), "path={:?}", path)? {
+                let included = track!(load_toml_table(&included, depth + 1))?;
+                merge_toml_table(&mut merged, included);
+            }
+        }
+    }
+    merge_toml_table(&mut merged, table);
+    Ok(merged)
+}
+
+fn merge_toml_table(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Array(a)), toml::Value::Array(b)) => a.extend(b),
+            (Some(toml::Value::Table(a)), toml::Value::Table(b)) => merge_toml_table(a, b),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Returns the paths matching `pattern` in lexicographical order.
+fn expand_include(base_dir: &Path, pattern: &str) -> cotoxy::Result<Vec<PathBuf>> {
+    let path = base_dir.join(pattern);
+    let file_name = track_assert_some!(
+        path.file_name().and_then(|n| n.to_str()),
+        ErrorKind::Config,
+        "Invalid include pattern: {:?}",
+        pattern
+    );
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![path.clone()]);
+    }
+
+    let dir = path.parent().unwrap_or(base_dir);
+    let mut paths = Vec::new();
+    for entry in track!(fs::read_dir(dir).map_err(Error::from), "dir={:?}", dir)? {
+        let entry = track!(entry.map_err(Error::from))?;
+        let is_match = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| is_wildcard_match(file_name.as_bytes(), name.as_bytes()));
+        if is_match && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            is_wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && is_wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => is_wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => is_wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Expands environment variables in the string values in `value` (see `interpolate_env`).
+///
+/// This is applied after parsing, so that expanded values are never interpreted as TOML.
+fn interpolate_env_values(value: &mut toml::Value) -> cotoxy::Result<()> {
+    match *value {
+        toml::Value::String(ref mut s) => *s = track!(interpolate_env(s))?,
+        toml::Value::Array(ref mut values) => {
+            for value in values {
+                track!(interpolate_env_values(value))?;
+            }
+        }
+        toml::Value::Table(ref mut table) => {
+            for (key, value) in table.iter_mut() {
+                track!(interpolate_env_values(value), "key={:?}", key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` in a string with environment variables.
+///
+/// `$$` is expanded to a literal `$`.
+fn interpolate_env(text: &str) -> cotoxy::Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if rest.starts_with('$') {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+        if !rest.starts_with('{') {
+            expanded.push('$');
+            continue;
+        }
+        let end = track_assert_some!(rest.find('}'), ErrorKind::Config, "Unterminated `${{`");
+        let expr = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let mut tokens = expr.splitn(2, ":-");
+        let name = tokens.next().expect("Never fails");
+        let default = tokens.next();
+        match (env::var(name), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(e), None) => track_panic!(ErrorKind::Config, "{}: {:?}", e, name),
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
 }
 
 fn main() {
-    env_logger::init();
+    // Audit records of administrative operations are logged unless `RUST_LOG` says otherwise.
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("error,cotoxy::audit=info"),
+    )
+    .init();
+
+    let mut args = Args::parse();
+    if let Some(SubCommand::Tail {
+        admin_addr,
+        admin_socket,
+        admin_token,
+        admin_token_file,
+        client,
+        backend,
+    }) = args.command.take()
+    {
+        let token = track_try_unwrap!(load_admin_token(
+            admin_token.as_deref(),
+            admin_token_file.as_deref()
+        ));
+        track_try_unwrap!(tail(admin_addr, admin_socket, token, client, backend));
+        return;
+    }
+
+    let print_config = args.print_config;
+    let config = track_try_unwrap!(Config::load(args));
+    if print_config {
+        let toml = track_try_unwrap!(serdeconv::to_toml_string(&config)
+            .map_err(|e| Error::from(ErrorKind::Other.takes_over(e))));
+        print!("{}", toml);
+        return;
+    }
+
+    let mut proxies = Vec::new();
+    if !config.service.is_empty() {
+        proxies.push(track_try_unwrap!(make_proxy(&config, None)));
+    }
+    for p in &config.proxies {
+        proxies.push(track_try_unwrap!(make_proxy(&config, Some(p))));
+    }
+    if let Some(limit) = config.max_buffered_bytes {
+        let budget = MemoryBudget::new(limit);
+        for proxy in &mut proxies {
+            proxy.memory_budget(budget.clone());
+        }
+    }
+
+    // Signals must be blocked before any executor threads are spawned, so that they inherit the mask.
+    let shutdown = Arc::new(Shutdown::default());
+    #[cfg(unix)]
+    track_try_unwrap!(handle_signals(shutdown.clone()));
+
+    if config.pin_threads {
+        execute_pinned(config.threads, proxies, &shutdown);
+    } else if config.threads == 1 {
+        execute(InPlaceExecutor::new().unwrap(), &proxies, &shutdown);
+    } else {
+        execute(
+            ThreadPoolExecutor::with_thread_count(config.threads).unwrap(),
+            &proxies,
+            &shutdown,
+        );
+    }
+}
 
-    let args = Args::parse();
-    let bind_addr: SocketAddr = args.bind_addr;
-    let consul_addr: SocketAddr = args.consul_addr;
-    let service = args.service;
-    let threads: usize = args.threads;
-    let connect_timeout: u64 = args.connect_timeout;
+/// The handles of the running proxies, to which `Command::Shutdown` is sent on SIGTERM or SIGINT.
+#[derive(Default)]
+struct Shutdown {
+    state: Mutex<(bool, Vec<CommandSender>)>,
+}
+impl Shutdown {
+    /// Adds the handle of a proxy, which is shut down at once if the shutdown has already been requested.
+    fn add(&self, commands: CommandSender) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.0 {
+            let _ = commands.send(Command::Shutdown);
+        }
+        state.1.push(commands);
+    }
 
-    let mut proxy = ProxyServerBuilder::new(&service);
-    proxy.bind_addr(bind_addr);
-    proxy.connect_timeout(Duration::from_millis(connect_timeout));
+    /// Shuts down all the proxies, and returns `false` if this has already been called.
+    fn request(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.0 {
+            return false;
+        }
+        state.0 = true;
+        for commands in &state.1 {
+            // Fails only if the proxy has already stopped.
+            let _ = commands.send(Command::Shutdown);
+        }
+        true
+    }
+}
 
-    proxy.consul().consul_addr(consul_addr);
-    if let Some(service_port) = args.service_port {
+/// Blocks SIGTERM and SIGINT in the calling thread, and spawns a thread which waits for them.
+///
+/// The first signal shuts down the proxies gracefully, and the second one makes the process exit immediately.
+#[cfg(unix)]
+fn handle_signals(shutdown: Arc<Shutdown>) -> cotoxy::Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        let e = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        track_assert_eq!(
+            e,
+            0,
+            ErrorKind::Other,
+            "Cannot block signals: {}",
+            io::Error::from_raw_os_error(e)
+        );
+        set
+    };
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+        log::info!("Received signal {}", signal);
+        if !shutdown.request() {
+            log::warn!("Shutdown has already been requested; exiting immediately");
+            process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+fn make_proxy(config: &Config, p: Option<&ProxyConfig>) -> cotoxy::Result<ProxyServerBuilder> {
+    let service = p.map_or(&config.service, |p| &p.service);
+    let mut proxy = ProxyServerBuilder::new(service);
+    proxy.bind_addr(p.map_or(config.bind_addr, |p| p.bind_addr));
+    proxy.connect_timeout(Duration::from_millis(config.connect_timeout));
+    proxy.upstream_retry(track!(UpstreamRetryPolicy::new(
+        config.max_connect_attempts,
+        None,
+        Duration::from_millis(config.connect_backoff),
+        Duration::from_millis(config.connect_max_backoff),
+        config.requery_candidates
+    ))?);
+    proxy.fallback_servers(config.fallback.clone());
+    proxy.load_balancing(config.load_balancing);
+    if let Some(ref meta) = config.prefer_node_meta {
+        let (key, value) = track_assert_some!(
+            meta.split_once(':'),
+            ErrorKind::InvalidInput,
+            "Not a `<key>:<value>` pair: {:?}",
+            meta
+        );
+        proxy.prefer_node_meta(key, value);
+    }
+    if let Some(slow_start) = config.slow_start {
+        proxy.slow_start(Duration::from_secs(slow_start));
+    }
+    proxy.buffer_size(config.buffer_size);
+    if let Some(delay) = config.cork_delay {
+        proxy.cork_delay(Duration::from_millis(delay));
+    }
+    if let Some(limit) = config.bandwidth_limit {
+        proxy.bandwidth_limit(track!(BandwidthLimit::new(limit))?);
+    }
+
+    proxy
+        .consul()
+        .resolve_interval(Duration::from_secs(config.consul_resolve_interval))
+        .consul_addr(config.consul_addr.clone())
+        .consul_addr_failover(config.consul_addr_failover.clone());
+    if let Some(ref token) = config.consul_token {
+        proxy.consul().token(token.expose());
+    }
+    if let Some(ref path) = config.consul_token_file {
+        proxy.consul().token_file(path);
+    }
+    let https = config.consul_https
+        || config.consul_ca_file.is_some()
+        || config.consul_client_cert.is_some()
+        || config.consul_tls_skip_verify
+        || config.consul_tls_server_name.is_some();
+    track_assert!(
+        !https || cfg!(feature = "tls"),
+        ErrorKind::Config,
+        "HTTPS connections to the consul agent require the `tls` feature"
+    );
+    if https {
+        proxy.consul().https(true);
+    }
+    if let Some(ref path) = config.consul_ca_file {
+        proxy.consul().tls_ca_file(path);
+    }
+    match (&config.consul_client_cert, &config.consul_client_key) {
+        (Some(cert), Some(key)) => {
+            proxy.consul().tls_client_cert(cert, key);
+        }
+        (None, None) => {}
+        _ => track_panic!(
+            ErrorKind::Config,
+            "`consul_client_cert` and `consul_client_key` must be specified together"
+        ),
+    }
+    if config.consul_tls_skip_verify {
+        proxy.consul().tls_skip_verify(true);
+    }
+    if let Some(ref name) = config.consul_tls_server_name {
+        proxy.consul().tls_server_name(name);
+    }
+    if let Some(version) = config.consul_tls_min_version {
+        proxy.consul().tls_min_version(version);
+    }
+    proxy
+        .consul()
+        .tls_cipher_suites(config.consul_tls_cipher_suites.clone())
+        .tls_curves(config.consul_tls_curves.clone());
+    if let Some(ref name) = config.command_event {
+        proxy.command_event(name);
+    }
+    if let Some(ref prefix) = config.config_kv_prefix {
+        proxy.config_kv_prefix(prefix);
+    }
+    if let Some(ref prefix) = config.stats_kv_prefix {
+        proxy.publish_stats(prefix);
+    }
+    proxy.stats_interval(Duration::from_secs(config.stats_interval));
+    if let Some(ref name) = config.register {
+        let mut registration = ServiceRegistration::new(name);
+        for tag in &config.register_tags {
+            registration.add_tag(tag);
+        }
+        if let Some(address) = config.register_address {
+            registration.address(address);
+        }
+        let interval = Duration::from_secs(config.register_check_interval);
+        registration.check(if config.register_tcp_check {
+            RegistrationCheck::Tcp(interval)
+        } else {
+            RegistrationCheck::Ttl(interval)
+        });
+        proxy.register(registration);
+    }
+    if config.watch_candidates {
+        proxy.watch_candidates();
+    }
+    proxy.watch_wait(Duration::from_secs(config.watch_wait));
+    if let Some(ttl) = config.candidates_ttl {
+        proxy.candidates_ttl(Duration::from_millis(ttl));
+    }
+    proxy.refresh_interval(Duration::from_millis(config.refresh_interval));
+    proxy.refresh_jitter(config.refresh_jitter);
+    if let Some(ref id) = config.instance_id {
+        proxy.instance_id(id);
+    }
+    if let Some(ref dir) = config.chroot {
+        proxy.chroot(dir);
+    }
+    if let Some(addr) = p.map_or(config.admin_addr, |p| p.admin_addr) {
+        proxy.admin_addr(addr);
+    }
+    if let Some(path) = p.map_or(config.admin_socket.as_ref(), |p| p.admin_socket.as_ref()) {
+        track!(set_admin_socket(&mut proxy, config, path))?;
+    }
+    if let Some(token) = track!(load_admin_token(
+        config.admin_token.as_ref().map(Secret::expose),
+        config.admin_token_file.as_deref()
+    ))? {
+        proxy.admin_token(&token);
+    }
+    if let Some(fault) = p.map_or(config.fault.as_ref(), |p| p.fault.as_ref()) {
+        proxy.fault_injection(fault.clone());
+    }
+    if let Some(service_port) = p.map_or(config.service_port, |p| p.service_port) {
         proxy.service_port(service_port);
     }
-    if let Some(dc) = args.dc {
-        proxy.consul().dc(&dc);
+    if let Some(ref dc) = config.dc {
+        proxy.consul().dc(dc);
+    }
+    if !config.dc_failover.is_empty() {
+        proxy.consul().dc_failover(config.dc_failover.clone());
+    }
+    if let Some(ref ns) = config.ns {
+        proxy.consul().namespace(ns);
+    }
+    if let Some(ref peer) = config.peer {
+        proxy.consul().peer(peer);
+    }
+    if let Some(tag) = p.and_then(|p| p.tag.as_ref()) {
+        proxy.consul().tag(tag);
+    } else {
+        for tag in config.tag.iter().chain(&config.tags) {
+            proxy.consul().add_tag(tag);
+        }
+    }
+    if let Some(ref near) = config.near {
+        proxy.consul().near(near);
+    }
+    proxy.consul().only_passing(config.only_passing);
+    if let Some(ref name) = config.tagged_address {
+        proxy.consul().tagged_address(name);
+    }
+    proxy.consul().consistency(config.consistency);
+    if let Some(ref identity) = config.connect {
+        proxy.connect(identity);
+    }
+    if let Some(ref template) = config.spiffe_id_template {
+        proxy.consul().spiffe_id_template(template);
+    }
+    if let Some(max_stale) = config.max_stale {
+        proxy.consul().max_stale(Duration::from_millis(max_stale));
     }
-    if let Some(tag) = args.tag {
-        proxy.consul().tag(&tag);
+    proxy.consul().cached(config.cached);
+    if let Some(max_cache_age) = config.max_cache_age {
+        proxy
+            .consul()
+            .max_cache_age(Duration::from_millis(max_cache_age));
     }
-    if let Some(near) = args.near {
-        proxy.consul().near(&near);
+    proxy
+        .consul()
+        .request_timeout(Duration::from_millis(config.consul_request_timeout));
+    if let Some(retries) = config.consul_retries {
+        proxy.consul().retry(track!(RetryPolicy::new(
+            retries,
+            Duration::from_millis(config.consul_retry_backoff),
+            Duration::from_millis(config.consul_retry_max_backoff),
+            RetryPolicy::DEFAULT_JITTER
+        ))?);
     }
-    for m in args.node_meta {
+    if let Some(addr) = config.consul_dns_fallback {
+        proxy.consul().dns_fallback(addr);
+    }
+    proxy.consul().dns_domain(&config.consul_dns_domain);
+    if let Some(ref path) = config.consul_snapshot_file {
+        proxy.consul().snapshot_file(path);
+    }
+    for m in &config.maintenance {
+        proxy.add_maintenance_window(m.clone());
+    }
+    for m in &config.node_meta {
         let mut tokens = m.splitn(2, ':');
         let key = tokens.next().expect("Never fails");
         let value = tokens.next().unwrap_or("");
         proxy.consul().add_node_meta(key, value);
     }
+    for c in &config.allow_cidr {
+        proxy.add_allowed_cidr(track!(c.parse())?);
+    }
+    for c in &config.deny_cidr {
+        proxy.add_denied_cidr(track!(c.parse())?);
+    }
+    if let Some(rate) = config.client_rate {
+        proxy.client_rate_limit(track!(RateLimit::new(
+            rate,
+            config.client_burst,
+            Duration::from_millis(config.client_max_delay)
+        ))?);
+    }
+    if let Some(rate) = config.accept_rate {
+        proxy.accept_rate_limit(track!(RateLimit::new(
+            rate,
+            config.accept_burst,
+            Duration::from_millis(config.accept_max_delay)
+        ))?);
+    }
+    if let Some(threshold) = config.churn_threshold {
+        proxy.churn_limit(track!(ChurnLimit::new(
+            threshold,
+            Duration::from_millis(config.churn_lifetime),
+            Duration::from_secs(config.churn_window),
+            Duration::from_secs(config.churn_ban_duration)
+        ))?);
+    }
+    if let Some(failures) = config.outlier_failures {
+        proxy.outlier_detection(track!(OutlierDetection::new(
+            failures,
+            Duration::from_secs(config.outlier_ejection),
+            Duration::from_secs(config.outlier_max_ejection)
+        ))?);
+    }
+    if let Some(limit) = config.max_server_connections {
+        proxy.max_server_connections(limit);
+        proxy.server_queue_timeout(Duration::from_millis(config.server_queue_timeout));
+    }
+    track!(proxy.validate(), "service={:?}", service)?;
+    Ok(proxy)
+}
 
-    if threads == 1 {
-        execute(InPlaceExecutor::new().unwrap(), &proxy);
-    } else {
-        execute(
-            ThreadPoolExecutor::with_thread_count(threads).unwrap(),
-            &proxy,
+fn execute<E: Executor + Spawn>(
+    mut executor: E,
+    proxies: &[ProxyServerBuilder],
+    shutdown: &Shutdown,
+) {
+    let mut group = ProxyGroup::new();
+    for proxy in proxies {
+        group.add_server(executor.handle(), proxy);
+    }
+    for server in group.servers() {
+        shutdown.add(server.commands());
+    }
+    let fiber = executor.spawn_monitor(group);
+    track_try_unwrap!(executor.run_fiber(fiber).unwrap().map_err(Error::from));
+}
+
+/// Runs `proxies` on `threads` worker threads, each of which is pinned to a CPU core.
+///
+/// The threads of `ThreadPoolExecutor` cannot be pinned, and it spawns the fiber of a connection
+/// on an arbitrary thread. So every worker runs its own `InPlaceExecutor` instead.
+/// A listening socket cannot be shared by executors, thus every worker binds its own socket
+/// to the address of each proxy with `SO_REUSEPORT`: the first worker runs the proxies themselves,
+/// and the others run their replicas (see `ProxyServerBuilder::replica`).
+fn execute_pinned(threads: usize, mut proxies: Vec<ProxyServerBuilder>, shutdown: &Arc<Shutdown>) {
+    for proxy in &mut proxies {
+        #[cfg(unix)]
+        proxy.reuse_port(true);
+        proxy.stats(Arc::new(Stats::new()));
+    }
+    #[cfg(not(unix))]
+    let threads = {
+        log::warn!("SO_REUSEPORT is not supported on this platform, thus runs a single worker");
+        let _ = threads;
+        1
+    };
+    let shards = (0..threads)
+        .map(|i| {
+            if i == 0 {
+                proxies.clone()
+            } else {
+                proxies.iter().map(ProxyServerBuilder::replica).collect()
+            }
+        })
+        .collect::<Vec<Vec<_>>>();
+
+    let cores = available_cores();
+    let workers = shards
+        .into_iter()
+        .enumerate()
+        .map(|(i, shard)| {
+            let core = if cores.is_empty() {
+                None
+            } else {
+                Some(cores[i % cores.len()])
+            };
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                if let Some(core) = core {
+                    if let Err(e) = pin_current_thread(core) {
+                        log::warn!("Cannot pin worker #{} to CPU core {}: {}", i, core, e);
+                    }
+                }
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    execute(InPlaceExecutor::new().unwrap(), &shard, &shutdown)
+                }));
+                if result.is_err() {
+                    // The other workers should not keep running alone.
+                    process::exit(1);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn available_cores() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if libc::sched_getaffinity(0, size, &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| libc::CPU_ISSET(core, &set))
+            .collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_cores() -> Vec<usize> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_admin_socket(
+    proxy: &mut ProxyServerBuilder,
+    config: &Config,
+    path: &Path,
+) -> cotoxy::Result<()> {
+    let mut permissions = SocketPermissions::default();
+    if let Some(ref mode) = config.admin_socket_mode {
+        let mode = track!(
+            u32::from_str_radix(mode, 8).map_err(Error::from),
+            "mode={:?}",
+            mode
+        )?;
+        permissions.mode = Some(mode);
+    }
+    if let Some(ref owner) = config.admin_socket_owner {
+        let mut tokens = owner.splitn(2, ':');
+        let user = tokens.next().expect("Never fails");
+        if !user.is_empty() {
+            permissions.uid = Some(track!(resolve_id(user, false))?);
+        }
+        if let Some(group) = tokens.next() {
+            permissions.gid = Some(track!(resolve_id(group, true))?);
+        }
+    }
+    proxy.admin_socket(path, permissions);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_admin_socket(
+    _proxy: &mut ProxyServerBuilder,
+    _config: &Config,
+    path: &Path,
+) -> cotoxy::Result<()> {
+    track_panic!(
+        ErrorKind::Config,
+        "Unix sockets are not supported on this platform: {:?}",
+        path
+    );
+}
+
+/// Resolves a user (or group if `is_group` is `true`) name or numeric ID to the ID.
+#[cfg(unix)]
+fn resolve_id(name: &str, is_group: bool) -> cotoxy::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = track!(CString::new(name).map_err(|e| Error::from(ErrorKind::Config.cause(e))))?;
+    let id = unsafe {
+        if is_group {
+            let group = libc::getgrnam(c_name.as_ptr());
+            if group.is_null() {
+                None
+            } else {
+                Some((*group).gr_gid)
+            }
+        } else {
+            let user = libc::getpwnam(c_name.as_ptr());
+            if user.is_null() {
+                None
+            } else {
+                Some((*user).pw_uid)
+            }
+        }
+    };
+    let kind = if is_group { "group" } else { "user" };
+    Ok(track_assert_some!(
+        id,
+        ErrorKind::Config,
+        "Unknown {}: {:?}",
+        kind,
+        name
+    ))
+}
+
+/// Returns the admin API token given directly or read from `file`.
+fn load_admin_token(token: Option<&str>, file: Option<&Path>) -> cotoxy::Result<Option<String>> {
+    if let Some(path) = file {
+        track_assert!(
+            token.is_none(),
+            ErrorKind::Config,
+            "Both an admin token and a token file are specified"
         );
+        let token = track!(
+            fs::read_to_string(path).map_err(Error::from),
+            "path={:?}",
+            path
+        )?;
+        let token = token.trim();
+        track_assert!(
+            !token.is_empty(),
+            ErrorKind::Config,
+            "Empty admin token: path={:?}",
+            path
+        );
+        return Ok(Some(token.to_owned()));
     }
+    Ok(token.map(ToOwned::to_owned))
 }
 
-fn execute<E: Executor + Spawn>(mut executor: E, proxy: &ProxyServerBuilder) {
-    let proxy = proxy.finish(executor.handle());
-    let fiber = executor.spawn_monitor(proxy);
-    track_try_unwrap!(executor.run_fiber(fiber).unwrap().map_err(Error::from));
+fn tail(
+    admin_addr: Option<SocketAddr>,
+    admin_socket: Option<PathBuf>,
+    token: Option<String>,
+    client: Option<String>,
+    backend: Option<String>,
+) -> cotoxy::Result<()> {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    if let Some(ref client) = client {
+        query.append_pair("client", client);
+    }
+    if let Some(ref backend) = backend {
+        query.append_pair("backend", backend);
+    }
+    let query = query.finish();
+
+    let authorization =
+        token.map_or_else(String::new, |t| format!("Authorization: Bearer {}\r\n", t));
+    let request = |host: &dyn std::fmt::Display| {
+        format!(
+            "GET /events?{} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+            query, host, authorization
+        )
+    };
+    if let Some(path) = admin_socket {
+        #[cfg(unix)]
+        {
+            let stream = track!(
+                UnixStream::connect(&path).map_err(Error::from),
+                "{:?}",
+                path
+            )?;
+            return track!(print_events(stream, &request(&"localhost")));
+        }
+        #[cfg(not(unix))]
+        track_panic!(
+            ErrorKind::Config,
+            "Unix sockets are not supported on this platform: {:?}",
+            path
+        );
+    }
+    let admin_addr = track_assert_some!(
+        admin_addr,
+        ErrorKind::Config,
+        "No admin address is specified"
+    );
+    let stream = track!(TcpStream::connect(admin_addr).map_err(Error::from))?;
+    track!(print_events(stream, &request(&admin_addr)))
+}
+
+/// Sends `request` for the events to the admin API, and prints the streamed events.
+fn print_events<S: Read + Write>(mut stream: S, request: &str) -> cotoxy::Result<()> {
+    track!(stream.write_all(request.as_bytes()).map_err(Error::from))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    track!(reader.read_line(&mut status_line).map_err(Error::from))?;
+    track_assert!(
+        status_line.split_whitespace().nth(1) == Some("200"),
+        ErrorKind::Other,
+        "Unexpected response: {:?}",
+        status_line.trim_end()
+    );
+    loop {
+        let mut line = String::new();
+        track!(reader.read_line(&mut line).map_err(Error::from))?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in reader.lines() {
+        let line = track!(line.map_err(Error::from))?;
+        track!(writeln!(stdout, "{}", line).map_err(Error::from))?;
+        track!(stdout.flush().map_err(Error::from))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_env_works() {
+        env::set_var("COTOXY_TEST_INTERPOLATE", "foo");
+        env::remove_var("COTOXY_TEST_UNDEFINED");
+
+        assert_eq!(
+            interpolate_env("a${COTOXY_TEST_INTERPOLATE}b").unwrap(),
+            "afoob"
+        );
+        assert_eq!(
+            interpolate_env("${COTOXY_TEST_UNDEFINED:-bar}").unwrap(),
+            "bar"
+        );
+        assert_eq!(
+            interpolate_env("${COTOXY_TEST_INTERPOLATE:-bar}").unwrap(),
+            "foo"
+        );
+        assert_eq!(interpolate_env("$$ $x $").unwrap(), "$ $x $");
+        assert_eq!(
+            interpolate_env("$${COTOXY_TEST_INTERPOLATE}").unwrap(),
+            "${COTOXY_TEST_INTERPOLATE}"
+        );
+        assert!(interpolate_env("${COTOXY_TEST_UNDEFINED}").is_err());
+        assert!(interpolate_env("${COTOXY_TEST_INTERPOLATE").is_err());
+    }
+
+    #[test]
+    fn interpolate_env_values_works() {
+        env::set_var("COTOXY_TEST_INJECTION", "x\"\nbind_addr = \"0.0.0.0:1\"");
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            # ${COTOXY_TEST_UNDEFINED_IN_COMMENT}
+            service = "${COTOXY_TEST_INJECTION}"
+            threads = 2
+            tags = ["${COTOXY_TEST_INJECTION}"]
+            [nested]
+            name = "$${COTOXY_TEST_INJECTION}"
+            "#,
+        )
+        .unwrap();
+        interpolate_env_values(&mut value).unwrap();
+
+        let injected = "x\"\nbind_addr = \"0.0.0.0:1\"";
+        assert_eq!(value["service"].as_str(), Some(injected));
+        assert_eq!(value["tags"][0].as_str(), Some(injected));
+        assert_eq!(value["threads"].as_integer(), Some(2));
+        assert_eq!(
+            value["nested"]["name"].as_str(),
+            Some("${COTOXY_TEST_INJECTION}")
+        );
+        assert!(value.get("bind_addr").is_none());
+    }
+
+    #[test]
+    fn is_wildcard_match_works() {
+        assert!(is_wildcard_match(b"*.toml", b"a.toml"));
+        assert!(is_wildcard_match(b"*.toml", b".toml"));
+        assert!(is_wildcard_match(b"a?c*", b"abcdef"));
+        assert!(!is_wildcard_match(b"*.toml", b"a.yaml"));
+        assert!(!is_wildcard_match(b"a?c", b"ac"));
+    }
 }
@@ -0,0 +1,399 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use {Error, ErrorKind, Result};
+
+/// An action taken by the proxy server while a maintenance window is active.
+///
+/// This is (de)serialized as `"drain"` or `{ "switch_tag": <tag> }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceAction {
+    /// Refuses new connections (established connections are kept as they are).
+    Drain,
+
+    /// Selects servers by the given tag instead of the configured one.
+    SwitchTag(String),
+}
+
+/// A maintenance window which begins at the times matched by a cron-like schedule.
+///
+/// The schedule consists of the five fields `minute hour day-of-month month day-of-week`
+/// and is evaluated in UTC.
+/// Each field accepts `*`, numbers, ranges (`1-5`), lists (`1,3,5`) and steps (`*/15`, `0-30/10`, `5/15`).
+/// As in cron, a time matches if either of the day-of-month and day-of-week fields matches
+/// when neither of them starts with `*`, and `7` (as well as `0`) means Sunday.
+///
+/// This is (de)serialized as a table which has the `schedule`, `duration_secs` and `fallback_tag` fields.
+/// If `fallback_tag` is omitted, the action is `MaintenanceAction::Drain`,
+/// otherwise it is `MaintenanceAction::SwitchTag`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawMaintenanceWindow", into = "RawMaintenanceWindow")]
+pub struct MaintenanceWindow {
+    schedule: Schedule,
+    duration: Duration,
+    action: MaintenanceAction,
+}
+impl MaintenanceWindow {
+    /// Makes a new `MaintenanceWindow` instance.
+    ///
+    /// The window lasts for `duration` from each time matched by `schedule`.
+    pub fn new(schedule: &str, duration: Duration, action: MaintenanceAction) -> Result<Self> {
+        let schedule = track!(Schedule::parse(schedule))?;
+        Ok(MaintenanceWindow {
+            schedule,
+            duration,
+            action,
+        })
+    }
+
+    /// Returns the action taken while this window is active.
+    pub fn action(&self) -> &MaintenanceAction {
+        &self.action
+    }
+
+    /// Returns `true` if `time` is in this window.
+    pub fn is_active(&self, time: SystemTime) -> bool {
+        self.active_until(time).is_some()
+    }
+
+    /// Returns the end of the window which `time` is in, if any.
+    ///
+    /// If windows overlap, the end of the last one is returned.
+    pub(crate) fn active_until(&self, time: SystemTime) -> Option<SystemTime> {
+        let now = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+            .as_secs();
+        let duration = self.duration.as_secs();
+        if duration == 0 {
+            return None;
+        }
+
+        // The window which begins at the minute `m` is active if `m * 60 <= now < m * 60 + duration`.
+        let earliest = (now + 1).saturating_sub(duration).div_ceil(60);
+        let start = self.schedule.latest_match(earliest, now / 60)?;
+        Some(UNIX_EPOCH + Duration::from_secs(start * 60) + self.duration)
+    }
+}
+
+impl TryFrom<RawMaintenanceWindow> for MaintenanceWindow {
+    type Error = Error;
+    fn try_from(f: RawMaintenanceWindow) -> Result<Self> {
+        let action = if let Some(tag) = f.fallback_tag {
+            MaintenanceAction::SwitchTag(tag)
+        } else {
+            MaintenanceAction::Drain
+        };
+        track!(MaintenanceWindow::new(
+            &f.schedule,
+            Duration::from_secs(f.duration_secs),
+            action
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMaintenanceWindow {
+    schedule: String,
+    duration_secs: u64,
+    fallback_tag: Option<String>,
+}
+impl From<MaintenanceWindow> for RawMaintenanceWindow {
+    fn from(f: MaintenanceWindow) -> Self {
+        let fallback_tag = match f.action {
+            MaintenanceAction::Drain => None,
+            MaintenanceAction::SwitchTag(tag) => Some(tag),
+        };
+        RawMaintenanceWindow {
+            schedule: f.schedule.source,
+            duration_secs: f.duration.as_secs(),
+            fallback_tag,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Schedule {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+impl Schedule {
+    fn parse(s: &str) -> Result<Self> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        track_assert_eq!(
+            fields.len(),
+            5,
+            ErrorKind::Config,
+            "Schedule must have five fields: {:?}",
+            s
+        );
+        let mut weekdays = track!(parse_field(fields[4], 0, 7))?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            source: s.to_owned(),
+            minutes: track!(parse_field(fields[0], 0, 59))?,
+            hours: track!(parse_field(fields[1], 0, 23))?,
+            days: track!(parse_field(fields[2], 1, 31))?,
+            months: track!(parse_field(fields[3], 1, 12))?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    #[cfg(test)]
+    fn matches(&self, unix_minute: u64) -> bool {
+        let minute = unix_minute % 60;
+        let hour = (unix_minute / 60) % 24;
+        self.matches_day(unix_minute / (60 * 24))
+            && self.hours & (1 << hour) != 0
+            && self.minutes & (1 << minute) != 0
+    }
+
+    fn matches_day(&self, days_since_epoch: u64) -> bool {
+        let weekday = (days_since_epoch + 4) % 7; // 1970-01-01 was a Thursday
+        let (month, day) = month_and_day(days_since_epoch);
+
+        let day_matched = self.days & (1 << day) != 0;
+        let weekday_matched = self.weekdays & (1 << weekday) != 0;
+        let day_or_weekday_matched = match (self.any_day, self.any_weekday) {
+            (false, false) => day_matched || weekday_matched,
+            _ => day_matched && weekday_matched,
+        };
+        self.months & (1 << month) != 0 && day_or_weekday_matched
+    }
+
+    /// Returns the latest minute (since the Unix epoch) in `earliest..=latest` matched by this schedule, if any.
+    ///
+    /// Unmatched days and hours are skipped as a whole, so this takes a few steps per day in the range at most.
+    fn latest_match(&self, earliest: u64, latest: u64) -> Option<u64> {
+        let mut t = latest;
+        while t >= earliest {
+            let days = t / (60 * 24);
+            let hour = (t / 60) % 24;
+            let minute = t % 60;
+            let day_start = days * 60 * 24;
+            if !self.matches_day(days) {
+                t = day_start.checked_sub(1)?;
+                continue;
+            }
+            t = match highest_bit(self.hours, hour) {
+                None => day_start.checked_sub(1)?,
+                Some(h) if h < hour => day_start + h * 60 + 59,
+                Some(_) => match highest_bit(self.minutes, minute) {
+                    None => (day_start + hour * 60).checked_sub(1)?,
+                    Some(m) => return Some(t - minute + m).filter(|&m| m >= earliest),
+                },
+            };
+        }
+        None
+    }
+}
+
+/// Returns the highest set bit of `bits` at or below the position `upto` (`< 63`).
+fn highest_bit(bits: u64, upto: u64) -> Option<u64> {
+    let bits = bits & ((2 << upto) - 1);
+    if bits == 0 {
+        None
+    } else {
+        Some(63 - u64::from(bits.leading_zeros()))
+    }
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let mut tokens = item.splitn(2, '/');
+        let range = tokens.next().expect("Never fails");
+        let stepped = tokens.next();
+        let step = if let Some(step) = stepped {
+            track!(step.parse::<u64>().map_err(::Error::from))?
+        } else {
+            1
+        };
+        track_assert_ne!(step, 0, ErrorKind::Config, "Zero step: {:?}", field);
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            let start = track!(range[..i].parse::<u64>().map_err(::Error::from))?;
+            let end = track!(range[i + 1..].parse::<u64>().map_err(::Error::from))?;
+            (start, end)
+        } else {
+            // As in cron, `N/step` means `N-max/step`.
+            let start = track!(range.parse::<u64>().map_err(::Error::from))?;
+            (start, if stepped.is_some() { max } else { start })
+        };
+        track_assert!(
+            min <= start && start <= end && end <= max,
+            ErrorKind::Config,
+            "Out of range: {:?}",
+            field
+        );
+        for i in (start..=end).step_by(step as usize) {
+            bits |= 1 << i;
+        }
+    }
+    Ok(bits)
+}
+
+// See: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn month_and_day(days_since_epoch: u64) -> (u64, u64) {
+    let z = days_since_epoch + 719_468;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_01_2024: u64 = 1_704_067_200; // Monday
+
+    fn minute(secs: u64) -> u64 {
+        secs / 60
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parse_field_works() {
+        assert_eq!(parse_field("*", 0, 3).unwrap(), 0b1111);
+        assert_eq!(parse_field("1-2,5", 0, 7).unwrap(), 0b10_0110);
+        assert_eq!(
+            parse_field("*/15", 0, 59).unwrap(),
+            1 | 1 << 15 | 1 << 30 | 1 << 45
+        );
+        assert_eq!(
+            parse_field("0-30/10", 0, 59).unwrap(),
+            1 | 1 << 10 | 1 << 20 | 1 << 30
+        );
+        assert_eq!(
+            parse_field("5/15", 0, 59).unwrap(),
+            1 << 5 | 1 << 20 | 1 << 35 | 1 << 50
+        );
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+    }
+
+    #[test]
+    fn parse_schedule_works() {
+        assert!(Schedule::parse("0 0 * *").is_err());
+        assert!(Schedule::parse("0 0 * * * *").is_err());
+        assert!(Schedule::parse("0 24 * * *").is_err());
+        assert!(Schedule::parse("0 0 0 * *").is_err());
+        assert!(Schedule::parse("0 0 * 13 *").is_err());
+        assert!(Schedule::parse("0 0 * * 8").is_err());
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // The first day of a month or Mondays.
+        let schedule = Schedule::parse("0 0 1 * 1").unwrap();
+        assert!(schedule.matches(minute(JAN_01_2024)));
+        assert!(schedule.matches(minute(JAN_01_2024 + 7 * 86_400))); // Monday
+        assert!(schedule.matches(minute(JAN_01_2024 + 31 * 86_400))); // Feb 1 (Thursday)
+        assert!(!schedule.matches(minute(JAN_01_2024 + 8 * 86_400))); // Tuesday
+        assert!(!schedule.matches(minute(JAN_01_2024 + 60)));
+
+        // Only the first day of a month.
+        let schedule = Schedule::parse("0 0 1 * *").unwrap();
+        assert!(schedule.matches(minute(JAN_01_2024 + 31 * 86_400)));
+        assert!(!schedule.matches(minute(JAN_01_2024 + 7 * 86_400)));
+
+        // Only Mondays.
+        let schedule = Schedule::parse("0 0 * * 1").unwrap();
+        assert!(schedule.matches(minute(JAN_01_2024 + 7 * 86_400)));
+        assert!(!schedule.matches(minute(JAN_01_2024 + 31 * 86_400)));
+    }
+
+    #[test]
+    fn sunday_is_zero_or_seven() {
+        let sunday = minute(JAN_01_2024 + 6 * 86_400);
+        for s in &["0 0 * * 0", "0 0 * * 7", "0 0 * * 6-7"] {
+            let schedule = Schedule::parse(s).unwrap();
+            assert!(schedule.matches(sunday), "{}", s);
+            assert!(!schedule.matches(sunday - 24 * 60 * 2), "{}", s); // Friday
+        }
+    }
+
+    #[test]
+    fn month_and_day_works() {
+        assert_eq!(month_and_day(0), (1, 1));
+        assert_eq!(month_and_day(JAN_01_2024 / 86_400), (1, 1));
+        assert_eq!(month_and_day(JAN_01_2024 / 86_400 + 59), (2, 29));
+        assert_eq!(month_and_day(JAN_01_2024 / 86_400 + 365), (12, 31));
+    }
+
+    #[test]
+    fn latest_match_agrees_with_matches() {
+        let schedules = [
+            "*/7 3-5 * * *",
+            "30 2 1,15 * 0",
+            "5/20 */6 * 2 *",
+            "0 0 29 2 *",
+        ];
+        for s in &schedules {
+            let schedule = Schedule::parse(s).unwrap();
+            let latest = minute(JAN_01_2024) + 60 * 24 * 60;
+            let earliest = latest - 40 * 24 * 60;
+            let expected = (earliest..=latest).rev().find(|&m| schedule.matches(m));
+            assert_eq!(schedule.latest_match(earliest, latest), expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn is_active_works() {
+        let window = MaintenanceWindow::new(
+            "0 2 * * *",
+            Duration::from_secs(90),
+            MaintenanceAction::Drain,
+        )
+        .unwrap();
+        let start = JAN_01_2024 + 2 * 3600;
+        assert!(!window.is_active(at(start - 1)));
+        assert!(window.is_active(at(start)));
+        assert!(window.is_active(at(start + 89)));
+        assert!(!window.is_active(at(start + 90)));
+        assert_eq!(window.active_until(at(start + 30)), Some(at(start + 90)));
+
+        // A week-long window which begins on Mondays.
+        let window = MaintenanceWindow::new(
+            "0 0 * * 1",
+            Duration::from_secs(7 * 86_400 - 60),
+            MaintenanceAction::Drain,
+        )
+        .unwrap();
+        assert!(window.is_active(at(JAN_01_2024 + 6 * 86_400)));
+        assert!(!window.is_active(at(JAN_01_2024 + 7 * 86_400 - 30)));
+        assert!(window.is_active(at(JAN_01_2024 + 7 * 86_400)));
+
+        let window = MaintenanceWindow::new(
+            "* * * * *",
+            Duration::from_secs(0),
+            MaintenanceAction::Drain,
+        )
+        .unwrap();
+        assert!(!window.is_active(at(JAN_01_2024)));
+    }
+}
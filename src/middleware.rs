@@ -0,0 +1,57 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use {Endpoint, Result};
+
+/// A boxed `Endpoint`, which is passed through a chain of `Middleware`s.
+pub type BoxEndpoint = Box<dyn Endpoint + Send>;
+
+/// A middleware which wraps an endpoint of a proxy channel to transform (or observe) its bytes.
+///
+/// Middlewares are added to either leg (i.e., the client side or the server side) of
+/// the channels of a proxy server by `ProxyServerBuilder`, and are applied in the order they were added.
+/// For example, they can be used for TLS, compression, metrics taps or traffic recording.
+///
+/// Note that the channels whose endpoints are wrapped relay bytes through a userspace buffer,
+/// unless the wrapped endpoints pass through `Endpoint::raw_fd`.
+pub trait Middleware: fmt::Debug + Send + Sync {
+    /// Wraps `endpoint`, which is connected to `peer`.
+    ///
+    /// If an error is returned, the connection is closed.
+    fn wrap(&self, endpoint: BoxEndpoint, peer: SocketAddr) -> Result<BoxEndpoint>;
+}
+
+impl<E: Endpoint + ?Sized> Endpoint for Box<E> {
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+
+    fn set_cork(&self, cork: bool) -> io::Result<()> {
+        (**self).set_cork(cork)
+    }
+}
+
+/// Applies `middlewares` to `endpoint` in order.
+pub(crate) fn wrap_all<E>(
+    endpoint: E,
+    peer: SocketAddr,
+    middlewares: &[Arc<dyn Middleware>],
+) -> Result<BoxEndpoint>
+where
+    E: Endpoint + Send + 'static,
+{
+    let mut endpoint: BoxEndpoint = Box::new(endpoint);
+    for middleware in middlewares {
+        endpoint = track!(
+            middleware.wrap(endpoint, peer),
+            "middleware={:?}",
+            middleware
+        )?;
+    }
+    Ok(endpoint)
+}
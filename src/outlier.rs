@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, Result};
+
+/// Settings of the detection of backend servers which repeatedly fail to be connected.
+///
+/// A backend to which `consecutive_failures` connects in a row have failed is ejected for `ejection_duration`:
+/// it is tried only after all the other candidates have failed.
+/// After the ejection, the backend is readmitted on probation, where a single failure ejects it again
+/// for twice as long as the previous ejection (up to `max_ejection_duration`), and a success readmits it fully.
+///
+/// This is (de)serialized as a table which has the `consecutive_failures`, `ejection_secs`
+/// and `max_ejection_secs` fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawOutlierDetection", into = "RawOutlierDetection")]
+pub struct OutlierDetection {
+    consecutive_failures: u32,
+    ejection_duration: Duration,
+    max_ejection_duration: Duration,
+}
+impl OutlierDetection {
+    /// Makes a new `OutlierDetection` instance.
+    pub fn new(
+        consecutive_failures: u32,
+        ejection_duration: Duration,
+        max_ejection_duration: Duration,
+    ) -> Result<Self> {
+        track_assert!(
+            consecutive_failures > 0,
+            ErrorKind::Config,
+            "Consecutive failures must be positive"
+        );
+        track_assert!(
+            ejection_duration <= max_ejection_duration,
+            ErrorKind::Config,
+            "Ejection duration must not exceed the maximum: ejection={:?}, max={:?}",
+            ejection_duration,
+            max_ejection_duration
+        );
+        Ok(OutlierDetection {
+            consecutive_failures,
+            ejection_duration,
+            max_ejection_duration,
+        })
+    }
+
+    /// Returns the number of connect failures in a row which makes a backend ejected.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Returns how long a backend is ejected for the first time.
+    pub fn ejection_duration(&self) -> Duration {
+        self.ejection_duration
+    }
+
+    /// Returns the maximum duration of an ejection.
+    pub fn max_ejection_duration(&self) -> Duration {
+        self.max_ejection_duration
+    }
+}
+impl TryFrom<RawOutlierDetection> for OutlierDetection {
+    type Error = Error;
+    fn try_from(f: RawOutlierDetection) -> Result<Self> {
+        track!(OutlierDetection::new(
+            f.consecutive_failures,
+            Duration::from_secs(f.ejection_secs),
+            Duration::from_secs(f.max_ejection_secs)
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOutlierDetection {
+    consecutive_failures: u32,
+    ejection_secs: u64,
+    max_ejection_secs: u64,
+}
+impl From<OutlierDetection> for RawOutlierDetection {
+    fn from(f: OutlierDetection) -> Self {
+        RawOutlierDetection {
+            consecutive_failures: f.consecutive_failures,
+            ejection_secs: f.ejection_duration.as_secs(),
+            max_ejection_secs: f.max_ejection_duration.as_secs(),
+        }
+    }
+}
+
+/// A detector of failing backends, shared by the connection fibers of a proxy server.
+#[derive(Debug)]
+pub(crate) struct OutlierDetector {
+    settings: OutlierDetection,
+    backends: Mutex<HashMap<SocketAddr, BackendHealth>>,
+}
+impl OutlierDetector {
+    pub fn new(settings: OutlierDetection) -> Self {
+        OutlierDetector {
+            settings,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `true` if `backend` is currently ejected.
+    pub fn is_ejected(&self, backend: SocketAddr) -> bool {
+        let backends = self.backends.lock().expect("Never fails");
+        backends
+            .get(&backend)
+            .and_then(|b| b.ejected_until)
+            .is_some_and(|t| Instant::now() < t)
+    }
+
+    /// Records a failed connect to `backend`.
+    pub fn record_failure(&self, backend: SocketAddr) {
+        let now = Instant::now();
+        let mut backends = self.backends.lock().expect("Never fails");
+        let health = backends.entry(backend).or_default();
+        if health.ejected_until.is_some_and(|t| now < t) {
+            return;
+        }
+        health.failures += 1;
+        let on_probation = health.ejections > 0;
+        if !on_probation && health.failures < self.settings.consecutive_failures {
+            return;
+        }
+
+        let duration = self
+            .settings
+            .ejection_duration
+            .checked_mul(1 << health.ejections.min(16))
+            .map_or(self.settings.max_ejection_duration, |d| {
+                d.min(self.settings.max_ejection_duration)
+            });
+        health.ejections += 1;
+        health.failures = 0;
+        health.ejected_until = Some(now + duration);
+        log::warn!(
+            "Ejected the server {} for {:?} due to consecutive connect failures (ejections: {})",
+            backend,
+            duration,
+            health.ejections
+        );
+    }
+
+    /// Records a successful connect to `backend`, which readmits it fully.
+    pub fn record_success(&self, backend: SocketAddr) {
+        let mut backends = self.backends.lock().expect("Never fails");
+        if let Some(health) = backends.remove(&backend) {
+            if health.ejections > 0 {
+                log::info!("Readmitted the server {}", backend);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BackendHealth {
+    failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
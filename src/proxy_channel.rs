@@ -1,31 +1,254 @@
+use fibers::fiber;
 use fibers::net::TcpStream;
+use fibers::time::timer::{self, Timeout};
 use futures::{Async, Future, Poll};
-use std::io::{self, Read, Write};
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future as StdFuture;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tokio")]
+use std::task::{self, Context};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use {Error, Result};
+use bandwidth::Throttle;
+#[cfg(target_os = "linux")]
+use splice::SplicePipe;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use uring::UringRelay;
+use {BandwidthLimit, Error, MemoryBudget, Result};
 
+/// An endpoint of a `ProxyChannel`.
+///
+/// Like `fibers::net::TcpStream`, an I/O operation which would block must return `WouldBlock`,
+/// and the current fiber must be woken up when the endpoint becomes ready.
+pub trait Endpoint: Read + Write {
+    /// Returns the file descriptor of the underlying socket.
+    ///
+    /// If `Some(_)` is returned, bytes are read (or written) directly from (or to) the descriptor
+    /// by vectored I/O, or by `splice(2)` if both endpoints of a channel have descriptors (Linux only).
+    /// With the `io-uring` feature, the latter are relayed by `io_uring(7)` instead.
+    /// So endpoints which transform bytes (e.g., TLS streams) must return `None`.
+    ///
+    /// The default implementation returns `None`.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Corks (or uncorks) the underlying socket.
+    ///
+    /// While a socket is corked, partial segments are held back by the kernel.
+    /// The default implementation does nothing.
+    fn set_cork(&self, cork: bool) -> io::Result<()> {
+        let _ = cork;
+        Ok(())
+    }
+}
+impl Endpoint for TcpStream {
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.with_inner(|s| s.as_raw_fd()))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_cork(&self, cork: bool) -> io::Result<()> {
+        set_tcp_cork(self.with_inner(|s| s.as_raw_fd()), cork)
+    }
+}
+
+/// Sets `TCP_CORK` of the TCP socket `fd`.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_tcp_cork(fd: RawFd, cork: bool) -> io::Result<()> {
+    let value = libc::c_int::from(cork);
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CORK,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A buffer which relays bytes in one direction.
+///
+/// On Linux, bytes are moved kernel-to-kernel with `splice(2)` if possible.
+/// With the `io-uring` feature, they are moved by `io_uring(7)` instead if the kernel supports it,
+/// unless the reads are `throttled` (a completed read cannot be taken back to fit the allowance).
+#[derive(Debug)]
+enum RelayBuffer {
+    Buffer(Buffer),
+    #[cfg(target_os = "linux")]
+    Splice(SplicePipe),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Box<UringRelay>),
+}
+impl RelayBuffer {
+    fn new<R: Endpoint, W: Endpoint>(
+        pool: &BufferPool,
+        reader: &R,
+        writer: &W,
+        throttled: bool,
+    ) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            if reader.raw_fd().is_some() && writer.raw_fd().is_some() {
+                #[cfg(feature = "io-uring")]
+                {
+                    if !throttled && UringRelay::is_supported() {
+                        match UringRelay::new(pool.clone()) {
+                            Ok(relay) => return RelayBuffer::Uring(Box::new(relay)),
+                            Err(e) => log::warn!(
+                                "Cannot create an io_uring (falls back to a pipe): {}",
+                                e
+                            ),
+                        }
+                    }
+                }
+                match SplicePipe::new(pool.buffer_size, pool.budget.clone()) {
+                    Ok(pipe) => return RelayBuffer::Splice(pipe),
+                    Err(e) => log::warn!(
+                        "Cannot create a pipe (falls back to a userspace buffer): {}",
+                        e
+                    ),
+                }
+            }
+        }
+        let _ = (reader, writer, throttled);
+        RelayBuffer::Buffer(Buffer::new(pool.clone()))
+    }
+    fn read_from<R: Endpoint>(
+        &mut self,
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Async<Option<usize>>> {
+        match *self {
+            RelayBuffer::Buffer(ref mut b) => track!(b.read_from(reader, max)),
+            #[cfg(target_os = "linux")]
+            RelayBuffer::Splice(ref mut p) => track!(p.read_from(reader, max)),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RelayBuffer::Uring(ref mut u) => track!(u.read_from(reader, max)),
+        }
+    }
+    fn write_to<W: Endpoint>(&mut self, writer: &mut W) -> Result<Async<Option<usize>>> {
+        match *self {
+            RelayBuffer::Buffer(ref mut b) => track!(b.write_to(writer)),
+            #[cfg(target_os = "linux")]
+            RelayBuffer::Splice(ref mut p) => track!(p.write_to(writer)),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RelayBuffer::Uring(ref mut u) => track!(u.write_to(writer)),
+        }
+    }
+}
+
+/// A pool of relay buffers shared by the channels of a proxy server.
+///
+/// Buffers of closed channels are reused by new ones instead of being freed,
+/// which reduces allocator pressure under connection churn.
+///
+/// Buffers are allocated within `budget`, in units of `buffer_size` bytes.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    buffer_size: usize,
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    budget: MemoryBudget,
+}
+impl BufferPool {
+    /// Maximum number of idle buffers kept in a pool.
+    const MAX_IDLE_BUFFERS: usize = 1024;
+
+    /// The default size of a relay buffer.
+    pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+    /// Makes a new `BufferPool` instance.
+    pub fn new(buffer_size: usize, budget: MemoryBudget) -> Self {
+        BufferPool {
+            buffer_size,
+            free: Arc::new(Mutex::new(Vec::new())),
+            budget,
+        }
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+    pub(crate) fn allocate(&self) -> Vec<u8> {
+        let buf = self.free.lock().expect("Never fails").pop();
+        buf.unwrap_or_else(|| vec![0; self.buffer_size])
+    }
+    pub(crate) fn release(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().expect("Never fails");
+        if free.len() < Self::MAX_IDLE_BUFFERS {
+            free.push(buf);
+        }
+    }
+}
+
+/// A ring buffer allocated from a `BufferPool`.
 #[derive(Debug)]
 struct Buffer {
     inner: Vec<u8>,
-    write_start: usize,
-    read_start: usize,
+    head: usize,
+    len: usize,
+    pool: BufferPool,
 }
 impl Buffer {
-    fn new(capacity: usize) -> Self {
+    fn new(pool: BufferPool) -> Self {
         Buffer {
-            inner: vec![0; capacity],
-            write_start: 0,
-            read_start: 0,
+            inner: pool.allocate(),
+            head: 0,
+            len: 0,
+            pool,
         }
     }
-    fn read_from<R: Read + ::std::fmt::Debug>(
+    fn read_from<R: Endpoint>(
         &mut self,
         reader: &mut R,
+        max: usize,
     ) -> Result<Async<Option<usize>>> {
-        if self.read_start == self.inner.len() {
+        if self.len == self.inner.len() {
             return Ok(Async::NotReady);
         }
-        match reader.read(&mut self.inner[self.read_start..]) {
+        let budget = self
+            .pool
+            .budget
+            .acquire((self.inner.len() - self.len).min(max));
+        if budget == 0 {
+            return Ok(Async::NotReady);
+        }
+        let result = {
+            let tail = (self.head + self.len) % self.inner.len();
+            let (front, back) = self.inner.split_at_mut(tail);
+            let (first, second) = if tail < self.head {
+                (&mut back[..self.head - tail], &mut [][..])
+            } else {
+                (back, &mut front[..self.head])
+            };
+            let first_len = first.len().min(budget);
+            let second_len = second.len().min(budget - first_len);
+            read_vectored(
+                reader,
+                &mut [
+                    IoSliceMut::new(&mut first[..first_len]),
+                    IoSliceMut::new(&mut second[..second_len]),
+                ],
+            )
+        };
+        let used = if let Ok(size) = result { size } else { 0 };
+        self.pool.budget.release(budget - used);
+        match result {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     Ok(Async::NotReady)
@@ -35,19 +258,28 @@ impl Buffer {
             }
             Ok(0) => Ok(Async::Ready(None)),
             Ok(size) => {
-                self.read_start += size;
+                self.len += size;
                 Ok(Async::Ready(Some(size)))
             }
         }
     }
-    fn write_to<W: Write + ::std::fmt::Debug>(
-        &mut self,
-        writer: &mut W,
-    ) -> Result<Async<Option<usize>>> {
-        if self.write_start == self.read_start {
+    fn write_to<W: Endpoint>(&mut self, writer: &mut W) -> Result<Async<Option<usize>>> {
+        if self.len == 0 {
             return Ok(Async::NotReady);
         }
-        match writer.write(&self.inner[self.write_start..self.read_start]) {
+        let result = {
+            let end = self.head + self.len;
+            let (first, second) = if end <= self.inner.len() {
+                (&self.inner[self.head..end], &[][..])
+            } else {
+                (
+                    &self.inner[self.head..],
+                    &self.inner[..end - self.inner.len()],
+                )
+            };
+            write_vectored(writer, &[IoSlice::new(first), IoSlice::new(second)])
+        };
+        match result {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     Ok(Async::NotReady)
@@ -57,89 +289,548 @@ impl Buffer {
             }
             Ok(0) => Ok(Async::Ready(None)),
             Ok(size) => {
-                self.write_start += size;
-                if self.write_start == self.read_start {
-                    self.write_start = 0;
-                    self.read_start = 0;
+                self.pool.budget.release(size);
+                self.head = (self.head + size) % self.inner.len();
+                self.len -= size;
+                if self.len == 0 {
+                    self.head = 0;
                 }
                 Ok(Async::Ready(Some(size)))
             }
         }
     }
 }
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.budget.release(self.len);
+        let inner = std::mem::take(&mut self.inner);
+        self.pool.release(inner);
+    }
+}
+
+/// Reads into `bufs` with a single `readv(2)` call if `reader` has a file descriptor.
+///
+/// `fibers` starts monitoring the readiness of a socket only when an I/O operation on `TcpStream`
+/// returns `WouldBlock`, so an ordinary read is issued instead if `readv(2)` would block.
+#[cfg(unix)]
+fn read_vectored<R: Endpoint>(reader: &mut R, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+    let fd = if let Some(fd) = reader.raw_fd() {
+        fd
+    } else {
+        return reader.read_vectored(bufs);
+    };
+    let size = unsafe { libc::readv(fd, bufs.as_ptr() as *const libc::iovec, bufs.len() as _) };
+    if size >= 0 {
+        return Ok(size as usize);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        reader.read(&mut bufs[0])
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(not(unix))]
+fn read_vectored<R: Endpoint>(reader: &mut R, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+    reader.read_vectored(bufs)
+}
+
+/// Writes `bufs` with a single `writev(2)` call if `writer` has a file descriptor.
+///
+/// See `read_vectored` for why an ordinary write is issued if `writev(2)` would block.
+#[cfg(unix)]
+fn write_vectored<W: Endpoint>(writer: &mut W, bufs: &[IoSlice]) -> io::Result<usize> {
+    let fd = if let Some(fd) = writer.raw_fd() {
+        fd
+    } else {
+        return writer.write_vectored(bufs);
+    };
+    let size = unsafe { libc::writev(fd, bufs.as_ptr() as *const libc::iovec, bufs.len() as _) };
+    if size >= 0 {
+        return Ok(size as usize);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        writer.write(&bufs[0])
+    } else {
+        Err(e)
+    }
+}
 
+#[cfg(not(unix))]
+fn write_vectored<W: Endpoint>(writer: &mut W, bufs: &[IoSlice]) -> io::Result<usize> {
+    writer.write_vectored(bufs)
+}
+
+/// Coalesces the writes to a socket by corking it for a while.
+///
+/// While a socket is corked, the kernel sends only full-sized segments.
+/// The partial segment is flushed when the socket is uncorked after `delay`.
 #[derive(Debug)]
-pub struct ProxyChannel {
-    client: TcpStream,
-    client_buf: Buffer,
-    server: TcpStream,
-    server_buf: Buffer,
+struct Cork {
+    delay: Duration,
+    timeout: Option<Timeout>,
+}
+impl Cork {
+    fn new(delay: Duration) -> Self {
+        Cork {
+            delay,
+            timeout: None,
+        }
+    }
+
+    /// Corks `socket` unless it has already been corked.
+    fn engage<E: Endpoint>(&mut self, socket: &E) -> Result<()> {
+        if self.timeout.is_none() {
+            track!(socket.set_cork(true).map_err(Error::from))?;
+            let mut timeout = timer::timeout(self.delay);
+            // Polls once so that the fiber is woken up when it expires.
+            if timeout.poll().unwrap_or(Async::Ready(())).is_ready() {
+                return track!(socket.set_cork(false).map_err(Error::from));
+            }
+            self.timeout = Some(timeout);
+        }
+        Ok(())
+    }
+
+    /// Uncorks `socket` if the delay has expired.
+    fn poll_flush<E: Endpoint>(&mut self, socket: &E) -> Result<()> {
+        let expired = if let Some(ref mut timeout) = self.timeout {
+            timeout.poll().unwrap_or(Async::Ready(())).is_ready()
+        } else {
+            false
+        };
+        if expired {
+            self.timeout = None;
+            track!(socket.set_cork(false).map_err(Error::from))?;
+        }
+        Ok(())
+    }
 }
-impl ProxyChannel {
-    pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
 
-    pub fn new(client: TcpStream, server: TcpStream) -> Self {
-        let _ = client.with_inner(|socket| socket.set_nodelay(true));
-        let _ = server.with_inner(|socket| socket.set_nodelay(true));
+/// Maximum number of reads (or writes) issued to a socket in a pump.
+const MAX_OPS_PER_PUMP: usize = 16;
+
+/// The summary of a `ProxyChannel` which has been closed.
+#[derive(Debug, Clone)]
+pub struct ChannelClosed {
+    /// Number of bytes relayed from the client to the server.
+    pub upstream_bytes: u64,
+
+    /// Number of bytes relayed from the server to the client.
+    pub downstream_bytes: u64,
+
+    /// Time elapsed from the creation of the channel to its close.
+    pub duration: Duration,
+
+    /// Which side closed the connection.
+    pub reason: CloseReason,
+}
+
+/// The side which closed a `ProxyChannel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed the connection (displayed as `client_closed`).
+    ClientClosed,
+
+    /// The server closed the connection (displayed as `server_closed`).
+    ServerClosed,
+}
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::ClientClosed => write!(f, "client_closed"),
+            CloseReason::ServerClosed => write!(f, "server_closed"),
+        }
+    }
+}
+
+/// A future which relays bytes between a client and a server until either of them closes.
+///
+/// This needs to be run on a fiber of `fibers`.
+#[derive(Debug)]
+pub struct ProxyChannel<C = TcpStream, S = TcpStream> {
+    client: C,
+    client_buf: RelayBuffer,
+    client_cork: Option<Cork>,
+    client_throttle: Option<Throttle>,
+    server: S,
+    server_buf: RelayBuffer,
+    server_cork: Option<Cork>,
+    server_throttle: Option<Throttle>,
+    upstream_bytes: u64,
+    downstream_bytes: u64,
+    start_time: Instant,
+}
+impl<C: Endpoint, S: Endpoint> ProxyChannel<C, S> {
+    /// Maximum number of pumps in each direction per poll.
+    ///
+    /// When reached, the channel yields to the other fibers.
+    /// Yielding is relatively expensive in `fibers`, so this should not be too small.
+    const MAX_PUMPS_PER_POLL: usize = 256;
+
+    /// Makes a new channel which relays bytes between `client` and `server`.
+    ///
+    /// If `cork_delay` is `Some(_)`, the relayed writes to each socket are coalesced
+    /// for at most the delay (Linux only).
+    /// If `bandwidth` is `Some(_)`, the bytes read from both sockets are counted against it.
+    pub fn new(
+        client: C,
+        server: S,
+        pool: &BufferPool,
+        cork_delay: Option<Duration>,
+        bandwidth: Option<&BandwidthLimit>,
+    ) -> Self {
         ProxyChannel {
+            client_buf: RelayBuffer::new(pool, &client, &server, bandwidth.is_some()),
+            server_buf: RelayBuffer::new(pool, &server, &client, bandwidth.is_some()),
             client,
-            client_buf: Buffer::new(Self::DEFAULT_BUFFER_SIZE),
+            client_cork: cork_delay.map(Cork::new),
+            client_throttle: bandwidth.cloned().map(Throttle::new),
             server,
-            server_buf: Buffer::new(Self::DEFAULT_BUFFER_SIZE),
+            server_cork: cork_delay.map(Cork::new),
+            server_throttle: bandwidth.cloned().map(Throttle::new),
+            upstream_bytes: 0,
+            downstream_bytes: 0,
+            start_time: Instant::now(),
         }
     }
 }
-impl Future for ProxyChannel {
-    type Item = ();
+impl<C: Endpoint, S: Endpoint> Future for ProxyChannel<C, S> {
+    type Item = ChannelClosed;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop {
-            match track!(self.client_buf.read_from(&mut self.client))? {
-                Async::NotReady => {}
-                Async::Ready(None) => {
-                    log::info!("Connection closed by client while reading");
-                    return Ok(Async::Ready(()));
-                }
-                Async::Ready(Some(size)) => {
-                    log::debug!("Received {} bytes from client", size);
-                    continue;
+        if let Some(ref mut cork) = self.server_cork {
+            track!(cork.poll_flush(&self.server))?;
+        }
+        if let Some(ref mut cork) = self.client_cork {
+            track!(cork.poll_flush(&self.client))?;
+        }
+
+        // Both directions are pumped in turn so that a busy one cannot starve the other.
+        for _ in 0..Self::MAX_PUMPS_PER_POLL {
+            let upstream = track!(pump(
+                &mut self.client_buf,
+                &mut self.client,
+                &mut self.server,
+                &mut self.server_cork,
+                &mut self.client_throttle,
+                &mut self.upstream_bytes,
+                (Side::Client, Side::Server)
+            ))?;
+            let downstream = track!(pump(
+                &mut self.server_buf,
+                &mut self.server,
+                &mut self.client,
+                &mut self.client_cork,
+                &mut self.server_throttle,
+                &mut self.downstream_bytes,
+                (Side::Server, Side::Client)
+            ))?;
+            match (upstream, downstream) {
+                (Pump::Closed(side), _) | (_, Pump::Closed(side)) => {
+                    return Ok(Async::Ready(ChannelClosed {
+                        upstream_bytes: self.upstream_bytes,
+                        downstream_bytes: self.downstream_bytes,
+                        duration: self.start_time.elapsed(),
+                        reason: if side == Side::Client {
+                            CloseReason::ClientClosed
+                        } else {
+                            CloseReason::ServerClosed
+                        },
+                    }));
                 }
+                (Pump::Idle, Pump::Idle) => return Ok(Async::NotReady),
+                _ => {}
             }
-            match track!(self.client_buf.write_to(&mut self.server))? {
-                Async::NotReady => {}
-                Async::Ready(None) => {
-                    log::info!("Connection closed by server while writing");
-                    return Ok(Async::Ready(()));
-                }
-                Async::Ready(Some(size)) => {
-                    log::debug!("Sent {} bytes to server", size);
-                    continue;
+        }
+        fiber::yield_poll()
+    }
+}
+
+#[derive(Debug)]
+enum Pump {
+    Progress,
+    Idle,
+    Closed(Side),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Server,
+}
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Side::Client => write!(f, "client"),
+            Side::Server => write!(f, "server"),
+        }
+    }
+}
+
+/// Relays bytes from `reader` to `writer`.
+///
+/// Reads (and then writes) are repeated until they would block,
+/// up to `MAX_OPS_PER_PUMP` times.
+/// If `cork` is `Some(_)`, `writer` is corked before writing the bytes just read.
+/// If `throttle` is `Some(_)`, reads are suspended while its allowance is exhausted,
+/// and the allowance left is returned when a read would block.
+/// The number of bytes written is added to `sent`.
+fn pump<R: Endpoint, W: Endpoint>(
+    buf: &mut RelayBuffer,
+    reader: &mut R,
+    writer: &mut W,
+    cork: &mut Option<Cork>,
+    throttle: &mut Option<Throttle>,
+    sent: &mut u64,
+    (from, to): (Side, Side),
+) -> Result<Pump> {
+    let mut progress = false;
+    for _ in 0..MAX_OPS_PER_PUMP {
+        let max = throttle.as_mut().map_or(usize::MAX, |t| t.allowance());
+        if max == 0 {
+            break;
+        }
+        match track!(buf.read_from(reader, max))? {
+            Async::NotReady => {
+                if let Some(ref mut throttle) = *throttle {
+                    throttle.release();
                 }
+                break;
             }
-            match track!(self.server_buf.read_from(&mut self.server))? {
-                Async::NotReady => {}
-                Async::Ready(None) => {
-                    log::info!("Connection closed by server while reading");
-                    return Ok(Async::Ready(()));
-                }
-                Async::Ready(Some(size)) => {
-                    log::debug!("Received {} bytes from server", size);
-                    continue;
-                }
+            Async::Ready(None) => {
+                log::info!("Connection closed by {} while reading", from);
+                return Ok(Pump::Closed(from));
             }
-            match track!(self.server_buf.write_to(&mut self.client))? {
-                Async::NotReady => {}
-                Async::Ready(None) => {
-                    log::info!("Connection closed by client while writing");
-                    return Ok(Async::Ready(()));
+            Async::Ready(Some(size)) => {
+                log::debug!("Received {} bytes from {}", size, from);
+                if let Some(ref mut throttle) = *throttle {
+                    throttle.consume(size);
                 }
-                Async::Ready(Some(size)) => {
-                    log::debug!("Sent {} bytes to client", size);
-                    continue;
+                progress = true;
+            }
+        }
+    }
+    if let (true, Some(cork)) = (progress, cork.as_mut()) {
+        track!(cork.engage(writer))?;
+    }
+    for _ in 0..MAX_OPS_PER_PUMP {
+        match track!(buf.write_to(writer))? {
+            Async::NotReady => break,
+            Async::Ready(None) => {
+                log::info!("Connection closed by {} while writing", to);
+                return Ok(Pump::Closed(to));
+            }
+            Async::Ready(Some(size)) => {
+                log::debug!("Sent {} bytes to {}", size, to);
+                *sent += size as u64;
+                progress = true;
+            }
+        }
+    }
+    Ok(if progress { Pump::Progress } else { Pump::Idle })
+}
+
+/// A future which relays bytes between a client and a server until either of them closes, on the runtime of `tokio`.
+///
+/// This is the counterpart of `ProxyChannel` for the `tokio` feature.
+/// Each direction has a plain buffer of its own, which is not taken from a `BufferPool`.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct TokioProxyChannel<C = tokio::net::TcpStream, S = tokio::net::TcpStream> {
+    client: C,
+    client_buf: TokioBuffer,
+    server: S,
+    server_buf: TokioBuffer,
+    upstream_bytes: u64,
+    downstream_bytes: u64,
+    start_time: Instant,
+}
+#[cfg(feature = "tokio")]
+impl<C, S> TokioProxyChannel<C, S>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Maximum number of pumps in each direction per poll.
+    ///
+    /// When reached, the channel yields to the other tasks.
+    const MAX_PUMPS_PER_POLL: usize = 256;
+
+    /// Makes a new channel which relays bytes between `client` and `server`
+    /// through buffers of `buffer_size` bytes.
+    pub fn new(client: C, server: S, buffer_size: usize) -> Self {
+        TokioProxyChannel {
+            client,
+            client_buf: TokioBuffer::new(buffer_size),
+            server,
+            server_buf: TokioBuffer::new(buffer_size),
+            upstream_bytes: 0,
+            downstream_bytes: 0,
+            start_time: Instant::now(),
+        }
+    }
+}
+#[cfg(feature = "tokio")]
+impl<C, S> StdFuture for TokioProxyChannel<C, S>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Output = Result<ChannelClosed>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Self::Output> {
+        let this = &mut *self;
+        for _ in 0..Self::MAX_PUMPS_PER_POLL {
+            let upstream = track!(tokio_pump(
+                &mut this.client_buf,
+                &mut this.client,
+                &mut this.server,
+                &mut this.upstream_bytes,
+                (Side::Client, Side::Server),
+                cx
+            ))?;
+            let downstream = track!(tokio_pump(
+                &mut this.server_buf,
+                &mut this.server,
+                &mut this.client,
+                &mut this.downstream_bytes,
+                (Side::Server, Side::Client),
+                cx
+            ))?;
+            match (upstream, downstream) {
+                (Pump::Closed(side), _) | (_, Pump::Closed(side)) => {
+                    return task::Poll::Ready(Ok(ChannelClosed {
+                        upstream_bytes: this.upstream_bytes,
+                        downstream_bytes: this.downstream_bytes,
+                        duration: this.start_time.elapsed(),
+                        reason: if side == Side::Client {
+                            CloseReason::ClientClosed
+                        } else {
+                            CloseReason::ServerClosed
+                        },
+                    }));
                 }
+                (Pump::Idle, Pump::Idle) => return task::Poll::Pending,
+                _ => {}
+            }
+        }
+        cx.waker().wake_by_ref();
+        task::Poll::Pending
+    }
+}
+
+/// The buffer of a direction of a `TokioProxyChannel`.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+struct TokioBuffer {
+    inner: Vec<u8>,
+    head: usize,
+    tail: usize,
+}
+#[cfg(feature = "tokio")]
+impl TokioBuffer {
+    fn new(size: usize) -> Self {
+        TokioBuffer {
+            inner: vec![0; size],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    /// Reads bytes from `reader` into the free space (if any), returning `Some(0)` at the end of the stream.
+    fn poll_read_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        cx: &mut Context,
+    ) -> task::Poll<Result<Option<usize>>> {
+        if self.head == self.tail {
+            self.head = 0;
+            self.tail = 0;
+        } else if self.tail == self.inner.len() && self.head > 0 {
+            self.inner.copy_within(self.head..self.tail, 0);
+            self.tail -= self.head;
+            self.head = 0;
+        }
+        if self.tail == self.inner.len() {
+            return task::Poll::Ready(Ok(None));
+        }
+        let mut buf = ReadBuf::new(&mut self.inner[self.tail..]);
+        match Pin::new(reader).poll_read(cx, &mut buf) {
+            task::Poll::Pending => task::Poll::Pending,
+            task::Poll::Ready(result) => {
+                track!(result.map_err(Error::from))?;
+                let size = buf.filled().len();
+                self.tail += size;
+                task::Poll::Ready(Ok(Some(size)))
+            }
+        }
+    }
+
+    /// Writes the buffered bytes (if any) to `writer`, returning `Some(0)` if it has been closed.
+    fn poll_write_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        cx: &mut Context,
+    ) -> task::Poll<Result<Option<usize>>> {
+        if self.head == self.tail {
+            return task::Poll::Ready(Ok(None));
+        }
+        match Pin::new(writer).poll_write(cx, &self.inner[self.head..self.tail]) {
+            task::Poll::Pending => task::Poll::Pending,
+            task::Poll::Ready(result) => {
+                let size = track!(result.map_err(Error::from))?;
+                self.head += size;
+                task::Poll::Ready(Ok(Some(size)))
+            }
+        }
+    }
+}
+
+/// Relays bytes from `reader` to `writer` like `pump`, on the runtime of `tokio`.
+///
+/// When this returns `Pump::Idle`, `cx` is registered to be woken up by the socket which would block.
+#[cfg(feature = "tokio")]
+fn tokio_pump<R, W>(
+    buf: &mut TokioBuffer,
+    reader: &mut R,
+    writer: &mut W,
+    sent: &mut u64,
+    (from, to): (Side, Side),
+    cx: &mut Context,
+) -> Result<Pump>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut progress = false;
+    for _ in 0..MAX_OPS_PER_PUMP {
+        match track!(buf.poll_read_from(reader, cx))? {
+            task::Poll::Pending | task::Poll::Ready(None) => break,
+            task::Poll::Ready(Some(0)) => {
+                log::info!("Connection closed by {} while reading", from);
+                return Ok(Pump::Closed(from));
+            }
+            task::Poll::Ready(Some(size)) => {
+                log::debug!("Received {} bytes from {}", size, from);
+                progress = true;
+            }
+        }
+    }
+    for _ in 0..MAX_OPS_PER_PUMP {
+        match track!(buf.poll_write_to(writer, cx))? {
+            task::Poll::Pending | task::Poll::Ready(None) => break,
+            task::Poll::Ready(Some(0)) => {
+                log::info!("Connection closed by {} while writing", to);
+                return Ok(Pump::Closed(to));
+            }
+            task::Poll::Ready(Some(size)) => {
+                log::debug!("Sent {} bytes to {}", size, to);
+                *sent += size as u64;
+                progress = true;
             }
-            break;
         }
-        Ok(Async::NotReady)
     }
+    Ok(if progress { Pump::Progress } else { Pump::Idle })
 }
@@ -0,0 +1,184 @@
+use futures::{Async, Future, Poll, Stream};
+use std::path::{Path, PathBuf};
+
+use consul::EventWatcher;
+use proxy_server;
+use {Error, ErrorKind, ProxyServer, ProxyServerBuilder, Result, Spawner};
+
+/// A group of proxy servers which run in a single future.
+///
+/// Servers in the same group share Consul related infrastructure.
+/// For example, if multiple servers watch the same command events,
+/// only one watcher is run and the received commands are delivered to all of them.
+///
+/// Likewise, the root directory of the process is changed (see `ProxyServerBuilder::chroot`) only once,
+/// after all of the servers in the group have been bound. The servers must specify the same directory.
+///
+/// The group future terminates when all of the servers have terminated (see `Command::Shutdown`).
+pub struct ProxyGroup<S> {
+    servers: Vec<ProxyServer<S>>,
+    finished: Vec<bool>,
+    watchers: Vec<SharedWatcher>,
+    chroots: Vec<PathBuf>,
+    bound: bool,
+    change_root: fn(&Path) -> Result<()>,
+}
+impl<S: Spawner> ProxyGroup<S> {
+    /// Makes a new `ProxyGroup` instance which has no servers.
+    pub fn new() -> Self {
+        ProxyGroup {
+            servers: Vec::new(),
+            finished: Vec::new(),
+            watchers: Vec::new(),
+            chroots: Vec::new(),
+            bound: false,
+            change_root: proxy_server::change_root,
+        }
+    }
+
+    /// Builds a new proxy server with the given settings and adds it to the group.
+    pub fn add_server(&mut self, spawner: S, builder: &ProxyServerBuilder) -> &mut Self {
+        let mut server = builder.finish(spawner);
+        let index = self.servers.len();
+        if let Some(watcher) = server.take_event_watcher() {
+            if let Some(shared) = self
+                .watchers
+                .iter_mut()
+                .find(|w| w.watcher.is_same_source(&watcher))
+            {
+                shared.servers.push(index);
+            } else {
+                self.watchers.push(SharedWatcher {
+                    watcher,
+                    servers: vec![index],
+                });
+            }
+        }
+        if let Some(dir) = server.take_chroot() {
+            if !self.chroots.contains(&dir) {
+                self.chroots.push(dir);
+            }
+        }
+        self.servers.push(server);
+        self.finished.push(false);
+        self
+    }
+
+    /// Returns the servers in the group.
+    pub fn servers(&self) -> &[ProxyServer<S>] {
+        &self.servers
+    }
+}
+impl<S: Spawner> Default for ProxyGroup<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<S: Spawner> Future for ProxyGroup<S> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.bound {
+            let mut bound = true;
+            for server in &mut self.servers {
+                bound &= track!(server.poll_bind())?.is_ready();
+            }
+            if !bound {
+                return Ok(Async::NotReady);
+            }
+            self.bound = true;
+            track_assert!(
+                self.chroots.len() <= 1,
+                ErrorKind::Config,
+                "The servers in a group specify different chroot directories: {:?}",
+                self.chroots
+            );
+            if let Some(dir) = self.chroots.pop() {
+                track!((self.change_root)(&dir))?;
+                log::info!("Changed the root directory to {:?}", dir);
+            }
+        }
+        for shared in &mut self.watchers {
+            while let Async::Ready(Some(command)) = track!(shared.watcher.poll())? {
+                for &i in &shared.servers {
+                    if !self.finished[i] {
+                        self.servers[i].handle_command(command.clone());
+                    }
+                }
+            }
+        }
+        for (server, finished) in self.servers.iter_mut().zip(&mut self.finished) {
+            if !*finished {
+                *finished = track!(server.poll())?.is_ready();
+            }
+        }
+        if self.finished.is_empty() || self.finished.iter().any(|&f| !f) {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+struct SharedWatcher {
+    watcher: EventWatcher,
+    servers: Vec<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use futures::future;
+    use std::cell::RefCell;
+
+    use super::*;
+    use testing::InMemoryConsul;
+
+    thread_local! {
+        static ROOT_CHANGES: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_root_change(dir: &Path) -> Result<()> {
+        ROOT_CHANGES.with(|changes| changes.borrow_mut().push(dir.to_path_buf()));
+        Ok(())
+    }
+
+    #[test]
+    fn root_is_changed_once_after_all_servers_are_bound() {
+        let consul = InMemoryConsul::new();
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut group = ProxyGroup::new();
+        group.change_root = record_root_change;
+        for service in &["foo", "bar"] {
+            let mut builder = ProxyServerBuilder::new(service);
+            builder.bind_addr(([127, 0, 0, 1], 0).into());
+            builder.chroot("/var/empty");
+            builder.consul().transport(consul.clone());
+            group.add_server(executor.handle(), &builder);
+        }
+
+        let fiber = executor.spawn_monitor(future::poll_fn(move || {
+            track!(group.poll())?;
+            let bound = group
+                .servers()
+                .iter()
+                .filter(|s| s.local_addr().is_some())
+                .count();
+            let changes = ROOT_CHANGES.with(|changes| changes.borrow().len());
+            assert!(
+                changes == 0 || bound == 2,
+                "changed the root before binding"
+            );
+            if bound == 2 {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }));
+        let result: Result<()> = executor.run_fiber(fiber).unwrap().map_err(Error::from);
+        track_try_unwrap!(result);
+
+        let changes = ROOT_CHANGES.with(|changes| changes.borrow().clone());
+        assert_eq!(changes, vec![PathBuf::from("/var/empty")]);
+    }
+}
@@ -1,24 +1,113 @@
-use fibers::net::futures::{Connect, TcpListenerBind};
-use fibers::net::streams::Incoming;
-use fibers::net::{TcpListener, TcpStream};
-use fibers::time::timer::{TimeoutAfter, TimerExt};
-use fibers::Spawn;
+use fibers::net::futures::Connect;
+use fibers::net::TcpStream;
+use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout, TimeoutAfter, TimerExt};
+use fibers::{Executor, InPlaceExecutor, Spawn, ThreadPoolExecutor};
+use futures::future::Either;
 use futures::{Async, Future, Poll, Stream};
+use std::fmt;
+use std::io;
+use std::mem;
 use std::net::SocketAddr;
-use std::time::Duration;
-use trackable::error::Failed;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
 
-use consul::{ConsulClient, ServiceNode};
-use proxy_channel::ProxyChannel;
-use {AsyncResult, ConsulSettings, Error};
+use admin::{AdminListener, AdminServer};
+use admission::{AcceptFilter, Admission};
+use audit::{self, Caller};
+use balance::{Balancer, LoadBalancing};
+use capacity::{ServerSlot, ServerSlots};
+use churn::{ChurnDetector, ChurnLimit};
+use cidr::Cidr;
+use consul::{
+    CandidatesWatcher, ConfigWatcher, ConnectWatcher, ConsulClient, EventWatcher, FindCandidates,
+    Registrar, ServiceNode, StatsPublisher,
+};
+use control::{Command, CommandSender, DynamicConfig, Exclusions, Replicas};
+use error::{ConnectAttempt, ConnectAttempts};
+use event::{ConnectionEventKind, ConnectionEvents, EventHub};
+use fault::FaultInjection;
+use listener::{ClientStream, Connected, Incoming, ListenerBind};
+use maintenance::{MaintenanceAction, MaintenanceWindow};
+use middleware::{self, BoxEndpoint, Middleware};
+use outlier::{OutlierDetection, OutlierDetector};
+use proxy_channel::{BufferPool, ProxyChannel};
+use rate_limit::{ClientRateLimiter, GlobalRateLimiter, RateLimit};
+use registration::ServiceRegistration;
+use retry::UpstreamRetryPolicy;
+use routing::{ConnectionInfo, Route, Router};
+use secret::Secret;
+use spawner::Spawner;
+use stats::{ActiveConnection, Stats};
+use tls::{ConnectCerts, ConnectMiddleware, ConnectService};
+#[cfg(feature = "tokio")]
+use tokio_server::{TokioContext, TokioProxyServer};
+#[cfg(unix)]
+use unix::SocketPermissions;
+use {BackgroundServer, BandwidthLimit, ConsulSettings, Error, ErrorKind, MemoryBudget, Result};
+
+/// The delay before accepting clients again after a failure of accepting.
+pub(crate) const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The interval of checking for a free connection slot while waiting for one (see `ProxyServerBuilder::server_queue_timeout`).
+const SLOT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// A builder for `ProxyServer`.
 #[derive(Debug, Clone)]
 pub struct ProxyServerBuilder {
     bind_addr: SocketAddr,
     consul: ConsulSettings,
-    service_port: Option<u16>,
+    service_port: ServicePort,
     connect_timeout: Duration,
+    upstream_retry: UpstreamRetryPolicy,
+    fallback_servers: Vec<SocketAddr>,
+    load_balancing: LoadBalancing,
+    preferred_node_meta: Option<(String, String)>,
+    slow_start: Option<Duration>,
+    chroot: Option<PathBuf>,
+    replicas: Replicas,
+    is_replica: bool,
+    buffer_size: usize,
+    cork_delay: Option<Duration>,
+    memory_budget: MemoryBudget,
+    bandwidth_limit: Option<BandwidthLimit>,
+    client_middlewares: Vec<Arc<dyn Middleware>>,
+    server_middlewares: Vec<Arc<dyn Middleware>>,
+    connect: Option<String>,
+    maintenance_windows: Vec<MaintenanceWindow>,
+    allowed_cidrs: Vec<Cidr>,
+    denied_cidrs: Vec<Cidr>,
+    client_rate_limit: Option<RateLimit>,
+    accept_rate_limit: Option<RateLimit>,
+    churn_limit: Option<ChurnLimit>,
+    outlier_detection: Option<OutlierDetection>,
+    max_server_connections: Option<u64>,
+    server_queue_timeout: Option<Duration>,
+    accept_filter: Option<Arc<dyn AcceptFilter>>,
+    fault_injection: Option<FaultInjection>,
+    router: Option<Arc<dyn Router>>,
+    command_event: Option<String>,
+    config_kv_prefix: Option<String>,
+    stats_kv_prefix: Option<String>,
+    stats_interval: Duration,
+    registration: Option<ServiceRegistration>,
+    instance_id: Option<String>,
+    watch_candidates: bool,
+    watch_wait: Duration,
+    candidates_ttl: Option<Duration>,
+    refresh_interval: Duration,
+    refresh_jitter: f64,
+    admin_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    admin_socket: Option<(PathBuf, SocketPermissions)>,
+    admin_token: Option<Secret>,
+    #[cfg(unix)]
+    reuse_port: bool,
+    stats: Option<Arc<Stats>>,
 }
 impl ProxyServerBuilder {
     /// The default address to which the proxy server bind.
@@ -27,13 +116,73 @@ impl ProxyServerBuilder {
     /// The default timeout of a TCP connect operation.
     pub const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 1000;
 
+    /// The default size of the relay buffer allocated for each direction of a connection.
+    pub const DEFAULT_BUFFER_SIZE: usize = BufferPool::DEFAULT_BUFFER_SIZE;
+
+    /// The default interval of publishing statistics to the Consul KV store.
+    pub const DEFAULT_STATS_INTERVAL_SECS: u64 = 10;
+
+    /// The default wait time of blocking queries made by `watch_candidates`.
+    pub const DEFAULT_WATCH_WAIT_SECS: u64 = 300;
+
+    /// The default interval of periodic Consul queries.
+    pub const DEFAULT_REFRESH_INTERVAL_MS: u64 = 1000;
+
+    /// The default jitter fraction applied to the intervals of periodic Consul requests.
+    pub const DEFAULT_REFRESH_JITTER: f64 = 0.1;
+
     /// Makes a new `ProxyServerBuilder` for the given service.
     pub fn new(service: &str) -> Self {
         ProxyServerBuilder {
             bind_addr: Self::DEFAULT_BIND_ADDR.parse().expect("Never fails"),
             consul: ConsulSettings::new(service),
-            service_port: None,
+            service_port: ServicePort::Registered,
             connect_timeout: Duration::from_millis(Self::DEFAULT_CONNECT_TIMEOUT_MS),
+            upstream_retry: UpstreamRetryPolicy::default(),
+            fallback_servers: Vec::new(),
+            load_balancing: LoadBalancing::default(),
+            preferred_node_meta: None,
+            slow_start: None,
+            chroot: None,
+            replicas: Replicas::default(),
+            is_replica: false,
+            buffer_size: Self::DEFAULT_BUFFER_SIZE,
+            cork_delay: None,
+            memory_budget: MemoryBudget::unlimited(),
+            bandwidth_limit: None,
+            client_middlewares: Vec::new(),
+            server_middlewares: Vec::new(),
+            connect: None,
+            maintenance_windows: Vec::new(),
+            allowed_cidrs: Vec::new(),
+            denied_cidrs: Vec::new(),
+            client_rate_limit: None,
+            accept_rate_limit: None,
+            churn_limit: None,
+            outlier_detection: None,
+            max_server_connections: None,
+            server_queue_timeout: None,
+            accept_filter: None,
+            fault_injection: None,
+            router: None,
+            command_event: None,
+            config_kv_prefix: None,
+            stats_kv_prefix: None,
+            registration: None,
+            stats_interval: Duration::from_secs(Self::DEFAULT_STATS_INTERVAL_SECS),
+            instance_id: None,
+            watch_candidates: false,
+            watch_wait: Duration::from_secs(Self::DEFAULT_WATCH_WAIT_SECS),
+            candidates_ttl: None,
+            refresh_interval: Duration::from_millis(Self::DEFAULT_REFRESH_INTERVAL_MS),
+            refresh_jitter: Self::DEFAULT_REFRESH_JITTER,
+            admin_addr: None,
+            #[cfg(unix)]
+            admin_socket: None,
+            admin_token: None,
+            #[cfg(unix)]
+            reuse_port: false,
+            stats: None,
         }
     }
 
@@ -45,11 +194,39 @@ impl ProxyServerBuilder {
         self
     }
 
+    /// Makes the listening socket of the server set `SO_REUSEPORT`.
+    ///
+    /// Then servers on different threads (e.g., `replica`s on executors pinned to CPU cores)
+    /// can bind the same address, and the kernel distributes the incoming clients among them.
+    /// This is only supported on Unix platforms.
+    #[cfg(unix)]
+    pub fn reuse_port(&mut self, enabled: bool) -> &mut Self {
+        self.reuse_port = enabled;
+        self
+    }
+
     /// Sets the port number of the service handled by the proxy server.
     ///
     /// If omitted, the value of the selected node's `ServicePort` field registered in Consul will be used.
+    ///
+    /// This overrides `service_port_resolver`.
     pub fn service_port(&mut self, port: u16) -> &mut Self {
-        self.service_port = Some(port);
+        self.service_port = ServicePort::Fixed(port);
+        self
+    }
+
+    /// Sets the function which determines the port number of each service server.
+    ///
+    /// This is useful for services which register a port other than the one to be relayed to
+    /// (e.g., a management port), since the port can be derived from the registered one
+    /// or read from the metadata of the node.
+    ///
+    /// This overrides `service_port`.
+    pub fn service_port_resolver<F>(&mut self, resolve: F) -> &mut Self
+    where
+        F: Fn(&ServiceNode) -> u16 + Send + Sync + 'static,
+    {
+        self.service_port = ServicePort::Resolve(Arc::new(resolve));
         self
     }
 
@@ -61,135 +238,2104 @@ impl ProxyServerBuilder {
         self
     }
 
+    /// Sets the policy of trying the candidate servers of a connection.
+    ///
+    /// The connect timeout of the policy, if any, replaces `connect_timeout`
+    /// (but is replaced by the `connect_timeout_ms` key of `watch_config`).
+    ///
+    /// The default value is `UpstreamRetryPolicy::default()`, which tries all the candidates in turn
+    /// without waiting between them, and closes the connection once they have all failed.
+    pub fn upstream_retry(&mut self, policy: UpstreamRetryPolicy) -> &mut Self {
+        self.upstream_retry = policy;
+        self
+    }
+
+    /// Sets the static servers used when service discovery fails or returns no nodes.
+    ///
+    /// The servers are tried in the given order, and `service_port` is not applied to them.
+    ///
+    /// If omitted, connections are closed in such cases.
+    pub fn fallback_servers(&mut self, servers: Vec<SocketAddr>) -> &mut Self {
+        self.fallback_servers = servers;
+        self
+    }
+
+    /// Sets the strategy of balancing connections across the candidate servers.
+    ///
+    /// The strategy is not applied to the fallback servers and to the backends chosen by the router.
+    ///
+    /// The default value is `LoadBalancing::Ordered`.
+    pub fn load_balancing(&mut self, strategy: LoadBalancing) -> &mut Self {
+        self.load_balancing = strategy;
+        self
+    }
+
+    /// Makes the candidate servers whose node has the metadata entry `key:value` (e.g., `zone:us-east-1a`)
+    /// preferred over the others.
+    ///
+    /// The other candidates are tried only after all the preferred ones have failed to be connected
+    /// (or if there are none), so that connections stay within the local zone without relying on `ConsulSettings::near`.
+    /// `load_balancing` is applied to the preferred candidates and to the others separately.
+    ///
+    /// If omitted, no candidates are preferred.
+    pub fn prefer_node_meta(&mut self, key: &str, value: &str) -> &mut Self {
+        self.preferred_node_meta = Some((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets the slow-start window of the candidate servers which newly appear in Consul.
+    ///
+    /// During the window, a new server is tried first by a fraction of the connections which it would be otherwise,
+    /// ramping up linearly from 10% to 100%, so that its cold caches are not overwhelmed.
+    /// The servers found by the first query after the proxy starts are not regarded as new.
+    ///
+    /// If omitted, new servers receive their full share of connections immediately.
+    pub fn slow_start(&mut self, window: Duration) -> &mut Self {
+        self.slow_start = Some(window);
+        self
+    }
+
+    /// Sets the size of the relay buffer allocated for each direction of a connection.
+    ///
+    /// On Linux, this is used as the size of the kernel pipe through which bytes are `splice(2)`d
+    /// (the kernel rounds it up to a multiple of the page size).
+    /// With the experimental `io-uring` feature, connections without `bandwidth_limit` are relayed
+    /// by `io_uring(7)` through buffers of this size instead, falling back to pipes if the kernel lacks support.
+    ///
+    /// The default value is `ProxyServerBuilder::DEFAULT_BUFFER_SIZE`.
+    pub fn buffer_size(&mut self, size: usize) -> &mut Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Enables coalescing of relayed writes, which reduces the number of packets sent for
+    /// chatty protocols at the cost of up to `delay` of extra latency.
+    ///
+    /// While relaying, each socket is corked with `TCP_CORK` and then uncorked after `delay`.
+    /// This is only supported on Linux.
+    ///
+    /// By default, writes are not coalesced.
+    pub fn cork_delay(&mut self, delay: Duration) -> &mut Self {
+        self.cork_delay = Some(delay);
+        self
+    }
+
+    /// Sets the budget which caps the total number of bytes buffered by the connections of the server.
+    ///
+    /// The same budget can be set to multiple servers to share the cap among them.
+    ///
+    /// The default value is `MemoryBudget::unlimited()`.
+    pub fn memory_budget(&mut self, budget: MemoryBudget) -> &mut Self {
+        self.memory_budget = budget;
+        self
+    }
+
+    /// Sets the cap on the total throughput of the connections of the server.
+    ///
+    /// The same limit can be set to multiple servers to share the cap among them.
+    ///
+    /// By default, the throughput is not limited.
+    pub fn bandwidth_limit(&mut self, limit: BandwidthLimit) -> &mut Self {
+        self.bandwidth_limit = Some(limit);
+        self
+    }
+
+    /// Adds a middleware which wraps the client side of each connection.
+    ///
+    /// Middlewares are applied in the order they were added
+    /// (i.e., the last one is the outermost and the nearest to the relay buffer).
+    pub fn add_client_middleware<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.client_middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Adds a middleware which wraps the server side of each connection.
+    ///
+    /// See `add_client_middleware` for the order of middlewares.
+    pub fn add_server_middleware<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.server_middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Enables the [Connect]-native mode, in which clients are relayed to the sidecar proxies of the service.
+    ///
+    /// The sidecar proxies are discovered by `ConsulSettings::connect`, and connections to them are made
+    /// by mTLS with the leaf certificate of the service `identity` issued by the Connect CA.
+    /// The certificates are fetched from the consul agent every `refresh_interval`, and connections
+    /// are refused until they are fetched first.
+    ///
+    /// The certificates of the sidecar proxies have to be signed by the Connect CA, and identify the service
+    /// of each connection (the one selected by `Router`, if any) by the SPIFFE ID
+    /// `spiffe://<trust-domain>/ns/<namespace>/dc/<dc>/svc/<service>` (see `ConsulSettings::spiffe_id_template`),
+    /// where `<dc>` is the datacenter queried by `ConsulSettings` (or one of its failovers).
+    /// In turn, the sidecar proxies authorize `identity` by [intentions].
+    ///
+    /// This requires the `tls` feature.
+    ///
+    /// [Connect]: https://www.consul.io/docs/connect
+    /// [intentions]: https://www.consul.io/docs/connect/intentions
+    pub fn connect(&mut self, identity: &str) -> &mut Self {
+        self.consul.connect(true);
+        self.connect = Some(identity.to_owned());
+        self
+    }
+
+    /// Sets the directory to which the server changes its root directory after binding.
+    ///
+    /// In a `ProxyGroup`, the root directory is changed once after all of the servers in the group
+    /// have been bound, instead of by each server.
+    ///
+    /// This is only supported on Unix platforms and usually requires the `CAP_SYS_CHROOT` capability.
+    ///
+    /// The TLS files of `ConsulSettings` are loaded before the change, but the other files are opened
+    /// after it and thus must exist relative to `dir` (`validate` checks them):
+    /// the token and snapshot files of `ConsulSettings`, the Unix domain socket of the agent,
+    /// and `etc/resolv.conf` if the agent is specified by a hostname.
+    pub fn chroot<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.chroot = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a maintenance window.
+    ///
+    /// While the window is active, the server takes the action specified by the window
+    /// and automatically returns to normal operation when the window ends.
+    /// If multiple windows are active at the same time, the earliest added one takes precedence.
+    pub fn add_maintenance_window(&mut self, window: MaintenanceWindow) -> &mut Self {
+        self.maintenance_windows.push(window);
+        self
+    }
+
+    /// Adds a network from which clients are allowed to connect.
+    ///
+    /// If no networks are added, clients from any network are allowed unless denied.
+    pub fn add_allowed_cidr(&mut self, cidr: Cidr) -> &mut Self {
+        self.allowed_cidrs.push(cidr);
+        self
+    }
+
+    /// Adds a network from which clients are refused.
+    ///
+    /// Denied networks take precedence over allowed ones.
+    pub fn add_denied_cidr(&mut self, cidr: Cidr) -> &mut Self {
+        self.denied_cidrs.push(cidr);
+        self
+    }
+
+    /// Limits the rate of new connections from each client IP address.
+    ///
+    /// By default, the rate is not limited.
+    pub fn client_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.client_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits the rate of new connections accepted by the server regardless of their sources.
+    ///
+    /// Connections exceeding the limit are queued until `limit.max_delay()` at most,
+    /// and refused if they cannot be served within it.
+    ///
+    /// By default, the rate is not limited.
+    pub fn accept_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.accept_rate_limit = Some(limit);
+        self
+    }
+
+    /// Temporarily bans clients which rapidly open and close connections.
+    ///
+    /// By default, no clients are banned.
+    pub fn churn_limit(&mut self, limit: ChurnLimit) -> &mut Self {
+        self.churn_limit = Some(limit);
+        self
+    }
+
+    /// Temporarily ejects backend servers which repeatedly fail to be connected from the candidates.
+    ///
+    /// Ejected servers are tried only after all the other candidates have failed,
+    /// so that clients are still served if every server is ejected.
+    ///
+    /// By default, no servers are ejected.
+    pub fn outlier_detection(&mut self, settings: OutlierDetection) -> &mut Self {
+        self.outlier_detection = Some(settings);
+        self
+    }
+
+    /// Limits the number of simultaneous connections to each backend server.
+    ///
+    /// Connects in progress are counted as well, and a server at capacity is skipped when selecting a server.
+    /// If all the candidates are at capacity, the client is closed (or waits for up to `server_queue_timeout`).
+    ///
+    /// By default, the connections are not limited.
+    pub fn max_server_connections(&mut self, limit: u64) -> &mut Self {
+        self.max_server_connections = Some(limit);
+        self
+    }
+
+    /// Sets how long a client waits for a connection slot when all the candidate servers are at capacity
+    /// (see `max_server_connections`).
+    ///
+    /// By default, such clients are closed immediately.
+    pub fn server_queue_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.server_queue_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the filter which decides whether each accepted client is admitted.
+    ///
+    /// By default, every client is admitted (unless it is refused by the other settings).
+    pub fn accept_filter<F: AcceptFilter + 'static>(&mut self, filter: F) -> &mut Self {
+        self.accept_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Injects faults into the connections of the server.
+    ///
+    /// By default, no faults are injected.
+    pub fn fault_injection(&mut self, fault: FaultInjection) -> &mut Self {
+        self.fault_injection = Some(fault);
+        self
+    }
+
+    /// Sets the router which decides how each connection is routed.
+    ///
+    /// By default, every connection is routed to a server of the service of the proxy.
+    pub fn router<R: Router + 'static>(&mut self, router: R) -> &mut Self {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Makes the server watch the Consul [user events] which have the given name.
+    ///
+    /// The payload of each event is parsed as a `Command` and applied to the server.
+    /// For example, `consul event -name=<name> drain` makes the server refuse new connections.
+    ///
+    /// [user events]: https://www.consul.io/api/event.html
+    pub fn command_event(&mut self, name: &str) -> &mut Self {
+        self.command_event = Some(name.to_owned());
+        self
+    }
+
+    /// Makes the server watch the settings stored under the given prefix of the Consul KV store,
+    /// and apply them at runtime.
+    ///
+    /// The following keys under `<kv_prefix>/` are recognized:
+    /// - `tag`: Tag which replaces the configured ones of the Consul queries
+    /// - `connect_timeout_ms`: Timeout which replaces `connect_timeout`
+    /// - `maintenance`: If `true`, new connections are refused
+    ///
+    /// The keys are watched by blocking queries which wait up to `watch_wait`.
+    /// When a key is deleted, the configured setting is restored.
+    pub fn config_kv_prefix(&mut self, kv_prefix: &str) -> &mut Self {
+        self.config_kv_prefix = Some(kv_prefix.to_owned());
+        self
+    }
+
+    /// Makes the server periodically write its statistics into the Consul KV store.
+    ///
+    /// The statistics are written in JSON format under the key `<kv_prefix>/<service>/<instance_id>`.
+    pub fn publish_stats(&mut self, kv_prefix: &str) -> &mut Self {
+        self.stats_kv_prefix = Some(kv_prefix.to_owned());
+        self
+    }
+
+    /// Makes the server register itself as a Consul service, with the port to which it is bound.
+    ///
+    /// The registration is retried every `refresh_interval` until it succeeds, and the service is
+    /// deregistered when the server is shut down by `Command::Shutdown`.
+    pub fn register(&mut self, registration: ServiceRegistration) -> &mut Self {
+        self.registration = Some(registration);
+        self
+    }
+
+    /// Sets the interval of publishing statistics.
+    ///
+    /// The default value is `Duration::from_secs(ProxyServerBuilder::DEFAULT_STATS_INTERVAL_SECS)`.
+    pub fn stats_interval(&mut self, interval: Duration) -> &mut Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Sets the identifier of the proxy instance.
+    ///
+    /// If omitted, the hostname of the machine will be used.
+    pub fn instance_id(&mut self, id: &str) -> &mut Self {
+        self.instance_id = Some(id.to_owned());
+        self
+    }
+
+    /// Makes the server watch the candidate nodes of the service by Consul [blocking queries].
+    ///
+    /// Instead of querying Consul for every accepted client, the server keeps the nodes up to date
+    /// in the background, and new connections select servers from them (after exclusions).
+    /// The nodes of the services selected by `Router`s are still queried for each connection.
+    ///
+    /// Until the first response of the watch arrives, the nodes are queried as usual.
+    /// After that, if the watch fails, the last received nodes keep being used while it is retried
+    /// at the refresh interval.
+    ///
+    /// [blocking queries]: https://www.consul.io/api/features/blocking.html
+    pub fn watch_candidates(&mut self) -> &mut Self {
+        self.watch_candidates = true;
+        self
+    }
+
+    /// Sets the maximum time for which each blocking query of `watch_candidates` waits for changes.
+    ///
+    /// The default value is `Duration::from_secs(ProxyServerBuilder::DEFAULT_WATCH_WAIT_SECS)`.
+    pub fn watch_wait(&mut self, wait: Duration) -> &mut Self {
+        self.watch_wait = wait;
+        self
+    }
+
+    /// Makes the server cache the candidate nodes of the service, refreshing them every `ttl` in the background.
+    ///
+    /// New connections select servers from the cached nodes (after exclusions) without waiting for Consul,
+    /// and the nodes are queried for each connection only until the first refresh completes.
+    /// If a refresh fails, the cached nodes keep being used until the next one.
+    /// The nodes of the services selected by `Router`s are not cached.
+    ///
+    /// If `watch_candidates` is also specified, the cache is updated by blocking queries instead,
+    /// and `ttl` is not used.
+    pub fn candidates_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.candidates_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the interval of periodic Consul queries made in the background (e.g., command event polling).
+    ///
+    /// The default value is `Duration::from_millis(ProxyServerBuilder::DEFAULT_REFRESH_INTERVAL_MS)`.
+    pub fn refresh_interval(&mut self, interval: Duration) -> &mut Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// Sets the jitter fraction applied to the intervals of periodic Consul requests.
+    ///
+    /// Each interval is randomly scaled within `±jitter` of it (e.g., `0.1` means ±10%),
+    /// so that a large fleet of proxies doesn't synchronize their requests.
+    /// This also applies to the stats publishing interval.
+    ///
+    /// The default value is `ProxyServerBuilder::DEFAULT_REFRESH_JITTER`.
+    pub fn refresh_jitter(&mut self, jitter: f64) -> &mut Self {
+        self.refresh_jitter = jitter;
+        self
+    }
+
+    /// Sets the address to which the admin HTTP API server bind.
+    ///
+    /// If omitted, the admin API is disabled.
+    ///
+    /// The API has the following endpoints:
+    /// - `GET /events?client=<ip>&backend=<addr>`: streams connection events as newline-delimited JSON.
+    ///   The optional `client` and `backend` parameters filter the events.
+    /// - `POST /commands`: applies the `Command` in the request body (e.g., `drain`) to the server.
+    ///
+    /// Every request, as well as every command received by `command_event` and every transition of
+    /// maintenance windows, is logged as a JSON audit record to the `cotoxy::audit` log target.
+    pub fn admin_addr(&mut self, addr: SocketAddr) -> &mut Self {
+        self.admin_addr = Some(addr);
+        self
+    }
+
+    /// Sets the Unix socket file to which the admin HTTP API server bind.
+    ///
+    /// The file is created with the given ownership and permissions,
+    /// so that access to the API can be controlled by the local filesystem.
+    /// This can be used together with `admin_addr`.
+    #[cfg(unix)]
+    pub fn admin_socket<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        permissions: SocketPermissions,
+    ) -> &mut Self {
+        self.admin_socket = Some((path.as_ref().to_path_buf(), permissions));
+        self
+    }
+
+    /// Sets the bearer token required to access the admin HTTP API.
+    ///
+    /// If set, requests without the `Authorization: Bearer <token>` header are rejected
+    /// with `401 Unauthorized`.
+    ///
+    /// By default, the API can be accessed without authentication.
+    pub fn admin_token(&mut self, token: &str) -> &mut Self {
+        self.admin_token = Some(Secret::new(token));
+        self
+    }
+
+    /// Sets the statistics to which the server records its connections.
+    ///
+    /// By default, each server has its own statistics.
+    /// Servers sharing the same `Stats` (e.g., `replica`s) report (and publish) the aggregated numbers.
+    pub fn stats(&mut self, stats: Arc<Stats>) -> &mut Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Returns a builder of a replica of the server, which serves the same address on another thread.
+    ///
+    /// Both the server and its replicas should enable `reuse_port` to bind the address,
+    /// and share `stats`. The admin API, the registration, the stats publishing and
+    /// the root directory change are left to the original server, since they are process-wide.
+    /// The commands received via the admin API (or `ProxyServer::commands`) of the original server
+    /// are forwarded to its replicas, while `command_event` and `config_kv_prefix` are watched by every replica.
+    pub fn replica(&self) -> Self {
+        let mut replica = self.clone();
+        replica.is_replica = true;
+        replica.chroot = None;
+        replica.stats_kv_prefix = None;
+        replica.registration = None;
+        replica.admin_addr = None;
+        #[cfg(unix)]
+        {
+            replica.admin_socket = None;
+        }
+        replica
+    }
+
     /// Returns the mutable reference to `ConsulClientBuilder`.
     pub fn consul(&mut self) -> &mut ConsulSettings {
         &mut self.consul
     }
 
+    /// Builds a new proxy server with the specified settings, and runs it on a dedicated thread.
+    ///
+    /// This returns after the server has been bound (or has failed to bind).
+    /// The returned `BackgroundServer` implements `std::future::Future`,
+    /// so that it can be awaited on executors other than `fibers`.
+    pub fn spawn_background(&self) -> Result<BackgroundServer> {
+        track!(self.validate())?;
+        track!(BackgroundServer::spawn(self))
+    }
+
+    /// Builds a new proxy server with the specified settings, and runs it on the current thread.
+    ///
+    /// This blocks until the server stops (i.e., on an error).
+    /// Use `run_with_threads` to run the server on multiple worker threads,
+    /// or `finish` to run it on an existing executor of `fibers`.
+    pub fn run(&self) -> Result<()> {
+        let executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+        track!(self.run_on(executor))
+    }
+
+    /// Builds a new proxy server with the specified settings, and runs it on `threads` worker threads.
+    ///
+    /// This blocks until the server stops (i.e., on an error).
+    pub fn run_with_threads(&self, threads: usize) -> Result<()> {
+        track_assert_ne!(threads, 0, ErrorKind::Config, "Zero threads");
+        let executor = track!(ThreadPoolExecutor::with_thread_count(threads).map_err(Error::from))?;
+        track!(self.run_on(executor))
+    }
+
+    fn run_on<E: Executor + Spawn>(&self, mut executor: E) -> Result<()> {
+        let server = track!(self.try_finish(executor.handle()))?;
+        let fiber = executor.spawn_monitor(server);
+        let result = track!(executor.run_fiber(fiber).map_err(Error::from))?;
+        track!(result.map_err(Error::from))
+    }
+
+    /// Validates the specified settings.
+    ///
+    /// This reports an `ErrorKind::Config` error on the first invalid (or inconsistent) setting found.
+    /// Note that the availability of the addresses is not checked until the server is bound.
+    pub fn validate(&self) -> Result<()> {
+        track_assert!(
+            !self.consul.service_name().is_empty(),
+            ErrorKind::Config,
+            "Empty service name"
+        );
+        track_assert_ne!(self.buffer_size, 0, ErrorKind::Config, "Zero buffer size");
+        track_assert_ne!(
+            self.max_server_connections,
+            Some(0),
+            ErrorKind::Config,
+            "Zero max server connections"
+        );
+        track_assert_ne!(
+            self.connect_timeout,
+            Duration::from_secs(0),
+            ErrorKind::Config,
+            "Zero connect timeout"
+        );
+        track_assert_ne!(
+            self.refresh_interval,
+            Duration::from_secs(0),
+            ErrorKind::Config,
+            "Zero refresh interval"
+        );
+        track_assert!(
+            0.0 <= self.refresh_jitter && self.refresh_jitter <= 1.0,
+            ErrorKind::Config,
+            "Refresh jitter must be in the range [0.0, 1.0]: {}",
+            self.refresh_jitter
+        );
+        if self.stats_kv_prefix.is_some() {
+            track_assert_ne!(
+                self.stats_interval,
+                Duration::from_secs(0),
+                ErrorKind::Config,
+                "Zero stats interval"
+            );
+        }
+        if let Some(addr) = self.admin_addr {
+            track_assert!(
+                addr != self.bind_addr || addr.port() == 0,
+                ErrorKind::Config,
+                "The admin address conflicts with the bind address: {}",
+                addr
+            );
+        }
+        if let Some(ref fault) = self.fault_injection {
+            track!(fault.validate())?;
+        }
+        if let Some(ref registration) = self.registration {
+            track!(registration.validate())?;
+        }
+        track!(self.consul.validate())?;
+        if let Some(ref identity) = self.connect {
+            track_assert!(
+                cfg!(feature = "tls"),
+                ErrorKind::Config,
+                "Connect requires the `tls` feature: identity={:?}",
+                identity
+            );
+            track_assert!(
+                !identity.is_empty(),
+                ErrorKind::Config,
+                "Empty Connect identity"
+            );
+        }
+        if let Some(ref dir) = self.chroot {
+            track_assert!(
+                cfg!(unix),
+                ErrorKind::Config,
+                "chroot is not supported on this platform: {:?}",
+                dir
+            );
+            track_assert!(
+                dir.is_dir(),
+                ErrorKind::Config,
+                "Not a directory: chroot={:?}",
+                dir
+            );
+            track!(self.consul.validate_chroot(dir))?;
+        }
+        Ok(())
+    }
+
+    fn listener_bind(&self) -> ListenerBind {
+        #[cfg(unix)]
+        {
+            if self.reuse_port {
+                return ListenerBind::reuse_port(self.bind_addr);
+            }
+        }
+        ListenerBind::tcp(self.bind_addr)
+    }
+
+    /// Validates the specified settings, and then builds a new proxy server with them.
+    ///
+    /// See `validate` for the errors reported.
+    pub fn try_finish<S: Spawner>(&self, spawner: S) -> Result<ProxyServer<S>> {
+        track!(self.validate())?;
+        Ok(self.finish(spawner))
+    }
+
+    /// Validates the specified settings, and then builds a new proxy server running on the runtime of `tokio`.
+    ///
+    /// This is the counterpart of `try_finish` for the `tokio` feature, and needs to be called within the runtime.
+    /// The server is bound immediately, and starts accepting clients once it is spawned (or polled).
+    ///
+    /// The `tokio` implementation supports a subset of the settings:
+    /// `bind_addr`, `service_port`, `connect_timeout`, `upstream_retry`, `fallback_servers`, `load_balancing`,
+    /// `prefer_node_meta`, `slow_start`, `buffer_size`, `add_allowed_cidr`, `add_denied_cidr` and `stats`,
+    /// and the Consul settings except `https`, `connect`, `dc_failover`, `retry`, `dns_fallback`, `snapshot_file`
+    /// and `transport`. The others are reported as `ErrorKind::Config` errors rather than ignored.
+    #[cfg(feature = "tokio")]
+    pub fn finish_tokio(&self) -> Result<TokioProxyServer> {
+        track!(self.validate())?;
+        track!(self.validate_tokio())?;
+        track_assert!(
+            tokio::runtime::Handle::try_current().is_ok(),
+            ErrorKind::Other,
+            "Not called within the runtime of tokio"
+        );
+        let listener = track!(std::net::TcpListener::bind(self.bind_addr).map_err(Error::from))?;
+        track!(listener.set_nonblocking(true).map_err(Error::from))?;
+        let listener = track!(tokio::net::TcpListener::from_std(listener).map_err(Error::from))?;
+        let stats = self.stats.clone().unwrap_or_else(|| Arc::new(Stats::new()));
+        let consul = Arc::new(self.consul.client());
+        log::info!("Consul query url: {}", consul.query_url());
+        track!(TokioProxyServer::new(
+            listener,
+            TokioContext {
+                consul,
+                service_port: self.service_port.clone(),
+                connect_timeout: self
+                    .upstream_retry
+                    .connect_timeout()
+                    .unwrap_or(self.connect_timeout),
+                upstream_retry: self.upstream_retry.clone(),
+                fallback_servers: self.fallback_servers.clone(),
+                balancer: Arc::new(Balancer::new(
+                    self.load_balancing,
+                    self.preferred_node_meta.clone(),
+                    self.slow_start,
+                    stats.clone(),
+                )),
+                buffer_size: self.buffer_size,
+                allowed_cidrs: self.allowed_cidrs.clone(),
+                denied_cidrs: self.denied_cidrs.clone(),
+                stats,
+            }
+        ))
+    }
+
+    /// Reports an `ErrorKind::Config` error if the settings need a feature which is not implemented on `tokio`.
+    #[cfg(feature = "tokio")]
+    fn validate_tokio(&self) -> Result<()> {
+        #[cfg(unix)]
+        let reuse_port = self.reuse_port;
+        #[cfg(not(unix))]
+        let reuse_port = false;
+        #[cfg(unix)]
+        let admin_socket = self.admin_socket.is_some();
+        #[cfg(not(unix))]
+        let admin_socket = false;
+        let unsupported = [
+            ("chroot", self.chroot.is_some()),
+            ("replica", self.is_replica),
+            ("cork_delay", self.cork_delay.is_some()),
+            ("memory_budget", self.memory_budget.limit() != usize::MAX),
+            ("bandwidth_limit", self.bandwidth_limit.is_some()),
+            ("client_middleware", !self.client_middlewares.is_empty()),
+            ("server_middleware", !self.server_middlewares.is_empty()),
+            ("connect", self.connect.is_some()),
+            ("maintenance_window", !self.maintenance_windows.is_empty()),
+            ("client_rate_limit", self.client_rate_limit.is_some()),
+            ("accept_rate_limit", self.accept_rate_limit.is_some()),
+            ("churn_limit", self.churn_limit.is_some()),
+            ("outlier_detection", self.outlier_detection.is_some()),
+            (
+                "max_server_connections",
+                self.max_server_connections.is_some(),
+            ),
+            ("server_queue_timeout", self.server_queue_timeout.is_some()),
+            ("accept_filter", self.accept_filter.is_some()),
+            ("fault_injection", self.fault_injection.is_some()),
+            ("router", self.router.is_some()),
+            ("command_event", self.command_event.is_some()),
+            ("config_kv_prefix", self.config_kv_prefix.is_some()),
+            ("stats_kv_prefix", self.stats_kv_prefix.is_some()),
+            ("registration", self.registration.is_some()),
+            ("watch_candidates", self.watch_candidates),
+            ("candidates_ttl", self.candidates_ttl.is_some()),
+            ("admin_addr", self.admin_addr.is_some()),
+            ("admin_socket", admin_socket),
+            ("reuse_port", reuse_port),
+        ];
+        if let Some(&(name, _)) = unsupported.iter().find(|&&(_, used)| used) {
+            track_panic!(
+                ErrorKind::Config,
+                "The setting `{}` is not supported on tokio",
+                name
+            );
+        }
+        track!(self.consul.validate_tokio())
+    }
+
     /// Builds a new proxy server with the specified settings.
-    pub fn finish<S: Spawn>(&self, spawner: S) -> ProxyServer<S> {
-        let consul = self.consul.client();
+    ///
+    /// The settings are not validated, so invalid ones may cause errors (or odd behaviors)
+    /// after the server has started. Use `try_finish` to report them up front.
+    pub fn finish<S: Spawner>(&self, spawner: S) -> ProxyServer<S> {
+        let watch = if self.watch_candidates {
+            Some(CandidatesWatch {
+                wait: Some(self.watch_wait),
+                interval: self.refresh_interval,
+                jitter: self.refresh_jitter,
+            })
+        } else {
+            self.candidates_ttl.map(|ttl| CandidatesWatch {
+                wait: None,
+                interval: ttl,
+                jitter: self.refresh_jitter,
+            })
+        };
+        let mut watchers = Vec::new();
+        let consul = consul_client(&self.consul, watch, &mut watchers);
         log::debug!("Consul query url: {}", consul.query_url());
+        let maintenance = self
+            .maintenance_windows
+            .iter()
+            .map(|window| Maintenance {
+                window: window.clone(),
+                consul: maintenance_client(window, &self.consul, watch, &mut watchers),
+            })
+            .collect();
+        let stats = self.stats.clone().unwrap_or_else(|| Arc::new(Stats::new()));
+        let stats_publisher = self.stats_kv_prefix.as_ref().map(|prefix| {
+            let instance_id = self.instance_id.clone().unwrap_or_else(hostname);
+            let key = format!("{}/{}/{}", prefix, self.consul.service_name(), instance_id);
+            self.consul.stats_publisher(
+                &key,
+                self.stats_interval,
+                self.refresh_jitter,
+                stats.clone(),
+            )
+        });
+        let mut connect_certs = None;
+        let connect = self.connect.as_ref().map(|identity| {
+            let certs = Arc::new(ConnectCerts::default());
+            connect_certs = Some(certs.clone());
+            self.consul
+                .connect_watcher(identity, self.refresh_interval, self.refresh_jitter, certs)
+        });
+        let event_hub = EventHub::new();
+        let (command_tx, command_rx) = mpsc::channel();
+        if self.is_replica {
+            self.replicas.add(command_tx.clone());
+        }
+        let mut listeners = Vec::new();
+        if let Some(addr) = self.admin_addr {
+            listeners.push(AdminListener::tcp(addr));
+        }
+        #[cfg(unix)]
+        {
+            if let Some((ref path, ref permissions)) = self.admin_socket {
+                listeners.push(AdminListener::unix(path, permissions));
+            }
+        }
+        let admin = listeners
+            .into_iter()
+            .map(|listener| {
+                AdminServer::new(
+                    listener,
+                    self.admin_token.clone(),
+                    command_tx.clone(),
+                    event_hub.clone(),
+                )
+            })
+            .collect();
         ProxyServer {
             spawner,
             consul,
-            bind: Some(TcpListener::bind(self.bind_addr)),
+            configured_consul: self.consul.clone(),
+            consul_settings: self.consul.clone(),
+            watch,
+            watchers,
+            router: self.router.clone(),
+            accept_filter: self.accept_filter.clone(),
+            bind: Some(self.listener_bind()),
             incoming: None,
-            service_port: self.service_port,
-            connect_timeout: self.connect_timeout,
+            accept_retry: None,
+            local_addr: None,
+            chroot: self.chroot.clone(),
+            context: Arc::new(ConnectionContext {
+                service_port: self.service_port.clone(),
+                connect_timeout: self
+                    .upstream_retry
+                    .connect_timeout()
+                    .unwrap_or(self.connect_timeout),
+                upstream_retry: self.upstream_retry.clone(),
+                fallback_servers: self.fallback_servers.clone(),
+                balancer: Arc::new(Balancer::new(
+                    self.load_balancing,
+                    self.preferred_node_meta.clone(),
+                    self.slow_start,
+                    stats.clone(),
+                )),
+                outliers: self
+                    .outlier_detection
+                    .clone()
+                    .map(|settings| Arc::new(OutlierDetector::new(settings))),
+                slots: self
+                    .max_server_connections
+                    .map(|limit| Arc::new(ServerSlots::new(limit))),
+                server_queue_timeout: self.server_queue_timeout,
+                buffer_pool: BufferPool::new(self.buffer_size, self.memory_budget.clone()),
+                cork_delay: self.cork_delay,
+                bandwidth_limit: self.bandwidth_limit.clone(),
+                client_middlewares: self.client_middlewares.clone(),
+                server_middlewares: self.server_middlewares.clone(),
+                connect: connect_certs.map(|certs| (certs, self.consul.connect_service())),
+                churn: self.churn_limit.clone().map(ChurnDetector::new),
+                fault: self.fault_injection.clone(),
+                stats: stats.clone(),
+                event_hub: event_hub.clone(),
+            }),
+            maintenance,
+            active_maintenance: None,
+            maintenance_timer: None,
+            allowed_cidrs: self.allowed_cidrs.clone(),
+            denied_cidrs: self.denied_cidrs.clone(),
+            client_rate_limiter: self.client_rate_limit.clone().map(ClientRateLimiter::new),
+            accept_rate_limiter: self.accept_rate_limit.clone().map(GlobalRateLimiter::new),
+            events: self.command_event.as_ref().map(|name| {
+                self.consul
+                    .event_watcher(name, self.refresh_interval, self.refresh_jitter)
+            }),
+            config: self.config_kv_prefix.as_ref().map(|prefix| {
+                self.consul.config_watcher(
+                    prefix,
+                    self.watch_wait,
+                    self.refresh_interval,
+                    self.refresh_jitter,
+                )
+            }),
+            dynamic: DynamicConfig::default(),
+            draining: false,
+            excluded: Arc::new(Exclusions::default()),
+            stats,
+            stats_publisher,
+            connect,
+            registration: self.registration.clone().map(|settings| Registration {
+                settings,
+                interval: self.refresh_interval,
+                jitter: self.refresh_jitter,
+            }),
+            registrar: None,
+            shutdown: false,
+            admin,
+            replicas: if self.is_replica {
+                None
+            } else {
+                Some(self.replicas.clone())
+            },
+            commands: command_tx,
+            admin_commands: command_rx,
+            event_hub,
         }
     }
 }
 
+#[derive(Debug)]
+struct Maintenance {
+    window: MaintenanceWindow,
+    consul: Option<Arc<ConsulClient>>,
+}
+
+/// Returns the Consul client used during `window`, if the window switches the tag.
+fn maintenance_client(
+    window: &MaintenanceWindow,
+    settings: &ConsulSettings,
+    watch: Option<CandidatesWatch>,
+    watchers: &mut Vec<CandidatesWatcher>,
+) -> Option<Arc<ConsulClient>> {
+    if let MaintenanceAction::SwitchTag(ref tag) = *window.action() {
+        Some(consul_client(settings.clone().tag(tag), watch, watchers))
+    } else {
+        None
+    }
+}
+
+/// Settings of the watchers of the candidate nodes
+/// (see `ProxyServerBuilder::watch_candidates` and `ProxyServerBuilder::candidates_ttl`).
+#[derive(Debug, Clone, Copy)]
+struct CandidatesWatch {
+    wait: Option<Duration>,
+    interval: Duration,
+    jitter: f64,
+}
+
+/// Settings of the registration of the server (see `ProxyServerBuilder::register`),
+/// which starts after the server is bound.
+#[derive(Debug)]
+struct Registration {
+    settings: ServiceRegistration,
+    interval: Duration,
+    jitter: f64,
+}
+
+/// Makes the Consul client for `settings`.
+///
+/// If `watch` is `Some(_)`, the watcher of the client is pushed to `watchers`.
+fn consul_client(
+    settings: &ConsulSettings,
+    watch: Option<CandidatesWatch>,
+    watchers: &mut Vec<CandidatesWatcher>,
+) -> Arc<ConsulClient> {
+    let mut client = settings.client();
+    if let Some(watch) = watch {
+        watchers.push(client.watch(watch.wait, watch.interval, watch.jitter));
+    }
+    Arc::new(client)
+}
+
 /// Proxy server.
+///
+/// The server future fails only if the server cannot be started (e.g., the address cannot be bound).
+/// Errors after that, such as failures of accepting clients or of querying Consul, are logged and survived.
+/// The future terminates when `Command::Shutdown` is applied to the server.
 pub struct ProxyServer<S> {
     spawner: S,
-    consul: ConsulClient,
-    bind: Option<TcpListenerBind>,
+    consul: Arc<ConsulClient>,
+    configured_consul: ConsulSettings,
+    consul_settings: ConsulSettings,
+    watch: Option<CandidatesWatch>,
+    watchers: Vec<CandidatesWatcher>,
+    router: Option<Arc<dyn Router>>,
+    accept_filter: Option<Arc<dyn AcceptFilter>>,
+    bind: Option<ListenerBind>,
     incoming: Option<Incoming>,
-    service_port: Option<u16>,
-    connect_timeout: Duration,
+    accept_retry: Option<Timeout>,
+    local_addr: Option<SocketAddr>,
+    chroot: Option<PathBuf>,
+    context: Arc<ConnectionContext>,
+    maintenance: Vec<Maintenance>,
+    active_maintenance: Option<usize>,
+    maintenance_timer: Option<Timeout>,
+    allowed_cidrs: Vec<Cidr>,
+    denied_cidrs: Vec<Cidr>,
+    client_rate_limiter: Option<ClientRateLimiter>,
+    accept_rate_limiter: Option<GlobalRateLimiter>,
+    events: Option<EventWatcher>,
+    config: Option<ConfigWatcher>,
+    dynamic: DynamicConfig,
+    draining: bool,
+    excluded: Arc<Exclusions>,
+    stats: Arc<Stats>,
+    stats_publisher: Option<StatsPublisher>,
+    connect: Option<ConnectWatcher>,
+    registration: Option<Registration>,
+    registrar: Option<Registrar>,
+    shutdown: bool,
+    admin: Vec<AdminServer>,
+    replicas: Option<Replicas>,
+    commands: mpsc::Sender<Command>,
+    admin_commands: mpsc::Receiver<Command>,
+    event_hub: EventHub,
 }
-impl<S: Spawn> ProxyServer<S> {
+impl<S: Spawner> ProxyServer<S> {
     /// Makes a new `ProxyServer` for the given service with the default settings.
     ///
     /// This is equivalent to `ProxyServerBuilder::new(service).finish(spawner)`.
     pub fn new(spawner: S, service: &str) -> Self {
         ProxyServerBuilder::new(service).finish(spawner)
     }
-}
-impl<S: Spawn> Future for ProxyServer<S> {
-    type Item = ();
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Async::Ready(Some(listener)) = track!(self.bind.poll().map_err(Error::from))? {
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Returns the address to which the server is bound.
+    ///
+    /// This returns `None` until the server has been bound
+    /// (i.e., the server has been polled at least once and the bind succeeded).
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns a stream of the events which occur on the connections of the server from now on.
+    ///
+    /// This can be called multiple times, and each stream receives all the events.
+    pub fn events(&self) -> ConnectionEvents {
+        self.event_hub.subscribe()
+    }
+
+    /// Returns a handle to apply `Command`s to the server.
+    ///
+    /// The commands are applied in the same way as the ones received via the admin API.
+    pub fn commands(&self) -> CommandSender {
+        CommandSender::new(self.commands.clone())
+    }
+
+    pub(crate) fn event_hub(&self) -> &EventHub {
+        &self.event_hub
+    }
+
+    fn update_maintenance(&mut self) {
+        let now = SystemTime::now();
+        let active = self
+            .maintenance
+            .iter()
+            .position(|m| m.window.is_active(now));
+        if active != self.active_maintenance {
+            let action = if let Some(i) = active {
+                log::info!(
+                    "Entered a maintenance window: {:?}",
+                    self.maintenance[i].window.action()
+                );
+                format!("enter {:?}", self.maintenance[i].window.action())
+            } else {
+                log::info!("Exited the maintenance window");
+                "exit".to_owned()
+            };
+            audit::record(&Caller::MaintenanceWindow, &action, "applied");
+            self.active_maintenance = active;
+        }
+    }
+
+    /// Enters and exits the maintenance windows on schedule, even while no clients arrive.
+    ///
+    /// The windows are checked at every minute boundary (when schedules may match) and when the active one ends.
+    fn poll_maintenance(&mut self) {
+        if self.maintenance.is_empty() {
+            return;
+        }
+        loop {
+            if let Some(ref mut timer) = self.maintenance_timer {
+                if let Async::NotReady = timer.poll().unwrap_or(Async::Ready(())) {
+                    return;
+                }
+            }
+            self.update_maintenance();
+
+            let now = SystemTime::now();
+            let elapsed = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut delay = Duration::from_secs(60 - elapsed.as_secs() % 60)
+                - Duration::from_nanos(u64::from(elapsed.subsec_nanos()));
+            if let Some(end) = self
+                .active_maintenance
+                .and_then(|i| self.maintenance[i].window.active_until(now))
+            {
+                delay = delay.min(end.duration_since(now).unwrap_or_default());
+            }
+            self.maintenance_timer = Some(timer::timeout(delay));
+        }
+    }
+
+    pub(crate) fn take_event_watcher(&mut self) -> Option<EventWatcher> {
+        self.events.take()
+    }
+
+    /// Takes the directory to which the server changes its root directory,
+    /// so that the caller changes it instead (see `ProxyGroup`).
+    pub(crate) fn take_chroot(&mut self) -> Option<PathBuf> {
+        self.chroot.take()
+    }
+
+    /// Polls the listeners of the server, including the ones of the admin API,
+    /// until all of them have been bound.
+    pub(crate) fn poll_bind(&mut self) -> Poll<(), Error> {
+        if let Async::Ready(Some(listener)) = track!(self.bind.poll())? {
             log::info!("Proxy server started");
+            let local_addr = track!(listener.local_addr().map_err(Error::from))?;
+            if let Some(ref r) = self.registration {
+                self.registrar = Some(self.configured_consul.registrar(
+                    &r.settings,
+                    local_addr,
+                    r.interval,
+                    r.jitter,
+                ));
+            }
+            self.local_addr = Some(local_addr);
             self.incoming = Some(listener.incoming());
             self.bind = None;
         }
-        if let Some(ref mut incoming) = self.incoming {
-            if let Async::Ready(Some((client, _addr))) =
-                track!(incoming.poll().map_err(Error::from))?
-            {
-                let server =
-                    SelectServer::new(&self.consul, self.service_port, self.connect_timeout);
-                self.spawner.spawn(
-                    track_err!(client)
-                        .and_then(move |client| {
-                            track_err!(server).and_then(move |(server, _addr)| {
-                                track_err!(ProxyChannel::new(client, server))
-                            })
-                        })
-                        .map_err(move |e| {
-                            log::error!("Proxy channel terminated abnormally: {}", e);
-                        }),
+        let mut bound = self.bind.is_none();
+        for admin in &mut self.admin {
+            bound &= track!(admin.poll_bind())?.is_ready();
+        }
+        if bound {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    pub(crate) fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Shutdown => {
+                self.shutdown = true;
+                self.bind = None;
+                self.incoming = None;
+                if let Some(ref mut registrar) = self.registrar {
+                    registrar.deregister();
+                }
+                log::info!("Shutting down the proxy server");
+                return;
+            }
+            Command::Drain => self.draining = true,
+            Command::Resume => self.draining = false,
+            Command::Reload => {
+                self.draining = false;
+                self.excluded = Arc::new(Exclusions::default());
+                let mut settings = self.configured_consul.clone();
+                if let Some(ref tag) = self.dynamic.tag {
+                    settings = settings.with_tags(slice::from_ref(tag));
+                }
+                self.set_consul_settings(settings);
+            }
+            Command::Eject(node) => {
+                Arc::make_mut(&mut self.excluded).nodes.insert(node);
+            }
+            Command::Readmit(node) => {
+                Arc::make_mut(&mut self.excluded).nodes.remove(&node);
+            }
+            Command::Quarantine(key, value) => {
+                Arc::make_mut(&mut self.excluded)
+                    .node_meta
+                    .insert((key, value));
+            }
+            Command::Release(key, value) => {
+                Arc::make_mut(&mut self.excluded)
+                    .node_meta
+                    .remove(&(key, value));
+            }
+            Command::Retarget(target) => {
+                let settings = self.consul_settings.retarget(&target);
+                self.set_consul_settings(settings);
+            }
+        }
+        log::info!(
+            "Runtime state updated: draining={}, ejected={:?}, quarantined={:?}",
+            self.draining,
+            self.excluded.nodes,
+            self.excluded.node_meta
+        );
+    }
+
+    /// Applies the settings read from the Consul KV store (see `ProxyServerBuilder::config_kv_prefix`).
+    fn apply_dynamic_config(&mut self, config: DynamicConfig) {
+        if config.tag != self.dynamic.tag {
+            let tags = match config.tag {
+                Some(ref tag) => vec![tag.clone()],
+                None => self.configured_consul.tags().to_vec(),
+            };
+            let settings = self.consul_settings.with_tags(&tags);
+            self.set_consul_settings(settings);
+        }
+        if let Some(ref watcher) = self.config {
+            audit::record(
+                &Caller::ConsulKv(watcher.prefix().to_owned()),
+                &format!("config {:?}", config),
+                "applied",
+            );
+        }
+        log::info!("Dynamic settings updated: {:?}", config);
+        self.dynamic = config;
+    }
+
+    /// Switches the Consul queries of new connections to the ones made by `settings`.
+    ///
+    /// All the clients are replaced at once, so a new connection never mixes the old and new settings.
+    fn set_consul_settings(&mut self, settings: ConsulSettings) {
+        // The watchers of the old clients are dropped, which cancels their queries.
+        self.watchers.clear();
+        self.consul = consul_client(&settings, self.watch, &mut self.watchers);
+        for maintenance in &mut self.maintenance {
+            maintenance.consul = maintenance_client(
+                &maintenance.window,
+                &settings,
+                self.watch,
+                &mut self.watchers,
+            );
+        }
+        self.consul_settings = settings;
+        log::info!("Consul query url: {}", self.consul.query_url());
+    }
+
+    fn is_allowed_client(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        if self.denied_cidrs.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allowed_cidrs.is_empty() || self.allowed_cidrs.iter().any(|c| c.contains(ip))
+    }
+
+    fn handle_client(&mut self, client: Connected, addr: SocketAddr) {
+        self.stats.increment_accepted();
+        self.event_hub.emit(addr, || ConnectionEventKind::Accepted);
+        let mut delay = Duration::from_secs(0);
+        if let Some(ref filter) = self.accept_filter {
+            match filter.filter(addr) {
+                Admission::Allow => {}
+                Admission::Delay(d) => delay = d,
+                Admission::Deny => {
+                    log::info!("Refused the client {} by the accept filter", addr);
+                    self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                        reason: "filtered".to_owned(),
+                    });
+                    return;
+                }
+            }
+        }
+        if !self.is_allowed_client(addr) {
+            log::info!("Refused the client {} by the CIDR lists", addr);
+            self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                reason: "denied".to_owned(),
+            });
+            return;
+        }
+        if let Some(ref churn) = self.context.churn {
+            if churn.is_banned(addr.ip()) {
+                log::info!("Refused the banned client {}", addr);
+                self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                    reason: "banned".to_owned(),
+                });
+                return;
+            }
+        }
+        if self.draining {
+            log::info!("Refused the client {} while draining", addr);
+            self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                reason: "draining".to_owned(),
+            });
+            return;
+        }
+        if self.dynamic.maintenance {
+            log::info!("Refused the client {} during maintenance", addr);
+            self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                reason: "maintenance".to_owned(),
+            });
+            return;
+        }
+        self.update_maintenance();
+        let mut consul = self.consul.clone();
+        if let Some(i) = self.active_maintenance {
+            let maintenance = &self.maintenance[i];
+            if *maintenance.window.action() == MaintenanceAction::Drain {
+                log::info!("Refused the client {} during maintenance", addr);
+                self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                    reason: "maintenance".to_owned(),
+                });
+                return;
+            }
+            if let Some(ref c) = maintenance.consul {
+                consul = c.clone();
+            }
+        }
+        let mut destination = Destination::Service(consul);
+        if let Some(ref router) = self.router {
+            let info = ConnectionInfo::new(addr, self.local_addr);
+            match router.route(&info) {
+                Route::Default => {}
+                Route::Service { service, tag } => {
+                    log::debug!(
+                        "Routes the client {} to the service {:?} (tag: {:?})",
+                        addr,
+                        service,
+                        tag
+                    );
+                    let settings = self.consul_settings.with_service(&service, tag.as_deref());
+                    destination = Destination::Service(Arc::new(settings.client()));
+                }
+                Route::Backend(backend) => {
+                    log::debug!("Routes the client {} to the server {}", addr, backend);
+                    destination = Destination::Backend(backend);
+                }
+                Route::Reject(reason) => {
+                    log::info!("Refused the client {} by the router: {}", addr, reason);
+                    self.event_hub
+                        .emit(addr, || ConnectionEventKind::Refused { reason });
+                    return;
+                }
+            }
+        }
+
+        if let Some(ref mut limiter) = self.client_rate_limiter {
+            if let Some(d) = limiter.acquire(addr.ip()) {
+                delay = delay.max(d);
+            } else {
+                log::info!("Refused the client {} exceeding the rate limit", addr);
+                self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                    reason: "rate_limited".to_owned(),
+                });
+                return;
+            }
+        }
+        if let Some(ref mut limiter) = self.accept_rate_limiter {
+            if let Some(d) = limiter.acquire() {
+                delay = delay.max(d);
+            } else {
+                log::info!(
+                    "Refused the client {} exceeding the accept rate limit",
+                    addr
                 );
+                self.event_hub.emit(addr, || ConnectionEventKind::Refused {
+                    reason: "accept_rate_limited".to_owned(),
+                });
+                return;
+            }
+        }
+
+        if let Some(ref fault) = self.context.fault {
+            delay += fault.next_connect_delay();
+        }
+
+        // The rest of the setup (discovery and connect) runs on the fiber of the connection,
+        // so that the accepting fiber is not the bottleneck under high accept rates.
+        let excluded = self.excluded.clone();
+        let context = self.context.clone();
+        let connect_timeout = self
+            .dynamic
+            .connect_timeout
+            .unwrap_or(context.connect_timeout);
+        let setup = futures::lazy(move || {
+            context.serve(client, addr, destination, excluded, connect_timeout)
+        });
+        if delay == Duration::from_secs(0) {
+            self.spawner.spawn_task(Box::new(setup));
+        } else {
+            log::debug!("Delays the client {} for {:?}", addr, delay);
+            self.spawner
+                .spawn_task(Box::new(timer::timeout(delay).then(move |_| setup)));
+        }
+    }
+}
+impl<S: Spawner> Future for ProxyServer<S> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::NotReady = track!(self.poll_bind())? {
+            return Ok(Async::NotReady);
+        }
+        if let Some(dir) = self.chroot.take() {
+            track!(change_root(&dir))?;
+            log::info!("Changed the root directory to {:?}", dir);
+        }
+        if let Some(ref mut publisher) = self.stats_publisher {
+            track!(publisher.poll())?;
+        }
+        if let Some(ref mut watcher) = self.connect {
+            track!(watcher.poll())?;
+        }
+        for watcher in &mut self.watchers {
+            track!(watcher.poll())?;
+        }
+        for admin in &mut self.admin {
+            while let Async::Ready(Some(session)) = track!(admin.poll())? {
+                self.spawner
+                    .spawn_task(Box::new(session.map_err(|e: Error| {
+                        log::warn!("Admin session terminated abnormally: {}", e);
+                    })));
+            }
+        }
+        while let Async::Ready(Some(command)) = self.admin_commands.poll().expect("Never fails") {
+            if let Some(ref replicas) = self.replicas {
+                replicas.forward(&command);
+            }
+            self.handle_command(command);
+        }
+        loop {
+            let command = if let Some(ref mut events) = self.events {
+                track!(events.poll())?
+            } else {
+                Async::NotReady
+            };
+            if let Async::Ready(Some(command)) = command {
+                self.handle_command(command);
+            } else {
+                break;
+            }
+        }
+        loop {
+            let config = if let Some(ref mut watcher) = self.config {
+                track!(watcher.poll())?
+            } else {
+                Async::NotReady
+            };
+            if let Async::Ready(Some(config)) = config {
+                self.apply_dynamic_config(config);
+            } else {
+                break;
+            }
+        }
+        if let Some(ref mut registrar) = self.registrar {
+            if let Async::Ready(()) = track!(registrar.poll())? {
+                self.registrar = None;
+            }
+        }
+        self.poll_maintenance();
+        if self.shutdown {
+            if self.registrar.is_some() {
+                return Ok(Async::NotReady);
+            }
+            log::info!("Proxy server stopped");
+            return Ok(Async::Ready(()));
+        }
+
+        // Accepts until the listener would block, so that the fiber is woken up on new clients.
+        loop {
+            if let Some(ref mut retry) = self.accept_retry {
+                if let Async::NotReady = retry.poll().unwrap_or(Async::Ready(())) {
+                    break;
+                }
+            }
+            self.accept_retry = None;
+            let accepted = if let Some(ref mut incoming) = self.incoming {
+                incoming.poll()
+            } else {
+                Ok(Async::NotReady)
+            };
+            match accepted {
+                Err(e) => {
+                    // e.g., too many open files
+                    log::warn!(
+                        "Cannot accept a client (retries after {:?}): {}",
+                        ACCEPT_RETRY_DELAY,
+                        e
+                    );
+                    self.accept_retry = Some(timer::timeout(ACCEPT_RETRY_DELAY));
+                }
+                Ok(Async::Ready(Some((client, addr)))) => self.handle_client(client, addr),
+                Ok(_) => break,
             }
         }
         Ok(Async::NotReady)
     }
 }
 
+/// Settings and states shared by the connections of a proxy server.
+#[derive(Debug)]
+struct ConnectionContext {
+    service_port: ServicePort,
+    connect_timeout: Duration,
+    upstream_retry: UpstreamRetryPolicy,
+    fallback_servers: Vec<SocketAddr>,
+    balancer: Arc<Balancer>,
+    outliers: Option<Arc<OutlierDetector>>,
+    slots: Option<Arc<ServerSlots>>,
+    server_queue_timeout: Option<Duration>,
+    buffer_pool: BufferPool,
+    cork_delay: Option<Duration>,
+    bandwidth_limit: Option<BandwidthLimit>,
+    client_middlewares: Vec<Arc<dyn Middleware>>,
+    server_middlewares: Vec<Arc<dyn Middleware>>,
+
+    /// The Connect certificates, and the service of the servers specified by `Route::Backend`.
+    connect: Option<(Arc<ConnectCerts>, ConnectService)>,
+    churn: Option<ChurnDetector>,
+    fault: Option<FaultInjection>,
+    stats: Arc<Stats>,
+    event_hub: EventHub,
+}
+impl ConnectionContext {
+    /// Selects a server for the client, and then relays bytes between them.
+    fn serve(
+        self: Arc<Self>,
+        client: Connected,
+        addr: SocketAddr,
+        destination: Destination,
+        excluded: Arc<Exclusions>,
+        connect_timeout: Duration,
+    ) -> impl Future<Item = (), Error = ()> {
+        let connect = self.connect.as_ref().map(|(certs, backend_service)| {
+            let service = match destination {
+                Destination::Service(ref consul) => consul.connect_service(),
+                Destination::Backend(_) => None,
+            };
+            let service = service.unwrap_or(backend_service).clone();
+            ConnectMiddleware::new(certs.clone(), service)
+        });
+        let server = SelectServer::new(&self, destination, excluded, addr, connect_timeout);
+        let error_event_hub = self.event_hub.clone();
+        let context = Arc::clone(&self);
+        let panic_stats = self.stats.clone();
+        let served_at = Instant::now();
+        let channel = track_err!(client).and_then(move |client| {
+            track_err!(server).and_then(move |(server, node, backend, slot)| {
+                let active = ActiveConnection::new(self.stats.clone(), backend);
+                self.event_hub
+                    .emit(addr, || ConnectionEventKind::Connected { backend, node });
+                let _ = client.set_nodelay(true);
+                let _ = server.with_inner(|socket| socket.set_nodelay(true));
+                let channel = self.make_channel(client, addr, server, backend, connect);
+                track_err!(futures::done(channel).and_then(|c| c)).map(move |closed| {
+                    active.finish(&closed);
+                    drop(slot);
+                    log::info!(
+                        "Connection closed: client={}, server={}, upstream_bytes={}, downstream_bytes={}, duration={:?}, reason={}",
+                        addr,
+                        backend,
+                        closed.upstream_bytes,
+                        closed.downstream_bytes,
+                        closed.duration,
+                        closed.reason
+                    );
+                    self.event_hub
+                        .emit(addr, || ConnectionEventKind::Closed {
+                            backend,
+                            duration_ms: closed.duration.as_millis() as u64,
+                            upstream_bytes: closed.upstream_bytes,
+                            downstream_bytes: closed.downstream_bytes,
+                            reason: closed.reason.to_string(),
+                        });
+                })
+            })
+        });
+
+        // A panic is isolated to the connection: its sockets are closed while unwinding,
+        // and the worker thread keeps serving the other connections.
+        AssertUnwindSafe(channel)
+            .catch_unwind()
+            .then(move |result| match result {
+                Ok(result) => result,
+                Err(panic) => {
+                    panic_stats.increment_panicked();
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| (*s).to_owned())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_owned());
+                    Err(track!(
+                        Error::from(ErrorKind::Other.cause(format!("Panicked: {}", message))),
+                        "client={}",
+                        addr
+                    ))
+                }
+            })
+            .map_err(move |e| {
+                log::error!("Proxy channel terminated abnormally: {}", e);
+                error_event_hub.emit(addr, || ConnectionEventKind::Failed {
+                    reason: e.to_string(),
+                });
+            })
+            .then(move |result| {
+                context.record_close(addr, served_at.elapsed());
+                result
+            })
+    }
+
+    /// Makes a channel between `client` and `server`, wrapping them by the middlewares if any.
+    ///
+    /// In the Connect mode, `server` is wrapped by `connect` before the other middlewares.
+    fn make_channel(
+        &self,
+        client: ClientStream,
+        client_addr: SocketAddr,
+        server: TcpStream,
+        server_addr: SocketAddr,
+        connect: Option<ConnectMiddleware>,
+    ) -> Result<Either<ProxyChannel<ClientStream>, ProxyChannel<BoxEndpoint, BoxEndpoint>>> {
+        let fault = self.fault.as_ref().filter(|f| f.is_byte_level());
+        if self.client_middlewares.is_empty()
+            && self.server_middlewares.is_empty()
+            && connect.is_none()
+            && fault.is_none()
+        {
+            return Ok(Either::A(ProxyChannel::new(
+                client,
+                server,
+                &self.buffer_pool,
+                self.cork_delay,
+                self.bandwidth_limit.as_ref(),
+            )));
+        }
+        let client = track!(middleware::wrap_all(
+            client,
+            client_addr,
+            &self.client_middlewares
+        ))?;
+        let server: BoxEndpoint = if let Some(connect) = connect {
+            track!(connect.wrap(Box::new(server), server_addr))?
+        } else {
+            Box::new(server)
+        };
+        let server = track!(middleware::wrap_all(
+            server,
+            server_addr,
+            &self.server_middlewares
+        ))?;
+        let (client, server) = if let Some(fault) = fault {
+            track!(fault.inject(client, server))?
+        } else {
+            (client, server)
+        };
+        Ok(Either::B(ProxyChannel::new(
+            client,
+            server,
+            &self.buffer_pool,
+            self.cork_delay,
+            self.bandwidth_limit.as_ref(),
+        )))
+    }
+
+    fn record_close(&self, addr: SocketAddr, lifetime: Duration) {
+        if let Some(ref churn) = self.churn {
+            if churn.record_close(addr.ip(), lifetime) {
+                log::warn!(
+                    "Banned the client {} for {:?} due to connection churn",
+                    addr.ip(),
+                    churn.limit().ban_duration()
+                );
+                self.stats.increment_churn_bans();
+            }
+        }
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "localhost".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(unix)]
+pub(crate) fn change_root(dir: &Path) -> Result<()> {
+    track!(std::os::unix::fs::chroot(dir).map_err(Error::from))?;
+    track!(std::env::set_current_dir("/").map_err(Error::from))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn change_root(dir: &Path) -> Result<()> {
+    track_panic!(
+        ErrorKind::Config,
+        "chroot is not supported on this platform: {:?}",
+        dir
+    );
+}
+
+/// How the port number of a service server is determined.
+#[derive(Clone)]
+pub(crate) enum ServicePort {
+    /// The registered `ServicePort` is used.
+    Registered,
+
+    /// The given port is used.
+    Fixed(u16),
+
+    /// The port is determined by the given function.
+    Resolve(Arc<dyn Fn(&ServiceNode) -> u16 + Send + Sync>),
+}
+impl ServicePort {
+    pub(crate) fn socket_addr(&self, server: &ServiceNode) -> SocketAddr {
+        match *self {
+            ServicePort::Registered => server.socket_addr(None),
+            ServicePort::Fixed(port) => server.socket_addr(Some(port)),
+            ServicePort::Resolve(ref resolve) => server.socket_addr(Some(resolve(server))),
+        }
+    }
+}
+impl fmt::Debug for ServicePort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServicePort::Registered => write!(f, "Registered"),
+            ServicePort::Fixed(port) => write!(f, "Fixed({})", port),
+            ServicePort::Resolve(_) => write!(f, "Resolve(_)"),
+        }
+    }
+}
+
+/// Where the server for a connection is selected from.
+#[derive(Debug)]
+enum Destination {
+    /// The servers of a service, which are queried to Consul.
+    Service(Arc<ConsulClient>),
+
+    /// The server specified by `Route::Backend`.
+    Backend(SocketAddr),
+}
+
 struct SelectServer {
-    collect_candidates: Option<AsyncResult<Vec<ServiceNode>>>,
+    collect_candidates: Option<FindCandidates>,
+    failover: Option<Arc<ConsulClient>>,
+    requery: Option<Arc<ConsulClient>>,
+    excluded: Arc<Exclusions>,
     connect: Option<TimeoutAfter<Connect>>,
+    backoff: Option<Timeout>,
     candidates: Vec<ServiceNode>,
-    server: Option<ServiceNode>,
-    service_port: Option<u16>,
+    fallback: Vec<SocketAddr>,
+    balancer: Arc<Balancer>,
+    outliers: Option<Arc<OutlierDetector>>,
+    slots: Option<Arc<ServerSlots>>,
+    slot: Option<ServerSlot>,
+    saturated: Vec<ServiceNode>,
+    queue_timeout: Option<Duration>,
+    queue_deadline: Option<Instant>,
+    server: Option<(ServiceNode, SocketAddr)>,
+    connect_started: Instant,
+    attempts: ConnectAttempts,
+    service_port: ServicePort,
     connect_timeout: Duration,
+    retry: UpstreamRetryPolicy,
+    client: SocketAddr,
+    event_hub: EventHub,
 }
 impl SelectServer {
-    fn new(consul: &ConsulClient, service_port: Option<u16>, connect_timeout: Duration) -> Self {
-        SelectServer {
-            collect_candidates: Some(consul.find_candidates()),
+    fn new(
+        context: &ConnectionContext,
+        destination: Destination,
+        excluded: Arc<Exclusions>,
+        client: SocketAddr,
+        connect_timeout: Duration,
+    ) -> Self {
+        let service_port = context.service_port.clone();
+        let retry = context.upstream_retry.clone();
+        let mut failover = None;
+        let mut requery = None;
+        let mut fallback = context.fallback_servers.clone();
+        let mut cached = None;
+        let (collect_candidates, candidates, service_port) = match destination {
+            Destination::Service(consul) => {
+                failover = consul.failover();
+                if retry.requery() {
+                    requery = Some(consul.clone());
+                }
+                if let Some(candidates) = consul.cached_candidates(&excluded) {
+                    log::debug!("Candidates (cached): {:?}", candidates);
+                    cached = Some(candidates);
+                    (None, Vec::new(), service_port)
+                } else {
+                    (
+                        Some(consul.find_candidates(excluded.clone())),
+                        Vec::new(),
+                        service_port,
+                    )
+                }
+            }
+            Destination::Backend(addr) => {
+                fallback.clear();
+                let node = ServiceNode::new(String::new(), addr);
+                (None, vec![node], ServicePort::Registered)
+            }
+        };
+        let mut server = SelectServer {
+            collect_candidates,
+            failover,
+            requery,
+            excluded,
             connect: None,
-            candidates: Vec::new(),
+            backoff: None,
+            candidates,
+            fallback,
+            balancer: context.balancer.clone(),
+            outliers: context.outliers.clone(),
+            slots: context.slots.clone(),
+            slot: None,
+            saturated: Vec::new(),
+            queue_timeout: context.server_queue_timeout,
+            queue_deadline: None,
             server: None,
+            connect_started: Instant::now(),
+            attempts: ConnectAttempts::default(),
             service_port,
             connect_timeout,
+            retry,
+            client,
+            event_hub: context.event_hub.clone(),
+        };
+        if let Some(candidates) = cached {
+            server.set_candidates(candidates);
         }
+        server
+    }
+
+    /// Sets the candidates discovered by Consul, ordered by the load balancing strategy
+    /// (see `ProxyServerBuilder::load_balancing`) and with the ejected ones last (see `ProxyServerBuilder::outlier_detection`).
+    fn set_candidates(&mut self, mut candidates: Vec<ServiceNode>) {
+        let service_port = &self.service_port;
+        self.balancer
+            .order(&mut candidates, |node| service_port.socket_addr(node));
+        if let Some(ref outliers) = self.outliers {
+            let (ejected, mut admitted): (Vec<_>, Vec<_>) = candidates
+                .into_iter()
+                .partition(|node| outliers.is_ejected(service_port.socket_addr(node)));
+            if !ejected.is_empty() {
+                log::debug!("Ejected candidates: {:?}", ejected);
+            }
+            admitted.extend(ejected);
+            candidates = admitted;
+        }
+        candidates.reverse();
+        self.candidates = candidates;
+    }
+
+    /// Starts querying the candidates in the next datacenter, if any (see `ConsulSettings::dc_failover`).
+    fn fail_over(&mut self) -> bool {
+        if let Some(consul) = self.failover.take() {
+            log::info!("Fails over to {}", consul.query_url());
+            self.failover = consul.failover();
+            self.collect_candidates = Some(consul.find_candidates(self.excluded.clone()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Starts querying the candidates once more, if enabled (see `UpstreamRetryPolicy::requery`).
+    fn requery(&mut self) -> bool {
+        if let Some(consul) = self.requery.take() {
+            log::info!(
+                "Queries the candidates again after {} failed attempts",
+                self.attempts.attempts().len()
+            );
+            self.failover = consul.failover();
+            self.collect_candidates = Some(consul.find_candidates(self.excluded.clone()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Tries the candidates at capacity again after a while, or gives up if `ProxyServerBuilder::server_queue_timeout` has expired.
+    fn wait_for_slot(&mut self) -> Poll<<Self as Future>::Item, Error> {
+        let now = Instant::now();
+        let timeout = self.queue_timeout.unwrap_or_default();
+        let deadline = *self.queue_deadline.get_or_insert(now + timeout);
+        if deadline <= now {
+            log::warn!(
+                "All the {} candidate servers are at capacity",
+                self.saturated.len()
+            );
+            for node in mem::take(&mut self.saturated) {
+                let addr = self.service_port.socket_addr(&node);
+                self.attempts.push(ConnectAttempt {
+                    node: node.node,
+                    addr,
+                    error: ErrorKind::Other
+                        .cause("Too many connections to the server")
+                        .into(),
+                });
+            }
+            let attempts = mem::take(&mut self.attempts);
+            return Err(track!(Error::from(ErrorKind::NoCandidates.cause(attempts))));
+        }
+        self.candidates = mem::take(&mut self.saturated);
+        self.candidates.reverse();
+        self.backoff = Some(timer::timeout(SLOT_POLL_INTERVAL.min(deadline - now)));
+        self.poll()
+    }
+
+    /// Replaces the candidates with the fallback servers, if any (see `ProxyServerBuilder::fallback_servers`).
+    ///
+    /// The fallback servers are used at most once per connection.
+    fn fall_back(&mut self) -> bool {
+        if self.fallback.is_empty() {
+            return false;
+        }
+        log::warn!("Falls back to the static servers {:?}", self.fallback);
+        self.candidates = mem::take(&mut self.fallback)
+            .into_iter()
+            .rev()
+            .map(|addr| ServiceNode::new(String::new(), addr))
+            .collect();
+        self.service_port = ServicePort::Registered;
+        self.collect_candidates = None;
+        self.requery = None;
+        true
     }
 }
 impl Future for SelectServer {
-    type Item = (TcpStream, SocketAddr);
+    type Item = (TcpStream, ServiceNode, SocketAddr, Option<ServerSlot>);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Async::Ready(Some(candidates)) = track!(self.collect_candidates.poll())? {
-            log::debug!("Candidates: {:?}", candidates);
-            self.candidates = candidates;
-            self.candidates.reverse();
-            self.collect_candidates = None;
+        match self.collect_candidates.poll() {
+            Err(e) => {
+                if self.failover.is_none() && self.fallback.is_empty() {
+                    return Err(track!(e));
+                }
+                log::warn!("Cannot find candidates: {}", e);
+                if !self.fail_over() {
+                    self.fall_back();
+                }
+                return self.poll();
+            }
+            Ok(Async::Ready(Some(candidates))) => {
+                log::debug!("Candidates: {:?}", candidates);
+                self.set_candidates(candidates);
+                self.collect_candidates = None;
+            }
+            _ => {}
+        }
+        if let Some(ref mut backoff) = self.backoff {
+            if let Async::NotReady = backoff.poll().unwrap_or(Async::Ready(())) {
+                return Ok(Async::NotReady);
+            }
+            self.backoff = None;
         }
         if self.collect_candidates.is_none() && self.connect.is_none() {
-            let candidate = track_assert_some!(
-                self.candidates.pop(),
-                Failed,
-                "No available service servers"
+            let exhausted = self
+                .retry
+                .max_attempts()
+                .is_some_and(|n| self.attempts.attempts().len() >= n as usize);
+            let candidate = if exhausted {
+                log::warn!(
+                    "Gave up connecting after {} attempts",
+                    self.attempts.attempts().len()
+                );
+                let attempts = mem::take(&mut self.attempts);
+                return Err(track!(Error::from(ErrorKind::NoCandidates.cause(attempts))));
+            } else if let Some(candidate) = self.candidates.pop() {
+                candidate
+            } else if self.fail_over() {
+                return self.poll();
+            } else if self.attempts.attempts().is_empty()
+                && self.saturated.is_empty()
+                && self.fall_back()
+            {
+                // No nodes have been discovered.
+                return self.poll();
+            } else if !self.saturated.is_empty() {
+                return self.wait_for_slot();
+            } else if self.requery() {
+                return self.poll();
+            } else {
+                let attempts = mem::take(&mut self.attempts);
+                return Err(track!(Error::from(ErrorKind::NoCandidates.cause(attempts))));
+            };
+            let addr = self.service_port.socket_addr(&candidate);
+            log::debug!(
+                "Next candidate server is {} (node: {})",
+                addr,
+                candidate.node
             );
-            let addr = candidate.socket_addr(self.service_port);
-            log::debug!("Next candidate server is {}", addr);
+            if let Some(ref slots) = self.slots {
+                if let Some(slot) = slots.acquire(addr) {
+                    self.slot = Some(slot);
+                } else {
+                    log::debug!("The server {} is at capacity", addr);
+                    self.saturated.push(candidate);
+                    return self.poll();
+                }
+            }
+            self.event_hub
+                .emit(self.client, || ConnectionEventKind::CandidateSelected {
+                    backend: addr,
+                    node: candidate.node.clone(),
+                });
             self.connect = Some(TcpStream::connect(addr).timeout_after(self.connect_timeout));
-            self.server = Some(candidate);
+            self.server = Some((candidate, addr));
+            self.connect_started = Instant::now();
         }
         match self.connect.poll() {
             Err(e) => {
-                let server = self.server.take().expect("Never fails");
-                log::warn!(
-                    "Cannot connect to the server {}; {}",
-                    server.socket_addr(self.service_port),
-                    e.map(|e| e.to_string())
-                        .unwrap_or_else(|| "Connection timeout".to_owned())
-                );
+                let (server, addr) = self.server.take().expect("Never fails");
+                let e = connect_error(e);
+                log::warn!("Cannot connect to the server {}; {}", addr, e);
                 self.connect = None;
+                self.slot = None;
+                if !e.kind().is_retryable() {
+                    return Err(track!(e, "server={}", addr));
+                }
+                self.balancer.record_connect(addr, self.connect_timeout);
+                if let Some(ref outliers) = self.outliers {
+                    outliers.record_failure(addr);
+                }
+                self.attempts.push(ConnectAttempt {
+                    node: server.node,
+                    addr,
+                    error: e,
+                });
+                let tried = self.attempts.attempts().len();
+                let remaining = !self.candidates.is_empty()
+                    || self.failover.is_some()
+                    || self.requery.is_some();
+                if remaining && self.retry.max_attempts().is_none_or(|n| tried < n as usize) {
+                    self.backoff = self.retry.backoff(tried as u32 - 1).map(timer::timeout);
+                }
                 self.poll()
             }
             Ok(Async::Ready(Some(stream))) => {
-                let server = self.server.as_ref().expect("Never fails");
-                let addr = server.socket_addr(self.service_port);
-                log::info!("Connected to the server {}", addr);
-                Ok(Async::Ready((stream, addr)))
+                let (node, addr) = self.server.take().expect("Never fails");
+                self.balancer
+                    .record_connect(addr, self.connect_started.elapsed());
+                if let Some(ref outliers) = self.outliers {
+                    outliers.record_success(addr);
+                }
+                log::info!(
+                    "Connected to the server {} (node: {}, status: {:?})",
+                    addr,
+                    node.node,
+                    node.status
+                );
+                Ok(Async::Ready((stream, node, addr, self.slot.take())))
             }
             _ => Ok(Async::NotReady),
         }
     }
 }
+
+/// Converts an error of `TimeoutAfter<Connect>` (`None` means a timeout).
+pub(crate) fn connect_error(e: Option<io::Error>) -> Error {
+    match e {
+        None => ErrorKind::ConnectTimeout.cause("Connection timeout").into(),
+        Some(e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused => ErrorKind::ConnectRefused.cause(e).into(),
+            io::ErrorKind::TimedOut => ErrorKind::ConnectTimeout.cause(e).into(),
+            _ => Error::from(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::thread;
+
+    use super::*;
+    use event::ConnectionEvent;
+    use testing::{self, EchoServer, InMemoryConsul};
+
+    #[test]
+    fn drain_is_forwarded_to_replicas() {
+        let echo = track_try_unwrap!(EchoServer::start());
+        let consul = InMemoryConsul::new();
+        consul.register("foo", "node0", echo.addr());
+
+        let mut builder = ProxyServerBuilder::new("foo");
+        let original = track_try_unwrap!(testing::spawn_proxy(&mut builder, &consul));
+        let replica = track_try_unwrap!(testing::spawn_proxy(&mut builder.replica(), &consul));
+        let mut events = replica.events();
+        track_try_unwrap!(original.commands().send(Command::Drain));
+
+        // The command is forwarded asynchronously.
+        let drained = (0..100).any(|_| {
+            let client = TcpStream::connect(replica.local_addr()).unwrap();
+            let addr = client.local_addr().unwrap();
+            loop {
+                let event = next_event(&mut events);
+                match event.kind {
+                    _ if event.client != addr => {}
+                    ConnectionEventKind::Accepted => {}
+                    ConnectionEventKind::Refused { ref reason } => return reason == "draining",
+                    _ => {
+                        thread::sleep(Duration::from_millis(10));
+                        return false;
+                    }
+                }
+            }
+        });
+        assert!(drained);
+    }
+
+    // `ConnectionEvents` only notifies the fibers waiting on it, so it is polled here.
+    fn next_event(events: &mut ConnectionEvents) -> ConnectionEvent {
+        loop {
+            if let Async::Ready(event) = track_try_unwrap!(events.poll()) {
+                return event.expect("Never fails");
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn relayed_bytes_are_released_from_budget() {
+        use std::io::{Read, Write};
+
+        // With the `io-uring` feature, this exercises the relay by `io_uring(7)` (if the kernel supports it).
+        let echo = track_try_unwrap!(EchoServer::start());
+        let consul = InMemoryConsul::new();
+        consul.register("foo", "node0", echo.addr());
+
+        let budget = MemoryBudget::new(64 * 1024);
+        let mut builder = ProxyServerBuilder::new("foo");
+        builder.memory_budget(budget.clone());
+        let proxy = track_try_unwrap!(testing::spawn_proxy(&mut builder, &consul));
+
+        let mut client = TcpStream::connect(proxy.local_addr()).unwrap();
+        let data = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let mut writer = client.try_clone().unwrap();
+        let sent = data.clone();
+        let handle = thread::spawn(move || writer.write_all(&sent).unwrap());
+        let mut received = vec![0; data.len()];
+        client.read_exact(&mut received).unwrap();
+        handle.join().unwrap();
+        assert!(received == data);
+
+        client.write_all(&data[..1024]).unwrap();
+        drop(client);
+        let released = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            budget.used() == 0
+        });
+        assert!(released);
+    }
+}
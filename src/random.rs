@@ -0,0 +1,43 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+fn seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u64(d.as_secs());
+        hasher.write_u32(d.subsec_nanos());
+    }
+    hasher.finish() | 1
+}
+
+/// Returns a pseudo random number generated by xorshift64*.
+///
+/// This is not cryptographically secure.
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// Returns a pseudo random number in the range `[0.0, 1.0)`.
+pub fn next_f64() -> f64 {
+    (next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Returns `duration` randomly scaled within `±fraction` of it.
+pub fn jitter(duration: Duration, fraction: f64) -> Duration {
+    let fraction = fraction.clamp(0.0, 1.0);
+    let scale = 1.0 + fraction * (next_f64() * 2.0 - 1.0);
+    Duration::from_secs_f64(duration.as_secs_f64() * scale)
+}
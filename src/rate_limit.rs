@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, Result};
+
+/// Settings of a token-bucket rate limiter for new connections.
+///
+/// Connections exceeding the limit are delayed until tokens become available
+/// if the wait is at most `max_delay`, otherwise they are refused.
+///
+/// This is (de)serialized as a table which has the `rate`, `burst` (default: `1`)
+/// and `max_delay_ms` (default: `0`) fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawRateLimit", into = "RawRateLimit")]
+pub struct RateLimit {
+    rate: f64,
+    burst: u32,
+    max_delay: Duration,
+}
+impl RateLimit {
+    /// Makes a new `RateLimit` which allows `rate` connections per second on average
+    /// and bursts of up to `burst` connections.
+    pub fn new(rate: f64, burst: u32, max_delay: Duration) -> Result<Self> {
+        track_assert!(
+            rate > 0.0,
+            ErrorKind::Config,
+            "Rate must be positive: {}",
+            rate
+        );
+        track_assert!(burst > 0, ErrorKind::Config, "Burst must be positive");
+        Ok(RateLimit {
+            rate,
+            burst,
+            max_delay,
+        })
+    }
+
+    /// Returns the average number of connections allowed per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Returns the maximum number of connections allowed in a burst.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Returns the maximum delay applied to a connection exceeding the limit.
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+}
+impl TryFrom<RawRateLimit> for RateLimit {
+    type Error = Error;
+    fn try_from(f: RawRateLimit) -> Result<Self> {
+        track!(RateLimit::new(
+            f.rate,
+            f.burst,
+            Duration::from_millis(f.max_delay_ms)
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    rate: f64,
+
+    #[serde(default = "default_burst")]
+    burst: u32,
+
+    #[serde(default)]
+    max_delay_ms: u64,
+}
+impl From<RateLimit> for RawRateLimit {
+    fn from(f: RateLimit) -> Self {
+        RawRateLimit {
+            rate: f.rate,
+            burst: f.burst,
+            max_delay_ms: f.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    last_time: Instant,
+}
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(limit.burst),
+            last_time: now,
+        }
+    }
+
+    /// Takes a token, and returns how long the connection should be delayed.
+    ///
+    /// If the delay would exceed `limit.max_delay()`, no token is taken and `None` is returned.
+    /// Delayed connections reserve future tokens, so they are queued in arrival order.
+    pub fn acquire(&mut self, limit: &RateLimit, now: Instant) -> Option<Duration> {
+        self.take(limit, 1.0, now)
+    }
+
+    /// Takes `amount` tokens in the same way as `acquire`.
+    pub fn take(&mut self, limit: &RateLimit, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(limit, now);
+        let wait = if self.tokens >= amount {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((amount - self.tokens) / limit.rate)
+        };
+        if wait > limit.max_delay {
+            return None;
+        }
+        self.tokens -= amount;
+        Some(wait)
+    }
+
+    /// Returns `amount` tokens taken but not used.
+    pub fn give_back(&mut self, amount: f64) {
+        self.tokens += amount;
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_time).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(f64::from(limit.burst));
+        self.last_time = now;
+    }
+
+    fn is_full(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= f64::from(limit.burst)
+    }
+}
+
+/// A rate limiter which has a single token bucket shared by all clients.
+#[derive(Debug)]
+pub(crate) struct GlobalRateLimiter {
+    limit: RateLimit,
+    bucket: TokenBucket,
+}
+impl GlobalRateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        let bucket = TokenBucket::new(&limit, Instant::now());
+        GlobalRateLimiter { limit, bucket }
+    }
+
+    /// See `TokenBucket::acquire`.
+    pub fn acquire(&mut self) -> Option<Duration> {
+        self.bucket.acquire(&self.limit, Instant::now())
+    }
+}
+
+/// A rate limiter which has a token bucket for each client IP address.
+#[derive(Debug)]
+pub(crate) struct ClientRateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    prune_threshold: usize,
+}
+impl ClientRateLimiter {
+    /// Minimum number of buckets which triggers pruning.
+    const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+    pub fn new(limit: RateLimit) -> Self {
+        ClientRateLimiter {
+            limit,
+            buckets: HashMap::new(),
+            prune_threshold: Self::MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// See `TokenBucket::acquire`.
+    pub fn acquire(&mut self, client: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        if self.buckets.len() >= self.prune_threshold {
+            self.prune(now);
+        }
+        let limit = &self.limit;
+        self.buckets
+            .entry(client)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .acquire(limit, now)
+    }
+
+    /// Removes the buckets which have been refilled (i.e., equivalent to new ones).
+    fn prune(&mut self, now: Instant) {
+        let limit = &self.limit;
+        self.buckets.retain(|_, b| !b.is_full(limit, now));
+        self.prune_threshold = (self.buckets.len() * 2).max(Self::MIN_PRUNE_THRESHOLD);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use {Error, ErrorKind, Result};
+
+/// A health check of the service registered by `ServiceRegistration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationCheck {
+    /// A TTL check which the proxy server keeps passing at half of the given TTL.
+    Ttl(Duration),
+
+    /// A TCP check which the consul agent makes to the listening address at the given interval.
+    Tcp(Duration),
+}
+impl RegistrationCheck {
+    fn interval(&self) -> Duration {
+        match *self {
+            RegistrationCheck::Ttl(d) | RegistrationCheck::Tcp(d) => d,
+        }
+    }
+}
+
+/// Settings of the registration of a proxy server itself as a Consul service.
+///
+/// The service is registered via the [Register Service] API of the local consul agent
+/// after the server is bound, and is deregistered when the server is shut down by `Command::Shutdown`.
+///
+/// This is (de)serialized as a table which has the `name`, `id`, `tags`, `address`,
+/// `check` (`"ttl"` or `"tcp"`, default: `"ttl"`) and `check_interval_secs` (default: `10`) fields.
+///
+/// [Register Service]: https://www.consul.io/api/agent/service.html#register-service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawServiceRegistration", into = "RawServiceRegistration")]
+pub struct ServiceRegistration {
+    name: String,
+    id: Option<String>,
+    tags: Vec<String>,
+    address: Option<IpAddr>,
+    check: RegistrationCheck,
+}
+impl ServiceRegistration {
+    /// The default TTL (or interval) of the health check.
+    pub const DEFAULT_CHECK_INTERVAL_SECS: u64 = 10;
+
+    /// Makes a new `ServiceRegistration` instance for the service `name`.
+    ///
+    /// The check is `RegistrationCheck::Ttl` of `DEFAULT_CHECK_INTERVAL_SECS` by default.
+    pub fn new(name: &str) -> Self {
+        ServiceRegistration {
+            name: name.to_owned(),
+            id: None,
+            tags: Vec::new(),
+            address: None,
+            check: RegistrationCheck::Ttl(Duration::from_secs(Self::DEFAULT_CHECK_INTERVAL_SECS)),
+        }
+    }
+
+    /// Sets the ID of the service instance.
+    ///
+    /// The default value is `<name>-<port>`.
+    pub fn id(&mut self, id: &str) -> &mut Self {
+        self.id = Some(id.to_owned());
+        self
+    }
+
+    /// Adds a tag of the service.
+    pub fn add_tag(&mut self, tag: &str) -> &mut Self {
+        self.tags.push(tag.to_owned());
+        self
+    }
+
+    /// Sets the address of the service.
+    ///
+    /// If not set, the bind address of the server is registered unless it is unspecified (e.g., `0.0.0.0`),
+    /// in which case the consul agent uses the address of its node.
+    pub fn address(&mut self, address: IpAddr) -> &mut Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the health check of the service.
+    pub fn check(&mut self, check: RegistrationCheck) -> &mut Self {
+        self.check = check;
+        self
+    }
+
+    /// Returns the name of the service.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the tags of the service.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns the health check of the service.
+    pub fn health_check(&self) -> RegistrationCheck {
+        self.check
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        track_assert!(
+            !self.name.is_empty(),
+            ErrorKind::Config,
+            "Empty registered service name"
+        );
+        track_assert!(
+            self.id.as_ref().is_none_or(|id| !id.is_empty()),
+            ErrorKind::Config,
+            "Empty registered service ID"
+        );
+        track_assert_ne!(
+            self.check.interval(),
+            Duration::from_secs(0),
+            ErrorKind::Config,
+            "Zero check interval"
+        );
+        Ok(())
+    }
+
+    /// Returns the ID of the service instance listening on `local_addr`.
+    pub(crate) fn service_id(&self, local_addr: SocketAddr) -> String {
+        self.id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", self.name, local_addr.port()))
+    }
+
+    /// Returns the address registered for the service instance listening on `local_addr`.
+    pub(crate) fn service_address(&self, local_addr: SocketAddr) -> Option<IpAddr> {
+        self.address.or_else(|| {
+            if local_addr.ip().is_unspecified() {
+                None
+            } else {
+                Some(local_addr.ip())
+            }
+        })
+    }
+}
+impl TryFrom<RawServiceRegistration> for ServiceRegistration {
+    type Error = Error;
+    fn try_from(f: RawServiceRegistration) -> Result<Self> {
+        let interval = Duration::from_secs(f.check_interval_secs);
+        let registration = ServiceRegistration {
+            name: f.name,
+            id: f.id,
+            tags: f.tags,
+            address: f.address,
+            check: match f.check {
+                RawCheckKind::Ttl => RegistrationCheck::Ttl(interval),
+                RawCheckKind::Tcp => RegistrationCheck::Tcp(interval),
+            },
+        };
+        track!(registration.validate())?;
+        Ok(registration)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServiceRegistration {
+    name: String,
+    id: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    address: Option<IpAddr>,
+    #[serde(default = "default_check")]
+    check: RawCheckKind,
+    #[serde(default = "default_check_interval_secs")]
+    check_interval_secs: u64,
+}
+impl From<ServiceRegistration> for RawServiceRegistration {
+    fn from(f: ServiceRegistration) -> Self {
+        let check = match f.check {
+            RegistrationCheck::Ttl(_) => RawCheckKind::Ttl,
+            RegistrationCheck::Tcp(_) => RawCheckKind::Tcp,
+        };
+        RawServiceRegistration {
+            name: f.name,
+            id: f.id,
+            tags: f.tags,
+            address: f.address,
+            check,
+            check_interval_secs: f.check.interval().as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RawCheckKind {
+    Ttl,
+    Tcp,
+}
+
+fn default_check() -> RawCheckKind {
+    RawCheckKind::Ttl
+}
+
+fn default_check_interval_secs() -> u64 {
+    ServiceRegistration::DEFAULT_CHECK_INTERVAL_SECS
+}
@@ -0,0 +1,320 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http::AgentEndpoint;
+use {Error, ErrorKind, Result};
+
+/// The address of the consul agent, which is a socket address, a pair of a hostname and a port,
+/// or the path of a Unix domain socket.
+///
+/// The textual representation is `<ip>:<port>`, `<hostname>:<port>` or `unix://<path>`
+/// (an IPv6 address is enclosed in brackets, e.g., `[::1]:8500`).
+/// This is (de)serialized as the textual representation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConsulAddr {
+    /// A socket address.
+    Socket(SocketAddr),
+
+    /// A hostname and a port, where the hostname is resolved by DNS (see `ConsulSettings::resolve_interval`).
+    Host(String, u16),
+
+    /// The path of a Unix domain socket on which the agent listens (e.g., `-http-addr=unix:///var/run/consul.sock`).
+    Unix(PathBuf),
+}
+impl From<SocketAddr> for ConsulAddr {
+    fn from(f: SocketAddr) -> Self {
+        ConsulAddr::Socket(f)
+    }
+}
+impl FromStr for ConsulAddr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse() {
+            return Ok(ConsulAddr::Socket(addr));
+        }
+        if let Some(path) = s.strip_prefix("unix://") {
+            track_assert!(
+                !path.is_empty(),
+                ErrorKind::InvalidInput,
+                "Empty socket path: {:?}",
+                s
+            );
+            return Ok(ConsulAddr::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = track_assert_some!(
+            s.rsplit_once(':'),
+            ErrorKind::InvalidInput,
+            "Not a `<host>:<port>` address: {:?}",
+            s
+        );
+        track_assert!(
+            !host.is_empty() && !host.contains(':'),
+            ErrorKind::InvalidInput,
+            "Invalid host: {:?}",
+            s
+        );
+        let port = track!(port.parse::<u16>().map_err(Error::from), "addr={:?}", s)?;
+        Ok(ConsulAddr::Host(host.to_owned(), port))
+    }
+}
+impl fmt::Display for ConsulAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConsulAddr::Socket(ref addr) => write!(f, "{}", addr),
+            ConsulAddr::Host(ref host, port) => write!(f, "{}:{}", host, port),
+            ConsulAddr::Unix(ref path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+impl Serialize for ConsulAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for ConsulAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The addresses of the consul agents shared by the clones of a `ConsulSettings`.
+///
+/// Requests are sent to the first available agent in the order of the addresses.
+/// An agent becomes unavailable for `AgentAddr::FAILURE_BACKOFF` when a request to it fails
+/// (i.e., the agent cannot be connected or returns a 5xx response), and requests fail over to the next one
+/// in the meantime. If all the agents are unavailable, the one which failed earliest is used.
+///
+/// A hostname is resolved on a background thread, so that fibers are never blocked by DNS lookups,
+/// and is re-resolved every `interval` so that the agent can fail over by DNS.
+/// The thread is started by the first use of the address, and stops after all the clones are dropped.
+#[derive(Clone)]
+pub(crate) struct AgentAddr {
+    agents: Arc<Vec<Agent>>,
+}
+impl AgentAddr {
+    /// The period for which an agent is not used after a request to it failed.
+    pub const FAILURE_BACKOFF: Duration = Duration::from_secs(10);
+
+    /// Makes a new `AgentAddr` instance.
+    ///
+    /// `addrs` must not be empty.
+    pub fn new(addrs: Vec<ConsulAddr>, interval: Duration) -> Self {
+        assert!(!addrs.is_empty());
+        let agents = addrs
+            .into_iter()
+            .map(|addr| {
+                let resolver = if let ConsulAddr::Host(ref host, port) = addr {
+                    Some(Arc::new(Resolver {
+                        host: host.clone(),
+                        port,
+                        interval,
+                        resolved: Mutex::new(None),
+                        started: Once::new(),
+                    }))
+                } else {
+                    None
+                };
+                Agent {
+                    addr,
+                    resolver,
+                    failed_at: Mutex::new(None),
+                }
+            })
+            .collect();
+        AgentAddr {
+            agents: Arc::new(agents),
+        }
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &ConsulAddr> {
+        self.agents.iter().map(|a| &a.addr)
+    }
+
+    /// Starts resolving the hostnames in the background, if it is not started yet.
+    pub fn start(&self) {
+        for resolver in self.agents.iter().filter_map(|a| a.resolver.as_ref()) {
+            resolver.start();
+        }
+    }
+
+    /// Returns the host part of the URLs of the HTTP API, which is made from the first address.
+    ///
+    /// Since a Unix domain socket has no host, `localhost` is used for it.
+    pub fn url_host(&self) -> String {
+        if let ConsulAddr::Unix(_) = self.agents[0].addr {
+            "localhost".to_owned()
+        } else {
+            self.agents[0].addr.to_string()
+        }
+    }
+
+    /// Returns the index of the agent to which the next request is sent, and its endpoint.
+    ///
+    /// Agents whose hostnames have not been resolved yet are skipped. Fails if there are no such agents.
+    pub fn endpoint(&self) -> Result<(usize, AgentEndpoint)> {
+        let now = Instant::now();
+        let mut order = self
+            .agents
+            .iter()
+            .enumerate()
+            .map(|(i, agent)| {
+                let failed_at = *agent.failed_at.lock().expect("Never fails");
+                let failed_at = failed_at.filter(|&t| now < t + Self::FAILURE_BACKOFF);
+                (failed_at, i)
+            })
+            .collect::<Vec<_>>();
+        // `None` (i.e., available) is ordered before `Some(_)`.
+        order.sort();
+
+        let mut error = None;
+        for (_, i) in order {
+            match track!(self.agents[i].endpoint()) {
+                Ok(endpoint) => return Ok((i, endpoint)),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.expect("Never fails"))
+    }
+
+    /// Records the result of a request to the `index`-th agent.
+    pub fn report(&self, index: usize, ok: bool) {
+        let agent = &self.agents[index];
+        let mut failed_at = agent.failed_at.lock().expect("Never fails");
+        if ok {
+            if failed_at.take().is_some() && self.agents.len() > 1 {
+                log::info!("The consul agent {} is available again", agent.addr);
+            }
+        } else {
+            if failed_at.is_none() && self.agents.len() > 1 {
+                log::warn!(
+                    "The consul agent {} is unavailable; requests fail over to the other agents for {:?}",
+                    agent.addr,
+                    Self::FAILURE_BACKOFF
+                );
+            }
+            *failed_at = Some(Instant::now());
+        }
+    }
+}
+impl PartialEq for AgentAddr {
+    fn eq(&self, other: &Self) -> bool {
+        self.addrs().eq(other.addrs())
+    }
+}
+impl fmt::Display for AgentAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, addr) in self.addrs().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", addr)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for AgentAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AgentAddr({:?})", self.to_string())
+    }
+}
+
+struct Agent {
+    addr: ConsulAddr,
+    resolver: Option<Arc<Resolver>>,
+    failed_at: Mutex<Option<Instant>>,
+}
+impl Agent {
+    /// Fails if the hostname has not been resolved yet.
+    fn endpoint(&self) -> Result<AgentEndpoint> {
+        match self.addr {
+            ConsulAddr::Socket(addr) => Ok(AgentEndpoint::Tcp(addr)),
+            ConsulAddr::Unix(ref path) => Ok(AgentEndpoint::Unix(path.clone())),
+            ConsulAddr::Host(ref host, _) => {
+                let resolver = self.resolver.as_ref().expect("Never fails");
+                resolver.start();
+                let resolved = *resolver.resolved.lock().expect("Never fails");
+                let addr = track_assert_some!(
+                    resolved,
+                    ErrorKind::ConsulUnavailable,
+                    "The consul agent host {:?} has not been resolved yet",
+                    host
+                );
+                Ok(AgentEndpoint::Tcp(addr))
+            }
+        }
+    }
+}
+
+struct Resolver {
+    host: String,
+    port: u16,
+    interval: Duration,
+    resolved: Mutex<Option<SocketAddr>>,
+    started: Once,
+}
+impl Resolver {
+    /// The interval of retries until the hostname is resolved first.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn start(self: &Arc<Self>) {
+        self.started.call_once(|| {
+            let resolver = Arc::downgrade(self);
+            let thread = thread::Builder::new()
+                .name("cotoxy-resolver".to_owned())
+                .spawn(move || run(resolver));
+            if let Err(e) = thread {
+                log::warn!("Cannot start resolving {:?}: {}", self.host, e);
+            }
+        });
+    }
+
+    /// Resolves the hostname, and returns `true` if an address is found.
+    fn resolve(&self) -> bool {
+        let addr = match (self.host.as_str(), self.port).to_socket_addrs() {
+            Err(e) => {
+                log::warn!(
+                    "Cannot resolve the consul agent host {:?}: {}",
+                    self.host,
+                    e
+                );
+                return false;
+            }
+            Ok(mut addrs) => {
+                if let Some(addr) = addrs.next() {
+                    addr
+                } else {
+                    log::warn!("No addresses of the consul agent host {:?}", self.host);
+                    return false;
+                }
+            }
+        };
+        let mut resolved = self.resolved.lock().expect("Never fails");
+        if *resolved != Some(addr) {
+            log::info!("Resolved the consul agent host {:?} to {}", self.host, addr);
+            *resolved = Some(addr);
+        }
+        true
+    }
+}
+
+fn run(resolver: Weak<Resolver>) {
+    while let Some(resolver) = resolver.upgrade() {
+        let interval =
+            if resolver.resolve() || resolver.resolved.lock().expect("Never fails").is_some() {
+                resolver.interval
+            } else {
+                Resolver::RETRY_INTERVAL.min(resolver.interval)
+            };
+        drop(resolver);
+        thread::sleep(interval);
+    }
+}
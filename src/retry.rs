@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use random;
+use {Error, ErrorKind, Result};
+
+/// A policy of retrying failed requests with exponential backoff.
+///
+/// A request is retried up to `max_retries` times. The `n`-th retry (counting from zero) waits
+/// `initial_backoff * 2^n`, capped at `max_backoff` and randomly scaled within `±jitter` of it.
+///
+/// This is (de)serialized as a table which has the `max_retries`, `initial_backoff_ms`,
+/// `max_backoff_ms` and `jitter` (default: `0.1`) fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawRetryPolicy", into = "RawRetryPolicy")]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+}
+impl RetryPolicy {
+    /// The default jitter fraction applied to backoffs.
+    pub const DEFAULT_JITTER: f64 = 0.1;
+
+    /// Makes a new `RetryPolicy` instance.
+    pub fn new(
+        max_retries: u32,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        jitter: f64,
+    ) -> Result<Self> {
+        track_assert!(
+            initial_backoff <= max_backoff,
+            ErrorKind::Config,
+            "The initial backoff {:?} exceeds the maximum {:?}",
+            initial_backoff,
+            max_backoff
+        );
+        track_assert!(
+            (0.0..=1.0).contains(&jitter),
+            ErrorKind::Config,
+            "Jitter must be in the range [0.0, 1.0]: {}",
+            jitter
+        );
+        Ok(RetryPolicy {
+            max_retries,
+            initial_backoff,
+            max_backoff,
+            jitter,
+        })
+    }
+
+    /// Returns the maximum number of retries.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Returns the backoff before the first retry.
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff
+    }
+
+    /// Returns the upper bound of backoffs (before jitter is applied).
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// Returns the jitter fraction applied to backoffs.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Returns the backoff before the `retry`-th retry (counting from zero).
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << retry.min(31))
+            .map_or(self.max_backoff, |b| b.min(self.max_backoff));
+        random::jitter(backoff, self.jitter)
+    }
+}
+impl TryFrom<RawRetryPolicy> for RetryPolicy {
+    type Error = Error;
+    fn try_from(f: RawRetryPolicy) -> Result<Self> {
+        track!(RetryPolicy::new(
+            f.max_retries,
+            Duration::from_millis(f.initial_backoff_ms),
+            Duration::from_millis(f.max_backoff_ms),
+            f.jitter
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetryPolicy {
+    max_retries: u32,
+    initial_backoff_ms: u64,
+    max_backoff_ms: u64,
+
+    #[serde(default = "default_jitter")]
+    jitter: f64,
+}
+impl From<RetryPolicy> for RawRetryPolicy {
+    fn from(f: RetryPolicy) -> Self {
+        RawRetryPolicy {
+            max_retries: f.max_retries,
+            initial_backoff_ms: f.initial_backoff.as_millis() as u64,
+            max_backoff_ms: f.max_backoff.as_millis() as u64,
+            jitter: f.jitter,
+        }
+    }
+}
+
+fn default_jitter() -> f64 {
+    RetryPolicy::DEFAULT_JITTER
+}
+
+/// A policy of trying the candidate servers of a connection.
+///
+/// The candidates are tried in order until one of them is connected, up to `max_attempts` connects
+/// (all of them, if `None`), each of which times out after `connect_timeout`
+/// (`ProxyServerBuilder::connect_timeout`, if `None`).
+/// The `n`-th retry (counting from zero) waits `initial_backoff * 2^n`, capped at `max_backoff`
+/// and randomly scaled within `±RetryPolicy::DEFAULT_JITTER` of it (no wait, if `initial_backoff` is zero).
+/// If `requery` is `true` and all the candidates have failed, the candidates are queried to Consul once more
+/// and tried again (within `max_attempts`).
+///
+/// This is (de)serialized as a table which has the `max_attempts`, `connect_timeout_ms`,
+/// `initial_backoff_ms` (default: `0`), `max_backoff_ms` (default: `0`) and `requery` (default: `false`) fields.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawUpstreamRetryPolicy", into = "RawUpstreamRetryPolicy")]
+pub struct UpstreamRetryPolicy {
+    max_attempts: Option<u32>,
+    connect_timeout: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    requery: bool,
+}
+impl UpstreamRetryPolicy {
+    /// Makes a new `UpstreamRetryPolicy` instance.
+    pub fn new(
+        max_attempts: Option<u32>,
+        connect_timeout: Option<Duration>,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        requery: bool,
+    ) -> Result<Self> {
+        track_assert!(
+            max_attempts != Some(0),
+            ErrorKind::Config,
+            "Max attempts must be positive"
+        );
+        track_assert!(
+            connect_timeout != Some(Duration::from_secs(0)),
+            ErrorKind::Config,
+            "Zero connect timeout"
+        );
+        track_assert!(
+            initial_backoff <= max_backoff,
+            ErrorKind::Config,
+            "The initial backoff {:?} exceeds the maximum {:?}",
+            initial_backoff,
+            max_backoff
+        );
+        Ok(UpstreamRetryPolicy {
+            max_attempts,
+            connect_timeout,
+            initial_backoff,
+            max_backoff,
+            requery,
+        })
+    }
+
+    /// Returns the maximum number of connects per connection, if limited.
+    pub fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    /// Returns the timeout of each connect, if it overrides `ProxyServerBuilder::connect_timeout`.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Returns the backoff before the first retry.
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff
+    }
+
+    /// Returns the upper bound of backoffs (before jitter is applied).
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// Returns whether the candidates are queried again after all of them have failed.
+    pub fn requery(&self) -> bool {
+        self.requery
+    }
+
+    /// Returns the backoff before the `retry`-th retry (counting from zero), if any.
+    pub(crate) fn backoff(&self, retry: u32) -> Option<Duration> {
+        if self.initial_backoff == Duration::from_secs(0) {
+            return None;
+        }
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << retry.min(31))
+            .map_or(self.max_backoff, |b| b.min(self.max_backoff));
+        Some(random::jitter(backoff, RetryPolicy::DEFAULT_JITTER))
+    }
+}
+impl TryFrom<RawUpstreamRetryPolicy> for UpstreamRetryPolicy {
+    type Error = Error;
+    fn try_from(f: RawUpstreamRetryPolicy) -> Result<Self> {
+        track!(UpstreamRetryPolicy::new(
+            f.max_attempts,
+            f.connect_timeout_ms.map(Duration::from_millis),
+            Duration::from_millis(f.initial_backoff_ms),
+            Duration::from_millis(f.max_backoff_ms),
+            f.requery
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUpstreamRetryPolicy {
+    #[serde(default)]
+    max_attempts: Option<u32>,
+
+    #[serde(default)]
+    connect_timeout_ms: Option<u64>,
+
+    #[serde(default)]
+    initial_backoff_ms: u64,
+
+    #[serde(default)]
+    max_backoff_ms: u64,
+
+    #[serde(default)]
+    requery: bool,
+}
+impl From<UpstreamRetryPolicy> for RawUpstreamRetryPolicy {
+    fn from(f: UpstreamRetryPolicy) -> Self {
+        RawUpstreamRetryPolicy {
+            max_attempts: f.max_attempts,
+            connect_timeout_ms: f.connect_timeout.map(|t| t.as_millis() as u64),
+            initial_backoff_ms: f.initial_backoff.as_millis() as u64,
+            max_backoff_ms: f.max_backoff.as_millis() as u64,
+            requery: f.requery,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+/// A hook which decides how each accepted connection is routed.
+///
+/// A router is added to a proxy server by `ProxyServerBuilder::router`, and is consulted
+/// once per connection after the built-in admission checks (e.g., the CIDR lists and draining)
+/// have passed. Since it is invoked on the accepting fiber, it should return quickly.
+pub trait Router: fmt::Debug + Send + Sync {
+    /// Returns the routing directive for the connection described by `info`.
+    fn route(&self, info: &ConnectionInfo) -> Route;
+}
+
+/// Information about an accepted connection, which is passed to `Router::route`.
+///
+/// More fields (e.g., metadata sniffed from the first bytes) may be added in the future.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectionInfo {
+    /// Address of the client.
+    pub client_addr: SocketAddr,
+
+    /// Address to which the proxy server is bound.
+    pub local_addr: Option<SocketAddr>,
+}
+impl ConnectionInfo {
+    pub(crate) fn new(client_addr: SocketAddr, local_addr: Option<SocketAddr>) -> Self {
+        ConnectionInfo {
+            client_addr,
+            local_addr,
+        }
+    }
+}
+
+/// A routing directive returned by `Router::route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    /// Routes the connection as configured.
+    Default,
+
+    /// Routes the connection to a server of `service` (having `tag`, if any) instead.
+    ///
+    /// The other query parameters (e.g., `dc` and `near`) and the exclusions are retained,
+    /// but the tag switched by a maintenance window is not.
+    Service {
+        /// Name of the service.
+        service: String,
+
+        /// Tag which the server must have.
+        tag: Option<String>,
+    },
+
+    /// Routes the connection to the given server, without querying Consul.
+    ///
+    /// The exclusions and the service port setting are not applied to the server.
+    Backend(SocketAddr),
+
+    /// Rejects (i.e., closes) the connection for the given reason.
+    Reject(String),
+}
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A secret string, such as an ACL token.
+///
+/// The value is redacted in `Debug` and `Display` output and when serialized,
+/// so it does not leak into logs or dumped configurations.
+/// Use `expose` to get the actual value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Arc<str>);
+impl Secret {
+    /// The text shown in place of secret values.
+    pub const REDACTED: &'static str = "<redacted>";
+
+    /// Makes a new `Secret` instance.
+    pub fn new(value: &str) -> Self {
+        Secret(Arc::from(value))
+    }
+
+    /// Returns the actual value of the secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", Self::REDACTED)
+    }
+}
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Self::REDACTED)
+    }
+}
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(Self::REDACTED)
+    }
+}
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|s| Secret::new(&s))
+    }
+}
+
+/// A secret which is given directly or read from a file.
+#[derive(Debug, Clone)]
+pub(crate) enum SecretSource {
+    Value(Secret),
+    File(Arc<SecretFile>),
+}
+impl SecretSource {
+    pub fn file(path: &Path) -> Self {
+        SecretSource::File(Arc::new(SecretFile {
+            path: path.to_owned(),
+            last: Mutex::new(None),
+        }))
+    }
+
+    /// Returns the current value of the secret.
+    pub fn current(&self) -> Option<Secret> {
+        match *self {
+            SecretSource::Value(ref secret) => Some(secret.clone()),
+            SecretSource::File(ref file) => file.read(),
+        }
+    }
+}
+impl PartialEq for SecretSource {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SecretSource::Value(a), SecretSource::Value(b)) => a == b,
+            (SecretSource::File(a), SecretSource::File(b)) => a.path == b.path,
+            _ => false,
+        }
+    }
+}
+impl Eq for SecretSource {}
+
+/// A file which contains a secret (surrounding whitespace is trimmed).
+///
+/// The file is re-read whenever its modification time changes, so that rotated secrets take effect without restarts.
+#[derive(Debug)]
+pub(crate) struct SecretFile {
+    path: PathBuf,
+    last: Mutex<Option<(SystemTime, Secret)>>,
+}
+impl SecretFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the content of the file.
+    ///
+    /// If the file cannot be read (e.g., while it is being replaced), the last content is returned instead.
+    fn read(&self) -> Option<Secret> {
+        let mut last = self.last.lock().expect("Never fails");
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if let Some((time, ref secret)) = *last {
+            if modified == Some(time) {
+                return Some(secret.clone());
+            }
+        }
+        let value = match fs::read_to_string(&self.path) {
+            Err(e) => {
+                log::warn!("Cannot read the secret file {:?}: {}", self.path, e);
+                return last.as_ref().map(|l| l.1.clone());
+            }
+            Ok(value) => value,
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            log::warn!("Empty secret file: {:?}", self.path);
+            return last.as_ref().map(|l| l.1.clone());
+        }
+        let secret = Secret::new(value);
+        if last.as_ref().is_some_and(|l| l.1 != secret) {
+            log::info!("Reloaded the secret file {:?}", self.path);
+        }
+        if let Some(time) = modified {
+            *last = Some((time, secret.clone()));
+        }
+        Some(secret)
+    }
+}
@@ -0,0 +1,127 @@
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use trackable::error::ErrorKindExt;
+use url::Url;
+
+use {Error, ErrorKind, Result};
+
+/// A file which persists the last successful responses to the queries of candidate nodes
+/// (see `ConsulSettings::snapshot_file`).
+///
+/// The file is a JSON object which maps the query URLs to the response bodies,
+/// so that it can be shared by the clients of different queries (e.g., of failover datacenters).
+pub(crate) struct SnapshotFile {
+    path: PathBuf,
+    responses: Mutex<Option<HashMap<String, Arc<Vec<u8>>>>>,
+}
+impl SnapshotFile {
+    pub fn new(path: &Path) -> Self {
+        SnapshotFile {
+            path: path.to_owned(),
+            responses: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the last successful response to the query `url`, if any.
+    ///
+    /// The file is loaded when this or `save` is called for the first time.
+    pub fn load(&self, url: &Url) -> Option<Arc<Vec<u8>>> {
+        let mut responses = self.responses.lock().expect("Never fails");
+        self.loaded(&mut responses).get(url.as_str()).cloned()
+    }
+
+    /// Saves `body` as the last successful response to the query `url`.
+    ///
+    /// The file is rewritten only if the response has changed. Failures of writes are only logged.
+    pub fn save(&self, url: &Url, body: &Arc<Vec<u8>>) {
+        let mut responses = self.responses.lock().expect("Never fails");
+        let responses = self.loaded(&mut responses);
+        if responses.get(url.as_str()) == Some(body) {
+            return;
+        }
+        responses.insert(url.as_str().to_owned(), body.clone());
+
+        // Other proxies may share the file, so the responses written by them are retained.
+        if let Ok(current) = self.read() {
+            for (url, body) in current {
+                responses.entry(url).or_insert(body);
+            }
+        }
+        if let Err(e) = track!(self.write(responses)) {
+            log::warn!("Cannot write the snapshot file {:?}: {}", self.path, e);
+        }
+    }
+
+    fn loaded<'a>(
+        &self,
+        responses: &'a mut Option<HashMap<String, Arc<Vec<u8>>>>,
+    ) -> &'a mut HashMap<String, Arc<Vec<u8>>> {
+        responses.get_or_insert_with(|| match track!(self.read()) {
+            Err(e) => {
+                log::warn!("Cannot read the snapshot file {:?}: {}", self.path, e);
+                HashMap::new()
+            }
+            Ok(responses) => {
+                log::info!(
+                    "Loaded the snapshot file {:?}: queries={}",
+                    self.path,
+                    responses.len()
+                );
+                responses
+            }
+        })
+    }
+
+    fn read(&self) -> Result<HashMap<String, Arc<Vec<u8>>>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let bytes = track!(fs::read(&self.path).map_err(Error::from))?;
+        let values: HashMap<String, Value> = track!(serde_json::from_slice(&bytes)
+            .map_err(|e| Error::from(ErrorKind::DeserializeFailed.cause(e))))?;
+        let mut responses = HashMap::with_capacity(values.len());
+        for (url, value) in values {
+            let body = serde_json::to_vec(&value).expect("Never fails");
+            responses.insert(url, Arc::new(body));
+        }
+        Ok(responses)
+    }
+
+    /// Writes `responses` to a temporary file, and then renames it, so that the file is never left half-written.
+    fn write(&self, responses: &HashMap<String, Arc<Vec<u8>>>) -> Result<()> {
+        // The bodies are valid JSON, since they have been parsed successfully.
+        let mut bytes = b"{".to_vec();
+        for (i, (url, body)) in responses.iter().enumerate() {
+            if i > 0 {
+                bytes.push(b',');
+            }
+            bytes.extend(serde_json::to_vec(url).expect("Never fails"));
+            bytes.push(b':');
+            bytes.extend_from_slice(body);
+        }
+        bytes.push(b'}');
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        track!(
+            fs::write(&temp, &bytes).map_err(Error::from),
+            "path={:?}",
+            temp
+        )?;
+        track!(fs::rename(&temp, &self.path).map_err(Error::from))?;
+        Ok(())
+    }
+}
+impl fmt::Debug for SnapshotFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SnapshotFile {{ path: {:?}, .. }}", self.path)
+    }
+}
@@ -0,0 +1,22 @@
+use fibers::Spawn;
+use futures::Future;
+
+/// A task (e.g., the relay of a connection) spawned by a proxy server.
+pub type Task = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// A spawner of the tasks of proxy servers.
+///
+/// This is implemented for every `fibers::Spawn` (e.g., the handles of `fibers` executors),
+/// and can be implemented for the handles of other executors.
+///
+/// Note that the tasks currently depend on the I/O and timer facilities of `fibers`,
+/// so an implementation must eventually run them on fibers (e.g., by forwarding them to a `fibers` executor).
+pub trait Spawner {
+    /// Spawns `task`, which should be run to completion.
+    fn spawn_task(&self, task: Task);
+}
+impl<S: Spawn> Spawner for S {
+    fn spawn_task(&self, task: Task) {
+        self.spawn_boxed(task);
+    }
+}
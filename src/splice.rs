@@ -0,0 +1,170 @@
+use futures::Async;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use proxy_channel::Endpoint;
+use {Error, ErrorKind, MemoryBudget, Result};
+
+/// A relay buffer which moves bytes between sockets through a kernel pipe using `splice(2)`.
+///
+/// `fibers` wakes up a fiber only after an I/O operation on `TcpStream` returns `WouldBlock`.
+/// So, when `splice(2)` would block, a tiny ordinary read or write is issued instead to
+/// register the readiness monitor, and the bytes it moves are kept in `carry`.
+#[derive(Debug)]
+pub struct SplicePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    capacity: usize,
+    pending: usize,
+    carry: Vec<u8>,
+    budget: MemoryBudget,
+}
+impl SplicePipe {
+    pub fn new(capacity: usize, budget: MemoryBudget) -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(track!(Error::from(io::Error::last_os_error())));
+        }
+        let mut pipe = SplicePipe {
+            read_fd: fds[0],
+            write_fd: fds[1],
+            capacity,
+            pending: 0,
+            carry: Vec::new(),
+            budget,
+        };
+
+        // The kernel rounds the size up to a multiple of the page size, and
+        // rejects sizes larger than `/proc/sys/fs/pipe-max-size` (then the default size is used).
+        let mut size =
+            unsafe { libc::fcntl(pipe.write_fd, libc::F_SETPIPE_SZ, capacity as libc::c_int) };
+        if size < 0 {
+            size = unsafe { libc::fcntl(pipe.write_fd, libc::F_GETPIPE_SZ) };
+        }
+        if size < 0 {
+            return Err(track!(Error::from(io::Error::last_os_error())));
+        }
+        pipe.capacity = size as usize;
+        Ok(pipe)
+    }
+
+    pub fn read_from<R: Endpoint>(
+        &mut self,
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Async<Option<usize>>> {
+        if self.pending >= self.capacity {
+            return Ok(Async::NotReady);
+        }
+        let budget = self.budget.acquire((self.capacity - self.pending).min(max));
+        if budget == 0 {
+            return Ok(Async::NotReady);
+        }
+        let result = self.splice_from(reader, budget);
+        let used = if let Ok(Some(size)) = result { size } else { 0 };
+        self.budget.release(budget - used);
+        result.map(|size| match size {
+            None => Async::NotReady,
+            Some(0) => Async::Ready(None),
+            Some(size) => Async::Ready(Some(size)),
+        })
+    }
+
+    fn splice_from<R: Endpoint>(&mut self, reader: &mut R, len: usize) -> Result<Option<usize>> {
+        let fd = track_assert_some!(reader.raw_fd(), ErrorKind::Other);
+        match splice(fd, self.write_fd, len) {
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(track!(Error::from(e)));
+                }
+                if self.pending > 0 || !self.carry.is_empty() {
+                    // Either the pipe is full or the reader will be retried
+                    // when the pending bytes have been written.
+                    return Ok(None);
+                }
+                let mut buf = [0; 1];
+                match reader.read(&mut buf) {
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            Ok(None)
+                        } else {
+                            Err(track!(Error::from(e)))
+                        }
+                    }
+                    Ok(size) => {
+                        self.carry.extend_from_slice(&buf[..size]);
+                        Ok(Some(size))
+                    }
+                }
+            }
+            Ok(size) => {
+                self.pending += size;
+                Ok(Some(size))
+            }
+        }
+    }
+
+    pub fn write_to<W: Endpoint>(&mut self, writer: &mut W) -> Result<Async<Option<usize>>> {
+        if self.carry.is_empty() {
+            if self.pending == 0 {
+                return Ok(Async::NotReady);
+            }
+            let fd = track_assert_some!(writer.raw_fd(), ErrorKind::Other);
+            match splice(self.read_fd, fd, self.pending) {
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        return Err(track!(Error::from(e)));
+                    }
+                    let mut buf = [0; 1];
+                    let size = unsafe { libc::read(self.read_fd, buf.as_mut_ptr() as _, 1) };
+                    if size < 0 {
+                        return Err(track!(Error::from(io::Error::last_os_error())));
+                    }
+                    self.pending -= size as usize;
+                    self.carry.extend_from_slice(&buf[..size as usize]);
+                }
+                Ok(0) => return Ok(Async::Ready(None)),
+                Ok(size) => {
+                    self.pending -= size;
+                    self.budget.release(size);
+                    return Ok(Async::Ready(Some(size)));
+                }
+            }
+        }
+        match writer.write(&self.carry) {
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(Async::NotReady)
+                } else {
+                    Err(track!(Error::from(e)))
+                }
+            }
+            Ok(0) => Ok(Async::Ready(None)),
+            Ok(size) => {
+                self.carry.drain(..size);
+                self.budget.release(size);
+                Ok(Async::Ready(Some(size)))
+            }
+        }
+    }
+}
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        self.budget.release(self.pending + self.carry.len());
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let size = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if size < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(size as usize)
+    }
+}
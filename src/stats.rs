@@ -0,0 +1,199 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use ChannelClosed;
+
+/// Statistics of a proxy server.
+///
+/// Counters are sharded across worker threads, so updating them on the data path
+/// never contends with other threads or with `snapshot`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    accepted_connections: Counter,
+    active_connections: Counter,
+    churn_bans: Counter,
+    panicked_connections: Counter,
+    upstream_bytes: Counter,
+    downstream_bytes: Counter,
+    backends: RwLock<HashMap<SocketAddr, Arc<BackendCounters>>>,
+}
+impl Stats {
+    /// Makes a new `Stats` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a snapshot of the current statistics.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let backends = self.backends.read().expect("Never fails");
+        StatsSnapshot {
+            accepted_connections: self.accepted_connections.get(),
+            active_connections: self.active_connections.get(),
+            churn_bans: self.churn_bans.get(),
+            panicked_connections: self.panicked_connections.get(),
+            upstream_bytes: self.upstream_bytes.get(),
+            downstream_bytes: self.downstream_bytes.get(),
+            backends: backends
+                .iter()
+                .map(|(addr, b)| {
+                    let stats = BackendStats {
+                        active_connections: b.active_connections.get(),
+                        total_connections: b.total_connections.get(),
+                        upstream_bytes: b.upstream_bytes.get(),
+                        downstream_bytes: b.downstream_bytes.get(),
+                    };
+                    (*addr, stats)
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn increment_accepted(&self) {
+        self.accepted_connections.add(1);
+    }
+
+    pub(crate) fn increment_churn_bans(&self) {
+        self.churn_bans.add(1);
+    }
+
+    pub(crate) fn increment_panicked(&self) {
+        self.panicked_connections.add(1);
+    }
+
+    /// Returns the number of connections currently being proxied to `backend`.
+    pub(crate) fn active_connections_of(&self, backend: SocketAddr) -> u64 {
+        self.backends
+            .read()
+            .expect("Never fails")
+            .get(&backend)
+            .map_or(0, |b| b.active_connections.get())
+    }
+
+    fn backend(&self, backend: SocketAddr) -> Arc<BackendCounters> {
+        if let Some(b) = self.backends.read().expect("Never fails").get(&backend) {
+            return b.clone();
+        }
+        let mut backends = self.backends.write().expect("Never fails");
+        backends.entry(backend).or_default().clone()
+    }
+}
+
+#[derive(Debug, Default)]
+struct BackendCounters {
+    active_connections: Counter,
+    total_connections: Counter,
+    upstream_bytes: Counter,
+    downstream_bytes: Counter,
+}
+
+/// A counter sharded across threads.
+///
+/// Each thread updates its own cache line, and reads sum up all the shards.
+/// Shards are wrapping, so a value incremented on one thread can be decremented on another.
+#[derive(Debug, Default)]
+struct Counter {
+    shards: [Shard; Counter::SHARDS],
+}
+impl Counter {
+    const SHARDS: usize = 16;
+
+    fn add(&self, n: u64) {
+        self.shard().0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn sub(&self, n: u64) {
+        self.shard().0.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.shards
+            .iter()
+            .fold(0, |sum, s| sum.wrapping_add(s.0.load(Ordering::Relaxed)))
+    }
+
+    fn shard(&self) -> &Shard {
+        static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+        thread_local! {
+            static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % Counter::SHARDS;
+        }
+        &self.shards[SHARD.with(|i| *i)]
+    }
+}
+
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Shard(AtomicU64);
+
+/// A snapshot of the statistics of a proxy server.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSnapshot {
+    /// Number of accepted client connections.
+    pub accepted_connections: u64,
+
+    /// Number of connections currently being proxied.
+    pub active_connections: u64,
+
+    /// Number of times clients have been banned for connection churn.
+    pub churn_bans: u64,
+
+    /// Number of connections whose handling panicked.
+    pub panicked_connections: u64,
+
+    /// Number of bytes relayed from clients to backend servers by the closed connections.
+    pub upstream_bytes: u64,
+
+    /// Number of bytes relayed from backend servers to clients by the closed connections.
+    pub downstream_bytes: u64,
+
+    /// Per-backend statistics.
+    pub backends: BTreeMap<SocketAddr, BackendStats>,
+}
+
+/// Statistics of a backend server.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackendStats {
+    /// Number of connections currently being proxied to the backend.
+    pub active_connections: u64,
+
+    /// Number of connections which have been proxied to the backend.
+    pub total_connections: u64,
+
+    /// Number of bytes relayed from clients to the backend by the closed connections.
+    pub upstream_bytes: u64,
+
+    /// Number of bytes relayed from the backend to clients by the closed connections.
+    pub downstream_bytes: u64,
+}
+
+/// A guard which counts a connection as active while it is alive.
+#[derive(Debug)]
+pub(crate) struct ActiveConnection {
+    stats: Arc<Stats>,
+    backend: Arc<BackendCounters>,
+}
+impl ActiveConnection {
+    pub fn new(stats: Arc<Stats>, backend: SocketAddr) -> Self {
+        let backend = stats.backend(backend);
+        stats.active_connections.add(1);
+        backend.active_connections.add(1);
+        backend.total_connections.add(1);
+        ActiveConnection { stats, backend }
+    }
+
+    /// Counts the bytes relayed by the connection, and then stops counting it as active.
+    pub fn finish(self, closed: &ChannelClosed) {
+        self.stats.upstream_bytes.add(closed.upstream_bytes);
+        self.stats.downstream_bytes.add(closed.downstream_bytes);
+        self.backend.upstream_bytes.add(closed.upstream_bytes);
+        self.backend.downstream_bytes.add(closed.downstream_bytes);
+    }
+}
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active_connections.sub(1);
+        self.backend.active_connections.sub(1);
+    }
+}
@@ -0,0 +1,638 @@
+//! Utilities for testing proxy servers (and Consul queries) without a real Consul agent.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate cotoxy;
+//! use cotoxy::testing::{self, EchoServer, InMemoryConsul};
+//! use cotoxy::ProxyServerBuilder;
+//!
+//! # fn main() {
+//! let echo = EchoServer::start().unwrap();
+//! let consul = InMemoryConsul::new();
+//! consul.register("foo", "node0", echo.addr());
+//!
+//! let proxy = testing::spawn_proxy(&mut ProxyServerBuilder::new("foo"), &consul).unwrap();
+//! // Connections to `proxy.local_addr()` are relayed to `echo`.
+//! # }
+//! ```
+use fibers::sync::oneshot;
+use fibers::time::timer::{self, Timeout};
+use futures::{self, Async, Future, Poll};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+use url::Url;
+
+use base64;
+use http::{AgentEndpoint, HttpFuture, HttpMethod, HttpRequest, HttpResponse, HttpTransport};
+use {BackgroundServer, Error, ErrorKind, ProxyServerBuilder, Result};
+
+/// An in-memory substitute for the Consul agent.
+///
+/// This implements `HttpTransport` (or serves over HTTP by `serve_http`), and answers the requests issued by proxy servers:
+/// - `GET /v1/catalog/service/<service>`: returns the registered nodes of the service
+///   (the `dc`, `tag`, `near` and `node_meta` query parameters are ignored).
+/// - `GET /v1/health/service/<service>`: returns the same nodes as the above, in the shape of the health API.
+///   If the `passing` query parameter is given, the nodes made failing by `set_passing` are omitted.
+///
+///   Both support [blocking queries]: responses have the `X-Consul-Index` header, which is incremented
+///   whenever nodes are changed, and a request which has the `index` query parameter waits
+///   until the index exceeds it (or the `wait` query parameter, five minutes by default, expires).
+/// - `GET /v1/event/list?name=<name>`: returns the events fired by `fire_event`.
+/// - `PUT /v1/kv/<key>`: stores the request body.
+///
+/// Clones share the same state.
+///
+/// [blocking queries]: https://www.consul.io/api/features/blocking.html
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryConsul {
+    state: Arc<Mutex<State>>,
+}
+impl InMemoryConsul {
+    /// Makes a new `InMemoryConsul` instance which has no nodes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `node`, on which `service` runs at `addr`.
+    ///
+    /// If the node has already been registered for the service, its address is updated.
+    pub fn register(&self, service: &str, node: &str, addr: SocketAddr) -> &Self {
+        let address = addr.ip().to_string();
+        let tagged_addresses = ["lan", "wan"]
+            .iter()
+            .map(|name| (name.to_string(), address.clone()))
+            .collect();
+        self.insert(
+            service,
+            CatalogNode {
+                node: node.to_owned(),
+                address,
+                tagged_addresses: Some(tagged_addresses),
+                service_address: String::new(),
+                service_port: addr.port(),
+                node_meta: Some(BTreeMap::new()),
+                passing: true,
+            },
+        )
+    }
+
+    /// Registers the external `node`, on which `service` runs at `addr`.
+    ///
+    /// Unlike `register`, the node has neither `TaggedAddresses` nor `NodeMeta` (both are `null`)
+    /// and the address is given as `ServiceAddress`, as is the case for the external services
+    /// registered directly into the catalog (e.g., the nodes managed by consul-esm).
+    pub fn register_external(&self, service: &str, node: &str, addr: SocketAddr) -> &Self {
+        self.insert(
+            service,
+            CatalogNode {
+                node: node.to_owned(),
+                address: addr.ip().to_string(),
+                tagged_addresses: None,
+                service_address: addr.ip().to_string(),
+                service_port: addr.port(),
+                node_meta: None,
+                passing: true,
+            },
+        )
+    }
+
+    fn insert(&self, service: &str, node: CatalogNode) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        let nodes = state.services.entry(service.to_owned()).or_default();
+        nodes.retain(|n| n.node != node.node);
+        nodes.push(node);
+        state.nodes_changed();
+        self
+    }
+
+    /// Deregisters `node` from `service`.
+    pub fn deregister(&self, service: &str, node: &str) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        if let Some(nodes) = state.services.get_mut(service) {
+            nodes.retain(|n| n.node != node);
+        }
+        state.nodes_changed();
+        self
+    }
+
+    /// Sets the metadata entry of `node` in every service.
+    pub fn set_node_meta(&self, node: &str, key: &str, value: &str) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        for n in state
+            .services
+            .values_mut()
+            .flatten()
+            .filter(|n| n.node == node)
+        {
+            n.node_meta
+                .get_or_insert_with(BTreeMap::new)
+                .insert(key.to_owned(), value.to_owned());
+        }
+        state.nodes_changed();
+        self
+    }
+
+    /// Sets whether the health checks of `node` are passing in every service.
+    ///
+    /// Registered nodes are passing by default.
+    pub fn set_passing(&self, node: &str, passing: bool) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        for n in state
+            .services
+            .values_mut()
+            .flatten()
+            .filter(|n| n.node == node)
+        {
+            n.passing = passing;
+        }
+        state.nodes_changed();
+        self
+    }
+
+    /// Fires the user event `name` with `payload` (e.g., a `Command` such as `drain`).
+    pub fn fire_event(&self, name: &str, payload: &str) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        state.ltime += 1;
+        let event = UserEvent {
+            id: format!("event-{}", state.ltime),
+            name: name.to_owned(),
+            payload: base64::encode(payload.as_bytes()),
+            ltime: state.ltime,
+        };
+        state.events.push(event);
+        self
+    }
+
+    /// Returns the value stored at `key` in the KV store.
+    pub fn kv_get(&self, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().expect("Never fails");
+        state.kv.get(key.trim_matches('/')).cloned()
+    }
+
+    /// Serves the requests over HTTP on an ephemeral port of the loopback address, and returns the address.
+    ///
+    /// This is for the clients which cannot use this as an `HttpTransport` (e.g., `ProxyServerBuilder::finish_tokio`).
+    /// Each connection is served by its own thread, and blocking queries are answered immediately.
+    /// The server keeps running until the process exits.
+    pub fn serve_http(&self) -> Result<SocketAddr> {
+        let listener = track!(TcpListener::bind("127.0.0.1:0").map_err(Error::from))?;
+        let addr = track!(listener.local_addr().map_err(Error::from))?;
+        let consul = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let consul = consul.clone();
+                thread::spawn(move || consul.serve_connection(client, addr));
+            }
+        });
+        Ok(addr)
+    }
+
+    /// Answers a request received from `stream` (the connection is closed after the response).
+    fn serve_connection(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        let request = loop {
+            let size = track!(stream.read(&mut chunk).map_err(Error::from))?;
+            track_assert_ne!(size, 0, ErrorKind::Other, "Unexpected end of request");
+            buf.extend_from_slice(&chunk[..size]);
+            if let Some(request) = track!(parse_request(&buf, addr))? {
+                break request;
+            }
+        };
+        let response = track!(self.handle(&request))?;
+        let mut bytes = format!("HTTP/1.0 {} -\r\n", response.status);
+        for (name, value) in &response.headers {
+            bytes.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        bytes.push_str(&format!("Content-Length: {}\r\n\r\n", response.body.len()));
+        let mut bytes = bytes.into_bytes();
+        bytes.extend_from_slice(&response.body);
+        track!(stream.write_all(&bytes).map_err(Error::from))
+    }
+
+    fn handle(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let mut state = self.state.lock().expect("Never fails");
+        let segments = path_segments(&request.url);
+        let segments = segments.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        match (request.method, &segments[..]) {
+            (HttpMethod::Get, ["v1", "catalog", "service", service]) => {
+                let nodes = state.services.get(*service).cloned().unwrap_or_default();
+                let mut response = track!(json_response(&nodes))?;
+                response.headers.push(state.index_header());
+                Ok(response)
+            }
+            (HttpMethod::Get, ["v1", "health", "service", service]) => {
+                let only_passing = request.url.query_pairs().any(|(k, _)| k == "passing");
+                let entries = state
+                    .services
+                    .get(*service)
+                    .into_iter()
+                    .flatten()
+                    .filter(|n| n.passing || !only_passing)
+                    .map(HealthEntry::new)
+                    .collect::<Vec<_>>();
+                let mut response = track!(json_response(&entries))?;
+                response.headers.push(state.index_header());
+                Ok(response)
+            }
+            (HttpMethod::Get, ["v1", "event", "list"]) => {
+                let name = request
+                    .url
+                    .query_pairs()
+                    .find(|(k, _)| k == "name")
+                    .map(|(_, v)| v.into_owned());
+                let events = state
+                    .events
+                    .iter()
+                    .filter(|e| name.as_ref().is_none_or(|n| *n == e.name))
+                    .collect::<Vec<_>>();
+                json_response(&events)
+            }
+            (HttpMethod::Put, ["v1", "kv", key @ ..]) => {
+                state.kv.insert(key.join("/"), request.body.clone());
+                json_response(&true)
+            }
+            _ => Ok(HttpResponse::new(404, Vec::new())),
+        }
+    }
+}
+impl HttpTransport for InMemoryConsul {
+    fn send(&self, request: HttpRequest) -> HttpFuture {
+        let is_service_query = request.method == HttpMethod::Get
+            && path_segments(&request.url)
+                .get(2)
+                .is_some_and(|s| s == "service");
+        let index = request
+            .url
+            .query_pairs()
+            .find(|(k, _)| k == "index")
+            .and_then(|(_, v)| v.parse::<u64>().ok());
+        match index {
+            Some(index) if is_service_query => {
+                let wait = request
+                    .url
+                    .query_pairs()
+                    .find(|(k, _)| k == "wait")
+                    .and_then(|(_, v)| parse_wait(&v))
+                    .unwrap_or(Duration::from_secs(300));
+                Box::new(BlockingQuery {
+                    consul: self.clone(),
+                    request,
+                    index,
+                    timeout: timer::timeout(wait),
+                    changed: None,
+                })
+            }
+            _ => Box::new(futures::done(track!(self.handle(&request)))),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    services: HashMap<String, Vec<CatalogNode>>,
+    index: u64,
+    waiters: Vec<oneshot::Sender<()>>,
+    events: Vec<UserEvent>,
+    ltime: u64,
+    kv: HashMap<String, Vec<u8>>,
+}
+impl State {
+    fn nodes_changed(&mut self) {
+        self.index += 1;
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+
+    fn index_header(&self) -> (String, String) {
+        ("X-Consul-Index".to_owned(), self.index.max(1).to_string())
+    }
+}
+
+/// A service query which waits until the nodes are changed after `index`.
+struct BlockingQuery {
+    consul: InMemoryConsul,
+    request: HttpRequest,
+    index: u64,
+    timeout: Timeout,
+    changed: Option<oneshot::Receiver<()>>,
+}
+impl Future for BlockingQuery {
+    type Item = HttpResponse;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let expired = self.timeout.poll().unwrap_or(Async::Ready(())).is_ready();
+        if !expired {
+            loop {
+                if let Some(ref mut changed) = self.changed {
+                    if let Ok(Async::NotReady) = changed.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                let mut state = self.consul.state.lock().expect("Never fails");
+                if state.index.max(1) > self.index {
+                    break;
+                }
+                let (tx, rx) = oneshot::channel();
+                state.waiters.push(tx);
+                self.changed = Some(rx);
+            }
+        }
+        Ok(Async::Ready(track!(self.consul.handle(&self.request))?))
+    }
+}
+
+/// Parses a wait time of blocking queries (e.g., `500ms`, `10s` or `5m`).
+fn parse_wait(s: &str) -> Option<Duration> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit())?);
+    let n = n.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n * 60)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CatalogNode {
+    #[serde(rename = "Node")]
+    node: String,
+
+    #[serde(rename = "Address")]
+    address: String,
+
+    #[serde(rename = "TaggedAddresses")]
+    tagged_addresses: Option<BTreeMap<String, String>>,
+
+    #[serde(rename = "ServiceAddress")]
+    service_address: String,
+
+    #[serde(rename = "ServicePort")]
+    service_port: u16,
+
+    #[serde(rename = "NodeMeta")]
+    node_meta: Option<BTreeMap<String, String>>,
+
+    #[serde(skip)]
+    passing: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthEntry<'a> {
+    #[serde(rename = "Node")]
+    node: HealthNode<'a>,
+
+    #[serde(rename = "Service")]
+    service: HealthService<'a>,
+}
+impl<'a> HealthEntry<'a> {
+    fn new(node: &'a CatalogNode) -> Self {
+        HealthEntry {
+            node: HealthNode {
+                node: &node.node,
+                address: &node.address,
+                tagged_addresses: &node.tagged_addresses,
+                meta: &node.node_meta,
+            },
+            service: HealthService {
+                address: &node.service_address,
+                port: node.service_port,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthNode<'a> {
+    #[serde(rename = "Node")]
+    node: &'a str,
+
+    #[serde(rename = "Address")]
+    address: &'a str,
+
+    #[serde(rename = "TaggedAddresses")]
+    tagged_addresses: &'a Option<BTreeMap<String, String>>,
+
+    #[serde(rename = "Meta")]
+    meta: &'a Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthService<'a> {
+    #[serde(rename = "Address")]
+    address: &'a str,
+
+    #[serde(rename = "Port")]
+    port: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct UserEvent {
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Name")]
+    name: String,
+
+    #[serde(rename = "Payload")]
+    payload: String,
+
+    #[serde(rename = "LTime")]
+    ltime: u64,
+}
+
+fn path_segments(url: &Url) -> Vec<String> {
+    url.path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            url::form_urlencoded::parse(format!("_={}", s.replace('+', "%2B")).as_bytes())
+                .next()
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Parses `buf` as a request sent to the server at `addr`, returning `None` if more bytes are needed.
+fn parse_request(buf: &[u8], addr: SocketAddr) -> Result<Option<HttpRequest>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    let status = track!(req
+        .parse(buf)
+        .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+    let header_len = match status {
+        httparse::Status::Complete(n) => n,
+        httparse::Status::Partial => return Ok(None),
+    };
+    let content_length = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|h| {
+            String::from_utf8_lossy(h.value)
+                .trim()
+                .parse::<usize>()
+                .ok()
+        })
+        .unwrap_or(0);
+    let body = &buf[header_len..];
+    if body.len() < content_length {
+        return Ok(None);
+    }
+    let method = match req.method {
+        Some("PUT") => HttpMethod::Put,
+        _ => HttpMethod::Get,
+    };
+    let url = format!("http://{}{}", addr, req.path.unwrap_or("/"));
+    let url = track!(Url::parse(&url).map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+    Ok(Some(HttpRequest {
+        method,
+        addr: AgentEndpoint::Tcp(addr),
+        url: Arc::new(url),
+        token: None,
+        tls: None,
+        body: body[..content_length].to_vec(),
+    }))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<HttpResponse> {
+    let body =
+        track!(serde_json::to_vec(value).map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+    Ok(HttpResponse::new(200, body))
+}
+
+/// An `HttpTransport` which returns canned responses in the order they were pushed.
+///
+/// This is useful to test how responses of the Consul agent, including malformed or partial ones,
+/// are handled (e.g., by `ConsulSettings::find_candidates`).
+/// If no responses remain, requests fail.
+///
+/// Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+impl MockTransport {
+    /// Makes a new `MockTransport` instance which has no responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes a response with `status` and `body`.
+    pub fn push_response(&self, status: u16, body: &[u8]) -> &Self {
+        let response = HttpResponse::new(status, body.to_vec());
+        let mut state = self.state.lock().expect("Never fails");
+        state.responses.push_back(Ok(response));
+        self
+    }
+
+    /// Pushes an error, which represents a transport-level failure (e.g., a refused connection).
+    pub fn push_error(&self, error: Error) -> &Self {
+        let mut state = self.state.lock().expect("Never fails");
+        state.responses.push_back(Err(error));
+        self
+    }
+
+    /// Returns the requests received so far.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.state.lock().expect("Never fails").requests.clone()
+    }
+}
+impl HttpTransport for MockTransport {
+    fn send(&self, request: HttpRequest) -> HttpFuture {
+        let mut state = self.state.lock().expect("Never fails");
+        state.requests.push(request);
+        let result = state.responses.pop_front().unwrap_or_else(|| {
+            Err(track!(Error::from(
+                ErrorKind::ConsulUnavailable.cause("No canned responses")
+            )))
+        });
+        Box::new(futures::done(result))
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    responses: VecDeque<Result<HttpResponse>>,
+    requests: Vec<HttpRequest>,
+}
+
+/// A loopback TCP server which echoes back the received bytes.
+///
+/// Each connection is served by its own thread.
+/// The server stops accepting new connections when dropped.
+#[derive(Debug)]
+pub struct EchoServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+impl EchoServer {
+    /// Starts a new echo server on an ephemeral port of the loopback address.
+    pub fn start() -> Result<Self> {
+        let listener = track!(TcpListener::bind("127.0.0.1:0").map_err(Error::from))?;
+        let addr = track!(listener.local_addr().map_err(Error::from))?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = Arc::clone(&stopped);
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(client) = client {
+                    thread::spawn(move || echo(client));
+                }
+            }
+        });
+        Ok(EchoServer { addr, stopped })
+    }
+
+    /// Returns the address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the accepting thread.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn echo(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+        let size = stream.read(&mut buf)?;
+        if size == 0 {
+            return stream.shutdown(Shutdown::Write);
+        }
+        stream.write_all(&buf[..size])?;
+    }
+}
+
+/// Starts a proxy server which is bound to an ephemeral port of the loopback address
+/// and uses `consul` as the Consul agent.
+///
+/// The server runs on a dedicated thread (see `ProxyServerBuilder::spawn_background`),
+/// and stops when the returned `BackgroundServer` is dropped.
+/// Note that `builder` is modified.
+pub fn spawn_proxy(
+    builder: &mut ProxyServerBuilder,
+    consul: &InMemoryConsul,
+) -> Result<BackgroundServer> {
+    builder.bind_addr(([127, 0, 0, 1], 0).into());
+    builder.consul().transport(consul.clone());
+    track!(builder.spawn_background())
+}
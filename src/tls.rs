@@ -0,0 +1,1165 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::sync::{Mutex, OnceLock};
+
+#[cfg(feature = "tls")]
+use openssl::error::ErrorStack;
+#[cfg(feature = "tls")]
+use openssl::pkey::PKey;
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslVersion};
+#[cfg(feature = "tls")]
+use openssl::x509::X509;
+
+#[cfg(feature = "tls")]
+use base64;
+use http::{HttpFuture, HttpRequest};
+use {BoxEndpoint, Error, ErrorKind, Middleware, Result};
+
+/// A version of the TLS protocol.
+///
+/// This is (de)serialized as `"1.0"`, `"1.1"`, `"1.2"` or `"1.3"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TlsVersion {
+    /// TLS 1.0.
+    #[serde(rename = "1.0")]
+    Tls10,
+
+    /// TLS 1.1.
+    #[serde(rename = "1.1")]
+    Tls11,
+
+    /// TLS 1.2.
+    #[serde(rename = "1.2")]
+    Tls12,
+
+    /// TLS 1.3.
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+impl TlsVersion {
+    #[cfg(feature = "tls")]
+    fn ssl_version(self) -> SslVersion {
+        match self {
+            TlsVersion::Tls10 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+impl FromStr for TlsVersion {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => track_panic!(ErrorKind::InvalidInput, "Unknown TLS version: {:?}", s),
+        }
+    }
+}
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsVersion::Tls10 => write!(f, "1.0"),
+            TlsVersion::Tls11 => write!(f, "1.1"),
+            TlsVersion::Tls12 => write!(f, "1.2"),
+            TlsVersion::Tls13 => write!(f, "1.3"),
+        }
+    }
+}
+
+/// The TLS versions, cipher suites and curves which may be negotiated by TLS connections.
+///
+/// The unspecified ones are left to the defaults of OpenSSL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TlsPolicy {
+    pub min_version: Option<TlsVersion>,
+
+    /// The OpenSSL names of the cipher suites, such as `ECDHE-ECDSA-AES128-GCM-SHA256` (TLS 1.2 or earlier)
+    /// and `TLS_AES_128_GCM_SHA256` (TLS 1.3).
+    ///
+    /// The suites of TLS 1.3 are configured separately from the earlier ones,
+    /// thus specifying only either kind leaves the other to the defaults.
+    pub cipher_suites: Vec<String>,
+
+    /// The names of the curves (groups) used for key exchanges, such as `X25519` and `P-256`.
+    pub curves: Vec<String>,
+}
+impl TlsPolicy {
+    /// Checks that OpenSSL knows all of the cipher suites and the curves.
+    ///
+    /// This does nothing if the `tls` feature is disabled.
+    pub fn validate(&self) -> Result<()> {
+        #[cfg(feature = "tls")]
+        {
+            let mut builder =
+                track!(SslConnector::builder(SslMethod::tls_client()).map_err(ssl_error))?;
+            track!(self.apply(&mut builder))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn apply(&self, builder: &mut SslConnectorBuilder) -> Result<()> {
+        if let Some(version) = self.min_version {
+            track!(builder
+                .set_min_proto_version(Some(version.ssl_version()))
+                .map_err(ssl_error))?;
+        }
+        let (tls13, tls12): (Vec<&str>, Vec<&str>) = self
+            .cipher_suites
+            .iter()
+            .map(|s| s.as_str())
+            .partition(|s| s.starts_with("TLS_"));
+
+        // OpenSSL skips unknown names in a list as long as it knows some of them, thus each is checked alone.
+        for suite in &tls12 {
+            track!(
+                builder.set_cipher_list(suite).map_err(ssl_error),
+                "Unknown cipher suite: {:?}",
+                suite
+            )?;
+        }
+        for suite in &tls13 {
+            track!(
+                builder.set_ciphersuites(suite).map_err(ssl_error),
+                "Unknown cipher suite: {:?}",
+                suite
+            )?;
+        }
+        if !tls12.is_empty() {
+            track!(builder.set_cipher_list(&tls12.join(":")).map_err(ssl_error))?;
+        }
+        if !tls13.is_empty() {
+            track!(builder
+                .set_ciphersuites(&tls13.join(":"))
+                .map_err(ssl_error))?;
+        }
+        if !self.curves.is_empty() {
+            track!(
+                builder
+                    .set_groups_list(&self.curves.join(":"))
+                    .map_err(ssl_error),
+                "curves={:?}",
+                self.curves
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// TLS settings of the HTTPS connections to the Consul agent.
+///
+/// These are made by the TLS related methods of `ConsulSettings` (e.g., `ConsulSettings::https`),
+/// and passed to `HttpTransport`s as `HttpRequest::tls`.
+///
+/// `DefaultHttpTransport` supports HTTPS only if the `tls` feature of this crate is enabled.
+#[derive(Default)]
+pub struct TlsSettings {
+    ca_file: Option<PathBuf>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    skip_verify: bool,
+    server_name: Option<String>,
+    policy: TlsPolicy,
+
+    #[cfg(feature = "tls")]
+    connector: OnceLock<Result<SslConnector>>,
+}
+impl TlsSettings {
+    /// Returns the path of the PEM encoded CA certificates used to verify the agent, if specified.
+    ///
+    /// If omitted, the CA certificates of the system are used.
+    pub fn ca_file(&self) -> Option<&Path> {
+        self.ca_file.as_deref()
+    }
+
+    /// Returns the paths of the client certificate and its private key, if specified.
+    pub fn client_cert(&self) -> Option<(&Path, &Path)> {
+        self.client_cert
+            .as_ref()
+            .map(|(cert, key)| (cert.as_path(), key.as_path()))
+    }
+
+    /// Returns `true` if the certificate of the agent is not verified.
+    pub fn skip_verify(&self) -> bool {
+        self.skip_verify
+    }
+
+    /// Returns the name used to verify the certificate of the agent, if specified.
+    ///
+    /// If omitted, the hostname (or the IP address) of the agent is used.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Returns the minimum TLS version negotiated with the agent, if specified.
+    ///
+    /// If omitted, the default of OpenSSL is used.
+    pub fn min_version(&self) -> Option<TlsVersion> {
+        self.policy.min_version
+    }
+
+    /// Returns the OpenSSL names of the cipher suites which may be negotiated with the agent.
+    ///
+    /// If empty, the defaults of OpenSSL are used.
+    pub fn cipher_suites(&self) -> &[String] {
+        &self.policy.cipher_suites
+    }
+
+    /// Returns the names of the curves which may be used for key exchanges with the agent.
+    ///
+    /// If empty, the defaults of OpenSSL are used.
+    pub fn curves(&self) -> &[String] {
+        &self.policy.curves
+    }
+
+    pub(crate) fn set_ca_file(&mut self, path: PathBuf) {
+        self.ca_file = Some(path);
+    }
+
+    pub(crate) fn set_client_cert(&mut self, cert: PathBuf, key: PathBuf) {
+        self.client_cert = Some((cert, key));
+    }
+
+    pub(crate) fn set_skip_verify(&mut self, skip: bool) {
+        self.skip_verify = skip;
+    }
+
+    pub(crate) fn set_server_name(&mut self, name: String) {
+        self.server_name = Some(name);
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: TlsPolicy) {
+        self.policy = policy;
+    }
+
+    /// Loads the certificates and the key, so that they are read before the root directory is changed.
+    ///
+    /// This does nothing if the `tls` feature is disabled.
+    pub(crate) fn load(&self) -> Result<()> {
+        #[cfg(feature = "tls")]
+        track!(self.connector())?;
+        Ok(())
+    }
+
+    /// Returns the connector made by the settings, which is built on the first call.
+    #[cfg(feature = "tls")]
+    fn connector(&self) -> Result<SslConnector> {
+        self.connector
+            .get_or_init(|| track!(self.build_connector()))
+            .clone()
+    }
+
+    #[cfg(feature = "tls")]
+    fn build_connector(&self) -> Result<SslConnector> {
+        use openssl::ssl::SslVerifyMode;
+        use std::fs;
+        use trackable::error::ErrorKindExt;
+
+        let read =
+            |path: &PathBuf| fs::read(path).map_err(|e| Error::from(ErrorKind::Config.cause(e)));
+
+        // The CA certificates of the system are loaded by default.
+        let mut builder =
+            track!(SslConnector::builder(SslMethod::tls_client()).map_err(ssl_error))?;
+        if let Some(ref path) = self.ca_file {
+            let pem = track!(read(path), "ca_file={:?}", path)?;
+            let certs = pem_blocks(&pem, "CERTIFICATE");
+            track_assert!(
+                !certs.is_empty(),
+                ErrorKind::Config,
+                "No certificates: ca_file={:?}",
+                path
+            );
+            for cert in certs {
+                let cert = track!(X509::from_pem(cert).map_err(ssl_error))?;
+                track!(builder.cert_store_mut().add_cert(cert).map_err(ssl_error))?;
+            }
+        }
+        if let Some((ref cert_path, ref key_path)) = self.client_cert {
+            let cert = track!(read(cert_path), "client_cert={:?}", cert_path)?;
+            let key = track!(read(key_path), "client_key={:?}", key_path)?;
+            track!(
+                set_identity(&mut builder, &cert, &key),
+                "client_cert={:?}, client_key={:?}",
+                cert_path,
+                key_path
+            )?;
+        }
+        if self.skip_verify {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        track!(self.policy.apply(&mut builder))?;
+        Ok(builder.build())
+    }
+}
+impl Clone for TlsSettings {
+    fn clone(&self) -> Self {
+        // The cached connector is not cloned, since the settings of the clone may be changed.
+        TlsSettings {
+            ca_file: self.ca_file.clone(),
+            client_cert: self.client_cert.clone(),
+            skip_verify: self.skip_verify,
+            server_name: self.server_name.clone(),
+            policy: self.policy.clone(),
+            #[cfg(feature = "tls")]
+            connector: OnceLock::new(),
+        }
+    }
+}
+impl PartialEq for TlsSettings {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca_file == other.ca_file
+            && self.client_cert == other.client_cert
+            && self.skip_verify == other.skip_verify
+            && self.server_name == other.server_name
+            && self.policy == other.policy
+    }
+}
+impl fmt::Debug for TlsSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsSettings")
+            .field("ca_file", &self.ca_file)
+            .field("client_cert", &self.client_cert)
+            .field("skip_verify", &self.skip_verify)
+            .field("server_name", &self.server_name)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// The certificates used to make mTLS connections to [Connect] sidecar proxies,
+/// which are kept up to date by `ConnectWatcher`.
+///
+/// [Connect]: https://www.consul.io/docs/connect
+#[derive(Default)]
+pub(crate) struct ConnectCerts {
+    #[cfg(feature = "tls")]
+    certs: Mutex<Option<Arc<connect::Certs>>>,
+}
+impl ConnectCerts {
+    /// Replaces the certificates with the PEM encoded `roots` (the CA certificates),
+    /// `cert` (the leaf certificate) and its private key `key`.
+    ///
+    /// Connections made with the new certificates follow `policy`.
+    /// The trust domain and the local datacenter, against which the SPIFFE IDs of sidecar proxies are verified,
+    /// are taken from the SPIFFE ID of `cert`.
+    #[cfg(feature = "tls")]
+    pub fn update(&self, roots: &[&str], cert: &str, key: &str, policy: &TlsPolicy) -> Result<()> {
+        let certs = track!(connect::Certs::new(roots, cert, key, policy))?;
+        *self.certs.lock().expect("Never fails") = Some(Arc::new(certs));
+        Ok(())
+    }
+
+    /// Fails, since Connect is not supported without the `tls` feature.
+    #[cfg(not(feature = "tls"))]
+    pub fn update(&self, roots: &[&str], cert: &str, key: &str, policy: &TlsPolicy) -> Result<()> {
+        let _ = (roots, cert, key, policy);
+        track_panic!(
+            ErrorKind::Config,
+            "Connect is not supported (the `tls` feature is disabled)"
+        )
+    }
+}
+impl fmt::Debug for ConnectCerts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConnectCerts {{ .. }}")
+    }
+}
+
+/// The default template of the SPIFFE IDs of the sidecar proxies (see `ConnectService`).
+pub(crate) const DEFAULT_SPIFFE_ID_TEMPLATE: &str =
+    "spiffe://{trust_domain}/ns/{namespace}/dc/{dc}/svc/{service}";
+
+/// The placeholders of the SPIFFE ID templates.
+const SPIFFE_ID_PLACEHOLDERS: &[&str] = &["service", "namespace", "dc", "trust_domain"];
+
+/// The service whose [Connect] sidecar proxies a connection is made to.
+///
+/// The certificates of the sidecar proxies have to identify the service by the SPIFFE ID
+/// which is made from the template (`DEFAULT_SPIFFE_ID_TEMPLATE` by default),
+/// by replacing `{service}`, `{namespace}`, `{dc}` and `{trust_domain}` in it.
+///
+/// [Connect]: https://www.consul.io/docs/connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub(crate) struct ConnectService {
+    service: String,
+    namespace: String,
+
+    /// The datacenters in which the sidecar proxies may be (`None` is the local one).
+    dcs: Vec<Option<String>>,
+
+    spiffe_id_template: String,
+}
+impl ConnectService {
+    /// Makes the service `service` of `namespace` (or `default`), which is queried in `dc`
+    /// (or the local datacenter) and then in `dc_failover`.
+    pub fn new(
+        service: &str,
+        namespace: Option<&str>,
+        dc: Option<&str>,
+        dc_failover: &[String],
+    ) -> Self {
+        let mut dcs = vec![dc.map(ToOwned::to_owned)];
+        dcs.extend(dc_failover.iter().cloned().map(Some));
+        ConnectService {
+            service: service.to_owned(),
+            namespace: namespace.unwrap_or("default").to_owned(),
+            dcs,
+            spiffe_id_template: DEFAULT_SPIFFE_ID_TEMPLATE.to_owned(),
+        }
+    }
+
+    /// Replaces the template of the SPIFFE ID (see `validate_spiffe_id_template`).
+    pub fn spiffe_id_template(mut self, template: &str) -> Self {
+        self.spiffe_id_template = template.to_owned();
+        self
+    }
+
+    /// Returns `true` if the SPIFFE ID `id` identifies the service in `trust_domain`,
+    /// in which the local datacenter is `local_dc`.
+    #[cfg(feature = "tls")]
+    fn matches(&self, id: &str, trust_domain: &str, local_dc: &str) -> bool {
+        let (domain, path) = match spiffe_id(id) {
+            Some(x) => x,
+            None => return false,
+        };
+        self.dcs.iter().any(|dc| {
+            let dc = dc.as_ref().map_or(local_dc, |dc| dc.as_str());
+            let expected = self
+                .spiffe_id_template
+                .replace("{trust_domain}", trust_domain)
+                .replace("{namespace}", &self.namespace)
+                .replace("{dc}", dc)
+                .replace("{service}", &self.service);
+            spiffe_id(&expected).is_some_and(|(expected_domain, expected_path)| {
+                domain.eq_ignore_ascii_case(expected_domain) && path == expected_path
+            })
+        })
+    }
+}
+
+/// A middleware which makes the server side of connections mTLS with the certificates of `ConnectCerts`,
+/// and verifies that the peers are sidecar proxies of `ConnectService`.
+#[derive(Debug)]
+pub(crate) struct ConnectMiddleware {
+    certs: Arc<ConnectCerts>,
+    service: ConnectService,
+}
+impl ConnectMiddleware {
+    pub fn new(certs: Arc<ConnectCerts>, service: ConnectService) -> Self {
+        ConnectMiddleware { certs, service }
+    }
+}
+impl Middleware for ConnectMiddleware {
+    #[cfg(feature = "tls")]
+    fn wrap(&self, endpoint: BoxEndpoint, peer: SocketAddr) -> Result<BoxEndpoint> {
+        let certs = self.certs.certs.lock().expect("Never fails").clone();
+        let certs = track_assert_some!(
+            certs,
+            ErrorKind::ConsulUnavailable,
+            "The Connect certificates have not been fetched yet"
+        );
+        let endpoint = track!(connect::TlsEndpoint::connect(
+            certs,
+            self.service.clone(),
+            endpoint,
+            peer
+        ))?;
+        Ok(Box::new(endpoint))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn wrap(&self, endpoint: BoxEndpoint, peer: SocketAddr) -> Result<BoxEndpoint> {
+        let _ = (endpoint, &self.certs, &self.service);
+        track_panic!(
+            ErrorKind::Config,
+            "Connect is not supported (the `tls` feature is disabled): peer={}",
+            peer
+        )
+    }
+}
+
+#[cfg(feature = "tls")]
+mod connect {
+    use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslConnector, SslMethod, SslStream};
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::X509;
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use super::{
+        pem_blocks, pem_der, set_identity, spiffe_id, ssl_error, uri_sans, ConnectService,
+        TlsPolicy,
+    };
+    use {BoxEndpoint, Endpoint, Error, ErrorKind, Result};
+
+    /// A connector which presents the leaf certificate, and the trust domain and the local datacenter
+    /// taken from the SPIFFE ID of the certificate.
+    pub struct Certs {
+        connector: SslConnector,
+        trust_domain: String,
+        local_dc: String,
+    }
+    impl Certs {
+        /// Makes a connector which presents `cert` and trusts only `roots`.
+        pub fn new(roots: &[&str], cert: &str, key: &str, policy: &TlsPolicy) -> Result<Self> {
+            let mut builder =
+                track!(SslConnector::builder(SslMethod::tls_client()).map_err(ssl_error))?;
+
+            // Replaces the CA certificates of the system.
+            let mut store = track!(X509StoreBuilder::new().map_err(ssl_error))?;
+            for root in roots {
+                for cert in pem_blocks(root.as_bytes(), "CERTIFICATE") {
+                    let cert = track!(X509::from_pem(cert).map_err(ssl_error))?;
+                    track!(store.add_cert(cert).map_err(ssl_error))?;
+                }
+            }
+            builder.set_cert_store(store.build());
+            track!(set_identity(&mut builder, cert.as_bytes(), key.as_bytes()))?;
+            track!(policy.apply(&mut builder))?;
+            let connector = builder.build();
+
+            // `spiffe://<trust-domain>/ns/<namespace>/dc/<dc>/svc/<service>`
+            let leaf = pem_blocks(cert.as_bytes(), "CERTIFICATE");
+            let leaf = track_assert_some!(
+                leaf.first(),
+                ErrorKind::Config,
+                "No leaf certificate is found"
+            );
+            let uris = uri_sans(&track!(pem_der(leaf))?);
+            let id = uris
+                .iter()
+                .filter_map(|uri| spiffe_id(uri))
+                .find_map(|(domain, path)| match path[..] {
+                    ["ns", _, "dc", dc, "svc", _] => Some((domain.to_owned(), dc.to_owned())),
+                    _ => None,
+                });
+            let (trust_domain, local_dc) = track_assert_some!(
+                id,
+                ErrorKind::Config,
+                "The leaf certificate has no SPIFFE ID of a service: uris={:?}",
+                uris
+            );
+            Ok(Certs {
+                connector,
+                trust_domain,
+                local_dc,
+            })
+        }
+    }
+
+    /// A TLS client endpoint, which handshakes on the first reads (or writes).
+    ///
+    /// Once the handshake completes, the certificate of the peer is verified to identify `ConnectService`.
+    pub struct TlsEndpoint {
+        state: State,
+        certs: Arc<Certs>,
+        service: ConnectService,
+        peer: SocketAddr,
+    }
+    impl TlsEndpoint {
+        pub fn connect(
+            certs: Arc<Certs>,
+            service: ConnectService,
+            endpoint: BoxEndpoint,
+            peer: SocketAddr,
+        ) -> Result<Self> {
+            // Certificates of sidecar proxies identify services by SPIFFE IDs (URI SANs) instead of host names.
+            // They are verified by `verify` after handshakes instead.
+            let config = track!(certs.connector.configure().map_err(ssl_error))?
+                .verify_hostname(false)
+                .use_server_name_indication(false);
+            let result = config.connect(&peer.ip().to_string(), endpoint);
+            let mut endpoint = TlsEndpoint {
+                state: State::Failed,
+                certs,
+                service,
+                peer,
+            };
+            let state = track!(handshake_state(result).map_err(Error::from))?;
+            endpoint.state = track!(endpoint.verify(state).map_err(Error::from))?;
+            Ok(endpoint)
+        }
+
+        fn stream(&mut self) -> io::Result<&mut SslStream<BoxEndpoint>> {
+            if let State::Handshake(_) = self.state {
+                if let State::Handshake(mid) = mem::replace(&mut self.state, State::Failed) {
+                    let state = handshake_state(mid.handshake())?;
+                    self.state = self.verify(state)?;
+                }
+            }
+            match self.state {
+                State::Stream(ref mut s) => Ok(s),
+                State::Handshake(_) => Err(io::ErrorKind::WouldBlock.into()),
+                State::Failed => Err(io::Error::other("TLS handshake has failed")),
+            }
+        }
+
+        /// Fails if the handshake has completed, and the certificate of the peer has no SPIFFE ID of the service.
+        fn verify(&self, state: State) -> io::Result<State> {
+            if let State::Stream(ref stream) = state {
+                let uris = match stream.ssl().peer_certificate() {
+                    Some(cert) => uri_sans(&cert.to_der().map_err(io::Error::other)?),
+                    None => Vec::new(),
+                };
+                let verified = uris.iter().any(|uri| {
+                    self.service
+                        .matches(uri, &self.certs.trust_domain, &self.certs.local_dc)
+                });
+                if !verified {
+                    let e = format!(
+                        "The certificate of the sidecar proxy {} does not identify {:?}: uris={:?}",
+                        self.peer, self.service, uris
+                    );
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+                }
+            }
+            Ok(state)
+        }
+    }
+    impl Read for TlsEndpoint {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.stream()?.read(buf)
+        }
+    }
+    impl Write for TlsEndpoint {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream()?.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream()?.flush()
+        }
+    }
+    impl Endpoint for TlsEndpoint {
+        fn set_cork(&self, cork: bool) -> io::Result<()> {
+            match self.state {
+                State::Handshake(ref s) => s.get_ref().set_cork(cork),
+                State::Stream(ref s) => s.get_ref().set_cork(cork),
+                State::Failed => Ok(()),
+            }
+        }
+    }
+
+    enum State {
+        Handshake(MidHandshakeSslStream<BoxEndpoint>),
+        Stream(SslStream<BoxEndpoint>),
+        Failed,
+    }
+
+    fn handshake_state(
+        result: ::std::result::Result<SslStream<BoxEndpoint>, HandshakeError<BoxEndpoint>>,
+    ) -> io::Result<State> {
+        match result {
+            Ok(stream) => Ok(State::Stream(stream)),
+            Err(HandshakeError::WouldBlock(mid)) => Ok(State::Handshake(mid)),
+            Err(HandshakeError::Failure(mid)) => Err(io::Error::other(mid.into_error())),
+            Err(HandshakeError::SetupFailure(e)) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+/// Makes `builder` present the PEM encoded certificate `chain` (the leaf first) and its private key `key`.
+///
+/// The key may be in the PKCS #8, SEC1 (EC) or PKCS #1 (RSA) format, and has to be unencrypted.
+#[cfg(feature = "tls")]
+fn set_identity(builder: &mut SslConnectorBuilder, chain: &[u8], key: &[u8]) -> Result<()> {
+    let mut certs = pem_blocks(chain, "CERTIFICATE").into_iter();
+    let leaf = track_assert_some!(certs.next(), ErrorKind::Config, "No certificates");
+    let leaf = track!(X509::from_pem(leaf).map_err(ssl_error))?;
+    track!(builder.set_certificate(&leaf).map_err(ssl_error))?;
+    for cert in certs {
+        let cert = track!(X509::from_pem(cert).map_err(ssl_error))?;
+        track!(builder.add_extra_chain_cert(cert).map_err(ssl_error))?;
+    }
+    let key = track!(PKey::private_key_from_pem(key).map_err(ssl_error))?;
+    track!(builder.set_private_key(&key).map_err(ssl_error))?;
+    track!(builder.check_private_key().map_err(ssl_error))
+}
+
+#[cfg(feature = "tls")]
+fn ssl_error(e: ErrorStack) -> Error {
+    use trackable::error::ErrorKindExt;
+
+    Error::from(ErrorKind::Config.cause(e))
+}
+
+/// Decodes the body of a PEM block.
+#[cfg(feature = "tls")]
+fn pem_der(block: &[u8]) -> Result<Vec<u8>> {
+    use trackable::error::ErrorKindExt;
+
+    let text = String::from_utf8_lossy(block);
+    let body = text
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .flat_map(|l| l.chars().filter(|c| !c.is_whitespace()))
+        .collect::<String>();
+    let der = base64::decode(&body)
+        .ok_or_else(|| Error::from(ErrorKind::Config.cause("Malformed (or encrypted) PEM block")));
+    track!(der)
+}
+
+/// Reads a DER encoded value from `bytes`, and returns its tag, contents and the remaining bytes.
+#[cfg(feature = "tls")]
+fn der_read(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *bytes.first()?;
+    let first = *bytes.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (usize::from(first), 2)
+    } else {
+        let n = usize::from(first & 0x7F);
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = bytes
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, &b| (len << 8) | usize::from(b));
+        (len, 2 + n)
+    };
+    let value = bytes.get(header..header + len)?;
+    Some((tag, value, &bytes[header + len..]))
+}
+
+/// Returns the contents of the PEM blocks labeled `label` in `pem` (including their boundaries).
+#[cfg(feature = "tls")]
+fn pem_blocks<'a>(pem: &'a [u8], label: &str) -> Vec<&'a [u8]> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut blocks = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = find(rest, begin.as_bytes()) {
+        let block = &rest[start..];
+        if let Some(len) = find(block, end.as_bytes()) {
+            let len = len + end.len();
+            blocks.push(&block[..len]);
+            rest = &block[len..];
+        } else {
+            break;
+        }
+    }
+    blocks
+}
+
+#[cfg(feature = "tls")]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns the URIs in the subject alternative names of the DER encoded X.509 certificate `cert`.
+#[cfg(feature = "tls")]
+fn uri_sans(cert: &[u8]) -> Vec<String> {
+    /// `id-ce-subjectAltName` (2.5.29.17).
+    const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+    // TBSCertificate ::= SEQUENCE { [0] version, serialNumber, .., subjectPublicKeyInfo,
+    //                               [1] issuerUniqueID, [2] subjectUniqueID, [3] extensions }
+    let extensions = der_read(cert)
+        .and_then(|(_, certificate, _)| der_read(certificate))
+        .and_then(|(_, fields, _)| {
+            let mut rest = fields;
+            while let Some((tag, value, next)) = der_read(rest) {
+                if tag == 0xA3 {
+                    return der_read(value).map(|(_, extensions, _)| extensions);
+                }
+                rest = next;
+            }
+            None
+        });
+
+    let mut uris = Vec::new();
+    let mut rest = extensions.unwrap_or(&[]);
+    while let Some((_, extension, next)) = der_read(rest) {
+        rest = next;
+
+        // Extension ::= SEQUENCE { extnID, critical BOOLEAN DEFAULT FALSE, extnValue OCTET STRING }
+        let mut fields = match der_read(extension) {
+            Some((0x06, oid, fields)) if oid == OID_SUBJECT_ALT_NAME => fields,
+            _ => continue,
+        };
+        while let Some((tag, value, next)) = der_read(fields) {
+            fields = next;
+            if tag != 0x04 {
+                continue;
+            }
+
+            // GeneralNames ::= SEQUENCE OF GeneralName, in which URIs are `[6] IMPLICIT IA5String`
+            let mut names = der_read(value).map_or(&[][..], |(_, names, _)| names);
+            while let Some((tag, name, next)) = der_read(names) {
+                if tag == 0x86 {
+                    uris.push(String::from_utf8_lossy(name).into_owned());
+                }
+                names = next;
+            }
+        }
+    }
+    uris
+}
+
+/// Checks that `template` is a SPIFFE ID which identifies a service (see `ConnectService`),
+/// such as `spiffe://dc1/ns/default/svc/{service}`.
+pub(crate) fn validate_spiffe_id_template(template: &str) -> Result<()> {
+    track_assert!(
+        spiffe_id(template).is_some(),
+        ErrorKind::Config,
+        "Not a SPIFFE ID: {:?}",
+        template
+    );
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = track_assert_some!(
+            rest[start..].find('}'),
+            ErrorKind::Config,
+            "Unclosed placeholder in the SPIFFE ID template: {:?}",
+            template
+        ) + start;
+        let name = &rest[start + 1..end];
+        track_assert!(
+            SPIFFE_ID_PLACEHOLDERS.contains(&name),
+            ErrorKind::Config,
+            "Unknown placeholder {{{}}} in the SPIFFE ID template: {:?}",
+            name,
+            template
+        );
+        rest = &rest[end + 1..];
+    }
+    track_assert!(
+        template.contains("{service}"),
+        ErrorKind::Config,
+        "The SPIFFE ID template does not have the {{service}} placeholder: {:?}",
+        template
+    );
+    Ok(())
+}
+
+/// Splits the SPIFFE ID `id` into its trust domain and path segments.
+fn spiffe_id(id: &str) -> Option<(&str, Vec<&str>)> {
+    const SCHEME: &str = "spiffe://";
+
+    if !id.get(..SCHEME.len())?.eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+    let (domain, path) = id[SCHEME.len()..].split_once('/')?;
+    Some((domain, path.split('/').collect()))
+}
+
+/// Sends `request` over HTTPS to `addr`.
+#[cfg(feature = "tls")]
+pub(crate) fn exchange(addr: SocketAddr, request: HttpRequest) -> HttpFuture {
+    Box::new(exchange::TlsExchange::new(addr, request))
+}
+
+/// Fails, since HTTPS is not supported without the `tls` feature.
+#[cfg(not(feature = "tls"))]
+pub(crate) fn exchange(_addr: SocketAddr, request: HttpRequest) -> HttpFuture {
+    use futures;
+    use trackable::error::ErrorKindExt;
+
+    let e = ErrorKind::Config.cause(format!(
+        "HTTPS is not supported (the `tls` feature is disabled): url={}",
+        request.url
+    ));
+    Box::new(futures::failed(track!(Error::from(e))))
+}
+
+#[cfg(feature = "tls")]
+mod exchange {
+    use fibers::net::futures::Connect;
+    use fibers::net::TcpStream;
+    use futures::{Async, Future, Poll};
+    use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslStream};
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::net::SocketAddr;
+    use trackable::error::ErrorKindExt;
+
+    use http::{self, HttpRequest, HttpResponse};
+    use {Error, ErrorKind, Result};
+
+    /// A future which sends an HTTP request over TLS, and receives the response.
+    ///
+    /// Since `miasht` cannot run on TLS streams, this speaks a minimal subset of HTTP by itself
+    /// (see `http::request_bytes`).
+    pub struct TlsExchange {
+        addr: SocketAddr,
+        request: HttpRequest,
+        state: State,
+    }
+    impl TlsExchange {
+        pub fn new(addr: SocketAddr, request: HttpRequest) -> Self {
+            let connect = TcpStream::connect(addr);
+            TlsExchange {
+                addr,
+                request,
+                state: State::Connect(connect),
+            }
+        }
+
+        fn handshake(&self, stream: TcpStream) -> Result<State> {
+            let tls = self.request.tls.as_ref().expect("Never fails");
+            let connector = track!(tls.connector())?;
+            let domain = tls
+                .server_name()
+                .or_else(|| self.request.url.domain())
+                .map(ToOwned::to_owned)
+                .unwrap_or_else(|| self.addr.ip().to_string());
+            track!(handshake_state(connector.connect(&domain, stream), || {
+                http::request_bytes(&self.request)
+            }))
+        }
+    }
+    impl Future for TlsExchange {
+        type Item = HttpResponse;
+        type Error = Error;
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            loop {
+                let next = match mem::replace(&mut self.state, State::Done) {
+                    State::Connect(mut f) => match track!(f.poll().map_err(into_error))? {
+                        Async::NotReady => {
+                            self.state = State::Connect(f);
+                            return Ok(Async::NotReady);
+                        }
+                        Async::Ready(stream) => track!(self.handshake(stream))?,
+                    },
+                    State::Handshake(mid) => track!(handshake_state(mid.handshake(), || {
+                        http::request_bytes(&self.request)
+                    }))?,
+                    State::Write(mut stream, buf, mut written) => {
+                        match stream.write(&buf[written..]) {
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                self.state = State::Write(stream, buf, written);
+                                return Ok(Async::NotReady);
+                            }
+                            Err(e) => return Err(track!(into_error(e))),
+                            Ok(n) => written += n,
+                        }
+                        if written < buf.len() {
+                            State::Write(stream, buf, written)
+                        } else {
+                            State::Read(stream, Vec::new())
+                        }
+                    }
+                    State::Read(mut stream, mut buf) => {
+                        let mut chunk = [0; 4096];
+                        let eof = match stream.read(&mut chunk) {
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                self.state = State::Read(stream, buf);
+                                return Ok(Async::NotReady);
+                            }
+                            Err(e) => return Err(track!(into_error(e))),
+                            Ok(0) => true,
+                            Ok(n) => {
+                                buf.extend_from_slice(&chunk[..n]);
+                                false
+                            }
+                        };
+                        if let Some(response) = track!(http::parse_response(&buf, eof))? {
+                            return Ok(Async::Ready(response));
+                        }
+                        State::Read(stream, buf)
+                    }
+                    State::Done => panic!("Cannot poll TlsExchange twice"),
+                };
+                // The handshake state is only entered when the handshake would block.
+                let would_block = matches!(next, State::Handshake(_));
+                self.state = next;
+                if would_block {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+
+    enum State {
+        Connect(Connect),
+        Handshake(MidHandshakeSslStream<TcpStream>),
+        Write(SslStream<TcpStream>, Vec<u8>, usize),
+        Read(SslStream<TcpStream>, Vec<u8>),
+        Done,
+    }
+
+    fn handshake_state<F>(
+        result: ::std::result::Result<SslStream<TcpStream>, HandshakeError<TcpStream>>,
+        request_bytes: F,
+    ) -> Result<State>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        match result {
+            Ok(stream) => Ok(State::Write(stream, request_bytes(), 0)),
+            Err(HandshakeError::WouldBlock(mid)) => Ok(State::Handshake(mid)),
+            Err(HandshakeError::Failure(mid)) => Err(track!(Error::from(
+                ErrorKind::ConsulUnavailable.cause(mid.into_error())
+            ))),
+            Err(HandshakeError::SetupFailure(e)) => {
+                Err(track!(Error::from(ErrorKind::Config.cause(e))))
+            }
+        }
+    }
+
+    fn into_error(e: io::Error) -> Error {
+        Error::from(ErrorKind::ConsulUnavailable.cause(e))
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+
+    /// A self-signed certificate of the SPIFFE ID
+    /// `spiffe://11111111-2222-3333-4444-555555555555.consul/ns/default/dc/dc1/svc/web`.
+    const WEB_CERT: &str = "
+-----BEGIN CERTIFICATE-----
+MIIBzTCCAXSgAwIBAgIUdbwxTNXhYH9DeTtbWtrlWvY5qR4wCgYIKoZIzj0EAwIw
+DjEMMAoGA1UEAwwDd2ViMB4XDTI2MTAxNjA4MjIxN1oXDTM2MTAxMzA4MjIxN1ow
+DjEMMAoGA1UEAwwDd2ViMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEXJpTDRYE
+Pa9q9RqEryEZVj0rkyzVOVDDrMS/FMYXLyTUkLtGJt/f8pXIywJ2NThO0/4Upw7d
+YtTMHcQl0f+CAKOBrzCBrDAdBgNVHQ4EFgQUJRY4gpDzK4YGuiRBu9BovwIQ7vow
+HwYDVR0jBBgwFoAUJRY4gpDzK4YGuiRBu9BovwIQ7vowDwYDVR0TAQH/BAUwAwEB
+/zBZBgNVHREEUjBQhk5zcGlmZmU6Ly8xMTExMTExMS0yMjIyLTMzMzMtNDQ0NC01
+NTU1NTU1NTU1NTUuY29uc3VsL25zL2RlZmF1bHQvZGMvZGMxL3N2Yy93ZWIwCgYI
+KoZIzj0EAwIDRwAwRAIgZfOweRb5jdlI0RDLoHeEfEX2c/gVrjcHW4lE3ppzSzwC
+IFWRlZ4ntJj8i8nz+XR/l/Lh/1B3eNOyg15C+Br7ASRX
+-----END CERTIFICATE-----
+";
+
+    const TRUST_DOMAIN: &str = "11111111-2222-3333-4444-555555555555.consul";
+
+    #[test]
+    fn uri_sans_works() {
+        let der = pem_der(pem_blocks(WEB_CERT.as_bytes(), "CERTIFICATE")[0]).unwrap();
+        assert_eq!(
+            uri_sans(&der),
+            [format!(
+                "spiffe://{}/ns/default/dc/dc1/svc/web",
+                TRUST_DOMAIN
+            )]
+        );
+        assert!(uri_sans(&der[..der.len() / 2]).is_empty());
+    }
+
+    #[test]
+    fn spiffe_id_works() {
+        assert_eq!(
+            spiffe_id("SPIFFE://example.consul/ns/default/dc/dc1/svc/web"),
+            Some((
+                "example.consul",
+                vec!["ns", "default", "dc", "dc1", "svc", "web"]
+            ))
+        );
+        assert_eq!(spiffe_id("spiffe://example.consul"), None);
+        assert_eq!(spiffe_id("https://example.consul/ns/default"), None);
+    }
+
+    #[test]
+    fn connect_service_matches_works() {
+        let id = |dc: &str, service: &str| {
+            format!(
+                "spiffe://{}/ns/default/dc/{}/svc/{}",
+                TRUST_DOMAIN, dc, service
+            )
+        };
+
+        let local = ConnectService::new("web", None, None, &[]);
+        assert!(local.matches(&id("dc1", "web"), TRUST_DOMAIN, "dc1"));
+        assert!(!local.matches(&id("dc2", "web"), TRUST_DOMAIN, "dc1"));
+        assert!(!local.matches(&id("dc1", "db"), TRUST_DOMAIN, "dc1"));
+        assert!(!local.matches(&id("dc1", "web"), "other.consul", "dc1"));
+        assert!(!local.matches(&format!("{}/extra", id("dc1", "web")), TRUST_DOMAIN, "dc1"));
+
+        let failover = ConnectService::new("web", None, Some("dc2"), &["dc3".to_owned()]);
+        assert!(!failover.matches(&id("dc1", "web"), TRUST_DOMAIN, "dc1"));
+        assert!(failover.matches(&id("dc2", "web"), TRUST_DOMAIN, "dc1"));
+        assert!(failover.matches(&id("dc3", "web"), TRUST_DOMAIN, "dc1"));
+
+        let namespaced = ConnectService::new("web", Some("team"), None, &[]);
+        assert!(!namespaced.matches(&id("dc1", "web"), TRUST_DOMAIN, "dc1"));
+        assert!(namespaced.matches(
+            &format!("spiffe://{}/ns/team/dc/dc1/svc/web", TRUST_DOMAIN),
+            TRUST_DOMAIN,
+            "dc1"
+        ));
+    }
+
+    #[test]
+    fn spiffe_id_template_works() {
+        let template = "spiffe://dc1/ns/default/svc/{service}";
+        assert!(validate_spiffe_id_template(template).is_ok());
+
+        let service = ConnectService::new("web", None, None, &[]).spiffe_id_template(template);
+        assert!(service.matches("spiffe://dc1/ns/default/svc/web", TRUST_DOMAIN, "dc1"));
+        assert!(service.matches("spiffe://DC1/ns/default/svc/web", TRUST_DOMAIN, "dc2"));
+        assert!(!service.matches("spiffe://dc1/ns/default/svc/db", TRUST_DOMAIN, "dc1"));
+        assert!(!service.matches(
+            &format!("spiffe://{}/ns/default/dc/dc1/svc/web", TRUST_DOMAIN),
+            TRUST_DOMAIN,
+            "dc1"
+        ));
+
+        let service = ConnectService::new("web", Some("team"), Some("dc2"), &[])
+            .spiffe_id_template("spiffe://{trust_domain}/{dc}/{namespace}/{service}");
+        assert!(service.matches(
+            &format!("spiffe://{}/dc2/team/web", TRUST_DOMAIN),
+            TRUST_DOMAIN,
+            "dc1"
+        ));
+        assert!(!service.matches(
+            &format!("spiffe://{}/dc1/team/web", TRUST_DOMAIN),
+            TRUST_DOMAIN,
+            "dc1"
+        ));
+    }
+
+    #[test]
+    fn tls_policy_validate_works() {
+        assert!(TlsPolicy::default().validate().is_ok());
+
+        let policy = TlsPolicy {
+            min_version: Some(TlsVersion::Tls13),
+            cipher_suites: vec![
+                "TLS_AES_128_GCM_SHA256".to_owned(),
+                "ECDHE-ECDSA-AES128-GCM-SHA256".to_owned(),
+            ],
+            curves: vec!["X25519".to_owned(), "P-256".to_owned()],
+        };
+        assert!(policy.validate().is_ok());
+
+        for suite in &["NO-SUCH-CIPHER", "TLS_NO_SUCH_CIPHER"] {
+            let policy = TlsPolicy {
+                cipher_suites: vec!["TLS_AES_128_GCM_SHA256".to_owned(), suite.to_string()],
+                ..TlsPolicy::default()
+            };
+            assert!(policy.validate().is_err(), "suite={:?}", suite);
+        }
+
+        let policy = TlsPolicy {
+            curves: vec!["X25519".to_owned(), "NO-SUCH-CURVE".to_owned()],
+            ..TlsPolicy::default()
+        };
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn validate_spiffe_id_template_works() {
+        assert!(validate_spiffe_id_template(DEFAULT_SPIFFE_ID_TEMPLATE).is_ok());
+        assert!(validate_spiffe_id_template("spiffe://dc1/svc/{service}").is_ok());
+        assert!(validate_spiffe_id_template("https://dc1/svc/{service}").is_err());
+        assert!(validate_spiffe_id_template("spiffe://dc1/svc/web").is_err());
+        assert!(validate_spiffe_id_template("spiffe://dc1/svc/{service").is_err());
+        assert!(validate_spiffe_id_template("spiffe://dc1/{node}/{service}").is_err());
+    }
+}
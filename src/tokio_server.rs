@@ -0,0 +1,451 @@
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::error::Elapsed;
+use tokio::time::Sleep;
+use trackable::error::ErrorKindExt;
+
+use balance::Balancer;
+use cidr::Cidr;
+use consul::{ConsulClient, ServiceNode, TokioFindCandidates};
+use control::Exclusions;
+use error::{ConnectAttempt, ConnectAttempts};
+use proxy_channel::TokioProxyChannel;
+use proxy_server::{self, ServicePort, ACCEPT_RETRY_DELAY};
+use retry::UpstreamRetryPolicy;
+use stats::{ActiveConnection, Stats};
+use {Error, ErrorKind, Result};
+
+/// A proxy server running on the runtime of `tokio`.
+///
+/// This is the counterpart of `ProxyServer` for the `tokio` feature, and is built by `ProxyServerBuilder::finish_tokio`.
+/// The server is a `std::future::Future` which accepts clients and spawns a task (by `tokio::spawn`) for each of them.
+/// Like `ProxyServer`, it keeps running after failures of accepting, so it never completes
+/// (drop it to stop accepting clients).
+#[derive(Debug)]
+pub struct TokioProxyServer {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    accept_retry: Option<Pin<Box<Sleep>>>,
+    context: Arc<TokioContext>,
+}
+impl TokioProxyServer {
+    pub(crate) fn new(listener: TcpListener, context: TokioContext) -> Result<Self> {
+        let local_addr = track!(listener.local_addr().map_err(Error::from))?;
+        log::info!("Proxy server started: addr={}", local_addr);
+        Ok(TokioProxyServer {
+            listener,
+            local_addr,
+            accept_retry: None,
+            context: Arc::new(context),
+        })
+    }
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.context.stats.clone()
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn is_allowed_client(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        if self.context.denied_cidrs.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.context.allowed_cidrs.is_empty()
+            || self.context.allowed_cidrs.iter().any(|c| c.contains(ip))
+    }
+
+    fn handle_client(&mut self, client: TcpStream, addr: SocketAddr) {
+        self.context.stats.increment_accepted();
+        if !self.is_allowed_client(addr) {
+            log::info!("Refused the client {} by the CIDR lists", addr);
+            return;
+        }
+        log::debug!("New client arrived: {}", addr);
+        let select = TokioSelectServer::new(&self.context);
+        tokio::spawn(TokioConnection {
+            context: self.context.clone(),
+            addr,
+            client: Some(client),
+            select,
+            relay: None,
+        });
+    }
+}
+impl Future for TokioProxyServer {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        // Accepts until the listener would block, so that the task is woken up on new clients.
+        loop {
+            if let Some(ref mut retry) = this.accept_retry {
+                if retry.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            this.accept_retry = None;
+            match this.listener.poll_accept(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    // e.g., too many open files
+                    log::warn!(
+                        "Cannot accept a client (retries after {:?}): {}",
+                        ACCEPT_RETRY_DELAY,
+                        e
+                    );
+                    this.accept_retry = Some(Box::pin(tokio::time::sleep(ACCEPT_RETRY_DELAY)));
+                }
+                Poll::Ready(Ok((client, addr))) => this.handle_client(client, addr),
+            }
+        }
+    }
+}
+
+/// The settings shared by the connections of a `TokioProxyServer` (see `ProxyServerBuilder::finish_tokio`).
+#[derive(Debug)]
+pub(crate) struct TokioContext {
+    pub consul: Arc<ConsulClient>,
+    pub service_port: ServicePort,
+    pub connect_timeout: Duration,
+    pub upstream_retry: UpstreamRetryPolicy,
+    pub fallback_servers: Vec<SocketAddr>,
+    pub balancer: Arc<Balancer>,
+    pub buffer_size: usize,
+    pub allowed_cidrs: Vec<Cidr>,
+    pub denied_cidrs: Vec<Cidr>,
+    pub stats: Arc<Stats>,
+}
+
+/// A task which selects a server for a client, and then relays bytes between them.
+struct TokioConnection {
+    context: Arc<TokioContext>,
+    addr: SocketAddr,
+    client: Option<TcpStream>,
+    select: TokioSelectServer,
+    relay: Option<(TokioProxyChannel, ActiveConnection, SocketAddr)>,
+}
+impl TokioConnection {
+    fn poll_relay(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        if self.relay.is_none() {
+            let (server, backend) = match Pin::new(&mut self.select).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => track!(result)?,
+            };
+            let active = ActiveConnection::new(self.context.stats.clone(), backend);
+            let client = self.client.take().expect("Never fails");
+            let _ = client.set_nodelay(true);
+            let _ = server.set_nodelay(true);
+            let channel = TokioProxyChannel::new(client, server, self.context.buffer_size);
+            self.relay = Some((channel, active, backend));
+        }
+        let channel = &mut self.relay.as_mut().expect("Never fails").0;
+        let closed = match Pin::new(channel).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => track!(result)?,
+        };
+        let (_, active, backend) = self.relay.take().expect("Never fails");
+        active.finish(&closed);
+        log::info!(
+            "Connection closed: client={}, server={}, upstream_bytes={}, downstream_bytes={}, duration={:?}, reason={}",
+            self.addr,
+            backend,
+            closed.upstream_bytes,
+            closed.downstream_bytes,
+            closed.duration,
+            closed.reason
+        );
+        Poll::Ready(Ok(()))
+    }
+}
+impl Future for TokioConnection {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.poll_relay(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => Poll::Ready(()),
+            Poll::Ready(Err(e)) => {
+                log::error!("Proxy channel terminated abnormally: {}", e);
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+type TokioConnect =
+    Pin<Box<dyn Future<Output = std::result::Result<io::Result<TcpStream>, Elapsed>> + Send>>;
+
+/// The counterpart of `SelectServer` for `TokioProxyServer`.
+///
+/// The candidates are queried for each connection, and the fallback servers are used at most once per connection.
+struct TokioSelectServer {
+    collect_candidates: Option<TokioFindCandidates>,
+    requery: Option<Arc<ConsulClient>>,
+    excluded: Arc<Exclusions>,
+    connect: Option<TokioConnect>,
+    backoff: Option<Pin<Box<Sleep>>>,
+    candidates: Vec<ServiceNode>,
+    fallback: Vec<SocketAddr>,
+    balancer: Arc<Balancer>,
+    server: Option<(ServiceNode, SocketAddr)>,
+    connect_started: Instant,
+    attempts: ConnectAttempts,
+    service_port: ServicePort,
+    connect_timeout: Duration,
+    retry: UpstreamRetryPolicy,
+}
+impl TokioSelectServer {
+    fn new(context: &TokioContext) -> Self {
+        let excluded = Arc::new(Exclusions::default());
+        let requery = if context.upstream_retry.requery() {
+            Some(context.consul.clone())
+        } else {
+            None
+        };
+        TokioSelectServer {
+            collect_candidates: Some(context.consul.find_candidates_tokio(excluded.clone())),
+            requery,
+            excluded,
+            connect: None,
+            backoff: None,
+            candidates: Vec::new(),
+            fallback: context.fallback_servers.clone(),
+            balancer: context.balancer.clone(),
+            server: None,
+            connect_started: Instant::now(),
+            attempts: ConnectAttempts::default(),
+            service_port: context.service_port.clone(),
+            connect_timeout: context.connect_timeout,
+            retry: context.upstream_retry.clone(),
+        }
+    }
+
+    /// Sets the candidates discovered by Consul, ordered by the load balancing strategy.
+    fn set_candidates(&mut self, mut candidates: Vec<ServiceNode>) {
+        let service_port = &self.service_port;
+        self.balancer
+            .order(&mut candidates, |node| service_port.socket_addr(node));
+        candidates.reverse();
+        self.candidates = candidates;
+    }
+
+    /// Starts querying the candidates once more, if enabled (see `UpstreamRetryPolicy::requery`).
+    fn requery(&mut self) -> bool {
+        if let Some(consul) = self.requery.take() {
+            log::info!(
+                "Queries the candidates again after {} failed attempts",
+                self.attempts.attempts().len()
+            );
+            self.collect_candidates = Some(consul.find_candidates_tokio(self.excluded.clone()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Replaces the candidates with the fallback servers, if any (see `ProxyServerBuilder::fallback_servers`).
+    fn fall_back(&mut self) -> bool {
+        if self.fallback.is_empty() {
+            return false;
+        }
+        log::warn!("Falls back to the static servers {:?}", self.fallback);
+        self.candidates = mem::take(&mut self.fallback)
+            .into_iter()
+            .rev()
+            .map(|addr| ServiceNode::new(String::new(), addr))
+            .collect();
+        self.service_port = ServicePort::Registered;
+        self.collect_candidates = None;
+        self.requery = None;
+        true
+    }
+
+    fn give_up(&mut self) -> Poll<Result<(TcpStream, SocketAddr)>> {
+        let attempts = mem::take(&mut self.attempts);
+        Poll::Ready(Err(track!(Error::from(
+            ErrorKind::NoCandidates.cause(attempts)
+        ))))
+    }
+}
+impl Future for TokioSelectServer {
+    type Output = Result<(TcpStream, SocketAddr)>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            if let Some(ref mut f) = this.collect_candidates {
+                match Pin::new(f).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => {
+                        this.collect_candidates = None;
+                        if this.fallback.is_empty() {
+                            return Poll::Ready(Err(track!(e)));
+                        }
+                        log::warn!("Cannot find candidates: {}", e);
+                        this.fall_back();
+                        continue;
+                    }
+                    Poll::Ready(Ok(candidates)) => {
+                        log::debug!("Candidates: {:?}", candidates);
+                        this.collect_candidates = None;
+                        this.set_candidates(candidates);
+                    }
+                }
+            }
+            if let Some(ref mut backoff) = this.backoff {
+                if backoff.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            this.backoff = None;
+            if this.connect.is_none() {
+                let exhausted = this
+                    .retry
+                    .max_attempts()
+                    .is_some_and(|n| this.attempts.attempts().len() >= n as usize);
+                let candidate = if exhausted {
+                    log::warn!(
+                        "Gave up connecting after {} attempts",
+                        this.attempts.attempts().len()
+                    );
+                    return this.give_up();
+                } else if let Some(candidate) = this.candidates.pop() {
+                    candidate
+                } else if this.attempts.attempts().is_empty() && this.fall_back() {
+                    // No nodes have been discovered.
+                    continue;
+                } else if this.requery() {
+                    continue;
+                } else {
+                    return this.give_up();
+                };
+                let addr = this.service_port.socket_addr(&candidate);
+                log::debug!(
+                    "Next candidate server is {} (node: {})",
+                    addr,
+                    candidate.node
+                );
+                let connect = tokio::time::timeout(this.connect_timeout, TcpStream::connect(addr));
+                this.connect = Some(Box::pin(connect));
+                this.server = Some((candidate, addr));
+                this.connect_started = Instant::now();
+            }
+            let connected = match this.connect.as_mut().map(|f| f.as_mut().poll(cx)) {
+                Some(Poll::Ready(connected)) => connected,
+                _ => return Poll::Pending,
+            };
+            this.connect = None;
+            let (node, addr) = this.server.take().expect("Never fails");
+            match connected.map_err(|_| None).and_then(|r| r.map_err(Some)) {
+                Ok(stream) => {
+                    this.balancer
+                        .record_connect(addr, this.connect_started.elapsed());
+                    log::info!(
+                        "Connected to the server {} (node: {}, status: {:?})",
+                        addr,
+                        node.node,
+                        node.status
+                    );
+                    return Poll::Ready(Ok((stream, addr)));
+                }
+                Err(e) => {
+                    let e = proxy_server::connect_error(e);
+                    log::warn!("Cannot connect to the server {}; {}", addr, e);
+                    if !e.kind().is_retryable() {
+                        return Poll::Ready(Err(track!(e, "server={}", addr)));
+                    }
+                    this.balancer.record_connect(addr, this.connect_timeout);
+                    this.attempts.push(ConnectAttempt {
+                        node: node.node,
+                        addr,
+                        error: e,
+                    });
+                    let tried = this.attempts.attempts().len();
+                    let remaining = !this.candidates.is_empty() || this.requery.is_some();
+                    if remaining && this.retry.max_attempts().is_none_or(|n| tried < n as usize) {
+                        this.backoff = this
+                            .retry
+                            .backoff(tried as u32 - 1)
+                            .map(|d| Box::pin(tokio::time::sleep(d)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+
+    use super::*;
+    use testing::{EchoServer, InMemoryConsul};
+    use ProxyServerBuilder;
+
+    #[test]
+    fn relays_bytes_on_tokio() {
+        let echo = track_try_unwrap!(EchoServer::start());
+        let consul = InMemoryConsul::new();
+        consul.register("echo", "node0", echo.addr());
+        let consul_addr = track_try_unwrap!(consul.serve_http());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Cannot build a runtime");
+        let mut builder = ProxyServerBuilder::new("echo");
+        builder.bind_addr(([127, 0, 0, 1], 0).into());
+        builder.consul().consul_addr(consul_addr);
+        let server = {
+            let _guard = runtime.enter();
+            track_try_unwrap!(builder.finish_tokio())
+        };
+        let proxy_addr = server.local_addr();
+        let stats = server.stats();
+        thread::spawn(move || runtime.block_on(server));
+
+        let mut client = net::TcpStream::connect(proxy_addr).expect("Cannot connect");
+        for _ in 0..3 {
+            client.write_all(b"hello").expect("Cannot write");
+            let mut buf = [0; 5];
+            client.read_exact(&mut buf).expect("Cannot read");
+            assert_eq!(&buf, b"hello");
+        }
+        drop(client);
+        for _ in 0..100 {
+            if stats.snapshot().upstream_bytes == 15 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted_connections, 1);
+        assert_eq!(snapshot.upstream_bytes, 15);
+        assert_eq!(snapshot.downstream_bytes, 15);
+    }
+
+    #[test]
+    fn unsupported_settings_are_rejected() {
+        let mut builder = ProxyServerBuilder::new("echo");
+        builder.cork_delay(Duration::from_millis(1));
+        let e = builder.finish_tokio().expect_err("Unsupported setting");
+        assert_eq!(*e.kind(), ErrorKind::Config);
+
+        let mut builder = ProxyServerBuilder::new("echo");
+        builder.consul().dns_fallback(([127, 0, 0, 1], 8600).into());
+        let e = builder.finish_tokio().expect_err("Unsupported setting");
+        assert_eq!(*e.kind(), ErrorKind::Config);
+    }
+}
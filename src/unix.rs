@@ -0,0 +1,454 @@
+use fibers::fiber::{self, Context};
+use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::sync::oneshot::{Monitor, MonitorError};
+use futures::{Async, Future, Poll, Stream};
+use mio::unix::EventedFd;
+use mio::{self, PollOpt, Ready, Token};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvError;
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+use http::{self, HttpFuture, HttpRequest, HttpResponse};
+use {Error, ErrorKind, Result};
+
+/// Ownership and permissions given to the file of a `UnixListener`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketPermissions {
+    /// File mode (e.g., `0o660`).
+    pub mode: Option<u32>,
+
+    /// Owner user ID.
+    pub uid: Option<u32>,
+
+    /// Owner group ID.
+    pub gid: Option<u32>,
+}
+
+/// Credentials of the process on the other end of a `UnixStream`.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    /// Process ID.
+    pub pid: i32,
+
+    /// User ID.
+    pub uid: u32,
+
+    /// Group ID.
+    pub gid: u32,
+}
+
+/// A Unix domain socket listener which runs on `fibers`.
+///
+/// The socket file is created when the listener is made, and removed when it is dropped.
+#[derive(Debug)]
+pub struct UnixListener {
+    path: PathBuf,
+    state: ListenerState,
+    monitor: Option<Monitor<(), io::Error>>,
+}
+impl UnixListener {
+    /// Binds a new listener to `path`.
+    ///
+    /// A stale socket file at `path` is replaced.
+    /// Errors are reported by the first poll of the listener.
+    pub fn bind<P: AsRef<Path>>(path: P, permissions: &SocketPermissions) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let state = match track!(bind(&path, permissions)) {
+            Ok(listener) => ListenerState::Bound(Some(listener)),
+            Err(e) => ListenerState::Failed(Some(e)),
+        };
+        UnixListener {
+            path,
+            state,
+            monitor: None,
+        }
+    }
+
+    /// Returns the path of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `true` if the listener is ready to accept clients (i.e., it has been set up successfully).
+    pub fn is_listening(&self) -> bool {
+        matches!(self.state, ListenerState::Listening(_))
+    }
+}
+impl Stream for UnixListener {
+    type Item = Connected;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let next = match self.state {
+                ListenerState::Failed(ref mut e) => {
+                    let e = track_assert_some!(e.take(), ErrorKind::Other, "Polled after failure");
+                    return Err(e);
+                }
+                ListenerState::Bound(ref mut listener) => {
+                    let listener = listener.take().expect("Never fails");
+                    let register = |mut c: Context| c.poller().register(Evented(listener));
+                    let future = fiber::with_current_context(register);
+                    ListenerState::Registering(track_assert_some!(
+                        future,
+                        ErrorKind::Other,
+                        "Not in a fiber"
+                    ))
+                }
+                ListenerState::Registering(ref mut f) => {
+                    if let Async::Ready(handle) = track!(f.poll().map_err(register_error))? {
+                        ListenerState::Listening(handle)
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                ListenerState::Listening(ref handle) => {
+                    if let Some(mut monitor) = self.monitor.take() {
+                        if monitor.poll().map_err(into_error)?.is_not_ready() {
+                            self.monitor = Some(monitor);
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                    match handle.inner().0.accept() {
+                        Ok((stream, _)) => {
+                            track!(stream.set_nonblocking(true).map_err(Error::from))?;
+                            let register = |mut c: Context| c.poller().register(Evented(stream));
+                            let future = fiber::with_current_context(register);
+                            let future =
+                                track_assert_some!(future, ErrorKind::Other, "Not in a fiber");
+                            return Ok(Async::Ready(Some(Connected(Some(future)))));
+                        }
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock {
+                                return Err(track!(Error::from(e)));
+                            }
+                            self.monitor = Some(handle.monitor(Interest::Read));
+                        }
+                    }
+                    continue;
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        if let ListenerState::Failed(_) = self.state {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("Cannot remove the socket file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ListenerState {
+    Failed(Option<Error>),
+    Bound(Option<net::UnixListener>),
+    Registering(Register<Evented<net::UnixListener>>),
+    Listening(Arc<EventedHandle<Evented<net::UnixListener>>>),
+}
+
+fn bind(path: &Path, permissions: &SocketPermissions) -> Result<net::UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        track_assert!(
+            metadata.file_type().is_socket(),
+            ErrorKind::Config,
+            "Not a socket file: {:?}",
+            path
+        );
+        track!(
+            fs::remove_file(path).map_err(Error::from),
+            "path={:?}",
+            path
+        )?;
+    }
+    let listener = track!(
+        net::UnixListener::bind(path).map_err(Error::from),
+        "path={:?}",
+        path
+    )?;
+    track!(listener.set_nonblocking(true).map_err(Error::from))?;
+    if let Some(mode) = permissions.mode {
+        let permissions = fs::Permissions::from_mode(mode);
+        track!(
+            fs::set_permissions(path, permissions).map_err(Error::from),
+            "path={:?}",
+            path
+        )?;
+    }
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        track!(
+            std::os::unix::fs::chown(path, permissions.uid, permissions.gid).map_err(Error::from),
+            "path={:?}",
+            path
+        )?;
+    }
+    Ok(listener)
+}
+
+/// A future which represents a `UnixStream` accepted by a `UnixListener`.
+#[derive(Debug)]
+pub struct Connected(Option<Register<Evented<net::UnixStream>>>);
+impl Future for Connected {
+    type Item = UnixStream;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut future = self.0.take().expect("Cannot poll Connected twice");
+        if let Async::Ready(handle) = track!(future.poll().map_err(register_error))? {
+            Ok(Async::Ready(UnixStream {
+                handle,
+                read_monitor: None,
+                write_monitor: None,
+            }))
+        } else {
+            self.0 = Some(future);
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A Unix domain socket stream which runs on `fibers`.
+///
+/// Like `fibers::net::TcpStream`, an operation which would block returns `WouldBlock`
+/// and the current fiber is woken up when the socket becomes available.
+#[derive(Debug)]
+pub struct UnixStream {
+    handle: Arc<EventedHandle<Evented<net::UnixStream>>>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+}
+impl UnixStream {
+    /// Connects to the socket at `path`, and returns a future which registers the stream to the poller.
+    ///
+    /// The connection itself is made synchronously, which does not block for long on local sockets.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Connected> {
+        let path = path.as_ref();
+        let stream = track!(
+            net::UnixStream::connect(path).map_err(Error::from),
+            "path={:?}",
+            path
+        )?;
+        track!(stream.set_nonblocking(true).map_err(Error::from))?;
+        let register = |mut c: Context| c.poller().register(Evented(stream));
+        let future = fiber::with_current_context(register);
+        let future = track_assert_some!(future, ErrorKind::Other, "Not in a fiber");
+        Ok(Connected(Some(future)))
+    }
+
+    /// Returns the credentials of the peer process.
+    #[cfg(target_os = "linux")]
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.handle.inner().0.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+
+    /// Returns the credentials of the peer process.
+    #[cfg(not(target_os = "linux"))]
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Peer credentials are only available on Linux",
+        ))
+    }
+
+    fn operate<F, T>(&mut self, interest: Interest, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut net::UnixStream) -> io::Result<T>,
+    {
+        let monitor = if interest == Interest::Read {
+            &mut self.read_monitor
+        } else {
+            &mut self.write_monitor
+        };
+        loop {
+            if let Some(mut m) = monitor.take() {
+                if m.poll().map_err(into_io_error)?.is_not_ready() {
+                    *monitor = Some(m);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            } else {
+                match f(&mut self.handle.inner().0) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        *monitor = Some(self.handle.monitor(interest));
+                    }
+                    result => return result,
+                }
+            }
+        }
+    }
+}
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.operate(Interest::Read, |s| s.read(buf))
+    }
+}
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.operate(Interest::Write, |s| s.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.operate(Interest::Write, |s| s.flush())
+    }
+}
+
+/// An adapter which makes a std socket registrable to the poller of `fibers`.
+#[derive(Debug)]
+pub struct Evented<T>(pub(crate) T);
+impl<T: AsRawFd> mio::Evented for Evented<T> {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+pub(crate) fn into_io_error(e: MonitorError<io::Error>) -> io::Error {
+    e.unwrap_or_else(|| io::Error::other("Monitor channel disconnected"))
+}
+
+pub(crate) fn into_error(e: MonitorError<io::Error>) -> Error {
+    Error::from(into_io_error(e))
+}
+
+pub(crate) fn register_error(_: RecvError) -> Error {
+    Error::from(io::Error::other("Poller is unavailable"))
+}
+
+/// Sends `request` to the consul agent listening on the Unix domain socket at `path`.
+pub(crate) fn exchange(path: PathBuf, request: HttpRequest) -> HttpFuture {
+    Box::new(UnixExchange {
+        path,
+        state: ExchangeState::Connect(None, http::request_bytes(&request)),
+    })
+}
+
+/// A future which sends an HTTP request over a Unix domain socket, and receives the response.
+///
+/// Since `miasht` cannot run on Unix domain sockets, this speaks a minimal subset of HTTP by itself
+/// (see `http::request_bytes`). The socket is connected by the first poll, which runs on a fiber.
+struct UnixExchange {
+    path: PathBuf,
+    state: ExchangeState,
+}
+impl Future for UnixExchange {
+    type Item = HttpResponse;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match mem::replace(&mut self.state, ExchangeState::Done) {
+                ExchangeState::Connect(None, buf) => {
+                    let f = track!(
+                        UnixStream::connect(&self.path).map_err(into_exchange_error),
+                        "path={:?}",
+                        self.path
+                    )?;
+                    ExchangeState::Connect(Some(f), buf)
+                }
+                ExchangeState::Connect(Some(mut f), buf) => {
+                    match track!(f.poll().map_err(into_exchange_error))? {
+                        Async::NotReady => {
+                            self.state = ExchangeState::Connect(Some(f), buf);
+                            return Ok(Async::NotReady);
+                        }
+                        Async::Ready(stream) => ExchangeState::Write(stream, buf, 0),
+                    }
+                }
+                ExchangeState::Write(mut stream, buf, mut written) => {
+                    match stream.write(&buf[written..]) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            self.state = ExchangeState::Write(stream, buf, written);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(track!(into_exchange_error(Error::from(e)))),
+                        Ok(n) => written += n,
+                    }
+                    if written < buf.len() {
+                        ExchangeState::Write(stream, buf, written)
+                    } else {
+                        ExchangeState::Read(stream, Vec::new())
+                    }
+                }
+                ExchangeState::Read(mut stream, mut buf) => {
+                    let mut chunk = [0; 4096];
+                    let eof = match stream.read(&mut chunk) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            self.state = ExchangeState::Read(stream, buf);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(track!(into_exchange_error(Error::from(e)))),
+                        Ok(0) => true,
+                        Ok(n) => {
+                            buf.extend_from_slice(&chunk[..n]);
+                            false
+                        }
+                    };
+                    if let Some(response) = track!(http::parse_response(&buf, eof))? {
+                        return Ok(Async::Ready(response));
+                    }
+                    ExchangeState::Read(stream, buf)
+                }
+                ExchangeState::Done => panic!("Cannot poll UnixExchange twice"),
+            };
+            self.state = next;
+        }
+    }
+}
+
+enum ExchangeState {
+    Connect(Option<Connected>, Vec<u8>),
+    Write(UnixStream, Vec<u8>, usize),
+    Read(UnixStream, Vec<u8>),
+    Done,
+}
+
+fn into_exchange_error(e: Error) -> Error {
+    Error::from(ErrorKind::ConsulUnavailable.takes_over(e))
+}
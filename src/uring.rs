@@ -0,0 +1,407 @@
+use fibers::fiber;
+use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::sync::oneshot::Monitor;
+use futures::{Async, Future};
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::{Arc, OnceLock};
+use trackable::error::ErrorKindExt;
+
+use proxy_channel::{BufferPool, Endpoint};
+use {Error, ErrorKind, Result};
+
+const RECV_POLL: u64 = 0;
+const RECV: u64 = 1;
+const SEND_POLL: u64 = 2;
+const SEND: u64 = 3;
+const CANCEL: u64 = 4;
+
+/// A relay buffer which moves bytes between sockets by the `recv` and `send` operations of an `io_uring(7)`.
+///
+/// Each operation is linked to a preceding poll, because the kernel does not wait for
+/// the readiness of non-blocking sockets by itself.
+/// The ring is registered to the poller of `fibers`, which wakes up the fiber when completions are queued.
+pub struct UringRelay {
+    ring: IoUring,
+    registration: Registration,
+    monitor: Option<Monitor<(), io::Error>>,
+    buf: Vec<u8>,
+    head: usize,
+    tail: usize,
+    recv: Op,
+    send: Op,
+    pending_cqes: usize,
+    pool: BufferPool,
+}
+impl UringRelay {
+    /// Number of entries of a ring.
+    const ENTRIES: u32 = 8;
+
+    /// Returns `true` if the kernel supports the operations used by relays.
+    ///
+    /// The result is probed once per process.
+    pub fn is_supported() -> bool {
+        static SUPPORTED: OnceLock<bool> = OnceLock::new();
+        *SUPPORTED.get_or_init(|| match probe() {
+            Ok(true) => true,
+            Ok(false) => {
+                log::warn!(
+                    "The kernel lacks io_uring operations for relaying (falls back to epoll)"
+                );
+                false
+            }
+            Err(e) => {
+                log::warn!("io_uring is unavailable (falls back to epoll): {}", e);
+                false
+            }
+        })
+    }
+
+    pub fn new(pool: BufferPool) -> Result<Self> {
+        let ring = track!(IoUring::new(Self::ENTRIES).map_err(Error::from))?;
+        Ok(UringRelay {
+            ring,
+            registration: Registration::Unregistered,
+            monitor: None,
+            buf: pool.allocate(),
+            head: 0,
+            tail: 0,
+            recv: Op::Idle,
+            send: Op::Idle,
+            pending_cqes: 0,
+            pool,
+        })
+    }
+
+    pub fn read_from<R: Endpoint>(
+        &mut self,
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Async<Option<usize>>> {
+        loop {
+            match self.recv {
+                Op::Done(reserved, result) => {
+                    self.recv = Op::Idle;
+                    let size = result.max(0) as usize;
+                    self.pool.budget().release(reserved - size);
+                    if result == -libc::EAGAIN {
+                        continue;
+                    }
+                    let size = track!(check(result))?;
+                    if size == 0 {
+                        return Ok(Async::Ready(None));
+                    }
+                    self.tail += size;
+                    return Ok(Async::Ready(Some(size)));
+                }
+                Op::Idle => {
+                    if track!(self.submit_recv(reader, max))? {
+                        continue;
+                    }
+                    if self.send == Op::Idle || !track!(self.poll_ring())? {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Op::InFlight(_) => {
+                    if !track!(self.poll_ring())? {
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn write_to<W: Endpoint>(&mut self, writer: &mut W) -> Result<Async<Option<usize>>> {
+        loop {
+            match self.send {
+                Op::Done(_, result) => {
+                    self.send = Op::Idle;
+                    if result == -libc::EAGAIN {
+                        continue;
+                    }
+                    let size = track!(check(result))?;
+                    if size == 0 {
+                        return Ok(Async::Ready(None));
+                    }
+                    self.pool.budget().release(size);
+                    self.head += size;
+                    if self.head == self.tail && self.recv == Op::Idle {
+                        self.head = 0;
+                        self.tail = 0;
+                    }
+                    return Ok(Async::Ready(Some(size)));
+                }
+                Op::Idle => {
+                    if self.head == self.tail {
+                        return Ok(Async::NotReady);
+                    }
+                    let fd = track_assert_some!(writer.raw_fd(), ErrorKind::Other);
+                    let len = self.tail - self.head;
+                    let send = opcode::Send::new(
+                        types::Fd(fd),
+                        self.buf[self.head..].as_ptr(),
+                        len as u32,
+                    )
+                    .flags(libc::MSG_NOSIGNAL)
+                    .build();
+                    track!(self.submit(fd, libc::POLLOUT, SEND_POLL, send.user_data(SEND)))?;
+                    self.send = Op::InFlight(len);
+                }
+                Op::InFlight(_) => {
+                    if !track!(self.poll_ring())? {
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Submits a `recv` into the free space of the buffer, and returns `false` if there is none.
+    fn submit_recv<R: Endpoint>(&mut self, reader: &mut R, max: usize) -> Result<bool> {
+        if self.tail == self.buf.len() {
+            if self.head == 0 || self.send != Op::Idle {
+                return Ok(false);
+            }
+            self.buf.copy_within(self.head..self.tail, 0);
+            self.tail -= self.head;
+            self.head = 0;
+        }
+        let budget = self
+            .pool
+            .budget()
+            .acquire((self.buf.len() - self.tail).min(max));
+        if budget == 0 {
+            return Ok(false);
+        }
+        let fd = track_assert_some!(reader.raw_fd(), ErrorKind::Other);
+        let recv = opcode::Recv::new(
+            types::Fd(fd),
+            self.buf[self.tail..].as_mut_ptr(),
+            budget as u32,
+        )
+        .build();
+        if let Err(e) = track!(self.submit(fd, libc::POLLIN, RECV_POLL, recv.user_data(RECV))) {
+            self.pool.budget().release(budget);
+            return Err(e);
+        }
+        self.recv = Op::InFlight(budget);
+        Ok(true)
+    }
+
+    /// Submits `entry` linked to a poll of `events` on `fd`.
+    fn submit(
+        &mut self,
+        fd: RawFd,
+        events: i16,
+        poll_user_data: u64,
+        entry: squeue::Entry,
+    ) -> Result<()> {
+        let poll = opcode::PollAdd::new(types::Fd(fd), events as u32)
+            .build()
+            .user_data(poll_user_data)
+            .flags(squeue::Flags::IO_LINK);
+        unsafe {
+            track_assert!(
+                self.ring.submission().push_multiple(&[poll, entry]).is_ok(),
+                ErrorKind::Other,
+                "The submission queue is full"
+            );
+        }
+        self.pending_cqes += 2;
+        track!(self.ring.submit().map_err(Error::from))?;
+        Ok(())
+    }
+
+    /// Reaps the queued completions, and returns `true` if either operation has completed.
+    ///
+    /// Otherwise, the current fiber will be woken up when the next completion is queued.
+    fn poll_ring(&mut self) -> Result<bool> {
+        loop {
+            if self.reap() {
+                return Ok(true);
+            }
+            if track!(self.poll_monitor())?.is_not_ready() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn reap(&mut self) -> bool {
+        let mut completed = false;
+        for cqe in self.ring.completion() {
+            self.pending_cqes -= 1;
+            match cqe.user_data() {
+                RECV => {
+                    if let Op::InFlight(reserved) = self.recv {
+                        self.recv = Op::Done(reserved, cqe.result());
+                        completed = true;
+                    }
+                }
+                SEND => {
+                    if let Op::InFlight(len) = self.send {
+                        self.send = Op::Done(len, cqe.result());
+                        completed = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        completed
+    }
+
+    fn poll_monitor(&mut self) -> Result<Async<()>> {
+        loop {
+            let next = match self.registration {
+                Registration::Unregistered => {
+                    let fd = unsafe { BorrowedFd::borrow_raw(self.ring.as_raw_fd()) };
+                    let fd = RingFd(track!(fd.try_clone_to_owned().map_err(Error::from))?);
+                    let register = fiber::with_current_context(|mut c| c.poller().register(fd));
+                    let register = track_assert_some!(register, ErrorKind::Other, "Not in a fiber");
+                    Registration::Registering(register)
+                }
+                Registration::Registering(ref mut f) => {
+                    match track!(f.poll().map_err(|e| ErrorKind::Other.cause(e)))? {
+                        Async::NotReady => return Ok(Async::NotReady),
+                        Async::Ready(handle) => Registration::Registered(handle),
+                    }
+                }
+                Registration::Registered(ref handle) => {
+                    let monitor = self
+                        .monitor
+                        .get_or_insert_with(|| handle.monitor(Interest::Read));
+                    let result = monitor.poll().map_err(|e| Error::from(e.map(Error::from)));
+                    if track!(result)?.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                    self.monitor = None;
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.registration = next;
+        }
+    }
+}
+impl fmt::Debug for UringRelay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UringRelay {{ fd: {}, recv: {:?}, send: {:?}, .. }}",
+            self.ring.as_raw_fd(),
+            self.recv,
+            self.send
+        )
+    }
+}
+impl Drop for UringRelay {
+    fn drop(&mut self) {
+        // The kernel may still write to (or read from) the buffer,
+        // so the operations in flight are cancelled and awaited before it is freed.
+        if self.pending_cqes > 0 {
+            let mut cancels = Vec::new();
+            for &user_data in &[RECV_POLL, RECV, SEND_POLL, SEND] {
+                cancels.push(
+                    opcode::AsyncCancel::new(user_data)
+                        .build()
+                        .user_data(CANCEL),
+                );
+            }
+            if unsafe { self.ring.submission().push_multiple(&cancels) }.is_ok() {
+                self.pending_cqes += cancels.len();
+            }
+        }
+        while self.pending_cqes > 0 {
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                if e.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                // The ring is closed (which cancels the operations) without freeing the buffer.
+                log::error!(
+                    "Cannot wait for the cancellation of io_uring operations: {}",
+                    e
+                );
+                std::mem::forget(std::mem::take(&mut self.buf));
+                break;
+            }
+            self.reap();
+        }
+
+        let mut buffered = self.tail - self.head;
+        if let Op::InFlight(reserved) | Op::Done(reserved, _) = self.recv {
+            buffered += reserved;
+        }
+        self.pool.budget().release(buffered);
+        if !self.buf.is_empty() {
+            let buf = std::mem::take(&mut self.buf);
+            self.pool.release(buf);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Idle,
+    InFlight(usize),
+    Done(usize, i32),
+}
+
+#[derive(Debug)]
+enum Registration {
+    Unregistered,
+    Registering(Register<RingFd>),
+    Registered(Arc<EventedHandle<RingFd>>),
+}
+
+/// A duplicated file descriptor of a ring, which becomes readable when completions are queued.
+///
+/// The poller deregisters it asynchronously, so it must stay open even after the ring is dropped.
+#[derive(Debug)]
+struct RingFd(OwnedFd);
+impl Evented for RingFd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+fn probe() -> io::Result<bool> {
+    let ring = IoUring::new(2)?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe)?;
+    Ok([
+        opcode::PollAdd::CODE,
+        opcode::Recv::CODE,
+        opcode::Send::CODE,
+        opcode::AsyncCancel::CODE,
+    ]
+    .iter()
+    .all(|&code| probe.is_supported(code)))
+}
+
+fn check(result: i32) -> Result<usize> {
+    if result < 0 {
+        Err(track!(Error::from(io::Error::from_raw_os_error(-result))))
+    } else {
+        Ok(result as usize)
+    }
+}